@@ -0,0 +1,467 @@
+use crate::stream::{Endpoint, StreamFormat};
+use crab_cash::engine::{
+    AccountCreation, AmlThresholds, DayCount, DisputeWindow, FeeSchedule, InterestPolicy,
+    KycLimits, LedgerConfig, LockScope, PointInTime, RetentionPolicy, RuleChain, StatementPeriod,
+};
+use std::error::Error;
+use std::ffi::OsString;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+/// Command line arguments: `crab_cash [COMMAND] [OPTIONS] <transactions.csv>`
+///
+/// Commands:
+/// - none: print the account snapshots
+/// - `history <client> [--tx <id>]`: print the transaction history of a client,
+///   or the lifecycle of one of its transactions
+/// - `statement [--client <id>] [--from <ts>] [--to <ts>] [--format csv|markdown]`: print the
+///   statements of every client (or one) over a period of timestamps (`from` inclusive, `to` exclusive)
+/// - `trial-balance`: print the totals of every journal account, fails when they don't sum to zero
+/// - `reconcile <external.csv>`: compare the snapshots to an external CSV of the same shape and
+///   print the missing, extra and different clients, fails when there is any
+/// - `diff <before.csv> <after.csv>`: compare two snapshot files and print the change of every
+///   client, followed by their total
+/// - `aml-report [--thresholds <file.toml>]`: print the large deposits and withdrawals, and the
+///   smaller ones structured to stay below the thresholds, per client and period
+/// - `serve [--listen <addr>] [--workers <n>]`: serve a local HTTP/JSON API over the ledger, after
+///   loading the transactions file when one is given
+/// - `stream [--tcp <addr> | --unix <path>] [--format csv|jsonl] [--max-connections <n>]`: apply the
+///   records received on a socket and acknowledge each one, then print the snapshots on SIGINT/SIGTERM
+/// - `repl`: interactive shell applying records typed one at a time, starting from the records of
+///   the transactions file when one is given
+///
+/// Options:
+/// - `--dispute-window <window>`: `unlimited` (default), a duration (`3600`, `15m`, `12h`, `30d`)
+///   or a number of records suffixed by `r` (`1000r`)
+/// - `--compact-every <records>`: compact expired account history every N records
+/// - `--keep-last <n>`: keep at most N transactions per account in memory, the operation log still
+///   grows unless `--operation-log-limit` or `--no-operation-log` is given
+/// - `--deposits-only`: do not keep withdrawals in the account history
+/// - `--drop-expired`: drop transactions out of the dispute window from the account history
+/// - `--spill-dir <dir>`: spill evicted transactions to an on-disk index in `dir`
+/// - `--dedup-dir <dir>`: detect duplicate tx ids with a bloom filter backed by an exact index in `dir`
+/// - `--dedup-memory <size>`: memory used by the bloom filter (`65536`, `512K`, `16M`, `1G`), 16M by default
+/// - `--no-operation-log`: do not keep the log of applied operations, required by statements
+/// - `--operation-log-limit <n>`: keep at most the last N operations in the log
+/// - `--fees <file.toml>`: charge the fees of a TOML fee schedule on deposits and withdrawals
+/// - `--interest-rate <percent>`: annual interest rate paid by `accrue_interest` records
+/// - `--day-count <convention>`: `act/365` (default) or `act/360`, used with `--interest-rate`
+/// - `--account-creation <policy>`: `any-record` (default), `first-deposit` or `registered` (only
+///   the clients of `--registry`)
+/// - `--accounts <file.csv>`: only open accounts for the clients of the `client` column of the file,
+///   can't be combined with `--registry`
+/// - `--registry <file.csv>`: client registry (`client,name,country,kyc_tier,status,opened_on`)
+/// - `--kyc-limits <file.toml>`: deposit and withdrawal limits of registered clients by KYC tier
+/// - `--rules <file.toml>`: ordered validation rules checked before applying each transaction
+/// - `--join-registry`: append the registry fields to snapshots and CSV statements
+/// - `--chargeback-lock <scope>`: lock every wallet of the `client` (default) or only the `wallet`
+/// - `--by-wallet`: write one snapshot per wallet instead of one per client, without registry fields
+/// - `--events <file.jsonl>`: append every event of the ledger to a file, as JSON lines
+/// - `--detectors <file.toml>`: anomaly detectors run on every transaction, rejecting what they
+///   flag only when configured to block
+/// - `--alerts <file.csv>`: write the alerts of the anomaly detectors to a file (`client,rule,txs`)
+/// - `--as-of <point>`: print the snapshots as they were at a timestamp or after a record (`1000r`)
+#[derive(Debug)]
+pub struct CliArgs {
+    pub command: Command,
+    pub input: Option<PathBuf>, // Only optional for the serve, stream and repl commands, unused by diff
+    pub config: LedgerConfig,
+    pub spill_dir: Option<PathBuf>,
+    pub dedup_dir: Option<PathBuf>,
+    pub dedup_memory: usize,
+    pub registry: Option<PathBuf>,
+    pub events: Option<PathBuf>,
+    pub detectors: Option<PathBuf>,
+    pub alerts: Option<PathBuf>,
+    pub join_registry: bool,
+    pub by_wallet: bool,
+    pub as_of: Option<PointInTime>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Command {
+    Process,
+    History {
+        client: u16,
+        tx: Option<u32>,
+    },
+    Statement {
+        client: Option<u16>,
+        period: StatementPeriod,
+        format: StatementFormat,
+    },
+    TrialBalance,
+    AmlReport {
+        thresholds: AmlThresholds,
+    },
+    Reconcile {
+        external: PathBuf,
+    },
+    Diff {
+        before: PathBuf,
+        after: PathBuf,
+    },
+    Serve {
+        listen: String,
+        workers: usize,
+    },
+    Stream {
+        endpoint: Endpoint,
+        format: StreamFormat,
+        max_connections: usize,
+    },
+    Repl,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StatementFormat {
+    Csv,
+    Markdown,
+}
+
+const DEFAULT_DEDUP_MEMORY: usize = 16 * 1024 * 1024;
+const DEFAULT_LISTEN: &str = "127.0.0.1:8080";
+const DEFAULT_WORKERS: usize = 4;
+const DEFAULT_STREAM_ADDR: &str = "127.0.0.1:9090";
+const DEFAULT_MAX_CONNECTIONS: usize = 16;
+
+pub fn parse_args(args: impl IntoIterator<Item = OsString>) -> Result<CliArgs, Box<dyn Error>> {
+    let mut args = args.into_iter().peekable();
+    let mut command = Command::Process;
+    let mut input = None;
+    let mut config = LedgerConfig::default();
+    let mut retention = RetentionPolicy::default();
+    let mut spill_dir = None;
+    let mut dedup_dir = None;
+    let mut dedup_memory = DEFAULT_DEDUP_MEMORY;
+    let mut interest_rate = None;
+    let mut registry = None;
+    let mut accounts = false;
+    let mut events = None;
+    let mut detectors = None;
+    let mut alerts = None;
+    let mut join_registry = false;
+    let mut by_wallet = false;
+    let mut as_of = None;
+    let mut day_count = DayCount::default();
+
+    match args.peek().and_then(|a| a.to_str()) {
+        Some("history") => {
+            args.next();
+            let client = next_value(&mut args, "history")?.parse::<u16>()?;
+            command = Command::History { client, tx: None };
+        }
+        Some("statement") => {
+            args.next();
+            command = Command::Statement {
+                client: None,
+                period: StatementPeriod::default(),
+                format: StatementFormat::Csv,
+            };
+        }
+        Some("trial-balance") => {
+            args.next();
+            command = Command::TrialBalance;
+        }
+        Some("reconcile") => {
+            args.next();
+            let external = PathBuf::from(next_value(&mut args, "reconcile")?);
+            command = Command::Reconcile { external };
+        }
+        Some("diff") => {
+            args.next();
+            let before = PathBuf::from(next_value(&mut args, "diff")?);
+            let after = PathBuf::from(next_value(&mut args, "diff")?);
+            command = Command::Diff { before, after };
+        }
+        Some("aml-report") => {
+            args.next();
+            command = Command::AmlReport {
+                thresholds: AmlThresholds::default(),
+            };
+        }
+        Some("serve") => {
+            args.next();
+            command = Command::Serve {
+                listen: DEFAULT_LISTEN.to_string(),
+                workers: DEFAULT_WORKERS,
+            };
+        }
+        Some("stream") => {
+            args.next();
+            command = Command::Stream {
+                endpoint: Endpoint::Tcp(DEFAULT_STREAM_ADDR.to_string()),
+                format: StreamFormat::Csv,
+                max_connections: DEFAULT_MAX_CONNECTIONS,
+            };
+        }
+        Some("repl") => {
+            args.next();
+            command = Command::Repl;
+        }
+        _ => {}
+    }
+
+    while let Some(arg) = args.next() {
+        match arg.to_str() {
+            Some("--tx") => match &mut command {
+                Command::History { tx, .. } => {
+                    *tx = Some(next_value(&mut args, "--tx")?.parse::<u32>()?);
+                }
+                _ => Err("--tx is only valid with the history command")?,
+            },
+            Some(flag @ ("--listen" | "--workers")) => {
+                let Command::Serve { listen, workers } = &mut command else {
+                    Err(format!("{flag} is only valid with the serve command"))?
+                };
+                let value = next_value(&mut args, flag)?;
+                match flag {
+                    "--listen" => *listen = value,
+                    _ => *workers = value.parse::<usize>()?.max(1),
+                }
+            }
+            // `--format` belongs to the statement command unless streaming
+            Some(flag @ ("--tcp" | "--unix" | "--max-connections" | "--format"))
+                if flag != "--format" || matches!(command, Command::Stream { .. }) =>
+            {
+                let Command::Stream {
+                    endpoint,
+                    format,
+                    max_connections,
+                } = &mut command
+                else {
+                    Err(format!("{flag} is only valid with the stream command"))?
+                };
+                let value = next_value(&mut args, flag)?;
+                match flag {
+                    "--tcp" => *endpoint = Endpoint::Tcp(value),
+                    #[cfg(unix)]
+                    "--unix" => *endpoint = Endpoint::Unix(PathBuf::from(value)),
+                    #[cfg(not(unix))]
+                    "--unix" => Err("Unix sockets are not supported on this platform")?,
+                    "--max-connections" => *max_connections = value.parse::<usize>()?.max(1),
+                    _ => {
+                        *format = match value.as_str() {
+                            "csv" => StreamFormat::Csv,
+                            "jsonl" | "json" => StreamFormat::Jsonl,
+                            _ => Err(format!("unknown stream format {value}"))?,
+                        }
+                    }
+                }
+            }
+            Some(flag @ ("--client" | "--from" | "--to" | "--format")) => {
+                let Command::Statement {
+                    client,
+                    period,
+                    format,
+                } = &mut command
+                else {
+                    Err(format!("{flag} is only valid with the statement command"))?
+                };
+                let value = next_value(&mut args, flag)?;
+                match flag {
+                    "--client" => *client = Some(value.parse::<u16>()?),
+                    "--from" => period.from = Some(value.parse::<u64>()?),
+                    "--to" => period.to = Some(value.parse::<u64>()?),
+                    _ => {
+                        *format = match value.as_str() {
+                            "csv" => StatementFormat::Csv,
+                            "markdown" | "md" | "text" => StatementFormat::Markdown,
+                            _ => Err(format!("unknown statement format {value}"))?,
+                        }
+                    }
+                }
+            }
+            Some("--thresholds") => {
+                let Command::AmlReport { thresholds } = &mut command else {
+                    Err("--thresholds is only valid with the aml-report command")?
+                };
+                let path = next_value(&mut args, "--thresholds")?;
+                let file = std::fs::read_to_string(&path)
+                    .map_err(|err| format!("cannot read AML thresholds {path}: {err}"))?;
+                *thresholds = AmlThresholds::from_toml_str(&file)?;
+            }
+            Some("--dispute-window") => {
+                let value = next_value(&mut args, "--dispute-window")?;
+                config.dispute_window = DisputeWindow::from_str(&value)?;
+            }
+            Some("--compact-every") => {
+                let value = next_value(&mut args, "--compact-every")?;
+                config.compaction_interval = Some(value.parse::<u64>()?);
+            }
+            Some("--keep-last") => {
+                let value = next_value(&mut args, "--keep-last")?;
+                retention.keep_last = Some(value.parse::<usize>()?);
+            }
+            Some("--deposits-only") => retention.deposits_only = true,
+            Some("--drop-expired") => retention.drop_expired = true,
+            Some("--no-operation-log") => retention.skip_operation_log = true,
+            Some("--operation-log-limit") => {
+                let value = next_value(&mut args, "--operation-log-limit")?;
+                retention.operation_log_limit = Some(value.parse::<usize>()?);
+            }
+            Some("--spill-dir") => {
+                spill_dir = Some(PathBuf::from(next_value(&mut args, "--spill-dir")?));
+            }
+            Some("--dedup-dir") => {
+                dedup_dir = Some(PathBuf::from(next_value(&mut args, "--dedup-dir")?));
+            }
+            Some("--dedup-memory") => {
+                dedup_memory = parse_size(&next_value(&mut args, "--dedup-memory")?)?;
+            }
+            Some("--fees") => {
+                let path = next_value(&mut args, "--fees")?;
+                let schedule = std::fs::read_to_string(&path)
+                    .map_err(|err| format!("cannot read fee schedule {path}: {err}"))?;
+                config.fees = FeeSchedule::from_toml_str(&schedule)?;
+            }
+            Some("--interest-rate") => {
+                interest_rate = Some(next_value(&mut args, "--interest-rate")?.parse()?);
+            }
+            Some("--day-count") => {
+                day_count = DayCount::from_str(&next_value(&mut args, "--day-count")?)?;
+            }
+            Some("--account-creation") => {
+                config.account_creation =
+                    AccountCreation::from_str(&next_value(&mut args, "--account-creation")?)?;
+            }
+            Some("--accounts") => {
+                let path = next_value(&mut args, "--accounts")?;
+                accounts = true;
+                let file = std::fs::File::open(&path)
+                    .map_err(|err| format!("cannot read accounts file {path}: {err}"))?;
+                config.account_creation = AccountCreation::registered_from_csv(file)?;
+            }
+            Some("--registry") => {
+                registry = Some(PathBuf::from(next_value(&mut args, "--registry")?));
+            }
+            Some("--kyc-limits") => {
+                let path = next_value(&mut args, "--kyc-limits")?;
+                let limits = std::fs::read_to_string(&path)
+                    .map_err(|err| format!("cannot read KYC limits {path}: {err}"))?;
+                config.kyc_limits = KycLimits::from_toml_str(&limits)?;
+            }
+            Some("--rules") => {
+                let path = next_value(&mut args, "--rules")?;
+                let rules = std::fs::read_to_string(&path)
+                    .map_err(|err| format!("cannot read validation rules {path}: {err}"))?;
+                config.rules = RuleChain::from_toml_str(&rules)?;
+            }
+            Some("--join-registry") => join_registry = true,
+            Some("--events") => {
+                events = Some(PathBuf::from(next_value(&mut args, "--events")?));
+            }
+            Some("--detectors") => {
+                detectors = Some(PathBuf::from(next_value(&mut args, "--detectors")?));
+            }
+            Some("--alerts") => {
+                alerts = Some(PathBuf::from(next_value(&mut args, "--alerts")?));
+            }
+            Some("--chargeback-lock") => {
+                config.chargeback_lock =
+                    LockScope::from_str(&next_value(&mut args, "--chargeback-lock")?)?;
+            }
+            Some("--by-wallet") => by_wallet = true,
+            Some("--as-of") => {
+                if command != Command::Process {
+                    Err("--as-of is only valid without command")?
+                }
+                as_of = Some(PointInTime::from_str(&next_value(&mut args, "--as-of")?)?);
+            }
+            Some(flag) if flag.starts_with("--") => Err(format!("unknown option {flag}"))?,
+            _ if input.is_none() => input = Some(PathBuf::from(arg)),
+            _ => Err(format!("unexpected argument {arg:?}"))?,
+        }
+    }
+
+    config.interest = interest_rate.map(|annual_rate| InterestPolicy {
+        annual_rate,
+        day_count,
+    });
+
+    // Registered clients come from a single list, the registry when there is one
+    if accounts && registry.is_some() {
+        Err("--accounts can't be combined with --registry, use --account-creation registered")?
+    }
+    if !accounts
+        && registry.is_none()
+        && matches!(config.account_creation, AccountCreation::Registered(_))
+    {
+        Err("--account-creation registered requires --registry")?
+    }
+    if input.is_some() && matches!(command, Command::Diff { .. }) {
+        Err("diff compares snapshot files and doesn't take a transactions file")?
+    }
+    // Statements and the AML report are built from the operation log
+    if retention.skip_operation_log {
+        match command {
+            Command::Statement { .. } => {
+                Err("statement can't be combined with --no-operation-log")?
+            }
+            Command::AmlReport { .. } => {
+                Err("aml-report can't be combined with --no-operation-log")?
+            }
+            _ => {}
+        }
+    }
+    if as_of.is_some() && (by_wallet || join_registry) {
+        Err("--as-of can't be combined with --by-wallet or --join-registry")?
+    }
+    // Going back in time relies on the rollback log, which can't undo on-disk indexes
+    if as_of.is_some() || command == Command::Repl {
+        if spill_dir.is_some() || dedup_dir.is_some() {
+            Err("--spill-dir and --dedup-dir can't be used to go back in time")?
+        }
+        retention.rollback_log = true;
+    }
+    config.retention = retention;
+
+    match input {
+        None if !matches!(
+            command,
+            Command::Serve { .. } | Command::Stream { .. } | Command::Repl | Command::Diff { .. }
+        ) =>
+        {
+            Err(From::from("expected 1 argument, but got none"))
+        }
+        input => Ok(CliArgs {
+            command,
+            input,
+            config,
+            spill_dir,
+            dedup_dir,
+            dedup_memory,
+            registry,
+            events,
+            detectors,
+            alerts,
+            join_registry,
+            by_wallet,
+            as_of,
+        }),
+    }
+}
+
+fn next_value(
+    args: &mut impl Iterator<Item = OsString>,
+    flag: &str,
+) -> Result<String, Box<dyn Error>> {
+    match args.next().map(|v| v.into_string()) {
+        Some(Ok(value)) => Ok(value),
+        _ => Err(format!("expected a value after {flag}"))?,
+    }
+}
+
+// Parses a size in bytes with an optional K, M or G suffix (powers of 1024)
+fn parse_size(value: &str) -> Result<usize, Box<dyn Error>> {
+    let (digits, multiplier) = match value.trim().to_ascii_uppercase() {
+        v if v.ends_with('K') => (v[..v.len() - 1].to_owned(), 1 << 10),
+        v if v.ends_with('M') => (v[..v.len() - 1].to_owned(), 1 << 20),
+        v if v.ends_with('G') => (v[..v.len() - 1].to_owned(), 1 << 30),
+        v => (v, 1),
+    };
+    digits
+        .parse::<usize>()?
+        .checked_mul(multiplier)
+        .ok_or_else(|| From::from(format!("size too large: {value}")))
+}
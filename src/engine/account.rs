@@ -1,18 +1,64 @@
 use crate::engine::amount::{Amount, AmountError};
-use std::collections::HashMap;
+use crate::engine::dispute_window::{DisputeWindow, TxStamp};
+use crate::engine::history::{TxEvent, TxLifecycleEvent, TxStatus};
+use crate::engine::interest::InterestAccrual;
+use std::collections::{HashMap, HashSet};
 use thiserror::Error;
 
-#[derive(Debug, Clone, Copy)]
-enum AccountTxType {
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum AccountTxType {
     Deposit,
     Withdrawal,
 }
 
-#[derive(Debug, Clone, Copy)]
-struct AccountTx {
-    amount: Amount,
-    typ: AccountTxType,
-    is_disputed: bool,
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct AccountTx {
+    pub(crate) amount: Amount,
+    pub(crate) fee: Amount,
+    pub(crate) typ: AccountTxType,
+    pub(crate) is_disputed: bool,
+    pub(crate) stamp: TxStamp,
+    pub(crate) disputes: Vec<TxLifecycleEvent>, // Dispute / resolve / chargeback events, in order
+}
+
+impl AccountTx {
+    pub(crate) fn status(&self) -> TxStatus {
+        match self.disputes.last().map(|e| e.event) {
+            None | Some(TxEvent::Applied) => TxStatus::Applied,
+            Some(TxEvent::Disputed) => TxStatus::Disputed,
+            Some(TxEvent::Resolved) => TxStatus::Resolved,
+            Some(TxEvent::ChargedBack) => TxStatus::ChargedBack,
+            Some(TxEvent::Reversed) => TxStatus::Reversed,
+            Some(TxEvent::Corrected) => TxStatus::Corrected,
+        }
+    }
+
+    /// Full lifecycle, starting with the event applying the transaction
+    pub(crate) fn lifecycle(&self) -> Vec<TxLifecycleEvent> {
+        let applied = TxLifecycleEvent {
+            sequence: self.stamp.sequence,
+            timestamp: self.stamp.timestamp,
+            event: TxEvent::Applied,
+        };
+        std::iter::once(applied)
+            .chain(self.disputes.iter().copied())
+            .collect()
+    }
+
+    fn record(&mut self, event: TxEvent, now: &TxStamp) {
+        self.disputes.push(TxLifecycleEvent {
+            sequence: now.sequence,
+            timestamp: now.timestamp,
+            event,
+        });
+    }
+}
+
+/// Funds of an escrow still held for the seller
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Escrow {
+    pub(crate) seller: u16,
+    pub(crate) remaining: Amount,
 }
 
 #[derive(Debug, Clone)]
@@ -21,7 +67,12 @@ pub struct Account {
     pub amount_available: Amount,
     pub amount_held: Amount,
     pub is_locked: bool,
+    /// Part of the held funds sitting in escrow rather than held by a dispute
+    pub amount_escrowed: Amount,
     tx: HashMap<u32, AccountTx>,
+    expired: HashSet<u32>, // Tx ids compacted once their dispute window was over
+    escrows: HashMap<u32, Escrow>, // Open escrows funded by the account, by tx id
+    pub(crate) interest: InterestAccrual,
 }
 
 #[derive(Error, Debug)]
@@ -44,13 +95,51 @@ pub enum AccountOperationError {
     #[error("Transaction not disputed (tx id {0})")]
     TxNotDisputed(u32),
 
+    #[error("Fee is greater than the deposit (tx id {0})")]
+    InsufficientFundsForFee(u32),
+
+    #[error("Dispute window expired (tx id {0})")]
+    DisputeWindowExpired(u32),
+
+    #[error("Transaction was reversed (tx id {0})")]
+    TxReversed(u32),
+
+    #[error("Funds already withdrawn, transaction cannot be reversed or corrected (tx id {0})")]
+    InsufficientFundsForAmendment(u32),
+
+    #[error("Amount exceeds the funds left in escrow (tx id {0})")]
+    EscrowExceeded(u32),
+
     #[error("Withdrawal transaction cannot be disputed / resolved / charged back (tx id {0})")]
     InvalidWithdrawalDispute(u32),
 
-    #[error("Invalid Amount operation (tx id {0})")]
+    #[error("Invalid Amount operation: {0}")]
     InvalidAmountOperation(#[from] AmountError),
 }
 
+impl AccountOperationError {
+    /// Stable identifier of the error, for API clients
+    pub fn code(&self) -> &'static str {
+        match self {
+            AccountOperationError::AccountLocked(_) => "account_locked",
+            AccountOperationError::TxAlreadyExist(_) => "tx_already_exist",
+            AccountOperationError::TxUnknown(_) => "tx_unknown",
+            AccountOperationError::WithdrawalLimitExceeded(_) => "withdrawal_limit_exceeded",
+            AccountOperationError::TxAlreadyDisputed(_) => "tx_already_disputed",
+            AccountOperationError::TxNotDisputed(_) => "tx_not_disputed",
+            AccountOperationError::InsufficientFundsForFee(_) => "insufficient_funds_for_fee",
+            AccountOperationError::DisputeWindowExpired(_) => "dispute_window_expired",
+            AccountOperationError::TxReversed(_) => "tx_reversed",
+            AccountOperationError::InsufficientFundsForAmendment(_) => {
+                "insufficient_funds_for_amendment"
+            }
+            AccountOperationError::EscrowExceeded(_) => "escrow_exceeded",
+            AccountOperationError::InvalidWithdrawalDispute(_) => "invalid_withdrawal_dispute",
+            AccountOperationError::InvalidAmountOperation(_) => "invalid_amount_operation",
+        }
+    }
+}
+
 impl Account {
     pub fn new(client_id: u16) -> Self {
         Account {
@@ -58,24 +147,41 @@ impl Account {
             amount_available: Amount::new(),
             amount_held: Amount::new(),
             is_locked: false,
+            amount_escrowed: Amount::new(),
             tx: HashMap::new(),
+            expired: HashSet::new(),
+            escrows: HashMap::new(),
+            interest: InterestAccrual::default(),
         }
     }
 
-    pub fn deposit(&mut self, tx_id: u32, tx_amount: Amount) -> Result<(), AccountOperationError> {
+    /// Credit `tx_amount` minus the `fee` charged on the deposit
+    pub fn deposit(
+        &mut self,
+        tx_id: u32,
+        tx_amount: Amount,
+        fee: Amount,
+        stamp: TxStamp,
+    ) -> Result<(), AccountOperationError> {
         if self.is_locked {
             Err(AccountOperationError::AccountLocked(tx_id))?
         }
 
         if !self.tx.contains_key(&tx_id) {
-            self.amount_available = self.amount_available.add(&tx_amount)?;
+            if fee > tx_amount {
+                Err(AccountOperationError::InsufficientFundsForFee(tx_id))?
+            }
+            self.amount_available = self.amount_available.add(&tx_amount.sub(&fee)?)?;
 
             self.tx.insert(
                 tx_id,
                 AccountTx {
                     amount: tx_amount,
+                    fee,
                     typ: AccountTxType::Deposit,
                     is_disputed: false,
+                    stamp,
+                    disputes: vec![],
                 },
             );
         } else {
@@ -85,20 +191,31 @@ impl Account {
         Ok(())
     }
 
-    pub fn withdraw(&mut self, tx_id: u32, tx_amount: Amount) -> Result<(), AccountOperationError> {
+    /// Debit `tx_amount` plus the `fee` charged on the withdrawal
+    pub fn withdraw(
+        &mut self,
+        tx_id: u32,
+        tx_amount: Amount,
+        fee: Amount,
+        stamp: TxStamp,
+    ) -> Result<(), AccountOperationError> {
         if self.is_locked {
             Err(AccountOperationError::AccountLocked(tx_id))?
         }
         if !self.tx.contains_key(&tx_id) {
-            if self.amount_available >= tx_amount {
-                self.amount_available = self.amount_available.sub(&tx_amount)?;
+            let debit = tx_amount.add(&fee)?;
+            if self.amount_available >= debit {
+                self.amount_available = self.amount_available.sub(&debit)?;
 
                 self.tx.insert(
                     tx_id,
                     AccountTx {
                         amount: tx_amount,
+                        fee,
                         typ: AccountTxType::Withdrawal,
                         is_disputed: false,
+                        stamp,
+                        disputes: vec![],
                     },
                 );
             } else {
@@ -111,7 +228,13 @@ impl Account {
         Ok(())
     }
 
-    pub fn dispute(&mut self, tx_id: u32) -> Result<(), AccountOperationError> {
+    /// Hold the funds of a deposit, provided `now` is still within the dispute `window`
+    pub fn dispute(
+        &mut self,
+        tx_id: u32,
+        window: &DisputeWindow,
+        now: &TxStamp,
+    ) -> Result<(), AccountOperationError> {
         if self.is_locked {
             Err(AccountOperationError::AccountLocked(tx_id))?
         }
@@ -119,6 +242,13 @@ impl Account {
             if tx.is_disputed {
                 Err(AccountOperationError::TxAlreadyDisputed(tx_id))? // Already disputed -> ignored
             }
+            if tx.status() == TxStatus::Reversed {
+                Err(AccountOperationError::TxReversed(tx_id))?
+            }
+
+            if window.is_expired(&tx.stamp, now) {
+                Err(AccountOperationError::DisputeWindowExpired(tx_id))?
+            }
 
             match tx.typ {
                 AccountTxType::Deposit => {
@@ -136,13 +266,16 @@ impl Account {
             }
 
             tx.is_disputed = true;
+            tx.record(TxEvent::Disputed, now);
+        } else if self.expired.contains(&tx_id) {
+            Err(AccountOperationError::DisputeWindowExpired(tx_id))? // Compacted Tx
         } else {
             Err(AccountOperationError::TxUnknown(tx_id))? // Unknown Tx
         }
         Ok(())
     }
 
-    pub fn resolve(&mut self, tx_id: u32) -> Result<(), AccountOperationError> {
+    pub fn resolve(&mut self, tx_id: u32, now: &TxStamp) -> Result<(), AccountOperationError> {
         if self.is_locked {
             Err(AccountOperationError::AccountLocked(tx_id))?
         }
@@ -166,13 +299,14 @@ impl Account {
                 }
             }
             tx.is_disputed = false;
+            tx.record(TxEvent::Resolved, now);
         } else {
             Err(AccountOperationError::TxUnknown(tx_id))? // Unknown Tx
         }
         Ok(())
     }
 
-    pub fn chargeback(&mut self, tx_id: u32) -> Result<(), AccountOperationError> {
+    pub fn chargeback(&mut self, tx_id: u32, now: &TxStamp) -> Result<(), AccountOperationError> {
         if self.is_locked {
             Err(AccountOperationError::AccountLocked(tx_id))?
         }
@@ -193,11 +327,291 @@ impl Account {
 
             self.is_locked = true;
             tx.is_disputed = false; // Dispute resolved via chargeback
+            tx.record(TxEvent::ChargedBack, now);
         } else {
             Err(AccountOperationError::TxUnknown(tx_id))? // Unknown Tx
         }
         Ok(())
     }
+
+    /// Take `amount` from the available funds, to move it to another wallet of the client
+    pub fn transfer_out(
+        &mut self,
+        tx_id: u32,
+        amount: Amount,
+    ) -> Result<(), AccountOperationError> {
+        if self.is_locked {
+            Err(AccountOperationError::AccountLocked(tx_id))?
+        }
+        if self.amount_available < amount {
+            Err(AccountOperationError::WithdrawalLimitExceeded(tx_id))?
+        }
+        self.amount_available = self.amount_available.sub(&amount)?;
+        Ok(())
+    }
+
+    /// Add `amount` moved from another wallet of the client, or released from an escrow,
+    /// to the available funds
+    pub fn transfer_in(&mut self, tx_id: u32, amount: Amount) -> Result<(), AccountOperationError> {
+        if self.is_locked {
+            Err(AccountOperationError::AccountLocked(tx_id))?
+        }
+        self.amount_available = self.amount_available.add(&amount)?;
+        Ok(())
+    }
+
+    /// Hold `amount` of the available funds in escrow until it is released to `seller` or
+    /// refunded
+    pub fn fund_escrow(
+        &mut self,
+        tx_id: u32,
+        amount: Amount,
+        seller: u16,
+    ) -> Result<(), AccountOperationError> {
+        if self.is_locked {
+            Err(AccountOperationError::AccountLocked(tx_id))?
+        }
+        if self.escrows.contains_key(&tx_id) {
+            Err(AccountOperationError::TxAlreadyExist(tx_id))?
+        }
+        if self.amount_available < amount {
+            Err(AccountOperationError::WithdrawalLimitExceeded(tx_id))?
+        }
+
+        self.amount_available = self.amount_available.sub(&amount)?;
+        self.amount_held = self.amount_held.add(&amount)?;
+        self.amount_escrowed = self.amount_escrowed.add(&amount)?;
+        self.escrows.insert(
+            tx_id,
+            Escrow {
+                seller,
+                remaining: amount,
+            },
+        );
+        Ok(())
+    }
+
+    /// Take `amount` (everything left when `None`) out of an escrow, for its seller.
+    /// Returns the amount released.
+    pub fn release_escrow(
+        &mut self,
+        tx_id: u32,
+        amount: Option<Amount>,
+    ) -> Result<Amount, AccountOperationError> {
+        self.take_escrow(tx_id, amount)
+    }
+
+    /// Give back `amount` (everything left when `None`) of an escrow to the available funds.
+    /// Returns the amount refunded.
+    pub fn refund_escrow(
+        &mut self,
+        tx_id: u32,
+        amount: Option<Amount>,
+    ) -> Result<Amount, AccountOperationError> {
+        let amount = self.take_escrow(tx_id, amount)?;
+        self.amount_available = self.amount_available.add(&amount)?;
+        Ok(amount)
+    }
+
+    // Remove funds from an escrow and from the held funds, closing the escrow once empty
+    fn take_escrow(
+        &mut self,
+        tx_id: u32,
+        amount: Option<Amount>,
+    ) -> Result<Amount, AccountOperationError> {
+        if self.is_locked {
+            Err(AccountOperationError::AccountLocked(tx_id))?
+        }
+        let Some(escrow) = self.escrows.get_mut(&tx_id) else {
+            Err(AccountOperationError::TxUnknown(tx_id))?
+        };
+        let amount = amount.unwrap_or(escrow.remaining);
+        if amount > escrow.remaining {
+            Err(AccountOperationError::EscrowExceeded(tx_id))?
+        }
+
+        escrow.remaining = escrow.remaining.sub(&amount)?;
+        if escrow.remaining == Amount::new() {
+            self.escrows.remove(&tx_id);
+        }
+        self.amount_held = self.amount_held.sub(&amount)?;
+        self.amount_escrowed = self.amount_escrowed.sub(&amount)?;
+        Ok(amount)
+    }
+
+    /// Open escrow funded by the account
+    pub(crate) fn escrow(&self, tx_id: u32) -> Option<&Escrow> {
+        self.escrows.get(&tx_id)
+    }
+
+    /// Give back the fee charged on a charged back deposit, even if the account is locked.
+    /// Returns the amount refunded, zero when the fee was already refunded.
+    pub fn refund_fee(&mut self, tx_id: u32) -> Result<Amount, AccountOperationError> {
+        let Some(tx) = self.tx.get_mut(&tx_id) else {
+            Err(AccountOperationError::TxUnknown(tx_id))?
+        };
+        if tx.status() != TxStatus::ChargedBack {
+            Err(AccountOperationError::TxNotDisputed(tx_id))?
+        }
+
+        let fee = tx.fee;
+        self.amount_available = self.amount_available.add(&fee)?;
+        tx.fee = Amount::new();
+        Ok(fee)
+    }
+
+    /// Undo a deposit or a withdrawal without locking the account, giving back its fee.
+    /// Returns the change of the available funds (without the fee) and the fee refunded.
+    pub fn reverse(
+        &mut self,
+        tx_id: u32,
+        now: &TxStamp,
+    ) -> Result<(Amount, Amount), AccountOperationError> {
+        if self.is_locked {
+            Err(AccountOperationError::AccountLocked(tx_id))?
+        }
+        let Some(tx) = self.tx.get_mut(&tx_id) else {
+            Err(AccountOperationError::TxUnknown(tx_id))?
+        };
+        check_amendable(tx_id, tx)?;
+
+        let change = match tx.typ {
+            AccountTxType::Deposit => tx.amount.neg()?,
+            AccountTxType::Withdrawal => tx.amount,
+        };
+        let fee = tx.fee;
+        let available = self.amount_available.add(&change)?.add(&fee)?;
+        if available < Amount::new() && available < self.amount_available {
+            Err(AccountOperationError::InsufficientFundsForAmendment(tx_id))?
+        }
+
+        self.amount_available = available;
+        tx.fee = Amount::new();
+        tx.record(TxEvent::Reversed, now);
+        Ok((change, fee))
+    }
+
+    /// Amend the amount of a deposit or a withdrawal without locking the account.
+    /// The fee charged on the transaction is kept as is.
+    /// Returns the change of the available funds.
+    pub fn correct(
+        &mut self,
+        tx_id: u32,
+        tx_amount: Amount,
+        now: &TxStamp,
+    ) -> Result<Amount, AccountOperationError> {
+        if self.is_locked {
+            Err(AccountOperationError::AccountLocked(tx_id))?
+        }
+        let Some(tx) = self.tx.get_mut(&tx_id) else {
+            Err(AccountOperationError::TxUnknown(tx_id))?
+        };
+        check_amendable(tx_id, tx)?;
+
+        let change = match tx.typ {
+            AccountTxType::Deposit => {
+                if tx.fee > tx_amount {
+                    Err(AccountOperationError::InsufficientFundsForFee(tx_id))?
+                }
+                tx_amount.sub(&tx.amount)?
+            }
+            AccountTxType::Withdrawal => tx.amount.sub(&tx_amount)?,
+        };
+        let available = self.amount_available.add(&change)?;
+        if available < Amount::new() && available < self.amount_available {
+            Err(AccountOperationError::InsufficientFundsForAmendment(tx_id))?
+        }
+
+        self.amount_available = available;
+        tx.amount = tx_amount;
+        tx.record(TxEvent::Corrected, now);
+        Ok(change)
+    }
+
+    /// Drop the history of transactions that can no longer be disputed at `now`.
+    /// Transactions under dispute are kept so they can still be resolved or charged back.
+    /// Returns the number of transactions removed.
+    /// Only the ids of the removed transactions are kept, so that a later dispute on one of them
+    /// is still rejected as `DisputeWindowExpired`.
+    pub fn compact_expired(&mut self, window: &DisputeWindow, now: &TxStamp) -> usize {
+        let before = self.tx.len();
+        self.tx.retain(|tx_id, tx| {
+            let keep = tx.is_disputed || !window.is_expired(&tx.stamp, now);
+            if !keep {
+                self.expired.insert(*tx_id);
+            }
+            keep
+        });
+        before - self.tx.len()
+    }
+
+    /// Number of transactions kept in memory for this account
+    pub fn history_len(&self) -> usize {
+        self.tx.len()
+    }
+
+    /// Transactions kept in memory, in no particular order
+    pub(crate) fn history(&self) -> impl Iterator<Item = (u32, &AccountTx)> {
+        self.tx.iter().map(|(id, tx)| (*id, tx))
+    }
+
+    pub(crate) fn get_tx(&self, tx_id: u32) -> Option<&AccountTx> {
+        self.tx.get(&tx_id)
+    }
+
+    // Amount of a known transaction, zero when it is not in the history anymore
+    pub(crate) fn tx_amount(&self, tx_id: u32) -> Amount {
+        self.tx.get(&tx_id).map(|tx| tx.amount).unwrap_or_default()
+    }
+
+    pub(crate) fn knows_tx(&self, tx_id: u32) -> bool {
+        self.tx.contains_key(&tx_id)
+    }
+
+    /// Remove a transaction from the in-memory history without touching the balances
+    pub(crate) fn forget(&mut self, tx_id: u32) -> Option<AccountTx> {
+        self.tx.remove(&tx_id)
+    }
+
+    /// Bring back a transaction previously evicted from the in-memory history
+    pub(crate) fn restore(&mut self, tx_id: u32, tx: AccountTx) {
+        self.tx.entry(tx_id).or_insert(tx);
+    }
+
+    /// Evict the oldest transactions until at most `max` remain in memory.
+    /// Disputed transactions are never evicted, so the history can stay above `max`
+    /// while they are pending. Evicted transactions are returned oldest first.
+    pub(crate) fn evict_oldest(&mut self, max: usize) -> Vec<(u32, AccountTx)> {
+        if self.tx.len() <= max {
+            return vec![];
+        }
+
+        let mut candidates: Vec<(u64, u32)> = self
+            .tx
+            .iter()
+            .filter(|(_, tx)| !tx.is_disputed)
+            .map(|(id, tx)| (tx.stamp.sequence, *id))
+            .collect();
+        candidates.sort_unstable();
+
+        let excess = self.tx.len() - max;
+        candidates
+            .into_iter()
+            .take(excess)
+            .filter_map(|(_, id)| self.tx.remove(&id).map(|tx| (id, tx)))
+            .collect()
+    }
+}
+
+// Transactions under dispute or already reversed can't be reversed or corrected
+fn check_amendable(tx_id: u32, tx: &AccountTx) -> Result<(), AccountOperationError> {
+    if tx.is_disputed {
+        Err(AccountOperationError::TxAlreadyDisputed(tx_id))?
+    }
+    if tx.status() == TxStatus::Reversed {
+        Err(AccountOperationError::TxReversed(tx_id))?
+    }
+    Ok(())
 }
 
 #[cfg(test)]
@@ -211,10 +625,20 @@ mod tests {
         let mut account = Account::new(0);
 
         // Make Deposit
-        let _ = account.deposit(0, Amount::from_str("100.0").unwrap());
+        let _ = account.deposit(
+            0,
+            Amount::from_str("100.0").unwrap(),
+            Amount::new(),
+            TxStamp::default(),
+        );
 
         // Withdraw all
-        let _ = account.withdraw(1, Amount::from_str("100.0").unwrap());
+        let _ = account.withdraw(
+            1,
+            Amount::from_str("100.0").unwrap(),
+            Amount::new(),
+            TxStamp::default(),
+        );
 
         // Verify client 1: deposit 100.0 + withdrawal 100.0 = 0.0
         assert_eq!(account.amount_available, Amount::new());
@@ -222,7 +646,12 @@ mod tests {
         assert!(!account.is_locked);
 
         // Try to withdraw more and check that is ignored
-        let err = account.withdraw(2, Amount::from_str("50.0").unwrap());
+        let err = account.withdraw(
+            2,
+            Amount::from_str("50.0").unwrap(),
+            Amount::new(),
+            TxStamp::default(),
+        );
         assert!(err.is_err());
         let err = err.unwrap_err();
         assert!(matches!(
@@ -240,10 +669,15 @@ mod tests {
         let mut account = Account::new(0);
 
         // Make a deposit
-        let _ = account.deposit(0, Amount::from_str("100.0").unwrap());
+        let _ = account.deposit(
+            0,
+            Amount::from_str("100.0").unwrap(),
+            Amount::new(),
+            TxStamp::default(),
+        );
 
         // Dispute the deposit
-        let _ = account.dispute(0);
+        let _ = account.dispute(0, &DisputeWindow::Unlimited, &TxStamp::default());
 
         // Verify that the deposit is under dispute
         let deposit = account.tx.get(&0).unwrap();
@@ -252,7 +686,7 @@ mod tests {
         assert_eq!(account.amount_available, Amount::new());
 
         // Then resolve
-        let _ = account.resolve(0);
+        let _ = account.resolve(0, &TxStamp::default());
 
         // Verify that now the account is not locked and amount back to 100.0
         assert!(!account.is_locked);
@@ -260,7 +694,12 @@ mod tests {
         assert_eq!(account.amount_available, Amount::from_str("100.0").unwrap());
 
         // Try adding another deposit
-        let _ = account.deposit(1, Amount::from_str("200.0").unwrap());
+        let _ = account.deposit(
+            1,
+            Amount::from_str("200.0").unwrap(),
+            Amount::new(),
+            TxStamp::default(),
+        );
 
         // Verify client 1: deposit 100.0 + dispute + chargeback + deposit 200.0 = 0.0
         assert_eq!(account.amount_available, Amount::from_str("300.0").unwrap());
@@ -273,23 +712,33 @@ mod tests {
         let mut account = Account::new(0);
 
         // First make a deposit
-        let _ = account.deposit(0, Amount::from_str("100.0").unwrap());
+        let _ = account.deposit(
+            0,
+            Amount::from_str("100.0").unwrap(),
+            Amount::new(),
+            TxStamp::default(),
+        );
 
         // Then dispute the deposit
-        let _ = account.dispute(0);
+        let _ = account.dispute(0, &DisputeWindow::Unlimited, &TxStamp::default());
 
         // Verify that the deposit is under dispute
         let disputed_tx = account.tx.get(&0).unwrap();
         assert!(disputed_tx.is_disputed);
 
         // Then chargeback
-        let _ = account.chargeback(0);
+        let _ = account.chargeback(0, &TxStamp::default());
 
         // Verify that now the account is locked
         assert!(account.is_locked);
 
         // Try adding another deposit
-        let err = account.deposit(1, Amount::from_str("200.0").unwrap());
+        let err = account.deposit(
+            1,
+            Amount::from_str("200.0").unwrap(),
+            Amount::new(),
+            TxStamp::default(),
+        );
         assert!(err.is_err());
         let err = err.unwrap_err();
         assert!(matches!(err, AccountOperationError::AccountLocked(_)));
@@ -305,13 +754,23 @@ mod tests {
         let mut account = Account::new(0);
 
         // Make a deposit
-        let _ = account.deposit(0, Amount::from_str("100.0").unwrap());
+        let _ = account.deposit(
+            0,
+            Amount::from_str("100.0").unwrap(),
+            Amount::new(),
+            TxStamp::default(),
+        );
 
         // Withdraw
-        let _ = account.withdraw(1, Amount::from_str("50.0").unwrap());
+        let _ = account.withdraw(
+            1,
+            Amount::from_str("50.0").unwrap(),
+            Amount::new(),
+            TxStamp::default(),
+        );
 
         // Try dispute the withdrawal
-        let err = account.dispute(1);
+        let err = account.dispute(1, &DisputeWindow::Unlimited, &TxStamp::default());
         assert!(err.is_err());
         let err = err.unwrap_err();
         assert!(matches!(
@@ -336,11 +795,21 @@ mod tests {
         let mut account = Account::new(0);
 
         // First deposit
-        let res = account.deposit(0, Amount::from_str("100.0").unwrap());
+        let res = account.deposit(
+            0,
+            Amount::from_str("100.0").unwrap(),
+            Amount::new(),
+            TxStamp::default(),
+        );
         assert!(res.is_ok());
 
         // Second deposit with same tx id
-        let err = account.deposit(0, Amount::from_str("50.0").unwrap());
+        let err = account.deposit(
+            0,
+            Amount::from_str("50.0").unwrap(),
+            Amount::new(),
+            TxStamp::default(),
+        );
         assert!(err.is_err());
         let err = err.unwrap_err();
         assert!(matches!(err, AccountOperationError::TxAlreadyExist(0)));
@@ -356,12 +825,27 @@ mod tests {
         let mut account = Account::new(0);
 
         // Deposit then withdraw
-        let _ = account.deposit(0, Amount::from_str("100.0").unwrap());
-        let res = account.withdraw(1, Amount::from_str("50.0").unwrap());
+        let _ = account.deposit(
+            0,
+            Amount::from_str("100.0").unwrap(),
+            Amount::new(),
+            TxStamp::default(),
+        );
+        let res = account.withdraw(
+            1,
+            Amount::from_str("50.0").unwrap(),
+            Amount::new(),
+            TxStamp::default(),
+        );
         assert!(res.is_ok());
 
         // Second withdraw with same tx id
-        let err = account.withdraw(1, Amount::from_str("10.0").unwrap());
+        let err = account.withdraw(
+            1,
+            Amount::from_str("10.0").unwrap(),
+            Amount::new(),
+            TxStamp::default(),
+        );
         assert!(err.is_err());
         let err = err.unwrap_err();
         assert!(matches!(err, AccountOperationError::TxAlreadyExist(1)));
@@ -377,7 +861,7 @@ mod tests {
         let mut account = Account::new(0);
 
         // No tx with id 42
-        let err = account.dispute(42);
+        let err = account.dispute(42, &DisputeWindow::Unlimited, &TxStamp::default());
         assert!(err.is_err());
         let err = err.unwrap_err();
         assert!(matches!(err, AccountOperationError::TxUnknown(42)));
@@ -392,8 +876,13 @@ mod tests {
         let mut account = Account::new(0);
 
         // Make a deposit and dispute it
-        let _ = account.deposit(0, Amount::from_str("100.0").unwrap());
-        let _ = account.dispute(0);
+        let _ = account.deposit(
+            0,
+            Amount::from_str("100.0").unwrap(),
+            Amount::new(),
+            TxStamp::default(),
+        );
+        let _ = account.dispute(0, &DisputeWindow::Unlimited, &TxStamp::default());
 
         let disputed_tx = account.tx.get(&0).unwrap();
         assert!(disputed_tx.is_disputed);
@@ -401,7 +890,7 @@ mod tests {
         assert_eq!(account.amount_held, Amount::from_str("100.0").unwrap());
 
         // Disputing again should fail
-        let err = account.dispute(0);
+        let err = account.dispute(0, &DisputeWindow::Unlimited, &TxStamp::default());
         assert!(err.is_err());
         let err = err.unwrap_err();
         assert!(matches!(err, AccountOperationError::TxAlreadyDisputed(0)));
@@ -417,7 +906,7 @@ mod tests {
         let mut account = Account::new(0);
 
         // No tx with id 42
-        let err = account.resolve(42);
+        let err = account.resolve(42, &TxStamp::default());
         assert!(err.is_err());
         let err = err.unwrap_err();
         assert!(matches!(err, AccountOperationError::TxUnknown(42)));
@@ -432,9 +921,14 @@ mod tests {
         let mut account = Account::new(0);
 
         // Deposit but do not dispute
-        let _ = account.deposit(0, Amount::from_str("100.0").unwrap());
-
-        let err = account.resolve(0);
+        let _ = account.deposit(
+            0,
+            Amount::from_str("100.0").unwrap(),
+            Amount::new(),
+            TxStamp::default(),
+        );
+
+        let err = account.resolve(0, &TxStamp::default());
         assert!(err.is_err());
         let err = err.unwrap_err();
         assert!(matches!(err, AccountOperationError::TxNotDisputed(0)));
@@ -450,11 +944,21 @@ mod tests {
         let mut account = Account::new(0);
 
         // Deposit then withdraw
-        let _ = account.deposit(0, Amount::from_str("100.0").unwrap());
-        let _ = account.withdraw(1, Amount::from_str("50.0").unwrap());
-        let _ = account.dispute(1);
-
-        let err = account.resolve(1);
+        let _ = account.deposit(
+            0,
+            Amount::from_str("100.0").unwrap(),
+            Amount::new(),
+            TxStamp::default(),
+        );
+        let _ = account.withdraw(
+            1,
+            Amount::from_str("50.0").unwrap(),
+            Amount::new(),
+            TxStamp::default(),
+        );
+        let _ = account.dispute(1, &DisputeWindow::Unlimited, &TxStamp::default());
+
+        let err = account.resolve(1, &TxStamp::default());
         assert!(err.is_err());
         let err = err.unwrap_err();
         assert!(matches!(err, AccountOperationError::TxNotDisputed(1)));
@@ -469,7 +973,7 @@ mod tests {
     fn test_that_chargeback_on_unknown_tx_is_rejected() {
         let mut account = Account::new(0);
 
-        let err = account.chargeback(42);
+        let err = account.chargeback(42, &TxStamp::default());
         assert!(err.is_err());
         let err = err.unwrap_err();
         assert!(matches!(err, AccountOperationError::TxUnknown(42)));
@@ -484,9 +988,14 @@ mod tests {
         let mut account = Account::new(0);
 
         // Deposit but do not dispute
-        let _ = account.deposit(0, Amount::from_str("100.0").unwrap());
-
-        let err = account.chargeback(0);
+        let _ = account.deposit(
+            0,
+            Amount::from_str("100.0").unwrap(),
+            Amount::new(),
+            TxStamp::default(),
+        );
+
+        let err = account.chargeback(0, &TxStamp::default());
         assert!(err.is_err());
         let err = err.unwrap_err();
         assert!(matches!(err, AccountOperationError::TxNotDisputed(0)));
@@ -502,11 +1011,21 @@ mod tests {
         let mut account = Account::new(0);
 
         // Deposit then withdraw
-        let _ = account.deposit(0, Amount::from_str("100.0").unwrap());
-        let _ = account.withdraw(1, Amount::from_str("50.0").unwrap());
-        let _ = account.dispute(1);
-
-        let err = account.chargeback(1);
+        let _ = account.deposit(
+            0,
+            Amount::from_str("100.0").unwrap(),
+            Amount::new(),
+            TxStamp::default(),
+        );
+        let _ = account.withdraw(
+            1,
+            Amount::from_str("50.0").unwrap(),
+            Amount::new(),
+            TxStamp::default(),
+        );
+        let _ = account.dispute(1, &DisputeWindow::Unlimited, &TxStamp::default());
+
+        let err = account.chargeback(1, &TxStamp::default());
         assert!(err.is_err());
         let err = err.unwrap_err();
         assert!(matches!(err, AccountOperationError::TxNotDisputed(1)));
@@ -522,37 +1041,52 @@ mod tests {
         let mut account = Account::new(0);
 
         // Setup: deposit, dispute, then chargeback to lock account
-        let _ = account.deposit(0, Amount::from_str("100.0").unwrap());
-        let _ = account.dispute(0);
-        let _ = account.chargeback(0);
+        let _ = account.deposit(
+            0,
+            Amount::from_str("100.0").unwrap(),
+            Amount::new(),
+            TxStamp::default(),
+        );
+        let _ = account.dispute(0, &DisputeWindow::Unlimited, &TxStamp::default());
+        let _ = account.chargeback(0, &TxStamp::default());
         assert!(account.is_locked);
 
         // All further operations should be rejected with AccountLocked
-        let err = account.deposit(1, Amount::from_str("10.0").unwrap());
+        let err = account.deposit(
+            1,
+            Amount::from_str("10.0").unwrap(),
+            Amount::new(),
+            TxStamp::default(),
+        );
         assert!(matches!(
             err.unwrap_err(),
             AccountOperationError::AccountLocked(1)
         ));
 
-        let err = account.withdraw(2, Amount::from_str("10.0").unwrap());
+        let err = account.withdraw(
+            2,
+            Amount::from_str("10.0").unwrap(),
+            Amount::new(),
+            TxStamp::default(),
+        );
         assert!(matches!(
             err.unwrap_err(),
             AccountOperationError::AccountLocked(2)
         ));
 
-        let err = account.dispute(0);
+        let err = account.dispute(0, &DisputeWindow::Unlimited, &TxStamp::default());
         assert!(matches!(
             err.unwrap_err(),
             AccountOperationError::AccountLocked(0)
         ));
 
-        let err = account.resolve(0);
+        let err = account.resolve(0, &TxStamp::default());
         assert!(matches!(
             err.unwrap_err(),
             AccountOperationError::AccountLocked(0)
         ));
 
-        let err = account.chargeback(0);
+        let err = account.chargeback(0, &TxStamp::default());
         assert!(matches!(
             err.unwrap_err(),
             AccountOperationError::AccountLocked(0)
@@ -563,4 +1097,297 @@ mod tests {
         assert_eq!(account.amount_held, Amount::new());
         assert!(account.is_locked);
     }
+
+    #[test]
+    fn test_that_dispute_after_window_is_rejected() {
+        let mut account = Account::new(0);
+        let window = DisputeWindow::Records(2);
+        let stamp = |sequence| TxStamp {
+            sequence,
+            timestamp: None,
+        };
+
+        let _ = account.deposit(
+            0,
+            Amount::from_str("100.0").unwrap(),
+            Amount::new(),
+            stamp(1),
+        );
+        let _ = account.deposit(
+            1,
+            Amount::from_str("50.0").unwrap(),
+            Amount::new(),
+            stamp(2),
+        );
+
+        // Deposit 1 is still disputable two records later
+        let res = account.dispute(1, &window, &stamp(4));
+        assert!(res.is_ok());
+
+        // Deposit 0 is three records old and cannot be disputed anymore
+        let err = account.dispute(0, &window, &stamp(4));
+        assert!(matches!(
+            err.unwrap_err(),
+            AccountOperationError::DisputeWindowExpired(0)
+        ));
+
+        assert_eq!(account.amount_available, Amount::from_str("100.0").unwrap());
+        assert_eq!(account.amount_held, Amount::from_str("50.0").unwrap());
+        assert!(!account.is_locked);
+    }
+
+    #[test]
+    fn test_that_compaction_keeps_disputed_and_recent_transactions() {
+        let mut account = Account::new(0);
+        let window = DisputeWindow::Elapsed(60);
+        let stamp = |sequence, timestamp| TxStamp {
+            sequence,
+            timestamp: Some(timestamp),
+        };
+
+        let _ = account.deposit(
+            0,
+            Amount::from_str("100.0").unwrap(),
+            Amount::new(),
+            stamp(1, 0),
+        );
+        let _ = account.deposit(
+            1,
+            Amount::from_str("50.0").unwrap(),
+            Amount::new(),
+            stamp(2, 10),
+        );
+        let _ = account.withdraw(
+            2,
+            Amount::from_str("10.0").unwrap(),
+            Amount::new(),
+            stamp(3, 20),
+        );
+        let _ = account.deposit(
+            3,
+            Amount::from_str("5.0").unwrap(),
+            Amount::new(),
+            stamp(4, 100),
+        );
+        let _ = account.dispute(1, &window, &stamp(5, 30));
+
+        // At t=100, tx 0 and 2 are expired, tx 1 is disputed and tx 3 is recent
+        let removed = account.compact_expired(&window, &stamp(6, 100));
+        assert_eq!(removed, 2);
+        assert!(!account.tx.contains_key(&0));
+        assert!(account.tx.contains_key(&1));
+        assert!(!account.tx.contains_key(&2));
+        assert!(account.tx.contains_key(&3));
+
+        // A dispute on a compacted transaction is rejected as too late, not as unknown
+        let err = account.dispute(0, &window, &stamp(7, 100)).unwrap_err();
+        assert!(matches!(
+            err,
+            AccountOperationError::DisputeWindowExpired(0)
+        ));
+        let err = account.dispute(42, &window, &stamp(7, 100)).unwrap_err();
+        assert!(matches!(err, AccountOperationError::TxUnknown(42)));
+
+        // The disputed transaction can still be resolved
+        let res = account.resolve(1, &stamp(7, 110));
+        assert!(res.is_ok());
+        assert_eq!(account.amount_available, Amount::from_str("145.0").unwrap());
+        assert_eq!(account.amount_held, Amount::new());
+    }
+
+    #[test]
+    fn test_that_oldest_undisputed_transactions_are_evicted_first() {
+        let mut account = Account::new(0);
+        let stamp = |sequence| TxStamp {
+            sequence,
+            timestamp: None,
+        };
+
+        let _ = account.deposit(
+            10,
+            Amount::from_str("1.0").unwrap(),
+            Amount::new(),
+            stamp(1),
+        );
+        let _ = account.deposit(
+            11,
+            Amount::from_str("2.0").unwrap(),
+            Amount::new(),
+            stamp(2),
+        );
+        let _ = account.deposit(
+            12,
+            Amount::from_str("3.0").unwrap(),
+            Amount::new(),
+            stamp(3),
+        );
+        let _ = account.deposit(
+            13,
+            Amount::from_str("4.0").unwrap(),
+            Amount::new(),
+            stamp(4),
+        );
+        let _ = account.dispute(10, &DisputeWindow::Unlimited, &stamp(5));
+
+        // Tx 10 is disputed so 11 and 12 are evicted instead
+        let evicted = account.evict_oldest(2);
+        let evicted_ids: Vec<u32> = evicted.iter().map(|(id, _)| *id).collect();
+        assert_eq!(evicted_ids, vec![11, 12]);
+        assert_eq!(account.history_len(), 2);
+        assert!(account.knows_tx(10));
+        assert!(account.knows_tx(13));
+
+        // Balances are untouched by eviction
+        assert_eq!(account.amount_available, Amount::from_str("9.0").unwrap());
+        assert_eq!(account.amount_held, Amount::from_str("1.0").unwrap());
+
+        // A restored transaction can be disputed again
+        let (id, tx) = evicted[0].clone();
+        account.restore(id, tx);
+        let res = account.dispute(11, &DisputeWindow::Unlimited, &stamp(6));
+        assert!(res.is_ok());
+        assert_eq!(account.amount_held, Amount::from_str("3.0").unwrap());
+    }
+
+    #[test]
+    fn test_that_fees_are_charged_and_refunded() {
+        let mut account = Account::new(0);
+        let fee = Amount::from_str("1.0").unwrap();
+
+        // A deposit must cover its fee
+        let err = account.deposit(0, Amount::from_str("0.5").unwrap(), fee, TxStamp::default());
+        assert!(matches!(
+            err.unwrap_err(),
+            AccountOperationError::InsufficientFundsForFee(0)
+        ));
+
+        let _ = account.deposit(
+            1,
+            Amount::from_str("10.0").unwrap(),
+            fee,
+            TxStamp::default(),
+        );
+        assert_eq!(account.amount_available, Amount::from_str("9.0").unwrap());
+
+        // The withdrawal fee has to be available too
+        let err = account.withdraw(2, Amount::from_str("8.5").unwrap(), fee, TxStamp::default());
+        assert!(matches!(
+            err.unwrap_err(),
+            AccountOperationError::WithdrawalLimitExceeded(2)
+        ));
+        let _ = account.withdraw(3, Amount::from_str("3.0").unwrap(), fee, TxStamp::default());
+        assert_eq!(account.amount_available, Amount::from_str("5.0").unwrap());
+
+        // The whole deposit is disputed and charged back, then its fee is refunded once
+        let _ = account.dispute(1, &DisputeWindow::Unlimited, &TxStamp::default());
+        let err = account.refund_fee(1);
+        assert!(matches!(
+            err.unwrap_err(),
+            AccountOperationError::TxNotDisputed(1)
+        ));
+        let _ = account.chargeback(1, &TxStamp::default());
+        assert_eq!(account.amount_available, Amount::from_str("-5.0").unwrap());
+        assert_eq!(account.refund_fee(1).unwrap(), fee);
+        assert_eq!(account.refund_fee(1).unwrap(), Amount::new());
+        assert_eq!(account.amount_available, Amount::from_str("-4.0").unwrap());
+        assert_eq!(account.amount_held, Amount::new());
+        assert!(account.is_locked);
+    }
+
+    #[test]
+    fn test_that_transactions_can_be_reversed_and_corrected() {
+        let mut account = Account::new(1);
+        let amount = |s| Amount::from_str(s).unwrap();
+        let now = TxStamp::default();
+
+        let _ = account.deposit(1, amount("10.0"), amount("1.0"), now);
+        let _ = account.deposit(2, amount("20.0"), Amount::new(), now);
+        let _ = account.withdraw(3, amount("25.0"), Amount::new(), now);
+        assert_eq!(account.amount_available, amount("4.0"));
+
+        // Reversing the second deposit would need funds already withdrawn
+        let err = account.reverse(2, &now);
+        assert!(matches!(
+            err.unwrap_err(),
+            AccountOperationError::InsufficientFundsForAmendment(2)
+        ));
+
+        // The withdrawal is amended then reversed
+        assert_eq!(
+            account.correct(3, amount("20.0"), &now).unwrap(),
+            amount("5.0")
+        );
+        assert_eq!(account.get_tx(3).unwrap().status(), TxStatus::Corrected);
+        assert_eq!(
+            account.reverse(3, &now).unwrap(),
+            (amount("20.0"), Amount::new())
+        );
+        assert_eq!(account.amount_available, amount("29.0"));
+
+        // The first deposit is reversed with its fee and can't be disputed anymore
+        assert_eq!(
+            account.reverse(1, &now).unwrap(),
+            (amount("-10.0"), amount("1.0"))
+        );
+        assert_eq!(account.amount_available, amount("20.0"));
+        let err = account.dispute(1, &DisputeWindow::Unlimited, &now);
+        assert!(matches!(
+            err.unwrap_err(),
+            AccountOperationError::TxReversed(1)
+        ));
+        let err = account.correct(1, amount("5.0"), &now);
+        assert!(matches!(
+            err.unwrap_err(),
+            AccountOperationError::TxReversed(1)
+        ));
+
+        // Disputed transactions must be resolved first
+        let _ = account.dispute(2, &DisputeWindow::Unlimited, &now);
+        let err = account.correct(2, amount("15.0"), &now);
+        assert!(matches!(
+            err.unwrap_err(),
+            AccountOperationError::TxAlreadyDisputed(2)
+        ));
+        assert!(!account.is_locked);
+    }
+
+    #[test]
+    fn test_that_escrow_is_held_until_released_or_refunded() {
+        let mut account = Account::new(1);
+        let amount = |s| Amount::from_str(s).unwrap();
+
+        let _ = account.deposit(1, amount("100.0"), Amount::new(), TxStamp::default());
+        let err = account.fund_escrow(2, amount("100.0001"), 2);
+        assert!(matches!(
+            err.unwrap_err(),
+            AccountOperationError::WithdrawalLimitExceeded(2)
+        ));
+        account.fund_escrow(2, amount("60.0"), 2).unwrap();
+        assert_eq!(account.escrow(2).unwrap().seller, 2);
+        assert_eq!(account.amount_available, amount("40.0"));
+        assert_eq!(account.amount_held, amount("60.0"));
+        assert_eq!(account.amount_escrowed, amount("60.0"));
+
+        // Partial release, then the rest is refunded
+        assert_eq!(
+            account.release_escrow(2, Some(amount("15.0"))).unwrap(),
+            amount("15.0")
+        );
+        let err = account.refund_escrow(2, Some(amount("45.0001")));
+        assert!(matches!(
+            err.unwrap_err(),
+            AccountOperationError::EscrowExceeded(2)
+        ));
+        assert_eq!(account.refund_escrow(2, None).unwrap(), amount("45.0"));
+        assert_eq!(account.amount_available, amount("85.0"));
+        assert_eq!(account.amount_held, Amount::new());
+        assert_eq!(account.amount_escrowed, Amount::new());
+
+        // The escrow is closed once empty
+        let err = account.release_escrow(2, None);
+        assert!(matches!(
+            err.unwrap_err(),
+            AccountOperationError::TxUnknown(2)
+        ));
+    }
 }
@@ -0,0 +1,192 @@
+use crate::engine::{Transaction, TransactionType};
+use serde::Deserialize;
+use std::collections::HashSet;
+use std::fmt;
+use std::io;
+use std::str::FromStr;
+use thiserror::Error;
+
+/// When the ledger opens an account for a client it has never seen
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum AccountCreation {
+    /// Any record opens the account, even when it is rejected (legacy behaviour)
+    #[default]
+    AnyRecord,
+    /// Only a successful deposit opens the account
+    FirstDeposit,
+    /// Only a successful deposit of a registered client opens its account,
+    /// records of other clients are rejected
+    Registered(HashSet<u16>),
+}
+
+#[derive(Error, Debug)]
+pub enum AccountCreationError {
+    #[error("Invalid account creation policy: {0}")]
+    InvalidPolicy(String),
+
+    #[error("Invalid accounts file: {0}")]
+    Csv(#[from] csv::Error),
+}
+
+#[derive(Deserialize)]
+struct RegisteredClient {
+    client: u16,
+}
+
+impl AccountCreation {
+    /// Reads the registered clients from a CSV file with a `client` column.
+    /// Other columns are ignored.
+    pub fn registered_from_csv(reader: impl io::Read) -> Result<Self, AccountCreationError> {
+        let mut rdr = csv::ReaderBuilder::new()
+            .trim(csv::Trim::All)
+            .from_reader(reader);
+        let clients = rdr
+            .deserialize::<RegisteredClient>()
+            .map(|r| r.map(|r| r.client))
+            .collect::<Result<HashSet<u16>, csv::Error>>()?;
+
+        Ok(AccountCreation::Registered(clients))
+    }
+
+    pub(crate) fn is_registered(&self, client: u16) -> bool {
+        match self {
+            AccountCreation::Registered(clients) => clients.contains(&client),
+            _ => true,
+        }
+    }
+
+    /// Returns true when `tx` may open the account of its client
+    pub(crate) fn may_create(&self, tx: &Transaction) -> bool {
+        match self {
+            AccountCreation::AnyRecord => true,
+            AccountCreation::FirstDeposit | AccountCreation::Registered(_) => {
+                tx.typ == TransactionType::Deposit && self.is_registered(tx.account_id)
+            }
+        }
+    }
+
+    /// Returns true when an account opened by a rejected record is kept
+    pub(crate) fn keeps_rejected(&self) -> bool {
+        *self == AccountCreation::AnyRecord
+    }
+}
+
+impl FromStr for AccountCreation {
+    type Err = AccountCreationError;
+
+    /// Accepts `any-record`, `first-deposit` or `registered`, whose clients are read from a file
+    /// or taken from the registry attached to the ledger
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "any-record" => Ok(AccountCreation::AnyRecord),
+            "first-deposit" => Ok(AccountCreation::FirstDeposit),
+            "registered" => Ok(AccountCreation::Registered(HashSet::new())),
+            _ => Err(AccountCreationError::InvalidPolicy(s.into())),
+        }
+    }
+}
+
+impl fmt::Display for AccountCreation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AccountCreation::AnyRecord => write!(f, "any-record"),
+            AccountCreation::FirstDeposit => write!(f, "first-deposit"),
+            AccountCreation::Registered(clients) => {
+                write!(f, "registered ({} clients)", clients.len())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn transaction(typ: TransactionType, client: u16) -> Transaction {
+        Transaction {
+            id: 1,
+            account_id: client,
+            typ,
+            amount: Some("1".to_string()),
+            timestamp: None,
+            wallet: None,
+            to_wallet: None,
+            seller: None,
+        }
+    }
+
+    #[test]
+    fn test_that_registered_clients_are_read_from_csv() {
+        let policy =
+            AccountCreation::registered_from_csv("client, name\n1, Alice\n 7 , Bob\n".as_bytes())
+                .unwrap();
+        assert!(policy.is_registered(7));
+        assert!(!policy.is_registered(2));
+    }
+
+    #[test]
+    fn test_that_invalid_accounts_file_is_rejected() {
+        for invalid in ["client\nabc\n", "client\n70000\n", "name\nAlice\n"] {
+            assert!(
+                matches!(
+                    AccountCreation::registered_from_csv(invalid.as_bytes()),
+                    Err(AccountCreationError::Csv(_))
+                ),
+                "{invalid}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_that_registered_policy_without_file_has_no_client() {
+        // Clients of the `registered` policy come from the registry attached to the ledger
+        let policy = AccountCreation::from_str("registered").unwrap();
+        assert!(!policy.is_registered(7));
+    }
+
+    #[test]
+    fn test_that_policy_is_parsed_by_name() {
+        assert_eq!(
+            AccountCreation::from_str(" any-record ").unwrap(),
+            AccountCreation::AnyRecord
+        );
+        assert_eq!(
+            AccountCreation::from_str("first-deposit").unwrap(),
+            AccountCreation::FirstDeposit
+        );
+        assert!(matches!(
+            AccountCreation::from_str("first_deposit"),
+            Err(AccountCreationError::InvalidPolicy(_))
+        ));
+    }
+
+    #[test]
+    fn test_that_any_record_opens_an_account_and_keeps_it() {
+        let policy = AccountCreation::AnyRecord;
+        assert!(policy.may_create(&transaction(TransactionType::Dispute, 1)));
+        assert!(policy.keeps_rejected());
+    }
+
+    #[test]
+    fn test_that_only_deposits_open_an_account_with_first_deposit() {
+        let policy = AccountCreation::FirstDeposit;
+        assert!(policy.may_create(&transaction(TransactionType::Deposit, 1)));
+        for typ in [
+            TransactionType::Withdrawal,
+            TransactionType::Dispute,
+            TransactionType::Transfer,
+        ] {
+            assert!(!policy.may_create(&transaction(typ, 1)));
+        }
+        assert!(!policy.keeps_rejected());
+    }
+
+    #[test]
+    fn test_that_only_deposits_of_registered_clients_open_an_account() {
+        let policy = AccountCreation::Registered(HashSet::from([1]));
+        assert!(policy.may_create(&transaction(TransactionType::Deposit, 1)));
+        assert!(!policy.may_create(&transaction(TransactionType::Deposit, 2)));
+        assert!(!policy.may_create(&transaction(TransactionType::Withdrawal, 1)));
+        assert!(!policy.keeps_rejected());
+    }
+}
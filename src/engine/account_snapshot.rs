@@ -7,6 +7,22 @@ pub struct AccountSnapshot {
     pub client: String,
     pub available: String,
     pub held: String,
+    /// Funds in escrow, reported apart from the dispute holds of `held` once escrow is used
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub escrow: Option<String>,
+    pub total: String,
+    pub locked: bool,
+}
+
+/// Snapshot of a single wallet of a client
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct WalletSnapshot {
+    pub client: String,
+    pub wallet: String,
+    pub available: String,
+    pub held: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub escrow: Option<String>,
     pub total: String,
     pub locked: bool,
 }
@@ -0,0 +1,342 @@
+use crate::engine::amount::{Amount, AmountError};
+use crate::engine::dispute_window::DisputeWindow;
+use crate::engine::operation::{AppliedOperation, OperationKind};
+use serde::{Deserialize, Deserializer, Serialize};
+use std::collections::BTreeMap;
+use std::str::FromStr;
+use thiserror::Error;
+
+const DAY: u64 = 86_400;
+const REPORTING_THRESHOLD: &str = "10000";
+const STRUCTURING_COUNT: usize = 3;
+
+// Client, start of the period and direction of movements
+type MovementKey = (u16, Option<u64>, OperationKind);
+
+/// Thresholds of the AML report, loaded from a TOML file where every field is optional:
+///
+/// ```toml
+/// period = "1d"
+/// large_transaction = "10000"
+/// structuring_count = 3
+/// structuring_total = "10000"
+/// ```
+///
+/// Deposits and withdrawals are reported separately, per client and per period.
+/// A movement of at least `large_transaction` is reported on its own, and the smaller ones of a
+/// period are reported together as structuring when there are at least `structuring_count` of
+/// them adding up to `structuring_total` or more.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct AmlThresholds {
+    /// Length of the periods in seconds, aligned on the unix epoch (a day by default)
+    #[serde(deserialize_with = "deserialize_period")]
+    pub period: u64,
+    pub large_transaction: Amount,
+    pub structuring_count: usize,
+    pub structuring_total: Amount,
+}
+
+#[derive(Error, Debug)]
+pub enum AmlError {
+    #[error("Invalid AML thresholds: {0}")]
+    Parse(#[from] toml::de::Error),
+
+    #[error("Invalid AML thresholds: the period must last at least a second")]
+    EmptyPeriod,
+}
+
+/// Why movements are reported
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum AmlReportKind {
+    LargeTransaction,
+    Structuring,
+}
+
+/// Reportable event of the AML report, referencing the tx ids of the movements (separated by
+/// spaces). Movements without timestamp are reported over an unbounded period.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct AmlReportRow {
+    pub client: u16,
+    pub period_start: Option<u64>,
+    pub period_end: Option<u64>,
+    pub report: AmlReportKind,
+    pub operation: OperationKind,
+    pub count: usize,
+    pub total: String,
+    pub txs: String,
+}
+
+impl Default for AmlThresholds {
+    fn default() -> Self {
+        let threshold = Amount::from_str(REPORTING_THRESHOLD).unwrap_or_default();
+        AmlThresholds {
+            period: DAY,
+            large_transaction: threshold,
+            structuring_count: STRUCTURING_COUNT,
+            structuring_total: threshold,
+        }
+    }
+}
+
+impl AmlThresholds {
+    pub fn from_toml_str(s: &str) -> Result<Self, AmlError> {
+        let thresholds: AmlThresholds = toml::from_str(s)?;
+        if thresholds.period == 0 {
+            Err(AmlError::EmptyPeriod)?
+        }
+        Ok(thresholds)
+    }
+
+    // Start of the period of a timestamp
+    fn period_start(&self, timestamp: u64) -> u64 {
+        timestamp - timestamp % self.period.max(1)
+    }
+}
+
+// Period given as a duration such as `3600`, `12h` or `7d`
+fn deserialize_period<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u64, D::Error> {
+    let period = String::deserialize(deserializer)?;
+    match DisputeWindow::elapsed_from_str(&period) {
+        Ok(DisputeWindow::Elapsed(seconds)) => Ok(seconds),
+        _ => Err(serde::de::Error::custom(format!("invalid period {period}"))),
+    }
+}
+
+impl AmlReportRow {
+    /// Reportable events of the deposits and withdrawals of `operations`, ordered by client,
+    /// period, operation and report
+    pub(crate) fn build<'a>(
+        thresholds: &AmlThresholds,
+        operations: impl Iterator<Item = &'a AppliedOperation>,
+    ) -> Result<Vec<AmlReportRow>, AmountError> {
+        // Movements of every client, period and direction
+        let mut movements: BTreeMap<MovementKey, Vec<(u32, Amount)>> = BTreeMap::new();
+        for op in operations {
+            if !matches!(op.kind, OperationKind::Deposit | OperationKind::Withdrawal) {
+                continue;
+            }
+            let start = op.stamp.timestamp.map(|t| thresholds.period_start(t));
+            movements
+                .entry((op.client, start, op.kind))
+                .or_default()
+                .push((op.tx, op.amount));
+        }
+
+        let mut rows = vec![];
+        for ((client, start, operation), movements) in movements {
+            let row = |report, movements: &[&(u32, Amount)], total: Amount| AmlReportRow {
+                client,
+                period_start: start,
+                period_end: start.map(|start| start.saturating_add(thresholds.period)),
+                report,
+                operation,
+                count: movements.len(),
+                total: total.to_string(),
+                txs: movements
+                    .iter()
+                    .map(|(tx, _)| tx.to_string())
+                    .collect::<Vec<_>>()
+                    .join(" "),
+            };
+
+            let (large, small): (Vec<_>, Vec<_>) = movements
+                .iter()
+                .partition(|(_, amount)| *amount >= thresholds.large_transaction);
+            for movement in large {
+                rows.push(row(
+                    AmlReportKind::LargeTransaction,
+                    &[movement],
+                    movement.1,
+                ));
+            }
+            let total = small
+                .iter()
+                .try_fold(Amount::new(), |total, (_, amount)| total.add(amount))?;
+            if small.len() >= thresholds.structuring_count.max(1)
+                && total >= thresholds.structuring_total
+            {
+                rows.push(row(AmlReportKind::Structuring, &small, total));
+            }
+        }
+        Ok(rows)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::dispute_window::TxStamp;
+
+    // Reports large movements from 1000 and at least 2 smaller ones adding up to 900, per day
+    fn thresholds() -> AmlThresholds {
+        AmlThresholds::from_toml_str(
+            r#"
+            period = "1d"
+            large_transaction = "1000"
+            structuring_count = 2
+            structuring_total = "900"
+            "#,
+        )
+        .unwrap()
+    }
+
+    fn op(
+        client: u16,
+        tx: u32,
+        timestamp: Option<u64>,
+        kind: OperationKind,
+        amount: &str,
+    ) -> AppliedOperation {
+        AppliedOperation {
+            stamp: TxStamp {
+                sequence: tx as u64,
+                timestamp,
+            },
+            client,
+            tx,
+            kind,
+            amount: Amount::from_str(amount).unwrap(),
+            available: Amount::new(),
+            held: Amount::new(),
+        }
+    }
+
+    // Client, period, report and tx ids of the rows
+    fn report(operations: &[AppliedOperation]) -> Vec<(u16, Option<u64>, AmlReportKind, String)> {
+        AmlReportRow::build(&thresholds(), operations.iter())
+            .unwrap()
+            .into_iter()
+            .map(|r| (r.client, r.period_start, r.report, r.txs))
+            .collect()
+    }
+
+    #[test]
+    fn test_that_large_movement_is_reported_on_its_own() {
+        let operations = [
+            op(1, 1, Some(86_400), OperationKind::Deposit, "1000"),
+            op(1, 2, Some(86_400), OperationKind::Deposit, "999.9999"),
+        ];
+
+        let rows = AmlReportRow::build(&thresholds(), operations.iter()).unwrap();
+        assert_eq!(rows.len(), 1);
+        assert_eq!(rows[0].report, AmlReportKind::LargeTransaction);
+        assert_eq!((rows[0].count, rows[0].total.as_str()), (1, "1000.0000"));
+        assert_eq!(rows[0].txs, "1");
+    }
+
+    #[test]
+    fn test_that_small_movements_of_a_period_are_reported_as_structuring() {
+        let operations = [
+            op(1, 1, Some(86_400), OperationKind::Deposit, "1000"),
+            op(1, 2, Some(90_000), OperationKind::Deposit, "450"),
+            op(1, 3, Some(100_000), OperationKind::Deposit, "450"),
+        ];
+
+        let rows = AmlReportRow::build(&thresholds(), operations.iter()).unwrap();
+        assert_eq!(rows.len(), 2);
+        // The large movement is not part of the structuring
+        assert_eq!(rows[1].report, AmlReportKind::Structuring);
+        assert_eq!((rows[1].count, rows[1].total.as_str()), (2, "900.0000"));
+        assert_eq!(rows[1].txs, "2 3");
+    }
+
+    #[test]
+    fn test_that_structuring_needs_both_the_count_and_the_total() {
+        // Enough movements but not enough funds, then enough funds in a single movement
+        let too_little = [
+            op(1, 1, Some(0), OperationKind::Deposit, "449.9999"),
+            op(1, 2, Some(0), OperationKind::Deposit, "450"),
+        ];
+        assert_eq!(report(&too_little), vec![]);
+        let too_few = [op(1, 1, Some(0), OperationKind::Deposit, "950")];
+        assert_eq!(report(&too_few), vec![]);
+    }
+
+    #[test]
+    fn test_that_periods_are_aligned_on_the_unix_epoch() {
+        // The last second of a day and the first of the next one are in different periods
+        let operations = [
+            op(1, 1, Some(86_399), OperationKind::Deposit, "450"),
+            op(1, 2, Some(86_400), OperationKind::Deposit, "450"),
+            op(1, 3, Some(172_799), OperationKind::Deposit, "450"),
+        ];
+
+        let rows = AmlReportRow::build(&thresholds(), operations.iter()).unwrap();
+        assert_eq!(rows.len(), 1);
+        assert_eq!(rows[0].period_start, Some(86_400));
+        assert_eq!(rows[0].period_end, Some(172_800));
+        assert_eq!(rows[0].txs, "2 3");
+    }
+
+    #[test]
+    fn test_that_deposits_and_withdrawals_are_reported_apart() {
+        let operations = [
+            op(1, 1, Some(0), OperationKind::Deposit, "450"),
+            op(1, 2, Some(0), OperationKind::Withdrawal, "450"),
+            op(2, 3, Some(0), OperationKind::Deposit, "450"),
+        ];
+        assert_eq!(report(&operations), vec![]);
+    }
+
+    #[test]
+    fn test_that_other_operations_are_not_reported() {
+        let operations = [
+            op(1, 1, Some(0), OperationKind::Fee, "5000"),
+            op(1, 2, Some(0), OperationKind::Interest, "5000"),
+            op(1, 3, Some(0), OperationKind::Dispute, "5000"),
+        ];
+        assert_eq!(report(&operations), vec![]);
+    }
+
+    #[test]
+    fn test_that_movements_without_timestamp_share_an_unbounded_period() {
+        let operations = [
+            op(2, 1, None, OperationKind::Withdrawal, "450"),
+            op(2, 2, None, OperationKind::Withdrawal, "450"),
+            op(2, 3, Some(0), OperationKind::Withdrawal, "450"),
+        ];
+
+        let rows = AmlReportRow::build(&thresholds(), operations.iter()).unwrap();
+        assert_eq!(rows.len(), 1);
+        assert_eq!((rows[0].period_start, rows[0].period_end), (None, None));
+        assert_eq!(rows[0].txs, "1 2");
+    }
+
+    #[test]
+    fn test_that_thresholds_default_to_a_day_and_10000() {
+        let thresholds = AmlThresholds::from_toml_str("").unwrap();
+        assert_eq!(thresholds, AmlThresholds::default());
+        assert_eq!(thresholds.period, 86_400);
+        assert_eq!(thresholds.large_transaction.to_string(), "10000.0000");
+        assert_eq!(
+            AmlThresholds::from_toml_str("period = \"12h\"")
+                .unwrap()
+                .period,
+            43_200
+        );
+    }
+
+    #[test]
+    fn test_that_invalid_thresholds_are_rejected() {
+        assert!(matches!(
+            AmlThresholds::from_toml_str("period = \"0\""),
+            Err(AmlError::EmptyPeriod)
+        ));
+        for invalid in [
+            "period = \"10r\"",
+            "period = \"unlimited\"",
+            "large_transaction = \"x\"",
+            "unknown = 1",
+        ] {
+            assert!(
+                matches!(
+                    AmlThresholds::from_toml_str(invalid),
+                    Err(AmlError::Parse(_))
+                ),
+                "{invalid}"
+            );
+        }
+    }
+}
@@ -1,3 +1,4 @@
+use serde::de::{self, Deserialize, Deserializer, Visitor};
 use std::fmt;
 use std::str::FromStr;
 use thiserror::Error;
@@ -34,6 +35,15 @@ impl Amount {
         Amount { store: 0 }
     }
 
+    /// Amount expressed in 1/10_000, for arithmetic that Amount doesn't cover
+    pub(crate) fn units(&self) -> i64 {
+        self.store
+    }
+
+    pub(crate) fn from_units(store: i64) -> Self {
+        Amount { store }
+    }
+
     pub fn add(&self, other: &Amount) -> Result<Amount, AmountError> {
         match self.store.checked_add(other.store) {
             Some(total) => Ok(Amount { store: total }),
@@ -47,6 +57,63 @@ impl Amount {
             None => Err(AmountError::Underflow)?,
         }
     }
+
+    pub fn neg(&self) -> Result<Amount, AmountError> {
+        match self.store.checked_neg() {
+            Some(total) => Ok(Amount { store: total }),
+            None => Err(AmountError::Overflow)?,
+        }
+    }
+
+    /// `rate` percent of the amount (e.g. a rate of 1.5 is 1.5%), rounded half away from zero
+    pub fn percent(&self, rate: &Amount) -> Result<Amount, AmountError> {
+        // store * rate.store / (100 * 10_000), computed on i128 to avoid intermediate overflow
+        let product = self.store as i128 * rate.store as i128;
+        let divisor: i128 = 1_000_000;
+        let mut quotient = product / divisor;
+        if (product % divisor).abs() * 2 >= divisor {
+            quotient += product.signum();
+        }
+        match i64::try_from(quotient) {
+            Ok(store) => Ok(Amount { store }),
+            Err(_) if quotient > 0 => Err(AmountError::Overflow)?,
+            Err(_) => Err(AmountError::Underflow)?,
+        }
+    }
+}
+
+/// Amounts are read from strings (`"1.5"`) to keep them exact.
+/// Integers and floats are accepted for convenience in configuration files.
+impl<'de> Deserialize<'de> for Amount {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct AmountVisitor;
+
+        impl Visitor<'_> for AmountVisitor {
+            type Value = Amount;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                write!(f, "an amount with up to 4 decimals")
+            }
+
+            fn visit_str<E: de::Error>(self, v: &str) -> Result<Amount, E> {
+                Amount::from_str(v).map_err(E::custom)
+            }
+
+            fn visit_i64<E: de::Error>(self, v: i64) -> Result<Amount, E> {
+                self.visit_str(&v.to_string())
+            }
+
+            fn visit_u64<E: de::Error>(self, v: u64) -> Result<Amount, E> {
+                self.visit_str(&v.to_string())
+            }
+
+            fn visit_f64<E: de::Error>(self, v: f64) -> Result<Amount, E> {
+                self.visit_str(&v.to_string())
+            }
+        }
+
+        deserializer.deserialize_any(AmountVisitor)
+    }
 }
 
 impl FromStr for Amount {
@@ -287,6 +354,34 @@ mod tests {
         assert!(matches!(sum.err().unwrap(), AmountError::Overflow));
     }
 
+    #[test]
+    pub fn test_that_percentage_is_rounded_half_away_from_zero() {
+        let amount = Amount::from_str("100.0").unwrap();
+        let rate = Amount::from_str("1.5").unwrap();
+        assert_eq!(amount.percent(&rate).unwrap().to_string(), "1.5000");
+
+        // 0.0333 * 1.5% = 0.00049950 -> 0.0005
+        let amount = Amount::from_str("0.0333").unwrap();
+        assert_eq!(amount.percent(&rate).unwrap().to_string(), "0.0005");
+
+        // 0.0333 * 1.4% = 0.00046620 -> 0.0005, 0.0321 * 1.4% = 0.00044940 -> 0.0004
+        let rate = Amount::from_str("1.4").unwrap();
+        assert_eq!(amount.percent(&rate).unwrap().to_string(), "0.0005");
+        let amount = Amount::from_str("0.0321").unwrap();
+        assert_eq!(amount.percent(&rate).unwrap().to_string(), "0.0004");
+
+        let amount = Amount::from_str("-0.0333").unwrap();
+        let rate = Amount::from_str("1.5").unwrap();
+        assert_eq!(amount.percent(&rate).unwrap().to_string(), "-0.0005");
+
+        let amount = Amount::from_str("922337203685477.5807").unwrap();
+        let rate = Amount::from_str("200").unwrap();
+        assert!(matches!(
+            amount.percent(&rate).unwrap_err(),
+            AmountError::Overflow
+        ));
+    }
+
     #[test]
     pub fn test_that_underflow_return_error() {
         let amount = Amount::from_str("-922337203685477.5807");
@@ -0,0 +1,551 @@
+use crate::engine::amount::Amount;
+use crate::engine::dispute_window::{DisputeWindow, DisputeWindowError, TxStamp};
+use crate::engine::observer::{LedgerEvent, LedgerObserver};
+use crate::engine::{Transaction, TransactionType};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::io::Write;
+use std::str::FromStr;
+use thiserror::Error;
+
+/// Heuristic fed by the transactions of the ledger, flagging suspicious behaviour of a client
+pub trait AnomalyDetector: fmt::Debug + Send {
+    /// Name of the detector, reported in its alerts
+    fn name(&self) -> &str;
+
+    /// Tx ids flagged by `activity` (its own included), given the activity recorded before it
+    fn inspect(&self, activity: &Activity) -> Option<Vec<u32>>;
+
+    /// Remember a transaction applied by the ledger
+    fn record(&mut self, _activity: &Activity) {}
+
+    /// Forget what was recorded of the record `sequence` of `client`, rolled back by the ledger
+    fn forget(&mut self, _client: u16, _sequence: u64) {}
+}
+
+/// Transaction seen by the detectors
+#[derive(Debug)]
+pub struct Activity<'a> {
+    pub tx: &'a Transaction,
+    /// Amount of the transaction, `None` for follow-ups or when missing or invalid
+    pub amount: Option<Amount>,
+    pub stamp: TxStamp,
+}
+
+/// Suspicious behaviour of a client, flagged by the `rule` detector
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Alert {
+    pub client: u16,
+    pub rule: String,
+    pub txs: Vec<u32>,
+}
+
+/// Detectors run in order on every transaction before the ledger applies it.
+/// Their alerts are reported as `AnomalyFlagged` events once the transaction is applied, and the
+/// transaction is rejected instead when a blocking detector flags it.
+///
+/// Detectors can be loaded from a TOML file, with their thresholds and windows (see
+/// `DisputeWindow` for the syntax):
+///
+/// ```toml
+/// [[detectors]]
+/// detector = "rapid_cycle"
+/// window = "1h"
+/// min_share = "90"
+///
+/// [[detectors]]
+/// detector = "near_threshold"
+/// threshold = "10000"
+/// margin = "500"
+/// block = true
+/// ```
+///
+/// The activity of the records undone by failed batches or `rollback_to` is forgotten.
+#[derive(Debug, Default)]
+pub struct Detectors {
+    detectors: Vec<(Box<dyn AnomalyDetector>, bool)>, // With whether they block what they flag
+}
+
+#[derive(Error, Debug)]
+pub enum DetectorError {
+    #[error("Invalid anomaly detectors: {0}")]
+    Parse(#[from] toml::de::Error),
+
+    #[error(transparent)]
+    Window(#[from] DisputeWindowError),
+}
+
+/// Flags withdrawals taking out at least `min_share` percent of a deposit made within `window`
+#[derive(Debug)]
+pub struct RapidCycle {
+    min_share: Amount,
+    deposits: Recent<Amount>,
+}
+
+/// Flags deposits of at least `threshold - margin` but below `threshold`, usually a reporting one
+#[derive(Debug)]
+pub struct NearThreshold {
+    pub threshold: Amount,
+    pub margin: Amount,
+}
+
+/// Flags the dispute making `count` disputes of a client within `window`
+#[derive(Debug)]
+pub struct RepeatedDisputes {
+    count: usize,
+    disputes: Recent<()>,
+}
+
+/// Flags withdrawals made within `window` of a deposit of at least `amount`
+#[derive(Debug)]
+pub struct LargeDepositWithdrawal {
+    amount: Amount,
+    deposits: Recent<()>,
+}
+
+/// Writes the alerts of the `AnomalyFlagged` events as CSV rows (`client,rule,txs`), the tx ids
+/// being separated by spaces
+pub struct AlertsCsvObserver<W: Write> {
+    writer: csv::Writer<W>,
+}
+
+// Recent transactions of every client, oldest first
+#[derive(Debug)]
+struct Recent<T> {
+    window: DisputeWindow,
+    by_client: HashMap<u16, VecDeque<(u32, TxStamp, T)>>,
+}
+
+// Detector of a TOML file, tagged by its name
+#[derive(Deserialize, Debug)]
+#[serde(tag = "detector", rename_all = "snake_case", deny_unknown_fields)]
+enum DetectorConfig {
+    RapidCycle {
+        window: String,
+        min_share: Amount,
+        #[serde(default)]
+        block: bool,
+    },
+    NearThreshold {
+        threshold: Amount,
+        margin: Amount,
+        #[serde(default)]
+        block: bool,
+    },
+    RepeatedDisputes {
+        window: String,
+        count: usize,
+        #[serde(default)]
+        block: bool,
+    },
+    WithdrawalAfterLargeDeposit {
+        window: String,
+        amount: Amount,
+        #[serde(default)]
+        block: bool,
+    },
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+struct DetectorFile {
+    #[serde(default)]
+    detectors: Vec<DetectorConfig>,
+}
+
+#[derive(Serialize)]
+struct AlertRow<'a> {
+    client: u16,
+    rule: &'a str,
+    txs: String,
+}
+
+impl<'a> Activity<'a> {
+    pub(crate) fn new(tx: &'a Transaction, stamp: TxStamp) -> Self {
+        Activity {
+            tx,
+            amount: tx.record_amount(),
+            stamp,
+        }
+    }
+}
+
+impl Detectors {
+    pub fn new() -> Self {
+        Detectors::default()
+    }
+
+    /// Run `detector` after the detectors already added, rejecting what it flags when `blocking`
+    pub fn with_detector(
+        mut self,
+        detector: impl AnomalyDetector + 'static,
+        blocking: bool,
+    ) -> Self {
+        self.detectors.push((Box::new(detector), blocking));
+        self
+    }
+
+    pub fn from_toml_str(s: &str) -> Result<Self, DetectorError> {
+        let file: DetectorFile = toml::from_str(s)?;
+        let mut detectors = Detectors::new();
+        for config in file.detectors {
+            detectors = match config {
+                DetectorConfig::RapidCycle {
+                    window,
+                    min_share,
+                    block,
+                } => detectors.with_detector(
+                    RapidCycle::new(DisputeWindow::from_str(&window)?, min_share),
+                    block,
+                ),
+                DetectorConfig::NearThreshold {
+                    threshold,
+                    margin,
+                    block,
+                } => detectors.with_detector(NearThreshold { threshold, margin }, block),
+                DetectorConfig::RepeatedDisputes {
+                    window,
+                    count,
+                    block,
+                } => detectors.with_detector(
+                    RepeatedDisputes::new(DisputeWindow::from_str(&window)?, count),
+                    block,
+                ),
+                DetectorConfig::WithdrawalAfterLargeDeposit {
+                    window,
+                    amount,
+                    block,
+                } => detectors.with_detector(
+                    LargeDepositWithdrawal::new(DisputeWindow::from_str(&window)?, amount),
+                    block,
+                ),
+            };
+        }
+        Ok(detectors)
+    }
+
+    /// Names of the detectors, in the order they run
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.detectors.iter().map(|(detector, _)| detector.name())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.detectors.is_empty()
+    }
+
+    // Alerts raised by `activity`, with whether their detector blocks it
+    pub(crate) fn inspect(&self, activity: &Activity) -> Vec<(Alert, bool)> {
+        self.detectors
+            .iter()
+            .filter_map(|(detector, blocking)| {
+                let txs = detector.inspect(activity)?;
+                let alert = Alert {
+                    client: activity.tx.account_id,
+                    rule: detector.name().to_string(),
+                    txs,
+                };
+                Some((alert, *blocking))
+            })
+            .collect()
+    }
+
+    pub(crate) fn record(&mut self, activity: &Activity) {
+        for (detector, _) in &mut self.detectors {
+            detector.record(activity);
+        }
+    }
+
+    pub(crate) fn forget(&mut self, client: u16, sequence: u64) {
+        for (detector, _) in &mut self.detectors {
+            detector.forget(client, sequence);
+        }
+    }
+}
+
+impl<T> Recent<T> {
+    fn new(window: DisputeWindow) -> Self {
+        Recent {
+            window,
+            by_client: HashMap::new(),
+        }
+    }
+
+    // Transactions of `client` still within the window at `now`
+    fn within(&self, client: u16, now: &TxStamp) -> impl Iterator<Item = &(u32, TxStamp, T)> {
+        self.by_client
+            .get(&client)
+            .into_iter()
+            .flatten()
+            .filter(|(_, stamp, _)| !self.window.is_expired(stamp, now))
+    }
+
+    // Remember a transaction, forgetting those of the client out of the window
+    fn push(&mut self, client: u16, tx: u32, stamp: TxStamp, value: T) {
+        let recent = self.by_client.entry(client).or_default();
+        while recent
+            .front()
+            .is_some_and(|(_, origin, _)| self.window.is_expired(origin, &stamp))
+        {
+            recent.pop_front();
+        }
+        recent.push_back((tx, stamp, value));
+    }
+
+    // Forget the transaction of a record that was rolled back
+    fn forget(&mut self, client: u16, sequence: u64) {
+        if let Some(recent) = self.by_client.get_mut(&client) {
+            recent.retain(|(_, stamp, _)| stamp.sequence != sequence);
+        }
+    }
+}
+
+impl RapidCycle {
+    pub fn new(window: DisputeWindow, min_share: Amount) -> Self {
+        RapidCycle {
+            min_share,
+            deposits: Recent::new(window),
+        }
+    }
+}
+
+impl AnomalyDetector for RapidCycle {
+    fn name(&self) -> &str {
+        "rapid_cycle"
+    }
+
+    fn inspect(&self, activity: &Activity) -> Option<Vec<u32>> {
+        let (TransactionType::Withdrawal, Some(amount)) = (activity.tx.typ, activity.amount) else {
+            return None;
+        };
+        let (deposit, _, _) = self
+            .deposits
+            .within(activity.tx.account_id, &activity.stamp)
+            .filter(|(_, _, deposit)| {
+                deposit
+                    .percent(&self.min_share)
+                    .is_ok_and(|share| amount >= share)
+            })
+            .last()?;
+        Some(vec![*deposit, activity.tx.id])
+    }
+
+    fn record(&mut self, activity: &Activity) {
+        if let (TransactionType::Deposit, Some(amount)) = (activity.tx.typ, activity.amount) {
+            let tx = activity.tx;
+            self.deposits
+                .push(tx.account_id, tx.id, activity.stamp, amount);
+        }
+    }
+
+    fn forget(&mut self, client: u16, sequence: u64) {
+        self.deposits.forget(client, sequence);
+    }
+}
+
+impl AnomalyDetector for NearThreshold {
+    fn name(&self) -> &str {
+        "near_threshold"
+    }
+
+    fn inspect(&self, activity: &Activity) -> Option<Vec<u32>> {
+        let (TransactionType::Deposit, Some(amount)) = (activity.tx.typ, activity.amount) else {
+            return None;
+        };
+        let floor = self.threshold.sub(&self.margin).ok()?;
+        (amount >= floor && amount < self.threshold).then(|| vec![activity.tx.id])
+    }
+}
+
+impl RepeatedDisputes {
+    pub fn new(window: DisputeWindow, count: usize) -> Self {
+        RepeatedDisputes {
+            count,
+            disputes: Recent::new(window),
+        }
+    }
+}
+
+impl AnomalyDetector for RepeatedDisputes {
+    fn name(&self) -> &str {
+        "repeated_disputes"
+    }
+
+    fn inspect(&self, activity: &Activity) -> Option<Vec<u32>> {
+        if activity.tx.typ != TransactionType::Dispute {
+            return None;
+        }
+        let mut txs: Vec<u32> = self
+            .disputes
+            .within(activity.tx.account_id, &activity.stamp)
+            .map(|(tx, _, _)| *tx)
+            .collect();
+        txs.push(activity.tx.id);
+        (txs.len() >= self.count).then_some(txs)
+    }
+
+    fn record(&mut self, activity: &Activity) {
+        if activity.tx.typ == TransactionType::Dispute {
+            let tx = activity.tx;
+            self.disputes.push(tx.account_id, tx.id, activity.stamp, ());
+        }
+    }
+
+    fn forget(&mut self, client: u16, sequence: u64) {
+        self.disputes.forget(client, sequence);
+    }
+}
+
+impl LargeDepositWithdrawal {
+    pub fn new(window: DisputeWindow, amount: Amount) -> Self {
+        LargeDepositWithdrawal {
+            amount,
+            deposits: Recent::new(window),
+        }
+    }
+}
+
+impl AnomalyDetector for LargeDepositWithdrawal {
+    fn name(&self) -> &str {
+        "withdrawal_after_large_deposit"
+    }
+
+    fn inspect(&self, activity: &Activity) -> Option<Vec<u32>> {
+        if activity.tx.typ != TransactionType::Withdrawal {
+            return None;
+        }
+        let (deposit, _, _) = self
+            .deposits
+            .within(activity.tx.account_id, &activity.stamp)
+            .last()?;
+        Some(vec![*deposit, activity.tx.id])
+    }
+
+    fn record(&mut self, activity: &Activity) {
+        let tx = activity.tx;
+        if tx.typ == TransactionType::Deposit
+            && activity.amount.is_some_and(|amount| amount >= self.amount)
+        {
+            self.deposits.push(tx.account_id, tx.id, activity.stamp, ());
+        }
+    }
+
+    fn forget(&mut self, client: u16, sequence: u64) {
+        self.deposits.forget(client, sequence);
+    }
+}
+
+impl<W: Write> AlertsCsvObserver<W> {
+    pub fn new(writer: W) -> Self {
+        AlertsCsvObserver {
+            writer: csv::Writer::from_writer(writer),
+        }
+    }
+}
+
+impl<W: Write + Send> LedgerObserver for AlertsCsvObserver<W> {
+    fn on_event(&mut self, event: &LedgerEvent) {
+        let LedgerEvent::AnomalyFlagged { client, rule, txs } = event else {
+            return;
+        };
+        let row = AlertRow {
+            client: *client,
+            rule,
+            txs: txs.iter().map(u32::to_string).collect::<Vec<_>>().join(" "),
+        };
+        let result = self
+            .writer
+            .serialize(row)
+            .map_err(std::io::Error::from)
+            .and_then(|()| self.writer.flush());
+        if let Err(e) = result {
+            log::warn!("Error writing alert {event:?}: {e}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_that_detectors_flag_suspicious_activity_within_their_window() {
+        let mut detectors = Detectors::from_toml_str(
+            r#"
+            [[detectors]]
+            detector = "rapid_cycle"
+            window = "2r"
+            min_share = "90"
+
+            [[detectors]]
+            detector = "near_threshold"
+            threshold = "10000"
+            margin = "500"
+
+            [[detectors]]
+            detector = "repeated_disputes"
+            window = "1h"
+            count = 2
+            block = true
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            detectors.names().collect::<Vec<_>>(),
+            vec!["rapid_cycle", "near_threshold", "repeated_disputes"]
+        );
+
+        let mut sequence = 0;
+        let mut feed = |typ, id, amount: Option<&str>| {
+            sequence += 1;
+            let tx = Transaction {
+                id,
+                account_id: 1,
+                amount: amount.map(String::from),
+                typ,
+                timestamp: None,
+                wallet: None,
+                to_wallet: None,
+                seller: None,
+            };
+            let activity = Activity::new(
+                &tx,
+                TxStamp {
+                    sequence,
+                    timestamp: None,
+                },
+            );
+            let alerts = detectors.inspect(&activity);
+            detectors.record(&activity);
+            alerts
+                .into_iter()
+                .map(|(alert, blocking)| (alert.rule, alert.txs, blocking))
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            feed(TransactionType::Deposit, 1, Some("9600")),
+            vec![("near_threshold".to_string(), vec![1], false)]
+        );
+        assert!(feed(TransactionType::Withdrawal, 2, Some("8000")).is_empty());
+        assert_eq!(
+            feed(TransactionType::Withdrawal, 3, Some("8640")),
+            vec![("rapid_cycle".to_string(), vec![1, 3], false)]
+        );
+        // Out of the window of the deposit
+        assert!(feed(TransactionType::Withdrawal, 4, Some("9000")).is_empty());
+        assert!(feed(TransactionType::Dispute, 1, None).is_empty());
+        assert_eq!(
+            feed(TransactionType::Dispute, 2, None),
+            vec![("repeated_disputes".to_string(), vec![1, 2], true)]
+        );
+
+        assert!(Detectors::from_toml_str("[[detectors]]\ndetector = \"unknown\"").is_err());
+        assert!(
+            Detectors::from_toml_str(
+                "[[detectors]]\ndetector = \"repeated_disputes\"\nwindow = \"1w\"\ncount = 2"
+            )
+            .is_err()
+        );
+    }
+}
@@ -0,0 +1,32 @@
+use crate::engine::account_creation::AccountCreation;
+use crate::engine::dispute_window::DisputeWindow;
+use crate::engine::fees::FeeSchedule;
+use crate::engine::interest::InterestPolicy;
+use crate::engine::registry::KycLimits;
+use crate::engine::retention::RetentionPolicy;
+use crate::engine::rules::RuleChain;
+use crate::engine::wallet::LockScope;
+
+/// Tunable behaviour of the `Ledger`.
+/// The default configuration matches the historical behaviour of the engine.
+#[derive(Debug, Clone, Default)]
+pub struct LedgerConfig {
+    /// How long deposits stay disputable
+    pub dispute_window: DisputeWindow,
+    /// Compact expired account history every N records (disabled when `None`)
+    pub compaction_interval: Option<u64>,
+    /// Limits on the transaction history kept in memory
+    pub retention: RetentionPolicy,
+    /// Fees charged on deposits and withdrawals
+    pub fees: FeeSchedule,
+    /// Interest paid on available balances by `accrue_interest` records (disabled when `None`)
+    pub interest: Option<InterestPolicy>,
+    /// Which records open the account of a new client
+    pub account_creation: AccountCreation,
+    /// Transaction limits of registered clients, by KYC tier
+    pub kyc_limits: KycLimits,
+    /// Wallets locked by a chargeback
+    pub chargeback_lock: LockScope,
+    /// Rules checked before applying a transaction
+    pub rules: RuleChain,
+}
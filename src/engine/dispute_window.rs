@@ -0,0 +1,165 @@
+use std::fmt;
+use std::str::FromStr;
+use thiserror::Error;
+
+/// Position of a record in the input feed.
+/// `sequence` is the 1-based index of the record given to the ledger and
+/// `timestamp` is the optional unix timestamp (seconds) read from the input.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TxStamp {
+    pub sequence: u64,
+    pub timestamp: Option<u64>,
+}
+
+/// How long a transaction stays disputable after it has been applied.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum DisputeWindow {
+    /// Transactions can be disputed forever (legacy behaviour)
+    #[default]
+    Unlimited,
+    /// Number of seconds elapsed between the transaction timestamp and the dispute timestamp
+    Elapsed(u64),
+    /// Number of records processed by the ledger after the transaction
+    Records(u64),
+}
+
+#[derive(Error, Debug, Clone)]
+pub enum DisputeWindowError {
+    #[error("Invalid dispute window duration: {0}")]
+    InvalidDuration(String),
+}
+
+impl DisputeWindow {
+    /// Returns true when a transaction stamped with `origin` can no longer be disputed at `now`.
+    /// With an `Elapsed` window, transactions missing a timestamp never expire as their age is unknown.
+    pub fn is_expired(&self, origin: &TxStamp, now: &TxStamp) -> bool {
+        match self {
+            DisputeWindow::Unlimited => false,
+            DisputeWindow::Elapsed(seconds) => match (origin.timestamp, now.timestamp) {
+                (Some(from), Some(to)) => to.saturating_sub(from) > *seconds,
+                _ => false,
+            },
+            DisputeWindow::Records(records) => {
+                now.sequence.saturating_sub(origin.sequence) > *records
+            }
+        }
+    }
+
+    /// Parses a duration such as `3600`, `90s`, `15m`, `12h` or `30d` into an `Elapsed` window
+    pub fn elapsed_from_str(s: &str) -> Result<Self, DisputeWindowError> {
+        let s = s.trim();
+        let (value, unit) = match s.char_indices().last() {
+            Some((idx, c)) if c.is_ascii_alphabetic() => (&s[..idx], Some(c)),
+            _ => (s, None),
+        };
+        let value = value
+            .parse::<u64>()
+            .map_err(|_| DisputeWindowError::InvalidDuration(s.into()))?;
+        let multiplier = match unit {
+            None | Some('s') => 1,
+            Some('m') => 60,
+            Some('h') => 3_600,
+            Some('d') => 86_400,
+            Some(_) => Err(DisputeWindowError::InvalidDuration(s.into()))?,
+        };
+        let seconds = value
+            .checked_mul(multiplier)
+            .ok_or(DisputeWindowError::InvalidDuration(s.into()))?;
+
+        Ok(DisputeWindow::Elapsed(seconds))
+    }
+}
+
+impl FromStr for DisputeWindow {
+    type Err = DisputeWindowError;
+
+    /// Accepts `unlimited`, a duration (see `elapsed_from_str`) or a record count suffixed by `r`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        if s == "unlimited" {
+            return Ok(DisputeWindow::Unlimited);
+        }
+        if let Some(records) = s.strip_suffix('r') {
+            return records
+                .parse::<u64>()
+                .map(DisputeWindow::Records)
+                .map_err(|_| DisputeWindowError::InvalidDuration(s.into()));
+        }
+        DisputeWindow::elapsed_from_str(s)
+    }
+}
+
+impl fmt::Display for DisputeWindow {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DisputeWindow::Unlimited => write!(f, "unlimited"),
+            DisputeWindow::Elapsed(seconds) => write!(f, "{seconds}s"),
+            DisputeWindow::Records(records) => write!(f, "{records}r"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_that_windows_can_be_parsed() {
+        assert_eq!(
+            DisputeWindow::from_str("unlimited").unwrap(),
+            DisputeWindow::Unlimited
+        );
+        assert_eq!(
+            DisputeWindow::from_str("3600").unwrap(),
+            DisputeWindow::Elapsed(3600)
+        );
+        assert_eq!(
+            DisputeWindow::from_str("15m").unwrap(),
+            DisputeWindow::Elapsed(900)
+        );
+        assert_eq!(
+            DisputeWindow::from_str("30d").unwrap(),
+            DisputeWindow::Elapsed(2_592_000)
+        );
+        assert_eq!(
+            DisputeWindow::from_str("1000r").unwrap(),
+            DisputeWindow::Records(1000)
+        );
+        assert!(DisputeWindow::from_str("10w").is_err());
+        assert!(DisputeWindow::from_str("abc").is_err());
+        assert!(DisputeWindow::from_str("").is_err());
+    }
+
+    #[test]
+    fn test_that_window_expiry_is_computed_from_origin() {
+        let origin = TxStamp {
+            sequence: 10,
+            timestamp: Some(1_000),
+        };
+        let within = TxStamp {
+            sequence: 15,
+            timestamp: Some(1_060),
+        };
+        let after = TxStamp {
+            sequence: 16,
+            timestamp: Some(1_061),
+        };
+
+        let window = DisputeWindow::Elapsed(60);
+        assert!(!window.is_expired(&origin, &within));
+        assert!(window.is_expired(&origin, &after));
+
+        let window = DisputeWindow::Records(5);
+        assert!(!window.is_expired(&origin, &within));
+        assert!(window.is_expired(&origin, &after));
+
+        assert!(!DisputeWindow::Unlimited.is_expired(&origin, &after));
+
+        // Missing timestamps never expire an elapsed window
+        let untimed = TxStamp {
+            sequence: 1_000,
+            timestamp: None,
+        };
+        assert!(!DisputeWindow::Elapsed(60).is_expired(&origin, &untimed));
+    }
+}
@@ -0,0 +1,215 @@
+use crate::engine::amount::{Amount, AmountError};
+use serde::Deserialize;
+use std::collections::HashMap;
+use thiserror::Error;
+
+/// Transaction types that can be charged a fee
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum FeeTxType {
+    Deposit,
+    Withdrawal,
+}
+
+/// Fee for transactions up to `up_to` (inclusive, no limit when absent)
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct FeeBand {
+    pub up_to: Option<Amount>,
+    pub flat: Option<Amount>,
+    pub percentage: Option<Amount>,
+}
+
+/// Fee charged on a transaction type, optionally restricted to a client tier.
+/// The fee is `flat + percentage` of the amount, or taken from the first band covering
+/// the amount when `bands` are given, then capped between `min` and `max`.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct FeeRule {
+    #[serde(rename = "type")]
+    pub typ: FeeTxType,
+    pub tier: Option<String>,
+    pub flat: Option<Amount>,
+    pub percentage: Option<Amount>,
+    #[serde(default)]
+    pub bands: Vec<FeeBand>,
+    pub min: Option<Amount>,
+    pub max: Option<Amount>,
+}
+
+/// Fee schedule, loaded from a TOML file:
+///
+/// ```toml
+/// refund_on_chargeback = true
+///
+/// [clients]
+/// 1 = "premium"
+///
+/// [[rules]]
+/// type = "deposit"
+/// percentage = "1.5"
+/// min = "0.5"
+/// max = "10"
+///
+/// [[rules]]
+/// type = "withdrawal"
+/// tier = "premium"
+/// bands = [{ up_to = "100", flat = "1" }, { percentage = "0.5" }]
+/// ```
+///
+/// For a transaction, the first rule matching the client tier is used, otherwise the first
+/// rule without tier. Clients not listed in `clients` have no tier.
+#[derive(Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct FeeSchedule {
+    #[serde(default)]
+    pub refund_on_chargeback: bool,
+    /// Tier of each client, keyed by client id
+    #[serde(default)]
+    pub clients: HashMap<String, String>,
+    #[serde(default)]
+    pub rules: Vec<FeeRule>,
+}
+
+#[derive(Error, Debug)]
+pub enum FeeError {
+    #[error("Invalid fee schedule: {0}")]
+    Parse(#[from] toml::de::Error),
+
+    #[error("Invalid fee rule: min is greater than max")]
+    InvalidCaps,
+}
+
+impl FeeSchedule {
+    pub fn from_toml_str(s: &str) -> Result<Self, FeeError> {
+        let schedule: FeeSchedule = toml::from_str(s)?;
+        if schedule
+            .rules
+            .iter()
+            .any(|r| matches!((r.min, r.max), (Some(min), Some(max)) if min > max))
+        {
+            Err(FeeError::InvalidCaps)?
+        }
+        Ok(schedule)
+    }
+
+    /// Fee due by `client` for a transaction of `amount`, zero when no rule applies
+    pub(crate) fn fee(
+        &self,
+        typ: FeeTxType,
+        client: u16,
+        amount: &Amount,
+    ) -> Result<Amount, AmountError> {
+        let tier = self.clients.get(&client.to_string());
+        let rules = || self.rules.iter().filter(|r| r.typ == typ);
+        let rule = rules()
+            .find(|r| tier.is_some() && r.tier.as_ref() == tier)
+            .or_else(|| rules().find(|r| r.tier.is_none()));
+
+        match rule {
+            None => Ok(Amount::new()),
+            Some(rule) => rule.fee(amount),
+        }
+    }
+}
+
+impl FeeRule {
+    fn fee(&self, amount: &Amount) -> Result<Amount, AmountError> {
+        let (flat, percentage) = if self.bands.is_empty() {
+            (self.flat, self.percentage)
+        } else {
+            match self
+                .bands
+                .iter()
+                .find(|b| b.up_to.is_none_or(|up_to| *amount <= up_to))
+            {
+                Some(band) => (band.flat, band.percentage),
+                None => (None, None),
+            }
+        };
+
+        let mut fee = flat.unwrap_or_default();
+        if let Some(percentage) = percentage {
+            fee = fee.add(&amount.percent(&percentage)?)?;
+        }
+        if let Some(min) = self.min
+            && fee < min
+        {
+            fee = min;
+        }
+        if let Some(max) = self.max
+            && fee > max
+        {
+            fee = max;
+        }
+        Ok(fee)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    const SCHEDULE: &str = r#"
+        refund_on_chargeback = true
+
+        [clients]
+        1 = "premium"
+
+        [[rules]]
+        type = "deposit"
+        percentage = "1.5"
+        min = "0.5"
+        max = "10"
+
+        [[rules]]
+        type = "withdrawal"
+        flat = 2
+
+        [[rules]]
+        type = "withdrawal"
+        tier = "premium"
+        bands = [{ up_to = "100", flat = "1" }, { up_to = "1000", percentage = 0.5 }]
+    "#;
+
+    fn fee(schedule: &FeeSchedule, typ: FeeTxType, client: u16, amount: &str) -> String {
+        schedule
+            .fee(typ, client, &Amount::from_str(amount).unwrap())
+            .unwrap()
+            .to_string()
+    }
+
+    #[test]
+    fn test_that_fees_follow_the_schedule() {
+        let schedule = FeeSchedule::from_toml_str(SCHEDULE).unwrap();
+        assert!(schedule.refund_on_chargeback);
+
+        // Percentage with min and max caps
+        assert_eq!(fee(&schedule, FeeTxType::Deposit, 2, "100"), "1.5000");
+        assert_eq!(fee(&schedule, FeeTxType::Deposit, 2, "10"), "0.5000");
+        assert_eq!(fee(&schedule, FeeTxType::Deposit, 1, "10000"), "10.0000");
+
+        // Flat fee for clients without tier
+        assert_eq!(fee(&schedule, FeeTxType::Withdrawal, 2, "500"), "2.0000");
+
+        // Bands for premium clients, no fee above the last band
+        assert_eq!(fee(&schedule, FeeTxType::Withdrawal, 1, "100"), "1.0000");
+        assert_eq!(fee(&schedule, FeeTxType::Withdrawal, 1, "500"), "2.5000");
+        assert_eq!(fee(&schedule, FeeTxType::Withdrawal, 1, "5000"), "0.0000");
+
+        // No fee without schedule
+        let schedule = FeeSchedule::default();
+        assert_eq!(fee(&schedule, FeeTxType::Deposit, 1, "100"), "0.0000");
+    }
+
+    #[test]
+    fn test_that_invalid_schedule_is_rejected() {
+        let err = FeeSchedule::from_toml_str("[[rules]]\ntype = \"deposit\"\nmin = 5\nmax = 1");
+        assert!(matches!(err.unwrap_err(), FeeError::InvalidCaps));
+
+        let err = FeeSchedule::from_toml_str("[[rules]]\ntype = \"dispute\"");
+        assert!(matches!(err.unwrap_err(), FeeError::Parse(_)));
+
+        let err = FeeSchedule::from_toml_str("[[rules]]\ntype = \"deposit\"\nflat = \"1.x\"");
+        assert!(matches!(err.unwrap_err(), FeeError::Parse(_)));
+    }
+}
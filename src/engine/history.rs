@@ -0,0 +1,68 @@
+use serde::Serialize;
+
+/// Kind of a transaction kept in an account history
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum TxKind {
+    Deposit,
+    Withdrawal,
+}
+
+/// Current dispute status of a transaction
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum TxStatus {
+    /// Applied and never disputed
+    Applied,
+    /// Under dispute, the funds are held
+    Disputed,
+    /// Last dispute was resolved, the funds are available again
+    Resolved,
+    /// Last dispute ended in a chargeback, the funds were removed
+    ChargedBack,
+    /// Undone by a reversal, without locking the account
+    Reversed,
+    /// Amount amended by a correction
+    Corrected,
+}
+
+/// Step in the life of a transaction
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum TxEvent {
+    Applied,
+    Disputed,
+    Resolved,
+    ChargedBack,
+    Reversed,
+    Corrected,
+}
+
+/// A transaction as seen in a client history.
+/// Amounts are formatted like in `AccountSnapshot`.
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct TxHistoryEntry {
+    pub sequence: u64,
+    pub timestamp: Option<u64>,
+    pub client: u16,
+    pub tx: u32,
+    #[serde(rename = "type")]
+    pub typ: TxKind,
+    pub amount: String,
+    pub status: TxStatus,
+}
+
+/// One step of a transaction lifecycle, stamped with the record that caused it
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TxLifecycleEvent {
+    pub sequence: u64,
+    pub timestamp: Option<u64>,
+    pub event: TxEvent,
+}
+
+/// Everything that happened to a transaction, in order
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TxLifecycle {
+    pub entry: TxHistoryEntry,
+    pub events: Vec<TxLifecycleEvent>,
+}
@@ -0,0 +1,280 @@
+use crate::engine::account::{AccountTx, AccountTxType};
+use crate::engine::amount::Amount;
+use crate::engine::dispute_window::TxStamp;
+use crate::engine::history::{TxEvent, TxLifecycleEvent};
+use std::collections::HashMap;
+use std::collections::hash_map::Entry;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+const BUCKET_COUNT: u32 = 64;
+
+/// On-disk index of the transactions evicted from the accounts in-memory history.
+///
+/// Transactions are appended to one of `BUCKET_COUNT` CSV files chosen from the tx id,
+/// so a lookup only scans a fraction of the spilled history. When a transaction is
+/// spilled more than once (e.g. restored for a dispute then evicted again), the last
+/// line wins. The dispute events of the transaction are spilled with it, so a reversed or
+/// charged back transaction keeps its status once loaded back.
+pub struct HistoryStore {
+    dir: PathBuf,
+    writers: HashMap<u32, BufWriter<File>>,
+}
+
+impl HistoryStore {
+    /// Open a store in `dir`, creating it if needed.
+    /// Buckets left by a previous run are removed as they don't match the new ledger.
+    pub fn open(dir: impl AsRef<Path>) -> io::Result<Self> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)?;
+        for bucket in 0..BUCKET_COUNT {
+            let path = bucket_path(&dir, bucket);
+            if path.exists() {
+                fs::remove_file(path)?;
+            }
+        }
+
+        Ok(HistoryStore {
+            dir,
+            writers: HashMap::new(),
+        })
+    }
+
+    pub(crate) fn spill(&mut self, client: u16, tx_id: u32, tx: &AccountTx) -> io::Result<()> {
+        let bucket = tx_id % BUCKET_COUNT;
+        let writer = match self.writers.entry(bucket) {
+            Entry::Occupied(e) => e.into_mut(),
+            Entry::Vacant(e) => {
+                let file = OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(bucket_path(&self.dir, bucket))?;
+                e.insert(BufWriter::new(file))
+            }
+        };
+
+        let typ = match tx.typ {
+            AccountTxType::Deposit => "deposit",
+            AccountTxType::Withdrawal => "withdrawal",
+        };
+        let timestamp = tx
+            .stamp
+            .timestamp
+            .map(|t| t.to_string())
+            .unwrap_or_default();
+        // Events as `event:sequence:timestamp`, separated by spaces
+        let events = tx
+            .disputes
+            .iter()
+            .map(|e| {
+                let timestamp = e.timestamp.map(|t| t.to_string()).unwrap_or_default();
+                format!("{}:{}:{timestamp}", event_name(e.event), e.sequence)
+            })
+            .collect::<Vec<_>>()
+            .join(" ");
+        writeln!(
+            writer,
+            "{client},{tx_id},{typ},{},{},{},{timestamp},{events}",
+            tx.amount, tx.fee, tx.stamp.sequence
+        )
+    }
+
+    /// Look up a spilled transaction of `client`. This reads a whole bucket from disk.
+    pub(crate) fn load(&mut self, client: u16, tx_id: u32) -> io::Result<Option<AccountTx>> {
+        let bucket = tx_id % BUCKET_COUNT;
+        let file = match self.writers.get_mut(&bucket) {
+            None => return Ok(None), // Nothing spilled in this bucket yet
+            Some(writer) => {
+                writer.flush()?;
+                File::open(bucket_path(&self.dir, bucket))?
+            }
+        };
+
+        let mut found = None;
+        for line in BufReader::new(file).lines() {
+            let line = line?;
+            if let Some((line_client, line_tx_id, tx)) = parse_line(&line) {
+                if line_client == client && line_tx_id == tx_id {
+                    found = Some(tx);
+                }
+            } else {
+                log::warn!("HistoryStore: ignoring corrupted line {line:?}");
+            }
+        }
+
+        Ok(found)
+    }
+}
+
+fn bucket_path(dir: &Path, bucket: u32) -> PathBuf {
+    dir.join(format!("bucket_{bucket:04}.csv"))
+}
+
+fn parse_line(line: &str) -> Option<(u16, u32, AccountTx)> {
+    let mut fields = line.split(',');
+    let client = fields.next()?.parse::<u16>().ok()?;
+    let tx_id = fields.next()?.parse::<u32>().ok()?;
+    let typ = match fields.next()? {
+        "deposit" => AccountTxType::Deposit,
+        "withdrawal" => AccountTxType::Withdrawal,
+        _ => return None,
+    };
+    let amount = Amount::from_str(fields.next()?).ok()?;
+    let fee = Amount::from_str(fields.next()?).ok()?;
+    let sequence = fields.next()?.parse::<u64>().ok()?;
+    let timestamp = parse_timestamp(fields.next()?)?;
+    let disputes = fields
+        .next()?
+        .split_whitespace()
+        .map(parse_event)
+        .collect::<Option<Vec<_>>>()?;
+
+    Some((
+        client,
+        tx_id,
+        AccountTx {
+            amount,
+            fee,
+            typ,
+            is_disputed: disputes.last().map(|e| e.event) == Some(TxEvent::Disputed),
+            stamp: TxStamp {
+                sequence,
+                timestamp,
+            },
+            disputes,
+        },
+    ))
+}
+
+fn parse_timestamp(field: &str) -> Option<Option<u64>> {
+    match field {
+        "" => Some(None),
+        t => Some(Some(t.parse::<u64>().ok()?)),
+    }
+}
+
+fn parse_event(field: &str) -> Option<TxLifecycleEvent> {
+    let mut parts = field.split(':');
+    let event = match parts.next()? {
+        "applied" => TxEvent::Applied,
+        "disputed" => TxEvent::Disputed,
+        "resolved" => TxEvent::Resolved,
+        "charged_back" => TxEvent::ChargedBack,
+        "reversed" => TxEvent::Reversed,
+        "corrected" => TxEvent::Corrected,
+        _ => return None,
+    };
+    let sequence = parts.next()?.parse::<u64>().ok()?;
+    let timestamp = parse_timestamp(parts.next()?)?;
+    Some(TxLifecycleEvent {
+        sequence,
+        timestamp,
+        event,
+    })
+}
+
+fn event_name(event: TxEvent) -> &'static str {
+    match event {
+        TxEvent::Applied => "applied",
+        TxEvent::Disputed => "disputed",
+        TxEvent::Resolved => "resolved",
+        TxEvent::ChargedBack => "charged_back",
+        TxEvent::Reversed => "reversed",
+        TxEvent::Corrected => "corrected",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::history::TxStatus;
+
+    #[test]
+    fn test_that_spilled_transactions_can_be_loaded_back() {
+        let dir = std::env::temp_dir().join(format!("crab_cash_history_{}", std::process::id()));
+        let mut store = HistoryStore::open(&dir).unwrap();
+
+        let deposit = AccountTx {
+            amount: Amount::from_str("12.3456").unwrap(),
+            fee: Amount::from_str("0.1").unwrap(),
+            typ: AccountTxType::Deposit,
+            is_disputed: false,
+            stamp: TxStamp {
+                sequence: 3,
+                timestamp: Some(1_700_000_000),
+            },
+            disputes: vec![],
+        };
+        let withdrawal = AccountTx {
+            amount: Amount::from_str("1.0").unwrap(),
+            fee: Amount::new(),
+            typ: AccountTxType::Withdrawal,
+            is_disputed: false,
+            stamp: TxStamp {
+                sequence: 4,
+                timestamp: None,
+            },
+            disputes: vec![],
+        };
+        let mut reversed = deposit.clone();
+        reversed.disputes = vec![
+            TxLifecycleEvent {
+                sequence: 5,
+                timestamp: None,
+                event: TxEvent::Disputed,
+            },
+            TxLifecycleEvent {
+                sequence: 6,
+                timestamp: Some(1_700_000_100),
+                event: TxEvent::Resolved,
+            },
+            TxLifecycleEvent {
+                sequence: 7,
+                timestamp: Some(1_700_000_200),
+                event: TxEvent::Reversed,
+            },
+        ];
+        store.spill(1, 7, &deposit).unwrap();
+        store.spill(1, 9, &reversed).unwrap();
+        store.spill(2, 7 + BUCKET_COUNT, &withdrawal).unwrap();
+
+        assert_eq!(store.load(1, 7).unwrap(), Some(deposit));
+        assert_eq!(store.load(2, 7 + BUCKET_COUNT).unwrap(), Some(withdrawal));
+        // Dispute events are kept, so is the status of the transaction
+        let loaded = store.load(1, 9).unwrap().unwrap();
+        assert_eq!(loaded, reversed);
+        assert_eq!(loaded.status(), TxStatus::Reversed);
+
+        // Unknown tx id or tx belonging to another client
+        assert_eq!(store.load(1, 8).unwrap(), None);
+        assert_eq!(store.load(2, 7).unwrap(), None);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_that_spilled_events_are_named_like_in_the_history() {
+        for event in [
+            TxEvent::Applied,
+            TxEvent::Disputed,
+            TxEvent::Resolved,
+            TxEvent::ChargedBack,
+            TxEvent::Reversed,
+            TxEvent::Corrected,
+        ] {
+            let name = event_name(event);
+            assert_eq!(
+                serde_json::to_string(&event).unwrap(),
+                format!("\"{name}\"")
+            );
+            let parsed = parse_event(&format!("{name}:1:")).unwrap();
+            assert_eq!(parsed.event, event);
+        }
+        assert_eq!(
+            serde_json::to_string(&TxStatus::ChargedBack).unwrap(),
+            "\"charged_back\""
+        );
+    }
+}
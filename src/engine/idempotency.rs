@@ -0,0 +1,183 @@
+use crate::engine::amount::Amount;
+use crate::engine::wallet::wallet_name;
+use crate::engine::{Transaction, TransactionType};
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+
+/// Outcome of the first request made with an idempotency key
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum IdempotentOutcome {
+    Applied,
+    /// Rejected by the ledger, with the reason
+    Rejected(String),
+}
+
+impl fmt::Display for IdempotentOutcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            IdempotentOutcome::Applied => write!(f, "applied"),
+            IdempotentOutcome::Rejected(reason) => write!(f, "rejected ({reason})"),
+        }
+    }
+}
+
+/// Result of a request carrying an idempotency key
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum IdempotentResult {
+    /// First request made with the key, applied by the ledger
+    Applied,
+    /// Exact retry of a previous request, not applied again
+    Retried(IdempotentOutcome),
+}
+
+/// What makes two requests identical: the timestamp is left out as retries come later,
+/// and amounts are compared by value (`10` and `10.0` are the same amount).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct RequestFingerprint {
+    typ: TransactionType,
+    client: u16,
+    tx: u32,
+    amount: Option<String>,
+    wallet: String,
+    to_wallet: Option<String>,
+    seller: Option<u16>,
+}
+
+impl From<&Transaction> for RequestFingerprint {
+    fn from(tx: &Transaction) -> Self {
+        let amount = tx.amount.as_ref().map(|amount| {
+            Amount::from_str(amount)
+                .map(|a| a.to_string())
+                .unwrap_or_else(|_| amount.trim().to_string())
+        });
+        RequestFingerprint {
+            typ: tx.typ,
+            client: tx.account_id,
+            tx: tx.id,
+            amount,
+            wallet: wallet_name(tx.wallet.as_deref()).to_string(),
+            to_wallet: tx.to_wallet.clone(),
+            seller: tx.seller,
+        }
+    }
+}
+
+/// Requests made with an idempotency key, with their outcome.
+/// NOTE: Keys are kept for the whole life of the ledger.
+#[derive(Debug, Default)]
+pub(crate) struct IdempotencyKeys {
+    requests: HashMap<String, (RequestFingerprint, IdempotentOutcome)>,
+}
+
+impl IdempotencyKeys {
+    pub(crate) fn get(&self, key: &str) -> Option<&(RequestFingerprint, IdempotentOutcome)> {
+        self.requests.get(key)
+    }
+
+    pub(crate) fn record(&mut self, key: &str, tx: &Transaction, outcome: IdempotentOutcome) {
+        self.requests
+            .insert(key.to_string(), (RequestFingerprint::from(tx), outcome));
+    }
+
+    pub(crate) fn remove(&mut self, key: &str) {
+        self.requests.remove(key);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn deposit(amount: &str) -> Transaction {
+        Transaction {
+            id: 1,
+            account_id: 1,
+            typ: TransactionType::Deposit,
+            amount: Some(amount.to_string()),
+            timestamp: None,
+            wallet: None,
+            to_wallet: None,
+            seller: None,
+        }
+    }
+
+    fn fingerprint(tx: &Transaction) -> RequestFingerprint {
+        RequestFingerprint::from(tx)
+    }
+
+    #[test]
+    fn test_that_amounts_are_compared_by_value() {
+        assert_eq!(fingerprint(&deposit("10")), fingerprint(&deposit(" 10.00")));
+        assert_ne!(
+            fingerprint(&deposit("10")),
+            fingerprint(&deposit("10.0001"))
+        );
+        // Invalid amounts are compared as text
+        assert_eq!(fingerprint(&deposit("ten ")), fingerprint(&deposit("ten")));
+    }
+
+    #[test]
+    fn test_that_timestamp_is_left_out_of_the_fingerprint() {
+        let retry = Transaction {
+            timestamp: Some(42),
+            ..deposit("10")
+        };
+        assert_eq!(fingerprint(&deposit("10")), fingerprint(&retry));
+    }
+
+    #[test]
+    fn test_that_missing_wallet_is_the_main_wallet() {
+        let main = Transaction {
+            wallet: Some(" main ".into()),
+            ..deposit("10")
+        };
+        let savings = Transaction {
+            wallet: Some("savings".into()),
+            ..deposit("10")
+        };
+        assert_eq!(fingerprint(&deposit("10")), fingerprint(&main));
+        assert_ne!(fingerprint(&deposit("10")), fingerprint(&savings));
+    }
+
+    #[test]
+    fn test_that_other_payload_has_another_fingerprint() {
+        let others = [
+            Transaction {
+                id: 2,
+                ..deposit("10")
+            },
+            Transaction {
+                account_id: 2,
+                ..deposit("10")
+            },
+            Transaction {
+                typ: TransactionType::Withdrawal,
+                ..deposit("10")
+            },
+            Transaction {
+                seller: Some(3),
+                ..deposit("10")
+            },
+        ];
+        for other in &others {
+            assert_ne!(fingerprint(&deposit("10")), fingerprint(other));
+        }
+    }
+
+    #[test]
+    fn test_that_keys_keep_the_outcome_until_removed() {
+        let mut keys = IdempotencyKeys::default();
+        keys.record("a", &deposit("10"), IdempotentOutcome::Applied);
+        keys.record("b", &deposit("5"), IdempotentOutcome::Rejected("no".into()));
+
+        assert_eq!(
+            keys.get("a"),
+            Some(&(fingerprint(&deposit("10")), IdempotentOutcome::Applied))
+        );
+        assert_eq!(keys.get("b").unwrap().1.to_string(), "rejected (no)");
+        keys.remove("a");
+        assert_eq!(keys.get("a"), None);
+        assert!(keys.get("b").is_some());
+    }
+}
@@ -0,0 +1,164 @@
+use crate::engine::amount::{Amount, AmountError};
+use std::fmt;
+use std::str::FromStr;
+use thiserror::Error;
+
+const SECONDS_PER_DAY: i128 = 86_400;
+
+/// Number of days in a year used to turn an annual rate into a rate per second
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum DayCount {
+    /// Actual elapsed days over a 365 days year
+    #[default]
+    Actual365,
+    /// Actual elapsed days over a 360 days year
+    Actual360,
+}
+
+#[derive(Error, Debug, Clone)]
+pub enum InterestError {
+    #[error("Invalid day count convention: {0}")]
+    InvalidDayCount(String),
+}
+
+impl DayCount {
+    fn days_per_year(&self) -> i128 {
+        match self {
+            DayCount::Actual365 => 365,
+            DayCount::Actual360 => 360,
+        }
+    }
+}
+
+impl FromStr for DayCount {
+    type Err = InterestError;
+
+    /// Accepts `act/365` or `act/360`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "act/365" | "actual/365" => Ok(DayCount::Actual365),
+            "act/360" | "actual/360" => Ok(DayCount::Actual360),
+            _ => Err(InterestError::InvalidDayCount(s.into())),
+        }
+    }
+}
+
+impl fmt::Display for DayCount {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DayCount::Actual365 => write!(f, "act/365"),
+            DayCount::Actual360 => write!(f, "act/360"),
+        }
+    }
+}
+
+/// Interest paid on available balances
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct InterestPolicy {
+    /// Annual rate in percent (e.g. 2.5 is 2.5% a year)
+    pub annual_rate: Amount,
+    pub day_count: DayCount,
+}
+
+/// Interest accrued by an account since its last payment.
+/// Available balances are integrated over time (balance x seconds) on every record touching
+/// the account. Negative balances earn nothing.
+/// Payments are truncated to the Amount precision and the truncated residue is carried to the
+/// next payment, so that the total paid never drifts from the exact interest.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub(crate) struct InterestAccrual {
+    since: Option<u64>,    // Timestamp up to which the balance has been integrated
+    balance_seconds: i128, // Sum of available balance (in 1/10_000) x elapsed seconds
+    residue: i128,         // Remainder of the last payment, in the payment denominator
+}
+
+impl InterestAccrual {
+    /// Integrate the `available` balance held since the last update up to `now`.
+    /// Records without timestamp don't move the clock.
+    pub(crate) fn track(
+        &mut self,
+        available: &Amount,
+        now: Option<u64>,
+    ) -> Result<(), AmountError> {
+        let Some(now) = now else {
+            return Ok(());
+        };
+        if let Some(since) = self.since
+            && now > since
+        {
+            let balance = available.units().max(0) as i128;
+            let increment = balance
+                .checked_mul((now - since) as i128)
+                .ok_or(AmountError::Overflow)?;
+            self.balance_seconds = self
+                .balance_seconds
+                .checked_add(increment)
+                .ok_or(AmountError::Overflow)?;
+        }
+        if self.since.is_none_or(|since| now > since) {
+            self.since = Some(now);
+        }
+        Ok(())
+    }
+
+    /// Interest due under `policy` for the balance integrated so far, with the residue to
+    /// keep once it is paid (see `settle`)
+    pub(crate) fn due(&self, policy: &InterestPolicy) -> Result<(Amount, i128), AmountError> {
+        // balance_seconds * rate / 100 / (days_per_year * seconds per day), rate in 1/10_000
+        let denominator = 100 * 10_000 * policy.day_count.days_per_year() * SECONDS_PER_DAY;
+        let numerator = self
+            .balance_seconds
+            .checked_mul(policy.annual_rate.units().max(0) as i128)
+            .and_then(|n| n.checked_add(self.residue))
+            .ok_or(AmountError::Overflow)?;
+        let units = i64::try_from(numerator / denominator).map_err(|_| AmountError::Overflow)?;
+
+        Ok((Amount::from_units(units), numerator % denominator))
+    }
+
+    /// Start a new accrual period once the interest returned by `due` has been paid
+    pub(crate) fn settle(&mut self, residue: i128) {
+        self.balance_seconds = 0;
+        self.residue = residue;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_that_day_count_can_be_parsed() {
+        assert_eq!(DayCount::from_str("act/365").unwrap(), DayCount::Actual365);
+        assert_eq!(DayCount::from_str("ACT/360").unwrap(), DayCount::Actual360);
+        assert!(DayCount::from_str("30/360").is_err());
+    }
+
+    #[test]
+    fn test_that_interest_residue_is_carried_to_next_payment() {
+        let policy = InterestPolicy {
+            annual_rate: Amount::from_str("3.65").unwrap(),
+            day_count: DayCount::Actual365,
+        };
+        let balance = Amount::from_str("1").unwrap();
+        let mut accrual = InterestAccrual::default();
+
+        // 1 at 3.65% for one day is 0.0001, paid in three periods of 8 hours
+        accrual.track(&balance, Some(0)).unwrap();
+        let mut paid = Amount::new();
+        for hours in [8, 16, 24] {
+            accrual.track(&balance, Some(hours * 3_600)).unwrap();
+            let (interest, residue) = accrual.due(&policy).unwrap();
+            accrual.settle(residue);
+            paid = paid.add(&interest).unwrap();
+        }
+        assert_eq!(paid, Amount::from_str("0.0001").unwrap());
+        assert_eq!(accrual.residue, 0);
+
+        // Negative balances earn nothing
+        accrual
+            .track(&balance.neg().unwrap(), Some(48 * 3_600))
+            .unwrap();
+        assert_eq!(accrual.due(&policy).unwrap().0, Amount::new());
+    }
+}
@@ -0,0 +1,440 @@
+use crate::engine::amount::{Amount, AmountError};
+use crate::engine::dispute_window::TxStamp;
+use crate::engine::operation::OperationKind;
+use crate::engine::wallet::MAIN_WALLET;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::fmt;
+use thiserror::Error;
+
+/// Accounts of the double-entry journal.
+/// Customer accounts, one pair per wallet of a client, are liabilities of the house: a deposit
+/// credits them.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum JournalAccount {
+    /// Funds the client can use in a wallet
+    CustomerAvailable(u16, String),
+    /// Funds of a wallet of the client held by a dispute or an escrow
+    CustomerHeld(u16, String),
+    /// External settlement account: money entering (deposits) and leaving (withdrawals) the system
+    Settlement,
+    /// Funds removed from clients by chargebacks
+    ChargebackLoss,
+    /// Fees collected by the house
+    HouseFees,
+    /// Interest paid by the house
+    HouseInterest,
+    /// Escrowed funds on their way from the buyer to the seller, back to zero after each release
+    EscrowClearing,
+}
+
+impl JournalAccount {
+    // Customer accounts grouped by client and wallet, the main wallet first, then the house
+    // accounts
+    fn sort_key(&self) -> (u8, u16, bool, &str, u8) {
+        match self {
+            JournalAccount::CustomerAvailable(client, wallet) => {
+                (0, *client, wallet != MAIN_WALLET, wallet, 0)
+            }
+            JournalAccount::CustomerHeld(client, wallet) => {
+                (0, *client, wallet != MAIN_WALLET, wallet, 1)
+            }
+            JournalAccount::Settlement => (1, 0, false, "", 0),
+            JournalAccount::ChargebackLoss => (2, 0, false, "", 0),
+            JournalAccount::HouseFees => (3, 0, false, "", 0),
+            JournalAccount::HouseInterest => (4, 0, false, "", 0),
+            JournalAccount::EscrowClearing => (5, 0, false, "", 0),
+        }
+    }
+}
+
+impl fmt::Display for JournalAccount {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            JournalAccount::CustomerAvailable(client, wallet) if wallet == MAIN_WALLET => {
+                write!(f, "customer:{client}:available")
+            }
+            JournalAccount::CustomerAvailable(client, wallet) => {
+                write!(f, "customer:{client}:{wallet}:available")
+            }
+            JournalAccount::CustomerHeld(client, wallet) if wallet == MAIN_WALLET => {
+                write!(f, "customer:{client}:held")
+            }
+            JournalAccount::CustomerHeld(client, wallet) => {
+                write!(f, "customer:{client}:{wallet}:held")
+            }
+            JournalAccount::Settlement => write!(f, "settlement"),
+            JournalAccount::ChargebackLoss => write!(f, "chargeback_loss"),
+            JournalAccount::HouseFees => write!(f, "house:fees"),
+            JournalAccount::HouseInterest => write!(f, "house:interest"),
+            JournalAccount::EscrowClearing => write!(f, "escrow:clearing"),
+        }
+    }
+}
+
+/// One side of a journal entry. A positive amount is a debit, a negative amount a credit.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Posting {
+    pub(crate) account: JournalAccount,
+    pub(crate) amount: Amount,
+}
+
+/// Postings of one record, their amounts always sum to zero
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct JournalEntry {
+    pub(crate) stamp: TxStamp,
+    pub(crate) tx: u32,
+    /// Operations applied by the record, in order
+    pub(crate) kinds: Vec<OperationKind>,
+    pub(crate) postings: Vec<Posting>,
+}
+
+#[derive(Error, Debug)]
+pub enum JournalError {
+    #[error("Unbalanced journal entry (tx id {0})")]
+    Unbalanced(u32),
+
+    #[error("Invalid Amount operation in journal: {0}")]
+    Amount(#[from] AmountError),
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct AccountTotals {
+    debit: Amount,
+    credit: Amount,
+}
+
+/// Double-entry journal kept alongside the accounts
+#[derive(Debug, Default)]
+pub(crate) struct Journal {
+    entries: Vec<JournalEntry>,
+    totals: HashMap<JournalAccount, AccountTotals>,
+    keep_entries: bool,
+}
+
+impl Journal {
+    pub(crate) fn new(keep_entries: bool) -> Self {
+        Journal {
+            entries: vec![],
+            totals: HashMap::new(),
+            keep_entries,
+        }
+    }
+
+    /// Posting of an operation of `amount` on the house side: the account the funds come from
+    /// or go to outside of the customer accounts. Operations moving funds between customer
+    /// accounts only have none.
+    pub(crate) fn house_posting(
+        kind: OperationKind,
+        amount: Amount,
+    ) -> Result<Option<Posting>, AmountError> {
+        let (account, amount) = match kind {
+            // Signed amounts: reversing a deposit credits the settlement account
+            OperationKind::Deposit | OperationKind::Reversal | OperationKind::Correction => {
+                (JournalAccount::Settlement, amount)
+            }
+            OperationKind::Withdrawal => (JournalAccount::Settlement, amount.neg()?),
+            OperationKind::Chargeback => (JournalAccount::ChargebackLoss, amount.neg()?),
+            OperationKind::Fee => (JournalAccount::HouseFees, amount.neg()?),
+            OperationKind::FeeRefund => (JournalAccount::HouseFees, amount),
+            OperationKind::Interest => (JournalAccount::HouseInterest, amount),
+            OperationKind::EscrowRelease => (JournalAccount::EscrowClearing, amount.neg()?),
+            OperationKind::EscrowPayout => (JournalAccount::EscrowClearing, amount),
+            OperationKind::Dispute
+            | OperationKind::Resolve
+            | OperationKind::EscrowFund
+            | OperationKind::EscrowRefund
+            | OperationKind::Transfer => return Ok(None),
+        };
+        Ok((amount != Amount::new()).then_some(Posting { account, amount }))
+    }
+
+    /// Postings of the change of the balances of a wallet from `before` to `after`, as
+    /// `(available, held)`. A balance that grows is credited. Unchanged balances are not posted.
+    pub(crate) fn customer_postings(
+        client: u16,
+        wallet: &str,
+        before: (Amount, Amount),
+        after: (Amount, Amount),
+    ) -> Result<Vec<Posting>, AmountError> {
+        let mut postings = vec![];
+        for (account, before, after) in [
+            (
+                JournalAccount::CustomerAvailable(client, wallet.to_string()),
+                before.0,
+                after.0,
+            ),
+            (
+                JournalAccount::CustomerHeld(client, wallet.to_string()),
+                before.1,
+                after.1,
+            ),
+        ] {
+            let amount = before.sub(&after)?;
+            if amount != Amount::new() {
+                postings.push(Posting { account, amount });
+            }
+        }
+        Ok(postings)
+    }
+
+    /// Record an entry, rejecting it when its postings don't sum to zero, i.e. when the wallets
+    /// didn't move as the operations of the record say.
+    /// Totals are only updated once the whole entry is known to be valid.
+    pub(crate) fn post(&mut self, entry: JournalEntry) -> Result<(), JournalError> {
+        let mut sum = Amount::new();
+        let mut updated: HashMap<JournalAccount, AccountTotals> = HashMap::new();
+        for posting in &entry.postings {
+            sum = sum.add(&posting.amount)?;
+
+            let totals = updated.entry(posting.account.clone()).or_insert_with(|| {
+                self.totals
+                    .get(&posting.account)
+                    .copied()
+                    .unwrap_or_default()
+            });
+            if posting.amount >= Amount::new() {
+                totals.debit = totals.debit.add(&posting.amount)?;
+            } else {
+                totals.credit = totals.credit.sub(&posting.amount)?;
+            }
+        }
+        if sum != Amount::new() {
+            Err(JournalError::Unbalanced(entry.tx))?
+        }
+
+        self.totals.extend(updated);
+        if self.keep_entries {
+            self.entries.push(entry);
+        }
+        Ok(())
+    }
+
+    /// Undo `entries`, the last entries posted, in reverse order.
+    /// Accounts left without any debit or credit are removed.
+    pub(crate) fn rollback(&mut self, entries: &[JournalEntry]) -> Result<(), JournalError> {
+        for entry in entries.iter().rev() {
+            for posting in &entry.postings {
+                let totals = self.totals.entry(posting.account.clone()).or_default();
+                if posting.amount >= Amount::new() {
+                    totals.debit = totals.debit.sub(&posting.amount)?;
+                } else {
+                    totals.credit = totals.credit.add(&posting.amount)?;
+                }
+                if totals.debit == Amount::new() && totals.credit == Amount::new() {
+                    self.totals.remove(&posting.account);
+                }
+            }
+        }
+        if self.keep_entries {
+            self.entries
+                .truncate(self.entries.len().saturating_sub(entries.len()));
+        }
+        Ok(())
+    }
+
+    /// Balance (debits - credits) of a journal account
+    pub(crate) fn balance(&self, account: &JournalAccount) -> Result<Amount, AmountError> {
+        let totals = self.totals.get(account).copied().unwrap_or_default();
+        totals.debit.sub(&totals.credit)
+    }
+
+    pub(crate) fn trial_balance(&self) -> Result<TrialBalance, AmountError> {
+        let mut accounts: Vec<&JournalAccount> = self.totals.keys().collect();
+        accounts.sort_by_key(|a| a.sort_key());
+
+        let mut debit = Amount::new();
+        let mut credit = Amount::new();
+        let mut rows = vec![];
+        for account in accounts {
+            let totals = self.totals[account];
+            debit = debit.add(&totals.debit)?;
+            credit = credit.add(&totals.credit)?;
+            rows.push(TrialBalanceRow {
+                account: account.to_string(),
+                debit: totals.debit.to_string(),
+                credit: totals.credit.to_string(),
+                balance: totals.debit.sub(&totals.credit)?.to_string(),
+            });
+        }
+
+        Ok(TrialBalance {
+            rows,
+            total_debit: debit.to_string(),
+            total_credit: credit.to_string(),
+            is_balanced: debit == credit,
+        })
+    }
+}
+
+/// Debit and credit totals of one journal account
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct TrialBalanceRow {
+    pub account: String,
+    pub debit: String,
+    pub credit: String,
+    pub balance: String,
+}
+
+/// Totals of every journal account. Balanced when the total debits equal the total credits,
+/// i.e. when the balances of all the accounts sum to zero.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TrialBalance {
+    pub rows: Vec<TrialBalanceRow>,
+    pub total_debit: String,
+    pub total_credit: String,
+    pub is_balanced: bool,
+}
+
+impl TrialBalance {
+    /// Write the rows as CSV followed by a `total` row
+    pub fn write_csv<W: std::io::Write>(&self, wtr: &mut csv::Writer<W>) -> Result<(), csv::Error> {
+        for row in &self.rows {
+            wtr.serialize(row)?;
+        }
+        let balance = if self.is_balanced {
+            "0.0000"
+        } else {
+            "unbalanced"
+        };
+        wtr.serialize(TrialBalanceRow {
+            account: String::from("total"),
+            debit: self.total_debit.clone(),
+            credit: self.total_credit.clone(),
+            balance: String::from(balance),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    fn amount(value: &str) -> Amount {
+        Amount::from_str(value).unwrap()
+    }
+
+    // Entry of a record applying `kind` for `value`, which moved the main wallet of client 1
+    // from `before` to `after`
+    fn entry(
+        kind: OperationKind,
+        value: &str,
+        before: (&str, &str),
+        after: (&str, &str),
+    ) -> JournalEntry {
+        let mut postings: Vec<Posting> = Journal::house_posting(kind, amount(value))
+            .unwrap()
+            .into_iter()
+            .collect();
+        postings.extend(
+            Journal::customer_postings(
+                1,
+                MAIN_WALLET,
+                (amount(before.0), amount(before.1)),
+                (amount(after.0), amount(after.1)),
+            )
+            .unwrap(),
+        );
+        JournalEntry {
+            stamp: TxStamp::default(),
+            tx: 1,
+            kinds: vec![kind],
+            postings,
+        }
+    }
+
+    #[test]
+    fn test_that_unbalanced_entry_is_rejected() {
+        let mut journal = Journal::new(true);
+
+        // The wallet didn't receive what was deposited
+        let err = journal
+            .post(entry(OperationKind::Deposit, "10", ("0", "0"), ("9", "0")))
+            .unwrap_err();
+        assert!(matches!(err, JournalError::Unbalanced(1)));
+
+        // Nothing was recorded
+        assert!(journal.entries.is_empty());
+        assert_eq!(
+            journal.balance(&JournalAccount::Settlement).unwrap(),
+            Amount::new()
+        );
+    }
+
+    #[test]
+    fn test_that_funds_moved_between_customer_accounts_must_add_up() {
+        let mut journal = Journal::new(true);
+
+        // A dispute only holds funds, the held funds must come out of the available ones
+        let dispute = entry(OperationKind::Dispute, "10", ("10", "0"), ("0", "10"));
+        assert_eq!(dispute.postings.len(), 2);
+        assert!(journal.post(dispute).is_ok());
+        let err = journal
+            .post(entry(
+                OperationKind::Resolve,
+                "10",
+                ("0", "10"),
+                ("10", "5"),
+            ))
+            .unwrap_err();
+        assert!(matches!(err, JournalError::Unbalanced(1)));
+        assert_eq!(journal.entries.len(), 1);
+    }
+
+    #[test]
+    fn test_that_trial_balance_sums_to_zero() {
+        let mut journal = Journal::new(false);
+
+        for entry in [
+            entry(OperationKind::Deposit, "10", ("0", "0"), ("10", "0")),
+            entry(OperationKind::Dispute, "10", ("10", "0"), ("0", "10")),
+            entry(OperationKind::Chargeback, "10", ("0", "10"), ("0", "0")),
+        ] {
+            assert!(journal.post(entry).is_ok());
+        }
+
+        // Entries are not kept, the totals are
+        assert!(journal.entries.is_empty());
+        let trial_balance = journal.trial_balance().unwrap();
+        assert!(trial_balance.is_balanced);
+        assert_eq!(trial_balance.total_debit, "30.0000");
+
+        let balances: Vec<(&str, &str)> = trial_balance
+            .rows
+            .iter()
+            .map(|r| (r.account.as_str(), r.balance.as_str()))
+            .collect();
+        assert_eq!(
+            balances,
+            vec![
+                ("customer:1:available", "0.0000"),
+                ("customer:1:held", "0.0000"),
+                ("settlement", "10.0000"),
+                ("chargeback_loss", "-10.0000"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_that_wallet_accounts_are_named_after_their_wallet() {
+        let accounts = [
+            JournalAccount::CustomerHeld(1, "savings".into()),
+            JournalAccount::Settlement,
+            JournalAccount::CustomerAvailable(1, "savings".into()),
+            JournalAccount::CustomerAvailable(1, MAIN_WALLET.into()),
+        ];
+        let mut sorted: Vec<&JournalAccount> = accounts.iter().collect();
+        sorted.sort_by_key(|a| a.sort_key());
+        let names: Vec<String> = sorted.iter().map(|a| a.to_string()).collect();
+        assert_eq!(
+            names,
+            vec![
+                "customer:1:available",
+                "customer:1:savings:available",
+                "customer:1:savings:held",
+                "settlement"
+            ]
+        );
+    }
+}
@@ -1,14 +1,37 @@
-use crate::engine::account::{Account, AccountOperationError};
-use crate::engine::account_snapshot::AccountSnapshot;
+use crate::engine::account::{Account, AccountOperationError, AccountTx, AccountTxType};
+use crate::engine::account_creation::AccountCreation;
+use crate::engine::account_snapshot::{AccountSnapshot, WalletSnapshot};
+use crate::engine::aml::{AmlReportRow, AmlThresholds};
 use crate::engine::amount::{Amount, AmountError};
+use crate::engine::anomaly::{Activity, Alert, Detectors};
+use crate::engine::config::LedgerConfig;
+use crate::engine::dispute_window::TxStamp;
+use crate::engine::fees::FeeTxType;
+use crate::engine::history::{TxHistoryEntry, TxKind, TxLifecycle};
+use crate::engine::history_store::HistoryStore;
+use crate::engine::idempotency::{
+    IdempotencyKeys, IdempotentOutcome, IdempotentResult, RequestFingerprint,
+};
+use crate::engine::journal::{Journal, JournalAccount, JournalEntry, JournalError, TrialBalance};
+use crate::engine::observer::{LedgerEvent, LedgerObserver};
+use crate::engine::operation::{AppliedOperation, OperationKind, OperationLog};
+use crate::engine::point_in_time::PointInTime;
+use crate::engine::reconcile::{Discrepancy, reconcile};
+use crate::engine::registry::{
+    ClientInfo, ClientRegistry, ClientStatus, RegistryColumns, TierLimits,
+};
+use crate::engine::rules::RuleContext;
+use crate::engine::statement::{Statement, StatementPeriod};
+use crate::engine::tx_id_filter::{ProcessedTxIds, TxIdFilter};
+use crate::engine::wallet::{LockScope, MAIN_WALLET, wallet_name};
 use crate::engine::{Transaction, TransactionType};
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::str::FromStr;
 use thiserror::Error;
 
 #[derive(Error, Debug)]
 pub enum LedgerError {
-    #[error("Account operation failed (tx id {0})")]
+    #[error("Account operation failed: {0}")]
     Account(#[from] AccountOperationError),
 
     #[error("Duplicate transaction id (tx id {0})")]
@@ -17,16 +40,153 @@ pub enum LedgerError {
     #[error("Missing Amount id (tx id {0})")]
     MissingAmount(u32),
 
-    #[error("Amount parsing failed (tx id {0})")]
+    #[error("Amount parsing failed: {0}")]
     Amount(#[from] AmountError),
 
     #[error("Negative Tx amount is not allowed (tx id {0})")]
     NegativeTxAmount(u32),
+
+    #[error("Storage failure: {0}")]
+    Storage(#[from] std::io::Error),
+
+    #[error("Journal failure: {0}")]
+    Journal(#[from] JournalError),
+
+    #[error("Journal does not match the balances of client {0}")]
+    JournalMismatch(u16),
+
+    #[error("Interest accrual is not configured (tx id {0})")]
+    InterestDisabled(u32),
+
+    #[error("Interest accrual requires a timestamp (tx id {0})")]
+    MissingTimestamp(u32),
+
+    #[error("Client {0} is not registered")]
+    UnregisteredClient(u16),
+
+    #[error("Client {0} has no account")]
+    NoAccount(u16),
+
+    #[error("Client {0} is not active")]
+    ClientNotActive(u16),
+
+    #[error("Amount exceeds the limit of the client KYC tier (tx id {0})")]
+    KycLimitExceeded(u32),
+
+    #[error("Idempotency key {0} was already used for a different request")]
+    IdempotencyConflict(String),
+
+    #[error("Transfer requires another wallet of the client as destination (tx id {0})")]
+    InvalidTransfer(u32),
+
+    #[error("Escrow requires a seller other than the buyer (tx id {0})")]
+    InvalidEscrow(u32),
+
+    #[error("Going back in time requires the rollback log")]
+    NoRollbackLog,
+
+    #[error("Rollback is not supported with {0}")]
+    RollbackUnsupported(&'static str),
+
+    #[error("Rejected by the {rule} rule (tx id {tx})")]
+    RuleViolation { rule: String, tx: u32 },
+
+    #[error("Flagged by the {rule} anomaly detector (tx id {tx})")]
+    AnomalyFlagged { rule: String, tx: u32 },
+
+    #[error("Operations of client {0} were dropped from the operation log")]
+    OperationLogTruncated(u16),
+
+    #[error("Operations were dropped from the operation log")]
+    OperationLogIncomplete,
+}
+
+impl LedgerError {
+    /// Stable identifier of the error, for API clients. Account errors keep their own code.
+    pub fn code(&self) -> &'static str {
+        match self {
+            LedgerError::Account(e) => e.code(),
+            LedgerError::DuplicateTxId(_) => "duplicate_tx_id",
+            LedgerError::MissingAmount(_) => "missing_amount",
+            LedgerError::Amount(_) => "invalid_amount",
+            LedgerError::NegativeTxAmount(_) => "negative_tx_amount",
+            LedgerError::Storage(_) => "storage",
+            LedgerError::Journal(_) => "journal",
+            LedgerError::JournalMismatch(_) => "journal_mismatch",
+            LedgerError::InterestDisabled(_) => "interest_disabled",
+            LedgerError::MissingTimestamp(_) => "missing_timestamp",
+            LedgerError::UnregisteredClient(_) => "unregistered_client",
+            LedgerError::NoAccount(_) => "no_account",
+            LedgerError::ClientNotActive(_) => "client_not_active",
+            LedgerError::KycLimitExceeded(_) => "kyc_limit_exceeded",
+            LedgerError::IdempotencyConflict(_) => "idempotency_conflict",
+            LedgerError::InvalidTransfer(_) => "invalid_transfer",
+            LedgerError::InvalidEscrow(_) => "invalid_escrow",
+            LedgerError::NoRollbackLog => "no_rollback_log",
+            LedgerError::RollbackUnsupported(_) => "rollback_unsupported",
+            LedgerError::RuleViolation { .. } => "rule_violation",
+            LedgerError::AnomalyFlagged { .. } => "anomaly_flagged",
+            LedgerError::OperationLogTruncated(_) => "operation_log_truncated",
+            LedgerError::OperationLogIncomplete => "operation_log_incomplete",
+        }
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum BatchError {
+    #[error("Batch rolled back, transaction #{index} failed (tx id {tx}): {source}")]
+    Rejected {
+        index: usize,
+        tx: u32,
+        source: LedgerError,
+    },
+
+    #[error("Batch rollback failed: {0}")]
+    Rollback(#[from] JournalError),
+
+    #[error("Storage failure: {0}")]
+    Storage(#[from] std::io::Error),
+}
+
+/// Changes made by a batch or a record, to undo them
+#[derive(Debug, Default)]
+struct Undo {
+    accounts: HashMap<(u16, String), Option<Account>>, // Wallets touched, as they were before
+    operations: usize,                                 // Length of the operation log before
+    entries: Vec<JournalEntry>,                        // Journal entries posted
+    tx_ids: HashSet<u32>,                              // Tx ids created
+    activity: Vec<(u16, u64)>, // Records fed to the detectors, by client and sequence
+}
+
+/// Changes made by a record, kept by the rollback log
+#[derive(Debug)]
+struct RecordUndo {
+    stamp: TxStamp,              // Stamp of the record
+    last_timestamp: Option<u64>, // Last timestamp seen before the record
+    changes: Undo,
+    idempotency_key: Option<String>, // Key first used by the record
 }
 
 pub struct Ledger {
-    tx_processed: HashSet<u32>,
-    accounts: HashMap<u16, Account>,
+    tx_processed: ProcessedTxIds,
+    accounts: HashMap<u16, Account>, // Main wallet of every client
+    wallets: HashMap<u16, BTreeMap<String, Account>>, // Other wallets of the clients, by name
+    tx_wallets: HashMap<u32, String>, // Named wallet of the transactions created outside the main one
+    config: LedgerConfig,
+    sequence: u64, // Number of records given to the ledger
+    last_timestamp: Option<u64>,
+    history_store: Option<HistoryStore>,
+    operations: OperationLog, // Every operation applied (the last ones with a limit), in order
+    journal: Journal,
+    batch: Option<Undo>,           // Batch being processed by `process_batch`
+    rollback_log: Vec<RecordUndo>, // Changes of every record, when `retention.rollback_log` is set
+    idempotency_keys: IdempotencyKeys,
+    registry: ClientRegistry,
+    has_escrows: bool, // Snapshots report escrowed funds once an escrow was funded
+    observers: Vec<Box<dyn LedgerObserver>>,
+    events: Vec<LedgerEvent>, // Events not delivered yet, of the record or the batch being processed
+    touched: Vec<(u16, String, Option<bool>)>, // Wallets touched by the record, with their lock before it (`None` when missing)
+    detectors: Detectors,
 }
 
 impl Default for Ledger {
@@ -37,21 +197,280 @@ impl Default for Ledger {
 
 impl Ledger {
     pub fn new() -> Self {
+        Self::with_config(LedgerConfig::default())
+    }
+
+    pub fn with_config(config: LedgerConfig) -> Self {
+        // Journal entries are kept along with a complete operation log
+        let keep_entries =
+            !config.retention.skip_operation_log && config.retention.operation_log_limit.is_none();
+        let operations = OperationLog::new(config.retention.operation_log_limit);
         Ledger {
             accounts: HashMap::new(),
-            tx_processed: HashSet::new(),
+            wallets: HashMap::new(),
+            tx_wallets: HashMap::new(),
+            tx_processed: ProcessedTxIds::Exact(HashSet::new()),
+            config,
+            sequence: 0,
+            last_timestamp: None,
+            history_store: None,
+            operations,
+            journal: Journal::new(keep_entries),
+            batch: None,
+            rollback_log: vec![],
+            idempotency_keys: IdempotencyKeys::default(),
+            registry: ClientRegistry::default(),
+            has_escrows: false,
+            observers: vec![],
+            events: vec![],
+            touched: vec![],
+            detectors: Detectors::new(),
+        }
+    }
+
+    /// Spill the transactions evicted by the retention policy to `store` instead of dropping them,
+    /// so that they can still be disputed.
+    pub fn with_history_store(mut self, store: HistoryStore) -> Self {
+        self.history_store = Some(store);
+        self
+    }
+
+    /// Attach the `registry` of clients: records of inactive clients are rejected and the
+    /// transactions of registered clients are limited by their KYC tier.
+    /// With the `Registered` account creation policy, the clients of the registry are the
+    /// registered ones, whatever the policy listed.
+    pub fn with_registry(mut self, registry: ClientRegistry) -> Self {
+        if let AccountCreation::Registered(clients) = &mut self.config.account_creation {
+            *clients = registry.clients().collect();
         }
+        self.registry = registry;
+        self
+    }
+
+    /// Detect duplicate transaction ids with a fixed-size `filter` instead of an in-memory set.
+    /// Must be set before processing any transaction.
+    pub fn with_tx_id_filter(mut self, filter: TxIdFilter) -> Self {
+        self.tx_processed = ProcessedTxIds::Filtered(filter);
+        self
+    }
+
+    /// Run anomaly detectors on every transaction, see `Detectors`
+    pub fn with_detectors(mut self, detectors: Detectors) -> Self {
+        self.detectors = detectors;
+        self
+    }
+
+    /// Notify `observer` of every event of the ledger
+    pub fn with_observer(mut self, observer: impl LedgerObserver + 'static) -> Self {
+        self.observers.push(Box::new(observer));
+        self
     }
 
     pub fn process_transaction(&mut self, tx: &Transaction) -> Result<(), LedgerError> {
-        let account = self
-            .accounts
-            .entry(tx.account_id)
-            .or_insert_with(|| Account::new(tx.account_id));
+        let first_event = self.events.len();
+        self.touched.clear();
+        let result = self.process_record(tx);
+        if !self.observers.is_empty() {
+            self.report(tx, first_event, &result);
+        }
+        result
+    }
+
+    fn process_record(&mut self, tx: &Transaction) -> Result<(), LedgerError> {
+        let last_timestamp = self.last_timestamp;
+        let stamp = self.next_stamp(tx);
+        if self.config.retention.rollback_log {
+            self.rollback_log.push(RecordUndo {
+                stamp,
+                last_timestamp,
+                changes: Undo {
+                    operations: self.operations.len(),
+                    ..Undo::default()
+                },
+                idempotency_key: None,
+            });
+        }
+
+        if let Some(interval) = self.config.compaction_interval
+            && stamp.sequence.is_multiple_of(interval)
+        {
+            let removed = self.compact_expired_history();
+            log::debug!("Compacted {removed} expired transactions from account history");
+        }
+
+        if let Some(info) = self.registry.get(tx.account_id)
+            && info.status != ClientStatus::Active
+        {
+            Err(LedgerError::ClientNotActive(tx.account_id))?
+        }
+
+        let policy = &self.config.account_creation;
+        if !self.is_known(tx.account_id) && !policy.may_create(tx) {
+            if !policy.is_registered(tx.account_id) {
+                Err(LedgerError::UnregisteredClient(tx.account_id))?
+            }
+            Err(LedgerError::NoAccount(tx.account_id))?
+        }
+
+        let activity = Activity::new(tx, stamp);
+        let alerts = self.detectors.inspect(&activity);
+        if let Some((alert, _)) = alerts.iter().find(|(_, blocking)| *blocking) {
+            let rule = alert.rule.clone();
+            self.flag(alerts);
+            return Err(LedgerError::AnomalyFlagged { rule, tx: tx.id });
+        }
+
+        let wallet = self.wallet_of(tx);
+        let known = self.wallet(tx.account_id, &wallet).is_some();
+        let result = self.apply_transaction(tx, &wallet, stamp);
+        if result.is_err() && !known && !self.config.account_creation.keeps_rejected() {
+            self.remove_wallet(tx.account_id, &wallet);
+        }
+
+        // Alerts are only raised by the transactions applied, or blocked
+        if result.is_ok() {
+            self.detectors.record(&activity);
+            if !self.detectors.is_empty() {
+                for undo in self.undos_mut() {
+                    undo.activity.push((tx.account_id, stamp.sequence));
+                }
+            }
+            self.flag(alerts);
+        }
+        result
+    }
+
+    // Report the alerts raised by a transaction
+    fn flag(&mut self, alerts: Vec<(Alert, bool)>) {
+        for (alert, _) in alerts {
+            log::info!(
+                "Anomaly {} flagged for client {}: tx ids {:?}",
+                alert.rule,
+                alert.client,
+                alert.txs
+            );
+            self.emit(LedgerEvent::AnomalyFlagged {
+                client: alert.client,
+                rule: alert.rule,
+                txs: alert.txs,
+            });
+        }
+    }
 
+    // Wallet a transaction applies to: follow-ups go to the wallet of the transaction they
+    // refer to, whatever wallet the record names
+    fn wallet_of(&self, tx: &Transaction) -> String {
         match tx.typ {
+            TransactionType::Dispute
+            | TransactionType::Resolve
+            | TransactionType::Chargeback
+            | TransactionType::Reversal
+            | TransactionType::Correction
+            | TransactionType::EscrowRelease
+            | TransactionType::EscrowRefund => self
+                .tx_wallets
+                .get(&tx.id)
+                .map_or(MAIN_WALLET, String::as_str)
+                .to_string(),
+            _ => wallet_name(tx.wallet.as_deref()).to_string(),
+        }
+    }
+
+    fn apply_transaction(
+        &mut self,
+        tx: &Transaction,
+        wallet: &str,
+        stamp: TxStamp,
+    ) -> Result<(), LedgerError> {
+        // Transactions creating a new tx id must not reuse one
+        let creates_tx = matches!(
+            tx.typ,
+            TransactionType::Deposit
+                | TransactionType::Withdrawal
+                | TransactionType::AccrueInterest
+                | TransactionType::Transfer
+                | TransactionType::EscrowFund
+        );
+        let duplicate = creates_tx && self.is_processed(tx.id)?;
+        let mut created = false;
+
+        // Transfers must go to another wallet of the client, able to receive them
+        let destination = match tx.typ {
+            TransactionType::Transfer => Some(self.transfer_destination(tx, wallet)?),
+            _ => None,
+        };
+
+        // Escrow releases pay the main wallet of the seller, which must be able to receive them
+        let seller = match tx.typ {
+            TransactionType::EscrowRelease => self.escrow_seller(tx, wallet)?,
+            _ => None,
+        };
+
+        // Validation rules see the wallet as it is, before anything is changed
+        let (available, held, locked) = match self.wallet(tx.account_id, wallet) {
+            Some(account) => (
+                account.amount_available,
+                account.amount_held,
+                account.is_locked,
+            ),
+            None => (
+                Amount::new(),
+                Amount::new(),
+                self.opens_locked(tx.account_id),
+            ),
+        };
+        self.config
+            .rules
+            .check(&RuleContext::new(tx, available, held, locked, duplicate))?;
+
+        self.backup_wallet(tx.account_id, wallet);
+        if let Some(destination) = &destination {
+            self.backup_wallet(tx.account_id, destination);
+        }
+        if let Some(seller) = seller {
+            self.backup_wallet(seller, MAIN_WALLET);
+        }
+
+        // Balances are reported per client, whatever wallet the transaction applies to
+        let before = self.client_balances(tx.account_id)?;
+        // The journal posts the changes of every wallet the transaction may move funds of
+        let mut moved = vec![(tx.account_id, wallet.to_string())];
+        moved.extend(destination.iter().map(|to| (tx.account_id, to.clone())));
+        moved.extend(seller.map(|seller| (seller, MAIN_WALLET.to_string())));
+        let moved: Vec<_> = moved
+            .into_iter()
+            .map(|(client, wallet)| {
+                let before = self.wallet_balances(client, &wallet);
+                (client, wallet, before)
+            })
+            .collect();
+
+        // Limits of the KYC tier of registered clients
+        let unlimited = TierLimits::default();
+        let limits = match self.registry.get(tx.account_id) {
+            Some(info) => self.config.kyc_limits.tier(info.kyc_tier),
+            None => &unlimited,
+        };
+
+        let opens_locked = self.opens_locked(tx.account_id);
+        let account = wallet_entry(
+            &mut self.accounts,
+            &mut self.wallets,
+            tx.account_id,
+            wallet,
+            opens_locked,
+        );
+
+        if self.config.interest.is_some() {
+            account
+                .interest
+                .track(&account.amount_available, stamp.timestamp)?;
+        }
+
+        // Movements of funds caused by the transaction, in the order they are applied
+        let movements = match tx.typ {
             TransactionType::Deposit => {
-                if self.tx_processed.contains(&tx.id) {
+                if duplicate {
                     Err(LedgerError::DuplicateTxId(tx.id))?
                 }
 
@@ -64,11 +483,19 @@ impl Ledger {
                 if amount < Amount::new() {
                     Err(LedgerError::NegativeTxAmount(tx.id))?;
                 }
-                account.deposit(tx.id, amount)?;
-                self.tx_processed.insert(tx.id);
+                if limits.max_deposit.is_some_and(|max| amount > max) {
+                    Err(LedgerError::KycLimitExceeded(tx.id))?
+                }
+                let fee = self
+                    .config
+                    .fees
+                    .fee(FeeTxType::Deposit, tx.account_id, &amount)?;
+                account.deposit(tx.id, amount, fee, stamp)?;
+                created = true;
+                vec![(OperationKind::Deposit, amount), (OperationKind::Fee, fee)]
             }
             TransactionType::Withdrawal => {
-                if self.tx_processed.contains(&tx.id) {
+                if duplicate {
                     Err(LedgerError::DuplicateTxId(tx.id))?
                 }
                 let amount_str = tx
@@ -80,122 +507,2884 @@ impl Ledger {
                 if amount < Amount::new() {
                     Err(LedgerError::NegativeTxAmount(tx.id))?;
                 }
-                account.withdraw(tx.id, amount)?;
-                self.tx_processed.insert(tx.id);
+                if limits.max_withdrawal.is_some_and(|max| amount > max) {
+                    Err(LedgerError::KycLimitExceeded(tx.id))?
+                }
+                let fee = self
+                    .config
+                    .fees
+                    .fee(FeeTxType::Withdrawal, tx.account_id, &amount)?;
+                account.withdraw(tx.id, amount, fee, stamp)?;
+                created = true;
+                if self.config.retention.deposits_only {
+                    account.forget(tx.id);
+                }
+                vec![
+                    (OperationKind::Withdrawal, amount),
+                    (OperationKind::Fee, fee),
+                ]
+            }
+            TransactionType::Dispute => {
+                // Evicted transactions are brought back from disk to be disputed
+                if !account.knows_tx(tx.id)
+                    && let Some(store) = &mut self.history_store
+                    && let Some(spilled) = store.load(tx.account_id, tx.id)?
+                {
+                    log::debug!("Restored tx id {} from history store", tx.id);
+                    account.restore(tx.id, spilled);
+                }
+                account.dispute(tx.id, &self.config.dispute_window, &stamp)?;
+                vec![(OperationKind::Dispute, account.tx_amount(tx.id))]
+            }
+            TransactionType::Resolve => {
+                account.resolve(tx.id, &stamp)?;
+                vec![(OperationKind::Resolve, account.tx_amount(tx.id))]
+            }
+            TransactionType::Chargeback => {
+                account.chargeback(tx.id, &stamp)?;
+                let mut movements = vec![(OperationKind::Chargeback, account.tx_amount(tx.id))];
+                if self.config.fees.refund_on_chargeback {
+                    movements.push((OperationKind::FeeRefund, account.refund_fee(tx.id)?));
+                }
+                movements
+            }
+            TransactionType::Reversal => {
+                let (change, fee) = account.reverse(tx.id, &stamp)?;
+                vec![
+                    (OperationKind::Reversal, change),
+                    (OperationKind::FeeRefund, fee),
+                ]
+            }
+            TransactionType::Correction => {
+                let amount_str = tx
+                    .amount
+                    .as_ref()
+                    .ok_or(LedgerError::MissingAmount(tx.id))?;
+                let amount = Amount::from_str(amount_str)?;
+                if amount < Amount::new() {
+                    Err(LedgerError::NegativeTxAmount(tx.id))?;
+                }
+                vec![(
+                    OperationKind::Correction,
+                    account.correct(tx.id, amount, &stamp)?,
+                )]
+            }
+            TransactionType::AccrueInterest => {
+                let policy = self
+                    .config
+                    .interest
+                    .as_ref()
+                    .ok_or(LedgerError::InterestDisabled(tx.id))?;
+                if stamp.timestamp.is_none() {
+                    Err(LedgerError::MissingTimestamp(tx.id))?
+                }
+                if duplicate {
+                    Err(LedgerError::DuplicateTxId(tx.id))?
+                }
+
+                let (interest, residue) = account.interest.due(policy)?;
+                if interest > Amount::new() {
+                    // Interest is credited as a deposit, identified by the tx id of the record
+                    account.deposit(tx.id, interest, Amount::new(), stamp)?;
+                    created = true;
+                }
+                account.interest.settle(residue);
+                vec![(OperationKind::Interest, interest)]
+            }
+            TransactionType::Transfer => {
+                if duplicate {
+                    Err(LedgerError::DuplicateTxId(tx.id))?
+                }
+                let amount_str = tx
+                    .amount
+                    .as_ref()
+                    .ok_or(LedgerError::MissingAmount(tx.id))?;
+                let amount = Amount::from_str(amount_str)?;
+                if amount < Amount::new() {
+                    Err(LedgerError::NegativeTxAmount(tx.id))?;
+                }
+                account.transfer_out(tx.id, amount)?;
+                created = true;
+                vec![(OperationKind::Transfer, amount)]
+            }
+            TransactionType::EscrowFund => {
+                if duplicate {
+                    Err(LedgerError::DuplicateTxId(tx.id))?
+                }
+                let amount_str = tx
+                    .amount
+                    .as_ref()
+                    .ok_or(LedgerError::MissingAmount(tx.id))?;
+                let amount = Amount::from_str(amount_str)?;
+                if amount < Amount::new() {
+                    Err(LedgerError::NegativeTxAmount(tx.id))?;
+                }
+                let seller = tx
+                    .seller
+                    .filter(|seller| *seller != tx.account_id)
+                    .ok_or(LedgerError::InvalidEscrow(tx.id))?;
+                account.fund_escrow(tx.id, amount, seller)?;
+                created = true;
+                self.has_escrows = true;
+                vec![(OperationKind::EscrowFund, amount)]
+            }
+            TransactionType::EscrowRelease => {
+                let amount = escrow_amount(tx)?;
+                vec![(
+                    OperationKind::EscrowRelease,
+                    account.release_escrow(tx.id, amount)?,
+                )]
+            }
+            TransactionType::EscrowRefund => {
+                let amount = escrow_amount(tx)?;
+                vec![(
+                    OperationKind::EscrowRefund,
+                    account.refund_escrow(tx.id, amount)?,
+                )]
+            }
+        };
+
+        if let Some(destination) = &destination
+            && let Some((_, amount)) = movements.first()
+        {
+            self.complete_transfer(tx, wallet, destination, *amount, &stamp)?;
+        }
+        let payout = match (seller, movements.first()) {
+            (Some(seller), Some((_, amount))) => Some((
+                seller,
+                self.pay_seller(tx, seller, *amount, &stamp)?,
+                *amount,
+            )),
+            _ => None,
+        };
+        if tx.typ == TransactionType::Chargeback && self.config.chargeback_lock == LockScope::Client
+        {
+            self.lock_client(tx.account_id);
+        }
+
+        let mut entry = JournalEntry {
+            stamp,
+            tx: tx.id,
+            kinds: vec![],
+            postings: vec![],
+        };
+        let after = self.post_movements(tx, stamp, tx.account_id, before, movements, &mut entry)?;
+        debug_assert!(
+            self.client_balances(tx.account_id)
+                .is_ok_and(|balances| balances == after)
+        );
+        if let Some((seller, before, amount)) = payout {
+            self.post_movements(
+                tx,
+                stamp,
+                seller,
+                before,
+                vec![(OperationKind::EscrowPayout, amount)],
+                &mut entry,
+            )?;
+        }
+        // The customer side is what actually changed, so an entry only balances when the wallets
+        // moved as the operations say
+        for (client, wallet, before) in moved {
+            let after = self.wallet_balances(client, &wallet);
+            entry
+                .postings
+                .extend(Journal::customer_postings(client, &wallet, before, after)?);
+        }
+        if !entry.postings.is_empty() {
+            for undo in self.undos_mut() {
+                undo.entries.push(entry.clone());
+            }
+            self.journal.post(entry)?;
+        }
+
+        if created {
+            self.mark_processed(tx.id)?;
+            if wallet != MAIN_WALLET {
+                self.tx_wallets.insert(tx.id, wallet.to_string());
             }
-            TransactionType::Dispute => account.dispute(tx.id)?,
-            TransactionType::Resolve => account.resolve(tx.id)?,
-            TransactionType::Chargeback => account.chargeback(tx.id)?,
         }
+        self.retain_history(tx.account_id, wallet, &stamp)?;
 
         Ok(())
     }
 
-    // WARNING: Overflow error when computing total - will be swallowed and logged
-    pub fn account_snapshots(&self) -> impl Iterator<Item = AccountSnapshot> {
-        self.accounts.values().filter_map(|acc| {
-            match acc.amount_available.add(&acc.amount_held) {
-                Ok(total) => Some(AccountSnapshot {
-                    client: acc.id.to_string(),
-                    available: acc.amount_available.to_string(),
-                    held: acc.amount_held.to_string(),
-                    total: total.to_string(),
-                    locked: acc.is_locked,
-                }),
-                Err(_) => {
-                    // Total is overflowing => silently ignore but log
-                    log::warn!("Ledger::account_snapshots error: Overflow when computing Total. This is ignored silently.");
-                    None
-                }
+    // Post each movement to the operation log, with the running balances of `client` starting
+    // from `before`, and its house side to the journal `entry` of the record. Returns the
+    // balances after the last movement.
+    fn post_movements(
+        &mut self,
+        tx: &Transaction,
+        stamp: TxStamp,
+        client: u16,
+        before: (Amount, Amount),
+        movements: Vec<(OperationKind, Amount)>,
+        entry: &mut JournalEntry,
+    ) -> Result<(Amount, Amount), LedgerError> {
+        let (mut available, mut held) = before;
+        for (kind, amount) in movements {
+            if amount == Amount::new() && kind.skipped_when_zero() {
+                continue; // No fee charged or refunded, no interest paid, nothing corrected
             }
-        })
-    }
-}
 
-#[cfg(test)]
-#[allow(unused_imports)]
-mod tests {
-    use super::*;
-    use crate::engine::{Transaction, TransactionType};
+            (available, held) = kind.apply(&amount, &available, &held)?;
+            entry.kinds.push(kind);
+            entry.postings.extend(Journal::house_posting(kind, amount)?);
 
-    #[test]
-    fn test_that_duplicate_tx_id_is_rejected_by_ledger() {
-        let mut ledger = Ledger::new();
+            self.emit(LedgerEvent::operation(client, tx.id, kind, &amount));
+            if !self.config.retention.skip_operation_log {
+                self.operations.push(AppliedOperation {
+                    stamp,
+                    client,
+                    tx: tx.id,
+                    kind,
+                    amount,
+                    available,
+                    held,
+                });
+            }
+        }
+        Ok((available, held))
+    }
 
-        // First deposit with tx id 1 on client 1
-        let tx1 = Transaction {
-            id: 1,
-            account_id: 1,
-            typ: TransactionType::Deposit,
-            amount: Some(String::from("10.0")),
+    // Seller of the escrow released by `tx`, once it is known to be able to receive the funds
+    // left in escrow. Unknown escrows are left for the buyer account to reject.
+    fn escrow_seller(&self, tx: &Transaction, wallet: &str) -> Result<Option<u16>, LedgerError> {
+        let Some(escrow) = self
+            .wallet(tx.account_id, wallet)
+            .and_then(|account| account.escrow(tx.id))
+        else {
+            return Ok(None);
         };
-        assert!(ledger.process_transaction(&tx1).is_ok());
+        let seller = escrow.seller;
 
-        // Second deposit with same tx id 1, even on different client
-        let tx2 = Transaction {
-            id: 1,
-            account_id: 2,
-            typ: TransactionType::Deposit,
-            amount: Some(String::from("5.0")),
-        };
-        let err = ledger.process_transaction(&tx2).unwrap_err();
-        assert!(matches!(err, LedgerError::DuplicateTxId(1)));
+        if let Some(info) = self.registry.get(seller)
+            && info.status != ClientStatus::Active
+        {
+            Err(LedgerError::ClientNotActive(seller))?
+        }
+        if !self.config.account_creation.is_registered(seller) {
+            Err(LedgerError::UnregisteredClient(seller))?
+        }
+        match self.wallet(seller, MAIN_WALLET) {
+            Some(account) if account.is_locked => Err(AccountOperationError::AccountLocked(tx.id))?,
+            Some(account) => {
+                account.amount_available.add(&escrow.remaining)?;
+            }
+            None if self.opens_locked(seller) => Err(AccountOperationError::AccountLocked(tx.id))?,
+            None => {}
+        }
+        Ok(Some(seller))
     }
 
-    #[test]
-    fn test_that_invalid_amount_string_is_rejected() {
-        let mut ledger = Ledger::new();
+    // Credit the funds released from an escrow to the main wallet of `seller`.
+    // Returns the balances of the seller before the payout.
+    fn pay_seller(
+        &mut self,
+        tx: &Transaction,
+        seller: u16,
+        amount: Amount,
+        stamp: &TxStamp,
+    ) -> Result<(Amount, Amount), LedgerError> {
+        let before = self.client_balances(seller)?;
+        let tracks_interest = self.config.interest.is_some();
+        let account = wallet_entry(
+            &mut self.accounts,
+            &mut self.wallets,
+            seller,
+            MAIN_WALLET,
+            false,
+        );
+        if tracks_interest {
+            account
+                .interest
+                .track(&account.amount_available, stamp.timestamp)?;
+        }
+        account.transfer_in(tx.id, amount)?;
+        Ok(before)
+    }
 
-        let tx = Transaction {
-            id: 1,
-            account_id: 1,
-            typ: TransactionType::Deposit,
-            amount: Some(String::from("not_parsable")),
+    // Wallet receiving the funds of a transfer out of `source`
+    fn transfer_destination(&self, tx: &Transaction, source: &str) -> Result<String, LedgerError> {
+        let Some(to_wallet) = &tx.to_wallet else {
+            Err(LedgerError::InvalidTransfer(tx.id))?
         };
-        let err = ledger.process_transaction(&tx).unwrap_err();
-        assert!(matches!(err, LedgerError::Amount(_)));
+        let destination = wallet_name(Some(to_wallet));
+        if destination == source {
+            Err(LedgerError::InvalidTransfer(tx.id))?
+        }
+        let locked = match self.wallet(tx.account_id, destination) {
+            Some(account) => account.is_locked,
+            None => self.opens_locked(tx.account_id),
+        };
+        if locked {
+            Err(AccountOperationError::AccountLocked(tx.id))?
+        }
+        Ok(destination.to_string())
     }
 
-    #[test]
-    fn test_that_overflow_in_total_removes_account_from_snapshots() {
-        let mut ledger = Ledger::new();
+    // Credit the funds taken from `source` by a transfer to `destination`.
+    // They are put back in `source` if the destination cannot take them.
+    fn complete_transfer(
+        &mut self,
+        tx: &Transaction,
+        source: &str,
+        destination: &str,
+        amount: Amount,
+        stamp: &TxStamp,
+    ) -> Result<(), LedgerError> {
+        let tracks_interest = self.config.interest.is_some();
+        let account = wallet_entry(
+            &mut self.accounts,
+            &mut self.wallets,
+            tx.account_id,
+            destination,
+            false,
+        );
+        if tracks_interest {
+            account
+                .interest
+                .track(&account.amount_available, stamp.timestamp)?;
+        }
+        if let Err(e) = account.transfer_in(tx.id, amount) {
+            if let Some(source) =
+                wallet_mut(&mut self.accounts, &mut self.wallets, tx.account_id, source)
+            {
+                source.amount_available = source.amount_available.add(&amount)?;
+            }
+            Err(e)?
+        }
+        Ok(())
+    }
 
-        let acc = ledger.accounts.entry(1).or_insert_with(|| Account::new(1));
+    // Report the wallets opened and locked by `tx` around its operations, and its rejection.
+    // Events are delivered unless a batch is being processed.
+    fn report(&mut self, tx: &Transaction, first_event: usize, result: &Result<(), LedgerError>) {
+        let mut opened = vec![];
+        let mut locked = vec![];
+        for (client, wallet, was_locked) in std::mem::take(&mut self.touched) {
+            let Some(account) = self.wallet(client, &wallet) else {
+                continue; // Not opened, or closed again after a rejection
+            };
+            if account.is_locked && was_locked != Some(true) {
+                locked.push(LedgerEvent::AccountLocked {
+                    client,
+                    wallet: wallet.clone(),
+                });
+            }
+            if was_locked.is_none() {
+                opened.push(LedgerEvent::AccountCreated { client, wallet });
+            }
+        }
+        self.events.splice(first_event..first_event, opened);
+        self.events.extend(locked);
+        if let Err(e) = result {
+            self.events.push(LedgerEvent::rejected(tx, e));
+        }
+        if self.batch.is_none() {
+            self.notify();
+        }
+    }
 
-        // Force near-overflow values manually
-        acc.amount_available = Amount::from_str("922337203685477.5807").unwrap();
-        acc.amount_held = Amount::from_str("1.0").unwrap();
+    fn emit(&mut self, event: LedgerEvent) {
+        if !self.observers.is_empty() {
+            self.events.push(event);
+        }
+    }
 
-        // This should overflow available + held and thus be filtered out
-        let snapshots: Vec<_> = ledger.account_snapshots().collect();
-        assert!(snapshots.is_empty());
+    // Deliver the pending events to every observer
+    fn notify(&mut self) {
+        for event in self.events.drain(..) {
+            for observer in &mut self.observers {
+                observer.on_event(&event);
+            }
+        }
     }
 
-    #[test]
-    fn test_that_negative_deposit_amount_is_rejected() {
-        let mut ledger = Ledger::new();
+    // Lock every wallet of `client` after a chargeback on one of them
+    fn lock_client(&mut self, client: u16) {
+        let wallets: Vec<String> = self
+            .client_wallets(client)
+            .filter(|(_, account)| !account.is_locked)
+            .map(|(name, _)| name.to_string())
+            .collect();
+        for wallet in wallets {
+            self.backup_wallet(client, &wallet);
+            if let Some(account) =
+                wallet_mut(&mut self.accounts, &mut self.wallets, client, &wallet)
+            {
+                account.is_locked = true;
+            }
+        }
+    }
 
-        let tx = Transaction {
-            id: 1,
-            account_id: 1,
-            typ: TransactionType::Deposit,
-            amount: Some(String::from("-1.0")),
-        };
-        let err = ledger.process_transaction(&tx).unwrap_err();
-        assert!(matches!(err, LedgerError::NegativeTxAmount(1)));
+    // New wallets of a client are locked when a chargeback locked the whole client
+    fn opens_locked(&self, client: u16) -> bool {
+        self.config.chargeback_lock == LockScope::Client
+            && self
+                .client_wallets(client)
+                .any(|(_, account)| account.is_locked)
     }
 
-    #[test]
-    fn test_that_negative_withdrawal_amount_is_rejected() {
-        let mut ledger = Ledger::new();
+    // Keep the wallet as it was before the batch and the record, to be able to roll them back
+    // and to report the wallets opened or locked by the record
+    fn backup_wallet(&mut self, client: u16, wallet: &str) {
+        if !self.observers.is_empty()
+            && !self
+                .touched
+                .iter()
+                .any(|(c, w, _)| *c == client && w == wallet)
+        {
+            let locked = self.wallet(client, wallet).map(|acc| acc.is_locked);
+            self.touched.push((client, wallet.to_string(), locked));
+        }
 
-        let tx = Transaction {
-            id: 1,
-            account_id: 1,
-            typ: TransactionType::Withdrawal,
-            amount: Some(String::from("-1.0")),
-        };
-        let err = ledger.process_transaction(&tx).unwrap_err();
-        assert!(matches!(err, LedgerError::NegativeTxAmount(1)));
+        let key = (client, wallet.to_string());
+        let mut undos = self
+            .batch
+            .iter()
+            .chain(self.rollback_log.last().map(|record| &record.changes));
+        if undos.all(|undo| undo.accounts.contains_key(&key)) {
+            return;
+        }
+        let account = self.wallet(client, wallet).cloned();
+        for undo in self.undos_mut() {
+            undo.accounts
+                .entry(key.clone())
+                .or_insert_with(|| account.clone());
+        }
+    }
+
+    // Changes of the batch and of the record being processed (the last one of the rollback log)
+    fn undos_mut(&mut self) -> impl Iterator<Item = &mut Undo> {
+        let record = self
+            .rollback_log
+            .last_mut()
+            .map(|record| &mut record.changes);
+        self.batch.iter_mut().chain(record)
+    }
+
+    fn wallet(&self, client: u16, wallet: &str) -> Option<&Account> {
+        match wallet {
+            MAIN_WALLET => self.accounts.get(&client),
+            name => self.wallets.get(&client)?.get(name),
+        }
+    }
+
+    fn remove_wallet(&mut self, client: u16, wallet: &str) {
+        match wallet {
+            MAIN_WALLET => {
+                self.accounts.remove(&client);
+            }
+            name => {
+                if let Some(wallets) = self.wallets.get_mut(&client) {
+                    wallets.remove(name);
+                    if wallets.is_empty() {
+                        self.wallets.remove(&client);
+                    }
+                }
+            }
+        }
+    }
+
+    // Every wallet of `client`, the main one first then the others by name
+    fn client_wallets(&self, client: u16) -> impl Iterator<Item = (&str, &Account)> {
+        let main = self.accounts.get(&client).map(|acc| (MAIN_WALLET, acc));
+        let named = self
+            .wallets
+            .get(&client)
+            .into_iter()
+            .flat_map(|wallets| wallets.iter().map(|(name, acc)| (name.as_str(), acc)));
+        main.into_iter().chain(named)
+    }
+
+    fn all_wallets(&self) -> impl Iterator<Item = &Account> {
+        self.accounts
+            .values()
+            .chain(self.wallets.values().flat_map(|wallets| wallets.values()))
+    }
+
+    // Balances (available, held) of `client`, summed over its wallets
+    // Available and held balances of a wallet, empty when it doesn't exist
+    fn wallet_balances(&self, client: u16, wallet: &str) -> (Amount, Amount) {
+        self.wallet(client, wallet)
+            .map(|acc| (acc.amount_available, acc.amount_held))
+            .unwrap_or_default()
+    }
+
+    fn client_balances(&self, client: u16) -> Result<(Amount, Amount), AmountError> {
+        self.client_wallets(client).try_fold(
+            (Amount::new(), Amount::new()),
+            |(available, held), (_, acc)| {
+                Ok((
+                    available.add(&acc.amount_available)?,
+                    held.add(&acc.amount_held)?,
+                ))
+            },
+        )
+    }
+
+    fn is_known(&self, client: u16) -> bool {
+        self.accounts.contains_key(&client) || self.wallets.contains_key(&client)
+    }
+
+    // Every client with at least one wallet, ordered by client id
+    fn clients(&self) -> Vec<u16> {
+        let mut clients: Vec<u16> = self
+            .accounts
+            .keys()
+            .chain(self.wallets.keys())
+            .copied()
+            .collect();
+        clients.sort_unstable();
+        clients.dedup();
+        clients
+    }
+
+    /// Process `tx` at most once for the idempotency `key`.
+    /// An exact retry of the request first made with `key` is not applied again and returns the
+    /// outcome of that first request, while using `key` for a different request is rejected as
+    /// `IdempotencyConflict`.
+    pub fn process_idempotent(
+        &mut self,
+        key: &str,
+        tx: &Transaction,
+    ) -> Result<IdempotentResult, LedgerError> {
+        if let Some((fingerprint, outcome)) = self.idempotency_keys.get(key) {
+            if *fingerprint != RequestFingerprint::from(tx) {
+                Err(LedgerError::IdempotencyConflict(key.to_string()))?
+            }
+            return Ok(IdempotentResult::Retried(outcome.clone()));
+        }
+
+        let result = self.process_transaction(tx);
+        let outcome = match &result {
+            Ok(()) => Some(IdempotentOutcome::Applied),
+            // Storage failures are not an answer to the request, it can be retried
+            Err(LedgerError::Storage(_)) => None,
+            Err(e) => Some(IdempotentOutcome::Rejected(e.to_string())),
+        };
+        if let Some(outcome) = outcome {
+            self.idempotency_keys.record(key, tx, outcome);
+            if let Some(record) = self.rollback_log.last_mut() {
+                record.idempotency_key = Some(key.to_string());
+            }
+        }
+        result.map(|()| IdempotentResult::Applied)
+    }
+
+    /// Apply `transactions` all-or-nothing.
+    /// When one of them fails, every change made by the batch is rolled back and the error is
+    /// returned with the position of the failing transaction.
+    /// NOTE: Rolled back records still count in the sequence of records seen by the ledger.
+    pub fn process_batch(&mut self, transactions: &[Transaction]) -> Result<(), BatchError> {
+        let start = self.sequence;
+        self.batch = Some(Undo {
+            operations: self.operations.len(),
+            ..Undo::default()
+        });
+
+        for (index, tx) in transactions.iter().enumerate() {
+            let first_event = self.events.len();
+            if let Err(source) = self.process_transaction(tx) {
+                self.rollback_batch(start)?;
+                // Only the failure is reported, with the alerts blocking it: nothing else happened
+                let alerts: Vec<LedgerEvent> = self
+                    .events
+                    .drain(first_event..)
+                    .filter(|event| matches!(event, LedgerEvent::AnomalyFlagged { .. }))
+                    .collect();
+                self.events.clear();
+                self.events.extend(alerts);
+                self.emit(LedgerEvent::rejected(tx, &source));
+                self.notify();
+                Err(BatchError::Rejected {
+                    index,
+                    tx: tx.id,
+                    source,
+                })?
+            }
+        }
+
+        // Tx ids are only marked as processed once the whole batch is applied
+        if let Some(batch) = self.batch.take() {
+            for tx_id in batch.tx_ids {
+                self.tx_processed.insert(tx_id)?;
+            }
+        }
+        self.notify();
+        Ok(())
+    }
+
+    // Undo the batch of the records that came after `start`, which are left without changes
+    fn rollback_batch(&mut self, start: u64) -> Result<(), JournalError> {
+        let Some(batch) = self.batch.take() else {
+            return Ok(());
+        };
+        self.undo(batch)?;
+
+        for record in self
+            .rollback_log
+            .iter_mut()
+            .filter(|record| record.stamp.sequence > start)
+        {
+            record.changes = Undo {
+                operations: self.operations.len(),
+                ..Undo::default()
+            };
+        }
+        Ok(())
+    }
+
+    fn undo(&mut self, changes: Undo) -> Result<(), JournalError> {
+        for ((client, wallet), account) in changes.accounts {
+            match account {
+                Some(account) => {
+                    *wallet_entry(
+                        &mut self.accounts,
+                        &mut self.wallets,
+                        client,
+                        &wallet,
+                        false,
+                    ) = account;
+                }
+                None => self.remove_wallet(client, &wallet),
+            }
+        }
+        for (client, sequence) in changes.activity {
+            self.detectors.forget(client, sequence);
+        }
+        for tx_id in &changes.tx_ids {
+            self.tx_wallets.remove(tx_id);
+            self.tx_processed.remove(*tx_id);
+        }
+        self.operations.truncate(changes.operations);
+        self.journal.rollback(&changes.entries)
+    }
+
+    fn is_processed(&mut self, tx_id: u32) -> Result<bool, LedgerError> {
+        if let Some(batch) = &self.batch
+            && batch.tx_ids.contains(&tx_id)
+        {
+            return Ok(true);
+        }
+        Ok(self.tx_processed.contains(tx_id)?)
+    }
+
+    fn mark_processed(&mut self, tx_id: u32) -> Result<(), LedgerError> {
+        if let Some(record) = self.rollback_log.last_mut() {
+            record.changes.tx_ids.insert(tx_id);
+        }
+        match &mut self.batch {
+            Some(batch) => {
+                batch.tx_ids.insert(tx_id);
+            }
+            None => self.tx_processed.insert(tx_id)?,
+        }
+        Ok(())
+    }
+
+    // Apply the retention policy to the wallet touched by the last transaction
+    fn retain_history(
+        &mut self,
+        account_id: u16,
+        wallet: &str,
+        now: &TxStamp,
+    ) -> Result<(), LedgerError> {
+        let Some(account) = wallet_mut(&mut self.accounts, &mut self.wallets, account_id, wallet)
+        else {
+            return Ok(());
+        };
+        let retention = &self.config.retention;
+
+        if retention.drop_expired {
+            account.compact_expired(&self.config.dispute_window, now);
+        }
+
+        if let Some(keep_last) = retention.keep_last
+            && account.history_len() > keep_last
+        {
+            for (tx_id, tx) in account.evict_oldest(keep_last) {
+                match &mut self.history_store {
+                    Some(store) => store.spill(account_id, tx_id, &tx)?,
+                    None => log::debug!("Dropped tx id {tx_id} from history (no history store)"),
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Transactions of `client` kept in memory, in the order they were applied.
+    /// Returns `None` for an unknown client. Transactions evicted by the retention policy are not listed.
+    pub fn client_history(&self, client: u16) -> Option<Vec<TxHistoryEntry>> {
+        if !self.is_known(client) {
+            return None;
+        }
+        let mut entries: Vec<TxHistoryEntry> = self
+            .client_wallets(client)
+            .flat_map(|(_, acc)| acc.history())
+            .map(|(tx_id, tx)| history_entry(client, tx_id, tx))
+            .collect();
+        entries.sort_by_key(|e| e.sequence);
+        Some(entries)
+    }
+
+    /// Lifecycle of a transaction (applied, then every dispute, resolve and chargeback)
+    pub fn transaction_lifecycle(&self, tx_id: u32) -> Option<TxLifecycle> {
+        self.all_wallets().find_map(|acc| {
+            acc.get_tx(tx_id).map(|tx| TxLifecycle {
+                entry: history_entry(acc.id, tx_id, tx),
+                events: tx.lifecycle(),
+            })
+        })
+    }
+
+    /// Statement of `client` over `period`, with running balances after each operation.
+    /// Returns `None` for an unknown client. Empty when the operation log is disabled, fails when
+    /// the period covers operations dropped from it.
+    pub fn statement(
+        &self,
+        client: u16,
+        period: StatementPeriod,
+    ) -> Option<Result<Statement, LedgerError>> {
+        if !self.is_known(client) {
+            return None;
+        }
+        Some(self.build_statement(client, period, self.operations.iter()))
+    }
+
+    /// Statements of every client over `period`, ordered by client id
+    pub fn statements(&self, period: StatementPeriod) -> Result<Vec<Statement>, LedgerError> {
+        // Operations of every client, gathered in one pass over the log
+        let mut operations: HashMap<u16, Vec<&AppliedOperation>> = HashMap::new();
+        for op in self.operations.iter() {
+            operations.entry(op.client).or_default().push(op);
+        }
+        self.clients()
+            .into_iter()
+            .map(|client| {
+                let ops = operations.remove(&client).unwrap_or_default();
+                self.build_statement(client, period, ops.into_iter())
+            })
+            .collect()
+    }
+
+    // Statement of `client` from its operations still in the log. The last operation dropped
+    // from the log gives the opening balance, provided the period starts after it.
+    fn build_statement<'a>(
+        &'a self,
+        client: u16,
+        period: StatementPeriod,
+        operations: impl Iterator<Item = &'a AppliedOperation>,
+    ) -> Result<Statement, LedgerError> {
+        let dropped = self.operations.last_dropped(client);
+        if let Some(op) = dropped
+            && !period.is_before(op.stamp.timestamp)
+        {
+            Err(LedgerError::OperationLogTruncated(client))?
+        }
+        let mut statement =
+            Statement::build(client, period, dropped.into_iter().chain(operations))?;
+        statement.holder = self.registry.get(client).cloned();
+        Ok(statement)
+    }
+
+    /// Reportable deposits and withdrawals of every client over the periods of `thresholds`.
+    /// Empty when the operation log is disabled, fails when operations were dropped from it.
+    pub fn aml_report(&self, thresholds: &AmlThresholds) -> Result<Vec<AmlReportRow>, LedgerError> {
+        if self.operations.is_truncated() {
+            Err(LedgerError::OperationLogIncomplete)?
+        }
+        Ok(AmlReportRow::build(thresholds, self.operations.iter())?)
+    }
+
+    /// Differences between the snapshots of the ledger and an external view of the clients
+    pub fn reconcile(&self, theirs: impl IntoIterator<Item = AccountSnapshot>) -> Vec<Discrepancy> {
+        reconcile(self.account_snapshots(), theirs)
+    }
+
+    /// Totals of every journal account, balanced when they sum to zero
+    pub fn trial_balance(&self) -> Result<TrialBalance, LedgerError> {
+        Ok(self.journal.trial_balance()?)
+    }
+
+    /// Check that the journal balances of every wallet match its account balances
+    pub fn verify_journal(&self) -> Result<(), LedgerError> {
+        for client in self.clients() {
+            for (wallet, account) in self.client_wallets(client) {
+                let available = self
+                    .journal
+                    .balance(&JournalAccount::CustomerAvailable(
+                        client,
+                        wallet.to_string(),
+                    ))?
+                    .neg()?;
+                let held = self
+                    .journal
+                    .balance(&JournalAccount::CustomerHeld(client, wallet.to_string()))?
+                    .neg()?;
+                if (available, held) != (account.amount_available, account.amount_held) {
+                    Err(LedgerError::JournalMismatch(client))?
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Remove from every account the history of transactions whose dispute window is over.
+    /// Returns the number of transactions removed.
+    pub fn compact_expired_history(&mut self) -> usize {
+        let now = self.current_stamp();
+        self.accounts
+            .values_mut()
+            .chain(self.wallets.values_mut().flat_map(|w| w.values_mut()))
+            .map(|acc| acc.compact_expired(&self.config.dispute_window, &now))
+            .sum()
+    }
+
+    // Records without timestamp inherit the last one seen so that elapsed windows keep moving
+    fn next_stamp(&mut self, tx: &Transaction) -> TxStamp {
+        self.sequence += 1;
+        if tx.timestamp.is_some() {
+            self.last_timestamp = tx.timestamp;
+        }
+        self.current_stamp()
+    }
+
+    fn current_stamp(&self) -> TxStamp {
+        TxStamp {
+            sequence: self.sequence,
+            timestamp: self.last_timestamp,
+        }
+    }
+
+    /// Number of records given to the ledger, the sequence number of the last one
+    pub fn sequence(&self) -> u64 {
+        self.sequence
+    }
+
+    /// Roll the ledger back to `point`, undoing every record given after it from the rollback log.
+    /// Nothing is undone when `point` is not in the past.
+    /// NOTE: History dropped by `compaction_interval` is not restored.
+    pub fn rollback_to(&mut self, point: PointInTime) -> Result<(), LedgerError> {
+        let sequence = self.sequence_at(point)?;
+        if matches!(self.tx_processed, ProcessedTxIds::Filtered(_)) {
+            Err(LedgerError::RollbackUnsupported("a tx id filter"))?
+        }
+        if self.history_store.is_some() {
+            Err(LedgerError::RollbackUnsupported("a history store"))?
+        }
+        if self.operations.is_truncated() {
+            Err(LedgerError::RollbackUnsupported(
+                "a truncated operation log",
+            ))?
+        }
+
+        let mut undone = false;
+        while let Some(record) = self
+            .rollback_log
+            .pop_if(|record| record.stamp.sequence > sequence)
+        {
+            self.undo(record.changes)?;
+            if let Some(key) = &record.idempotency_key {
+                self.idempotency_keys.remove(key);
+            }
+            self.sequence = sequence;
+            self.last_timestamp = record.last_timestamp;
+            undone = true;
+        }
+        if undone {
+            self.emit(LedgerEvent::RolledBack { sequence });
+            self.notify();
+        }
+        Ok(())
+    }
+
+    /// Snapshots of every client as they were at `point`, from the rollback log
+    pub fn snapshots_at(&self, point: PointInTime) -> Result<Vec<AccountSnapshot>, LedgerError> {
+        let sequence = self.sequence_at(point)?;
+
+        // Wallets changed after `point`, as they were before the oldest of these changes
+        let mut past: HashMap<(u16, &str), Option<&Account>> = HashMap::new();
+        for record in self
+            .rollback_log
+            .iter()
+            .rev()
+            .take_while(|record| record.stamp.sequence > sequence)
+        {
+            for ((client, wallet), account) in &record.changes.accounts {
+                past.insert((*client, wallet.as_str()), account.as_ref());
+            }
+        }
+
+        let mut clients = self.clients();
+        clients.extend(past.keys().map(|(client, _)| *client));
+        clients.sort_unstable();
+        clients.dedup();
+        Ok(clients
+            .into_iter()
+            .filter_map(|client| {
+                let current = self.client_wallets(client);
+                let changed = past
+                    .keys()
+                    .filter(|(c, wallet)| *c == client && self.wallet(client, wallet).is_none())
+                    .map(|(_, wallet)| (*wallet, None));
+                let wallets: Vec<&Account> = current
+                    .map(|(wallet, acc)| (wallet, Some(acc)))
+                    .chain(changed)
+                    .filter_map(|(wallet, acc)| past.get(&(client, wallet)).copied().unwrap_or(acc))
+                    .collect();
+                if wallets.is_empty() {
+                    return None;
+                }
+                self.snapshot_of(client, &wallets)
+            })
+            .collect())
+    }
+
+    // Sequence number of the last record given at `point`
+    fn sequence_at(&self, point: PointInTime) -> Result<u64, LedgerError> {
+        if !self.config.retention.rollback_log {
+            Err(LedgerError::NoRollbackLog)?
+        }
+        Ok(match point {
+            PointInTime::Sequence(sequence) => sequence,
+            PointInTime::Timestamp(timestamp) => self
+                .rollback_log
+                .iter()
+                .rev()
+                .find(|record| record.stamp.timestamp.is_none_or(|ts| ts <= timestamp))
+                .map_or(0, |record| record.stamp.sequence),
+        })
+    }
+
+    /// Registry details of a client, when it is registered
+    pub fn client_info(&self, client: u16) -> Option<&ClientInfo> {
+        self.registry.get(client)
+    }
+
+    /// Account snapshots joined with the registry details of their client
+    pub fn registered_snapshots(
+        &self,
+    ) -> impl Iterator<Item = (AccountSnapshot, RegistryColumns)> + '_ {
+        self.clients().into_iter().filter_map(|client| {
+            let columns = RegistryColumns::from(self.registry.get(client));
+            Some((self.client_snapshot(client)?, columns))
+        })
+    }
+
+    // WARNING: Overflow error when computing total - will be swallowed and logged
+    /// Snapshot of every client, summed over its wallets. A client is locked when any of its
+    /// wallets is.
+    pub fn account_snapshots(&self) -> impl Iterator<Item = AccountSnapshot> {
+        self.clients()
+            .into_iter()
+            .filter_map(|client| self.client_snapshot(client))
+    }
+
+    /// Snapshot of every wallet, ordered by client then wallet (main wallet first)
+    pub fn wallet_snapshots(&self) -> impl Iterator<Item = WalletSnapshot> {
+        self.clients().into_iter().flat_map(move |client| {
+            self.client_wallets(client)
+                .filter_map(move |(wallet, acc)| {
+                    let amounts = self.snapshot_amounts(
+                        &acc.amount_available,
+                        &acc.amount_held,
+                        &acc.amount_escrowed,
+                    )?;
+                    Some(WalletSnapshot {
+                        client: client.to_string(),
+                        wallet: wallet.to_string(),
+                        available: amounts.available,
+                        held: amounts.held,
+                        escrow: amounts.escrow,
+                        total: amounts.total,
+                        locked: acc.is_locked,
+                    })
+                })
+        })
+    }
+
+    /// Snapshot of `client`, summed over its wallets. `None` for an unknown client.
+    pub fn client_snapshot(&self, client: u16) -> Option<AccountSnapshot> {
+        if !self.is_known(client) {
+            return None;
+        }
+        let wallets: Vec<&Account> = self.client_wallets(client).map(|(_, acc)| acc).collect();
+        self.snapshot_of(client, &wallets)
+    }
+
+    // Snapshot of `client` summed over `wallets`
+    fn snapshot_of(&self, client: u16, wallets: &[&Account]) -> Option<AccountSnapshot> {
+        let sums = wallets.iter().try_fold(
+            (Amount::new(), Amount::new(), Amount::new()),
+            |(available, held, escrowed), acc| {
+                Ok::<_, AmountError>((
+                    available.add(&acc.amount_available)?,
+                    held.add(&acc.amount_held)?,
+                    escrowed.add(&acc.amount_escrowed)?,
+                ))
+            },
+        );
+        let Ok((available, held, escrowed)) = sums else {
+            log::warn!(
+                "Ledger::account_snapshots error: Overflow when summing wallets. This is ignored silently."
+            );
+            return None;
+        };
+        let amounts = self.snapshot_amounts(&available, &held, &escrowed)?;
+        Some(AccountSnapshot {
+            client: client.to_string(),
+            available: amounts.available,
+            held: amounts.held,
+            escrow: amounts.escrow,
+            total: amounts.total,
+            locked: wallets.iter().any(|acc| acc.is_locked),
+        })
+    }
+
+    // Amounts of a snapshot: escrowed funds are part of the held funds, but reported apart
+    fn snapshot_amounts(
+        &self,
+        available: &Amount,
+        held: &Amount,
+        escrowed: &Amount,
+    ) -> Option<SnapshotAmounts> {
+        match (available.add(held), held.sub(escrowed)) {
+            (Ok(total), Ok(disputed)) => Some(SnapshotAmounts {
+                available: available.to_string(),
+                held: disputed.to_string(),
+                escrow: self.has_escrows.then(|| escrowed.to_string()),
+                total: total.to_string(),
+            }),
+            _ => {
+                // Total is overflowing => silently ignore but log
+                log::warn!(
+                    "Ledger::account_snapshots error: Overflow when computing Total. This is ignored silently."
+                );
+                None
+            }
+        }
+    }
+}
+
+struct SnapshotAmounts {
+    available: String,
+    held: String,
+    escrow: Option<String>,
+    total: String,
+}
+
+// Amount of an escrow release or refund, everything left in escrow when missing
+fn escrow_amount(tx: &Transaction) -> Result<Option<Amount>, LedgerError> {
+    let Some(amount_str) = &tx.amount else {
+        return Ok(None);
+    };
+    let amount = Amount::from_str(amount_str)?;
+    if amount < Amount::new() {
+        Err(LedgerError::NegativeTxAmount(tx.id))?;
+    }
+    Ok(Some(amount))
+}
+
+// Wallet of `client`, opened (locked when `locked`) if it does not exist yet.
+// Takes the wallet maps rather than the ledger so that other fields stay usable.
+fn wallet_entry<'a>(
+    accounts: &'a mut HashMap<u16, Account>,
+    wallets: &'a mut HashMap<u16, BTreeMap<String, Account>>,
+    client: u16,
+    wallet: &str,
+    locked: bool,
+) -> &'a mut Account {
+    let open = || {
+        let mut account = Account::new(client);
+        account.is_locked = locked;
+        account
+    };
+    match wallet {
+        MAIN_WALLET => accounts.entry(client).or_insert_with(open),
+        name => wallets
+            .entry(client)
+            .or_default()
+            .entry(name.to_string())
+            .or_insert_with(open),
+    }
+}
+
+fn wallet_mut<'a>(
+    accounts: &'a mut HashMap<u16, Account>,
+    wallets: &'a mut HashMap<u16, BTreeMap<String, Account>>,
+    client: u16,
+    wallet: &str,
+) -> Option<&'a mut Account> {
+    match wallet {
+        MAIN_WALLET => accounts.get_mut(&client),
+        name => wallets.get_mut(&client)?.get_mut(name),
+    }
+}
+
+fn history_entry(client: u16, tx_id: u32, tx: &AccountTx) -> TxHistoryEntry {
+    TxHistoryEntry {
+        sequence: tx.stamp.sequence,
+        timestamp: tx.stamp.timestamp,
+        client,
+        tx: tx_id,
+        typ: match tx.typ {
+            AccountTxType::Deposit => TxKind::Deposit,
+            AccountTxType::Withdrawal => TxKind::Withdrawal,
+        },
+        amount: tx.amount.to_string(),
+        status: tx.status(),
+    }
+}
+
+#[cfg(test)]
+#[allow(unused_imports)]
+mod tests {
+    use super::*;
+    use crate::engine::anomaly::RepeatedDisputes;
+    use crate::engine::dispute_window::DisputeWindow;
+    use crate::engine::fees::FeeSchedule;
+    use crate::engine::history::{TxEvent, TxStatus};
+    use crate::engine::interest::{DayCount, InterestPolicy};
+    use crate::engine::point_in_time::PointInTime;
+    use crate::engine::registry::{KycLimits, KycTier};
+    use crate::engine::retention::RetentionPolicy;
+    use crate::engine::{Transaction, TransactionType};
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn test_that_duplicate_tx_id_is_rejected_by_ledger() {
+        let mut ledger = Ledger::new();
+
+        // First deposit with tx id 1 on client 1
+        let tx1 = Transaction {
+            id: 1,
+            account_id: 1,
+            typ: TransactionType::Deposit,
+            amount: Some(String::from("10.0")),
+            timestamp: None,
+            wallet: None,
+            to_wallet: None,
+            seller: None,
+        };
+        assert!(ledger.process_transaction(&tx1).is_ok());
+
+        // Second deposit with same tx id 1, even on different client
+        let tx2 = Transaction {
+            id: 1,
+            account_id: 2,
+            typ: TransactionType::Deposit,
+            amount: Some(String::from("5.0")),
+            timestamp: None,
+            wallet: None,
+            to_wallet: None,
+            seller: None,
+        };
+        let err = ledger.process_transaction(&tx2).unwrap_err();
+        assert!(matches!(err, LedgerError::DuplicateTxId(1)));
+    }
+
+    #[test]
+    fn test_that_invalid_amount_string_is_rejected() {
+        let mut ledger = Ledger::new();
+
+        let tx = Transaction {
+            id: 1,
+            account_id: 1,
+            typ: TransactionType::Deposit,
+            amount: Some(String::from("not_parsable")),
+            timestamp: None,
+            wallet: None,
+            to_wallet: None,
+            seller: None,
+        };
+        let err = ledger.process_transaction(&tx).unwrap_err();
+        assert!(matches!(err, LedgerError::Amount(_)));
+    }
+
+    #[test]
+    fn test_that_overflow_in_total_removes_account_from_snapshots() {
+        let mut ledger = Ledger::new();
+
+        let acc = ledger.accounts.entry(1).or_insert_with(|| Account::new(1));
+
+        // Force near-overflow values manually
+        acc.amount_available = Amount::from_str("922337203685477.5807").unwrap();
+        acc.amount_held = Amount::from_str("1.0").unwrap();
+
+        // This should overflow available + held and thus be filtered out
+        let snapshots: Vec<_> = ledger.account_snapshots().collect();
+        assert!(snapshots.is_empty());
+    }
+
+    #[test]
+    fn test_that_negative_deposit_amount_is_rejected() {
+        let mut ledger = Ledger::new();
+
+        let tx = Transaction {
+            id: 1,
+            account_id: 1,
+            typ: TransactionType::Deposit,
+            amount: Some(String::from("-1.0")),
+            timestamp: None,
+            wallet: None,
+            to_wallet: None,
+            seller: None,
+        };
+        let err = ledger.process_transaction(&tx).unwrap_err();
+        assert!(matches!(err, LedgerError::NegativeTxAmount(1)));
+    }
+
+    #[test]
+    fn test_that_negative_withdrawal_amount_is_rejected() {
+        let mut ledger = Ledger::new();
+
+        let tx = Transaction {
+            id: 1,
+            account_id: 1,
+            typ: TransactionType::Withdrawal,
+            amount: Some(String::from("-1.0")),
+            timestamp: None,
+            wallet: None,
+            to_wallet: None,
+            seller: None,
+        };
+        let err = ledger.process_transaction(&tx).unwrap_err();
+        assert!(matches!(err, LedgerError::NegativeTxAmount(1)));
+    }
+
+    #[test]
+    fn test_that_dispute_outside_elapsed_window_is_rejected() {
+        let mut ledger = Ledger::with_config(LedgerConfig {
+            dispute_window: DisputeWindow::Elapsed(3_600),
+            ..LedgerConfig::default()
+        });
+
+        let deposit = Transaction {
+            id: 1,
+            account_id: 1,
+            typ: TransactionType::Deposit,
+            amount: Some(String::from("10.0")),
+            timestamp: Some(1_000),
+            wallet: None,
+            to_wallet: None,
+            seller: None,
+        };
+        assert!(ledger.process_transaction(&deposit).is_ok());
+
+        // Dispute without timestamp inherits the last timestamp seen and is within the window
+        let other = Transaction {
+            id: 2,
+            account_id: 2,
+            typ: TransactionType::Deposit,
+            amount: Some(String::from("5.0")),
+            timestamp: Some(4_600),
+            wallet: None,
+            to_wallet: None,
+            seller: None,
+        };
+        assert!(ledger.process_transaction(&other).is_ok());
+        let dispute = Transaction {
+            id: 1,
+            account_id: 1,
+            typ: TransactionType::Dispute,
+            amount: None,
+            timestamp: None,
+            wallet: None,
+            to_wallet: None,
+            seller: None,
+        };
+        assert!(ledger.process_transaction(&dispute).is_ok());
+        let resolve = Transaction {
+            typ: TransactionType::Resolve,
+            ..dispute.clone()
+        };
+        assert!(ledger.process_transaction(&resolve).is_ok());
+
+        // One hour and one second later the deposit can't be disputed anymore
+        let late_dispute = Transaction {
+            timestamp: Some(4_601),
+            wallet: None,
+            to_wallet: None,
+            ..dispute
+        };
+        let err = ledger.process_transaction(&late_dispute).unwrap_err();
+        assert!(matches!(
+            err,
+            LedgerError::Account(AccountOperationError::DisputeWindowExpired(1))
+        ));
+    }
+
+    #[test]
+    fn test_that_expired_history_is_compacted_on_interval() {
+        let mut ledger = Ledger::with_config(LedgerConfig {
+            dispute_window: DisputeWindow::Records(1),
+            compaction_interval: Some(3),
+            ..LedgerConfig::default()
+        });
+
+        for id in 1..=3 {
+            let deposit = Transaction {
+                id,
+                account_id: 1,
+                typ: TransactionType::Deposit,
+                amount: Some(String::from("1.0")),
+                timestamp: None,
+                wallet: None,
+                to_wallet: None,
+                seller: None,
+            };
+            assert!(ledger.process_transaction(&deposit).is_ok());
+        }
+        // Deposit 1 is 2 records old when the third record triggers compaction
+        let dispute = Transaction {
+            id: 1,
+            account_id: 1,
+            typ: TransactionType::Dispute,
+            amount: None,
+            timestamp: None,
+            wallet: None,
+            to_wallet: None,
+            seller: None,
+        };
+        let err = ledger.process_transaction(&dispute).unwrap_err();
+        assert!(matches!(
+            err,
+            LedgerError::Account(AccountOperationError::DisputeWindowExpired(1))
+        ));
+        assert_eq!(
+            ledger.accounts.get(&1).unwrap().amount_available,
+            Amount::from_str("3.0").unwrap()
+        );
+    }
+
+    #[test]
+    fn test_that_evicted_transactions_can_be_disputed_from_history_store() {
+        let dir = std::env::temp_dir().join(format!("crab_cash_ledger_{}", std::process::id()));
+        let mut ledger = Ledger::with_config(LedgerConfig {
+            retention: RetentionPolicy {
+                keep_last: Some(1),
+                deposits_only: true,
+                ..RetentionPolicy::default()
+            },
+            ..LedgerConfig::default()
+        })
+        .with_history_store(HistoryStore::open(&dir).unwrap());
+
+        let transaction = |id, typ, amount: Option<&str>| Transaction {
+            id,
+            account_id: 1,
+            typ,
+            amount: amount.map(String::from),
+            timestamp: None,
+            wallet: None,
+            to_wallet: None,
+            seller: None,
+        };
+
+        for (id, amount) in [(1, "10.0"), (2, "20.0"), (3, "30.0")] {
+            let deposit = transaction(id, TransactionType::Deposit, Some(amount));
+            assert!(ledger.process_transaction(&deposit).is_ok());
+        }
+        let withdrawal = transaction(4, TransactionType::Withdrawal, Some("5.0"));
+        assert!(ledger.process_transaction(&withdrawal).is_ok());
+
+        // Only the last deposit is kept in memory, the withdrawal is not kept at all
+        assert_eq!(ledger.accounts.get(&1).unwrap().history_len(), 1);
+
+        // The first deposit is disputed from disk and stays in memory while disputed
+        let dispute = transaction(1, TransactionType::Dispute, None);
+        assert!(ledger.process_transaction(&dispute).is_ok());
+        let account = ledger.accounts.get(&1).unwrap();
+        assert_eq!(account.history_len(), 1);
+        assert_eq!(account.amount_held, Amount::from_str("10.0").unwrap());
+        assert_eq!(account.amount_available, Amount::from_str("45.0").unwrap());
+
+        let chargeback = transaction(1, TransactionType::Chargeback, None);
+        assert!(ledger.process_transaction(&chargeback).is_ok());
+        let account = ledger.accounts.get(&1).unwrap();
+        assert_eq!(account.amount_held, Amount::new());
+        assert!(account.is_locked);
+
+        // Withdrawals are never found as they are not spilled
+        let dispute = transaction(4, TransactionType::Dispute, None);
+        assert!(ledger.process_transaction(&dispute).is_err());
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_that_spilled_transactions_keep_their_status() {
+        let dir = std::env::temp_dir().join(format!("crab_cash_status_{}", std::process::id()));
+        let mut ledger = Ledger::with_config(LedgerConfig {
+            retention: RetentionPolicy {
+                keep_last: Some(1),
+                ..RetentionPolicy::default()
+            },
+            ..LedgerConfig::default()
+        })
+        .with_history_store(HistoryStore::open(&dir).unwrap());
+
+        let transaction = |id, typ, amount: Option<&str>| Transaction {
+            id,
+            account_id: 1,
+            typ,
+            amount: amount.map(String::from),
+            timestamp: None,
+            wallet: None,
+            to_wallet: None,
+            seller: None,
+        };
+
+        ledger
+            .process_transaction(&transaction(1, TransactionType::Deposit, Some("10.0")))
+            .unwrap();
+        ledger
+            .process_transaction(&transaction(1, TransactionType::Reversal, None))
+            .unwrap();
+        // The reversed deposit is evicted to disk
+        ledger
+            .process_transaction(&transaction(2, TransactionType::Deposit, Some("20.0")))
+            .unwrap();
+        assert_eq!(ledger.accounts.get(&1).unwrap().history_len(), 1);
+
+        // Loaded back as reversed, it can't be disputed
+        let err = ledger
+            .process_transaction(&transaction(1, TransactionType::Dispute, None))
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            LedgerError::Account(AccountOperationError::TxReversed(1))
+        ));
+        let account = ledger.accounts.get(&1).unwrap();
+        assert_eq!(account.amount_available, Amount::from_str("20.0").unwrap());
+        assert_eq!(account.amount_held, Amount::new());
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_that_rolled_back_batch_does_not_trip_blocking_detector() {
+        let detectors = Detectors::new()
+            .with_detector(RepeatedDisputes::new(DisputeWindow::Unlimited, 2), true);
+        let mut ledger = Ledger::new().with_detectors(detectors);
+        let transaction = |id, typ, amount: Option<&str>| Transaction {
+            id,
+            account_id: 1,
+            typ,
+            amount: amount.map(String::from),
+            timestamp: None,
+            wallet: None,
+            to_wallet: None,
+            seller: None,
+        };
+        for id in [1, 2] {
+            ledger
+                .process_transaction(&transaction(id, TransactionType::Deposit, Some("10")))
+                .unwrap();
+        }
+
+        // The dispute of the failed batch is rolled back, the detector must forget it
+        let err = ledger
+            .process_batch(&[
+                transaction(1, TransactionType::Dispute, None),
+                transaction(3, TransactionType::Withdrawal, Some("100")),
+            ])
+            .unwrap_err();
+        assert!(matches!(err, BatchError::Rejected { index: 1, .. }));
+
+        ledger
+            .process_transaction(&transaction(2, TransactionType::Dispute, None))
+            .unwrap();
+        let err = ledger
+            .process_transaction(&transaction(1, TransactionType::Dispute, None))
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            LedgerError::AnomalyFlagged { rule, tx: 1 } if rule == "repeated_disputes"
+        ));
+    }
+
+    #[test]
+    fn test_that_duplicate_tx_id_is_rejected_with_tx_id_filter() {
+        let dir = std::env::temp_dir().join(format!("crab_cash_dedup_{}", std::process::id()));
+        let mut ledger = Ledger::new().with_tx_id_filter(TxIdFilter::open(&dir, 64).unwrap());
+
+        for id in 0..100 {
+            let deposit = Transaction {
+                id,
+                account_id: 1,
+                typ: TransactionType::Deposit,
+                amount: Some(String::from("1.0")),
+                timestamp: None,
+                wallet: None,
+                to_wallet: None,
+                seller: None,
+            };
+            assert!(ledger.process_transaction(&deposit).is_ok());
+        }
+
+        let duplicate = Transaction {
+            id: 42,
+            account_id: 2,
+            typ: TransactionType::Withdrawal,
+            amount: Some(String::from("1.0")),
+            timestamp: None,
+            wallet: None,
+            to_wallet: None,
+            seller: None,
+        };
+        let err = ledger.process_transaction(&duplicate).unwrap_err();
+        assert!(matches!(err, LedgerError::DuplicateTxId(42)));
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_that_client_history_and_lifecycle_can_be_queried() {
+        let mut ledger = Ledger::new();
+        let transaction = |id, account_id, typ, amount: Option<&str>| Transaction {
+            id,
+            account_id,
+            typ,
+            amount: amount.map(String::from),
+            timestamp: None,
+            wallet: None,
+            to_wallet: None,
+            seller: None,
+        };
+
+        let records = [
+            transaction(3, 1, TransactionType::Deposit, Some("10.0")),
+            transaction(1, 1, TransactionType::Deposit, Some("5.0")),
+            transaction(2, 2, TransactionType::Deposit, Some("1.0")),
+            transaction(7, 1, TransactionType::Withdrawal, Some("2.0")),
+            transaction(3, 1, TransactionType::Dispute, None),
+            transaction(3, 1, TransactionType::Resolve, None),
+            transaction(1, 1, TransactionType::Dispute, None),
+        ];
+        for record in &records {
+            assert!(ledger.process_transaction(record).is_ok());
+        }
+
+        let history = ledger.client_history(1).unwrap();
+        let ids: Vec<u32> = history.iter().map(|e| e.tx).collect();
+        assert_eq!(ids, vec![3, 1, 7]);
+        assert_eq!(history[0].status, TxStatus::Resolved);
+        assert_eq!(history[1].status, TxStatus::Disputed);
+        assert_eq!(history[1].amount, "5.0000");
+        assert_eq!(history[2].typ, TxKind::Withdrawal);
+        assert_eq!(history[2].status, TxStatus::Applied);
+        assert!(ledger.client_history(3).is_none());
+
+        let lifecycle = ledger.transaction_lifecycle(3).unwrap();
+        assert_eq!(lifecycle.entry.client, 1);
+        let events: Vec<(u64, TxEvent)> = lifecycle
+            .events
+            .iter()
+            .map(|e| (e.sequence, e.event))
+            .collect();
+        assert_eq!(
+            events,
+            vec![
+                (1, TxEvent::Applied),
+                (5, TxEvent::Disputed),
+                (6, TxEvent::Resolved)
+            ]
+        );
+        assert!(ledger.transaction_lifecycle(99).is_none());
+    }
+
+    #[test]
+    fn test_that_statement_has_running_balances_over_period() {
+        let mut ledger = Ledger::new();
+        let transaction = |id, typ, amount: Option<&str>, timestamp| Transaction {
+            id,
+            account_id: 1,
+            typ,
+            amount: amount.map(String::from),
+            timestamp: Some(timestamp),
+            wallet: None,
+            to_wallet: None,
+            seller: None,
+        };
+
+        let records = [
+            transaction(1, TransactionType::Deposit, Some("10.0"), 100),
+            transaction(2, TransactionType::Deposit, Some("5.0"), 200),
+            transaction(3, TransactionType::Withdrawal, Some("20.0"), 210), // Rejected
+            transaction(2, TransactionType::Dispute, None, 300),
+            transaction(4, TransactionType::Withdrawal, Some("1.5"), 400),
+            transaction(2, TransactionType::Chargeback, None, 500),
+        ];
+        for record in &records {
+            let _ = ledger.process_transaction(record);
+        }
+
+        let period = StatementPeriod {
+            from: Some(200),
+            to: Some(500),
+        };
+        let statement = ledger.statement(1, period).unwrap().unwrap();
+        assert_eq!(statement.opening.total, "10.0000");
+
+        let lines: Vec<(u32, OperationKind, &str, &str, &str)> = statement
+            .lines
+            .iter()
+            .map(|l| {
+                (
+                    l.tx,
+                    l.operation,
+                    l.amount.as_str(),
+                    l.available.as_str(),
+                    l.held.as_str(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            lines,
+            vec![
+                (2, OperationKind::Deposit, "5.0000", "15.0000", "0.0000"),
+                (2, OperationKind::Dispute, "5.0000", "10.0000", "5.0000"),
+                (4, OperationKind::Withdrawal, "1.5000", "8.5000", "5.0000"),
+            ]
+        );
+        assert_eq!(statement.closing.total, "13.5000");
+
+        // The unbounded statement also contains the chargeback
+        let statement = ledger
+            .statement(1, StatementPeriod::default())
+            .unwrap()
+            .unwrap();
+        assert_eq!(statement.opening.total, "0.0000");
+        assert_eq!(statement.lines.len(), 5);
+        assert_eq!(statement.closing.total, "8.5000");
+
+        assert!(ledger.statement(2, period).is_none());
+    }
+
+    #[test]
+    fn test_that_limited_operation_log_keeps_statements_after_dropped_operations() {
+        let mut ledger = Ledger::with_config(LedgerConfig {
+            retention: RetentionPolicy {
+                operation_log_limit: Some(2),
+                ..RetentionPolicy::default()
+            },
+            ..LedgerConfig::default()
+        });
+        let transaction = |id, account_id, amount: &str, timestamp| Transaction {
+            id,
+            account_id,
+            typ: TransactionType::Deposit,
+            amount: Some(amount.to_string()),
+            timestamp: Some(timestamp),
+            wallet: None,
+            to_wallet: None,
+            seller: None,
+        };
+        for record in [
+            transaction(1, 1, "10", 100),
+            transaction(2, 1, "5", 200),
+            transaction(3, 2, "7", 300),
+        ] {
+            ledger.process_transaction(&record).unwrap();
+        }
+        assert_eq!(ledger.operations.iter().count(), 2);
+
+        // The dropped deposit gives the opening balance of the later periods
+        let after = StatementPeriod {
+            from: Some(150),
+            to: None,
+        };
+        let statement = ledger.statement(1, after).unwrap().unwrap();
+        assert_eq!(statement.opening.total, "10.0000");
+        assert_eq!(statement.lines.len(), 1);
+        assert_eq!(statement.closing.total, "15.0000");
+        assert_eq!(ledger.statements(after).unwrap().len(), 2);
+
+        // Periods covering the dropped deposit can't be built
+        let err = ledger
+            .statement(1, StatementPeriod::default())
+            .unwrap()
+            .unwrap_err();
+        assert!(matches!(err, LedgerError::OperationLogTruncated(1)));
+        let err = ledger.aml_report(&AmlThresholds::default()).unwrap_err();
+        assert!(matches!(err, LedgerError::OperationLogIncomplete));
+    }
+
+    #[test]
+    fn test_that_journal_stays_balanced_and_matches_accounts() {
+        let mut ledger = Ledger::new();
+        let transaction = |id, account_id, typ, amount: Option<&str>| Transaction {
+            id,
+            account_id,
+            typ,
+            amount: amount.map(String::from),
+            timestamp: None,
+            wallet: None,
+            to_wallet: None,
+            seller: None,
+        };
+
+        let records = [
+            transaction(1, 1, TransactionType::Deposit, Some("100.1234")),
+            transaction(2, 1, TransactionType::Withdrawal, Some("100.1234")),
+            transaction(1, 1, TransactionType::Dispute, None),
+            transaction(1, 1, TransactionType::Chargeback, None),
+            transaction(3, 2, TransactionType::Deposit, Some("5.0")),
+            transaction(3, 2, TransactionType::Dispute, None),
+            transaction(4, 2, TransactionType::Withdrawal, Some("1.0")), // Rejected
+            transaction(3, 2, TransactionType::Resolve, None),
+        ];
+        for record in &records {
+            let _ = ledger.process_transaction(record);
+        }
+
+        assert!(ledger.verify_journal().is_ok());
+        let trial_balance = ledger.trial_balance().unwrap();
+        assert!(trial_balance.is_balanced);
+
+        let balances: Vec<(&str, &str)> = trial_balance
+            .rows
+            .iter()
+            .map(|r| (r.account.as_str(), r.balance.as_str()))
+            .collect();
+        assert_eq!(
+            balances,
+            vec![
+                ("customer:1:available", "100.1234"),
+                ("customer:1:held", "0.0000"),
+                ("customer:2:available", "-5.0000"),
+                ("customer:2:held", "0.0000"),
+                ("settlement", "5.0000"),
+                ("chargeback_loss", "-100.1234"),
+            ]
+        );
+
+        // Tampering with an account is detected
+        ledger.accounts.get_mut(&2).unwrap().amount_available = Amount::new();
+        let err = ledger.verify_journal().unwrap_err();
+        assert!(matches!(err, LedgerError::JournalMismatch(2)));
+    }
+
+    #[test]
+    fn test_that_fees_are_charged_posted_and_refunded() {
+        let fees = FeeSchedule::from_toml_str(
+            r#"
+            refund_on_chargeback = true
+
+            [[rules]]
+            type = "deposit"
+            flat = "1"
+
+            [[rules]]
+            type = "withdrawal"
+            percentage = "10"
+            "#,
+        )
+        .unwrap();
+        let mut ledger = Ledger::with_config(LedgerConfig {
+            fees,
+            ..LedgerConfig::default()
+        });
+        let transaction = |id, typ, amount: Option<&str>| Transaction {
+            id,
+            account_id: 1,
+            typ,
+            amount: amount.map(String::from),
+            timestamp: None,
+            wallet: None,
+            to_wallet: None,
+            seller: None,
+        };
+
+        ledger
+            .process_transaction(&transaction(1, TransactionType::Deposit, Some("50")))
+            .unwrap();
+        ledger
+            .process_transaction(&transaction(2, TransactionType::Deposit, Some("20")))
+            .unwrap();
+        // 17 + 1.7 of fee is more than the 18 available once the first deposit is disputed
+        ledger
+            .process_transaction(&transaction(1, TransactionType::Dispute, None))
+            .unwrap();
+        let err = ledger
+            .process_transaction(&transaction(3, TransactionType::Withdrawal, Some("17")))
+            .unwrap_err();
+        assert!(matches!(err, LedgerError::Account(_)));
+        ledger
+            .process_transaction(&transaction(1, TransactionType::Chargeback, None))
+            .unwrap();
+
+        let account = &ledger.accounts[&1];
+        // 70 deposited - 2 of fees - 50 charged back + 1 of fee refunded
+        assert_eq!(account.amount_available, Amount::from_str("19").unwrap());
+        assert_eq!(account.amount_held, Amount::new());
+
+        let kinds: Vec<String> = ledger
+            .operations
+            .iter()
+            .map(|op| op.kind.to_string())
+            .collect();
+        assert_eq!(
+            kinds,
+            vec![
+                "deposit",
+                "fee",
+                "deposit",
+                "fee",
+                "dispute",
+                "chargeback",
+                "fee_refund"
+            ]
+        );
+
+        assert!(ledger.verify_journal().is_ok());
+        let trial_balance = ledger.trial_balance().unwrap();
+        assert!(trial_balance.is_balanced);
+        let house = trial_balance
+            .rows
+            .iter()
+            .find(|r| r.account == "house:fees")
+            .unwrap();
+        assert_eq!(house.balance, "-1.0000");
+    }
+
+    #[test]
+    fn test_that_interest_is_accrued_on_available_balance() {
+        let transaction = |id, typ, amount: Option<&str>, timestamp| Transaction {
+            id,
+            account_id: 1,
+            typ,
+            amount: amount.map(String::from),
+            timestamp,
+            wallet: None,
+            to_wallet: None,
+            seller: None,
+        };
+        const DAY: u64 = 86_400;
+
+        // Interest must be configured
+        let mut ledger = Ledger::new();
+        let err = ledger
+            .process_transaction(&transaction(
+                1,
+                TransactionType::AccrueInterest,
+                None,
+                Some(0),
+            ))
+            .unwrap_err();
+        assert!(matches!(err, LedgerError::InterestDisabled(1)));
+
+        let mut ledger = Ledger::with_config(LedgerConfig {
+            interest: Some(InterestPolicy {
+                annual_rate: Amount::from_str("36.5").unwrap(),
+                day_count: DayCount::Actual365,
+            }),
+            ..LedgerConfig::default()
+        });
+        let err = ledger
+            .process_transaction(&transaction(1, TransactionType::AccrueInterest, None, None))
+            .unwrap_err();
+        assert!(matches!(err, LedgerError::MissingTimestamp(1)));
+
+        let records = [
+            transaction(2, TransactionType::Deposit, Some("100"), Some(0)),
+            transaction(3, TransactionType::AccrueInterest, None, Some(DAY)), // 0.1
+            transaction(4, TransactionType::Withdrawal, Some("50"), Some(DAY)),
+            transaction(5, TransactionType::AccrueInterest, None, Some(3 * DAY)), // 0.1002
+            transaction(6, TransactionType::AccrueInterest, None, Some(3 * DAY)), // Nothing due
+        ];
+        for record in &records {
+            ledger.process_transaction(record).unwrap();
+        }
+
+        let account = &ledger.accounts[&1];
+        assert_eq!(
+            account.amount_available,
+            Amount::from_str("50.2002").unwrap()
+        );
+        assert_eq!(account.tx_amount(3), Amount::from_str("0.1").unwrap());
+        assert!(!account.knows_tx(6));
+
+        let kinds: Vec<String> = ledger
+            .operations
+            .iter()
+            .map(|op| op.kind.to_string())
+            .collect();
+        assert_eq!(kinds, vec!["deposit", "interest", "withdrawal", "interest"]);
+
+        assert!(ledger.verify_journal().is_ok());
+        let trial_balance = ledger.trial_balance().unwrap();
+        let house = trial_balance
+            .rows
+            .iter()
+            .find(|r| r.account == "house:interest")
+            .unwrap();
+        assert_eq!(house.balance, "0.2002");
+    }
+
+    #[test]
+    fn test_that_failing_batch_is_rolled_back() {
+        let mut ledger = Ledger::new();
+        let transaction = |id, account_id, typ, amount: Option<&str>| Transaction {
+            id,
+            account_id,
+            typ,
+            amount: amount.map(String::from),
+            timestamp: None,
+            wallet: None,
+            to_wallet: None,
+            seller: None,
+        };
+
+        ledger
+            .process_transaction(&transaction(1, 1, TransactionType::Deposit, Some("10")))
+            .unwrap();
+
+        // Moving 15 from client 1 to client 2 fails on the withdrawal leg
+        let transfer = [
+            transaction(2, 2, TransactionType::Deposit, Some("15")),
+            transaction(3, 1, TransactionType::Withdrawal, Some("15")),
+        ];
+        let err = ledger.process_batch(&transfer).unwrap_err();
+        assert!(matches!(
+            err,
+            BatchError::Rejected {
+                index: 1,
+                tx: 3,
+                ..
+            }
+        ));
+
+        assert!(!ledger.accounts.contains_key(&2));
+        assert_eq!(
+            ledger.accounts[&1].amount_available,
+            Amount::from_str("10").unwrap()
+        );
+        assert_eq!(ledger.operations.len(), 1);
+        assert!(ledger.verify_journal().is_ok());
+        let accounts: Vec<String> = ledger
+            .trial_balance()
+            .unwrap()
+            .rows
+            .into_iter()
+            .map(|r| r.account)
+            .collect();
+        assert_eq!(accounts, vec!["customer:1:available", "settlement"]);
+
+        // Tx ids of a rolled back batch can be used again
+        let transfer = [
+            transaction(2, 2, TransactionType::Deposit, Some("5")),
+            transaction(3, 1, TransactionType::Withdrawal, Some("5")),
+        ];
+        ledger.process_batch(&transfer).unwrap();
+        assert_eq!(
+            ledger.accounts[&1].amount_available,
+            Amount::from_str("5").unwrap()
+        );
+        assert_eq!(
+            ledger.accounts[&2].amount_available,
+            Amount::from_str("5").unwrap()
+        );
+
+        // A tx id reused inside a batch is a duplicate
+        let err = ledger
+            .process_batch(&[
+                transaction(4, 2, TransactionType::Deposit, Some("1")),
+                transaction(4, 2, TransactionType::Deposit, Some("1")),
+            ])
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            BatchError::Rejected {
+                index: 1,
+                tx: 4,
+                ..
+            }
+        ));
+        assert!(!ledger.is_processed(4).unwrap());
+        assert!(ledger.is_processed(3).unwrap());
+    }
+
+    fn idempotent(id: u32, typ: TransactionType, amount: &str) -> Transaction {
+        Transaction {
+            id,
+            account_id: 1,
+            typ,
+            amount: Some(amount.to_string()),
+            timestamp: None,
+            wallet: None,
+            to_wallet: None,
+            seller: None,
+        }
+    }
+
+    #[test]
+    fn test_that_idempotent_retries_return_the_original_outcome() {
+        let mut ledger = Ledger::new();
+
+        let deposit = idempotent(1, TransactionType::Deposit, "10");
+        assert_eq!(
+            ledger.process_idempotent("a", &deposit).unwrap(),
+            IdempotentResult::Applied
+        );
+        // Same request, with the amount written differently
+        assert_eq!(
+            ledger
+                .process_idempotent("a", &idempotent(1, TransactionType::Deposit, "10.00"))
+                .unwrap(),
+            IdempotentResult::Retried(IdempotentOutcome::Applied)
+        );
+        assert_eq!(
+            ledger.accounts[&1].amount_available,
+            Amount::from_str("10").unwrap()
+        );
+    }
+
+    #[test]
+    fn test_that_rejected_idempotent_request_stays_rejected() {
+        let mut ledger = Ledger::new();
+
+        let withdrawal = idempotent(2, TransactionType::Withdrawal, "15");
+        assert!(ledger.process_idempotent("b", &withdrawal).is_err());
+        // Even once it could be applied
+        ledger
+            .process_transaction(&idempotent(3, TransactionType::Deposit, "20"))
+            .unwrap();
+        assert!(matches!(
+            ledger.process_idempotent("b", &withdrawal).unwrap(),
+            IdempotentResult::Retried(IdempotentOutcome::Rejected(_))
+        ));
+        assert_eq!(
+            ledger.accounts[&1].amount_available,
+            Amount::from_str("20").unwrap()
+        );
+    }
+
+    #[test]
+    fn test_that_other_request_under_a_used_key_is_a_conflict() {
+        let mut ledger = Ledger::new();
+        let deposit = idempotent(1, TransactionType::Deposit, "10");
+        ledger.process_idempotent("a", &deposit).unwrap();
+
+        for other in [
+            idempotent(1, TransactionType::Deposit, "11"),
+            idempotent(2, TransactionType::Deposit, "10"),
+            idempotent(1, TransactionType::Withdrawal, "10"),
+        ] {
+            let err = ledger.process_idempotent("a", &other).unwrap_err();
+            assert!(matches!(err, LedgerError::IdempotencyConflict(key) if key == "a"));
+        }
+        // Neither applied nor recorded in place of the first request
+        assert_eq!(
+            ledger.accounts[&1].amount_available,
+            Amount::from_str("10").unwrap()
+        );
+        assert!(!ledger.is_processed(2).unwrap());
+        assert_eq!(
+            ledger.process_idempotent("a", &deposit).unwrap(),
+            IdempotentResult::Retried(IdempotentOutcome::Applied)
+        );
+    }
+
+    #[test]
+    fn test_that_rejected_records_do_not_open_accounts() {
+        let transaction = |id, account_id, typ, amount: Option<&str>| Transaction {
+            id,
+            account_id,
+            typ,
+            amount: amount.map(String::from),
+            timestamp: None,
+            wallet: None,
+            to_wallet: None,
+            seller: None,
+        };
+        let records = [
+            transaction(1, 1, TransactionType::Deposit, None), // Missing amount
+            transaction(2, 2, TransactionType::Dispute, None),
+            transaction(3, 3, TransactionType::Deposit, Some("5")),
+            transaction(4, 4, TransactionType::Deposit, Some("5")),
+        ];
+        let clients = |policy| {
+            let mut ledger = Ledger::with_config(LedgerConfig {
+                account_creation: policy,
+                ..LedgerConfig::default()
+            });
+            let errors: Vec<LedgerError> = records
+                .iter()
+                .filter_map(|r| ledger.process_transaction(r).err())
+                .collect();
+            let mut clients: Vec<u16> = ledger.accounts.keys().copied().collect();
+            clients.sort();
+            (clients, errors)
+        };
+
+        let (accounts, _) = clients(AccountCreation::AnyRecord);
+        assert_eq!(accounts, vec![1, 2, 3, 4]);
+
+        let (accounts, errors) = clients(AccountCreation::FirstDeposit);
+        assert_eq!(accounts, vec![3, 4]);
+        assert!(matches!(errors[1], LedgerError::NoAccount(2)));
+
+        let (accounts, errors) = clients(AccountCreation::Registered(HashSet::from([1, 3])));
+        assert_eq!(accounts, vec![3]);
+        assert!(matches!(errors[0], LedgerError::MissingAmount(1)));
+        assert!(matches!(errors[1], LedgerError::UnregisteredClient(2)));
+        assert!(matches!(errors[2], LedgerError::UnregisteredClient(4)));
+    }
+
+    #[test]
+    fn test_that_registry_rules_apply_to_registered_clients() {
+        let registry = ClientRegistry::from_csv(
+            "client,name,country,kyc_tier,status,opened_on\n\
+             1,Alice,FR,unverified,active,2024-01-31\n\
+             2,Bob,GB,full,closed,2023-06-01\n"
+                .as_bytes(),
+        )
+        .unwrap();
+        let mut ledger = Ledger::with_config(LedgerConfig {
+            kyc_limits: KycLimits::from_toml_str("[unverified]\nmax_deposit = \"100\"\n").unwrap(),
+            ..LedgerConfig::default()
+        })
+        .with_registry(registry.clone());
+        let deposit = |id, account_id, amount: &str| Transaction {
+            id,
+            account_id,
+            typ: TransactionType::Deposit,
+            amount: Some(amount.to_string()),
+            timestamp: None,
+            wallet: None,
+            to_wallet: None,
+            seller: None,
+        };
+
+        let err = ledger
+            .process_transaction(&deposit(1, 1, "100.0001"))
+            .unwrap_err();
+        assert!(matches!(err, LedgerError::KycLimitExceeded(1)));
+        ledger.process_transaction(&deposit(2, 1, "100")).unwrap();
+
+        let err = ledger.process_transaction(&deposit(3, 2, "1")).unwrap_err();
+        assert!(matches!(err, LedgerError::ClientNotActive(2)));
+        assert!(!ledger.accounts.contains_key(&2));
+
+        // Unregistered clients have no limits
+        ledger.process_transaction(&deposit(4, 3, "1000")).unwrap();
+
+        let statement = ledger
+            .statement(1, StatementPeriod::default())
+            .unwrap()
+            .unwrap();
+        assert_eq!(statement.holder.unwrap().name, "Alice");
+
+        let mut snapshots: Vec<(AccountSnapshot, RegistryColumns)> =
+            ledger.registered_snapshots().collect();
+        snapshots.sort_by(|a, b| a.0.client.cmp(&b.0.client));
+        assert_eq!(snapshots[0].1.kyc_tier, Some(KycTier::Unverified));
+        assert_eq!(snapshots[1].1, RegistryColumns::default());
+
+        // The registry lists the clients allowed to open an account
+        let mut ledger = Ledger::with_config(LedgerConfig {
+            account_creation: AccountCreation::Registered(HashSet::from([3])),
+            ..LedgerConfig::default()
+        })
+        .with_registry(registry);
+        ledger.process_transaction(&deposit(1, 1, "1")).unwrap();
+        let err = ledger.process_transaction(&deposit(2, 3, "1")).unwrap_err();
+        assert!(matches!(err, LedgerError::UnregisteredClient(3)));
+    }
+
+    #[test]
+    fn test_that_wallets_keep_their_own_funds_and_disputes() {
+        let transaction =
+            |id, typ, amount: Option<&str>, wallet: &str, to_wallet: Option<&str>| Transaction {
+                id,
+                account_id: 1,
+                typ,
+                amount: amount.map(String::from),
+                timestamp: None,
+                wallet: Some(wallet.to_string()),
+                to_wallet: to_wallet.map(String::from),
+                seller: None,
+            };
+        let process = |scope| {
+            let mut ledger = Ledger::with_config(LedgerConfig {
+                chargeback_lock: scope,
+                ..LedgerConfig::default()
+            });
+            let deposit = TransactionType::Deposit;
+            let transfer = TransactionType::Transfer;
+            ledger
+                .process_transaction(&transaction(1, deposit, Some("10"), "", None))
+                .unwrap();
+            ledger
+                .process_transaction(&transaction(2, deposit, Some("5"), "savings", None))
+                .unwrap();
+            ledger
+                .process_transaction(&transaction(
+                    3,
+                    transfer,
+                    Some("4"),
+                    "main",
+                    Some("savings"),
+                ))
+                .unwrap();
+            let err = ledger
+                .process_transaction(&transaction(4, transfer, Some("7"), "main", Some("escrow")))
+                .unwrap_err();
+            assert!(matches!(
+                err,
+                LedgerError::Account(AccountOperationError::WithdrawalLimitExceeded(4))
+            ));
+            let err = ledger
+                .process_transaction(&transaction(5, transfer, Some("1"), "main", Some(" ")))
+                .unwrap_err();
+            assert!(matches!(err, LedgerError::InvalidTransfer(5)));
+
+            // Disputes follow the deposit to its wallet, whatever the wallet of the record
+            for typ in [TransactionType::Dispute, TransactionType::Chargeback] {
+                ledger
+                    .process_transaction(&transaction(2, typ, None, "", None))
+                    .unwrap();
+            }
+            ledger
+        };
+
+        let ledger = process(LockScope::Client);
+        let wallets: Vec<WalletSnapshot> = ledger.wallet_snapshots().collect();
+        assert_eq!(wallets.len(), 2);
+        assert_eq!(
+            (wallets[0].wallet.as_str(), wallets[0].available.as_str()),
+            ("main", "6.0000")
+        );
+        assert_eq!(
+            (wallets[1].wallet.as_str(), wallets[1].available.as_str()),
+            ("savings", "4.0000")
+        );
+        assert!(wallets.iter().all(|w| w.locked));
+        let snapshot = ledger.account_snapshots().next().unwrap();
+        assert_eq!(
+            (snapshot.available.as_str(), snapshot.total.as_str()),
+            ("10.0000", "10.0000")
+        );
+        assert!(snapshot.locked);
+        ledger.verify_journal().unwrap();
+
+        // Only the wallet of the charged back deposit is locked, and it takes no transfers
+        let mut ledger = process(LockScope::Wallet);
+        let wallets: Vec<WalletSnapshot> = ledger.wallet_snapshots().collect();
+        assert!(!wallets[0].locked && wallets[1].locked);
+        let err = ledger
+            .process_transaction(&transaction(
+                6,
+                TransactionType::Transfer,
+                Some("1"),
+                "main",
+                Some("savings"),
+            ))
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            LedgerError::Account(AccountOperationError::AccountLocked(6))
+        ));
+        ledger
+            .process_transaction(&transaction(
+                7,
+                TransactionType::Withdrawal,
+                Some("6"),
+                "main",
+                None,
+            ))
+            .unwrap();
+        ledger.verify_journal().unwrap();
+    }
+
+    // Ledger locking the `scope` of a chargeback, after deposits of 10 to the main wallet and
+    // 5 to the savings wallet of client 1, the second one being charged back
+    fn ledger_with_charged_back_wallet(scope: LockScope) -> Ledger {
+        let mut ledger = Ledger::with_config(LedgerConfig {
+            chargeback_lock: scope,
+            ..LedgerConfig::default()
+        });
+        for (id, typ, amount, wallet) in [
+            (1, TransactionType::Deposit, Some("10"), "main"),
+            (2, TransactionType::Deposit, Some("5"), "savings"),
+            (2, TransactionType::Dispute, None, "main"),
+            (2, TransactionType::Chargeback, None, "main"),
+        ] {
+            ledger
+                .process_transaction(&in_wallet(id, typ, amount, wallet))
+                .unwrap();
+        }
+        ledger
+    }
+
+    fn in_wallet(id: u32, typ: TransactionType, amount: Option<&str>, wallet: &str) -> Transaction {
+        Transaction {
+            id,
+            account_id: 1,
+            typ,
+            amount: amount.map(String::from),
+            timestamp: None,
+            wallet: Some(wallet.to_string()),
+            to_wallet: None,
+            seller: None,
+        }
+    }
+
+    #[test]
+    fn test_that_wallet_lock_scope_only_locks_the_charged_back_wallet() {
+        let mut ledger = ledger_with_charged_back_wallet(LockScope::Wallet);
+
+        let locked: Vec<(String, bool)> = ledger
+            .wallet_snapshots()
+            .map(|w| (w.wallet, w.locked))
+            .collect();
+        assert_eq!(
+            locked,
+            vec![("main".to_string(), false), ("savings".to_string(), true)]
+        );
+        // The client is shown locked as one of its wallets is
+        assert!(ledger.client_snapshot(1).unwrap().locked);
+
+        ledger
+            .process_transaction(&in_wallet(3, TransactionType::Deposit, Some("1"), "main"))
+            .unwrap();
+        ledger
+            .process_transaction(&in_wallet(4, TransactionType::Deposit, Some("1"), "other"))
+            .unwrap();
+        let err = ledger
+            .process_transaction(&in_wallet(
+                5,
+                TransactionType::Deposit,
+                Some("1"),
+                "savings",
+            ))
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            LedgerError::Account(AccountOperationError::AccountLocked(5))
+        ));
+        assert_eq!(ledger.client_snapshot(1).unwrap().available, "12.0000");
+    }
+
+    #[test]
+    fn test_that_client_lock_scope_locks_every_wallet_even_new_ones() {
+        let mut ledger = ledger_with_charged_back_wallet(LockScope::Client);
+
+        assert!(ledger.wallet_snapshots().all(|w| w.locked));
+        for (id, wallet) in [(3, "main"), (4, "other")] {
+            let err = ledger
+                .process_transaction(&in_wallet(id, TransactionType::Deposit, Some("1"), wallet))
+                .unwrap_err();
+            assert!(matches!(
+                err,
+                LedgerError::Account(AccountOperationError::AccountLocked(_))
+            ));
+        }
+        assert_eq!(ledger.client_snapshot(1).unwrap().available, "10.0000");
+    }
+
+    #[test]
+    fn test_that_transfers_are_posted_to_both_wallets() {
+        let mut ledger = Ledger::new();
+        let transaction = |id, typ, amount: &str, to_wallet: Option<&str>| Transaction {
+            id,
+            account_id: 1,
+            typ,
+            amount: Some(amount.to_string()),
+            timestamp: None,
+            wallet: None,
+            to_wallet: to_wallet.map(String::from),
+            seller: None,
+        };
+        ledger
+            .process_transaction(&transaction(1, TransactionType::Deposit, "10", None))
+            .unwrap();
+        ledger
+            .process_transaction(&transaction(
+                2,
+                TransactionType::Transfer,
+                "4",
+                Some("savings"),
+            ))
+            .unwrap();
+
+        let trial_balance = ledger.trial_balance().unwrap();
+        assert!(trial_balance.is_balanced);
+        let balances: Vec<(&str, &str)> = trial_balance
+            .rows
+            .iter()
+            .map(|r| (r.account.as_str(), r.balance.as_str()))
+            .collect();
+        assert_eq!(
+            balances,
+            vec![
+                ("customer:1:available", "-6.0000"),
+                ("customer:1:savings:available", "-4.0000"),
+                ("settlement", "10.0000"),
+            ]
+        );
+        ledger.verify_journal().unwrap();
+
+        // Moving funds between the wallets of a client behind the journal is detected
+        ledger
+            .wallets
+            .get_mut(&1)
+            .unwrap()
+            .get_mut("savings")
+            .unwrap()
+            .amount_available = Amount::new();
+        ledger.accounts.get_mut(&1).unwrap().amount_available = Amount::from_str("10").unwrap();
+        let err = ledger.verify_journal().unwrap_err();
+        assert!(matches!(err, LedgerError::JournalMismatch(1)));
+    }
+
+    #[test]
+    fn test_that_escrow_is_released_to_the_seller_or_refunded() {
+        let mut ledger = Ledger::new();
+        let transaction = |id, account_id, typ, amount: Option<&str>, seller| Transaction {
+            id,
+            account_id,
+            typ,
+            amount: amount.map(String::from),
+            timestamp: None,
+            wallet: None,
+            to_wallet: None,
+            seller,
+        };
+        let snapshot = |ledger: &Ledger, client: &str| {
+            ledger
+                .account_snapshots()
+                .find(|s| s.client == client)
+                .unwrap()
+        };
+
+        ledger
+            .process_transaction(&transaction(
+                1,
+                1,
+                TransactionType::Deposit,
+                Some("100"),
+                None,
+            ))
+            .unwrap();
+        let err = ledger
+            .process_transaction(&transaction(
+                2,
+                1,
+                TransactionType::EscrowFund,
+                Some("60"),
+                Some(1),
+            ))
+            .unwrap_err();
+        assert!(matches!(err, LedgerError::InvalidEscrow(2)));
+        ledger
+            .process_transaction(&transaction(
+                2,
+                1,
+                TransactionType::EscrowFund,
+                Some("60"),
+                Some(2),
+            ))
+            .unwrap();
+        ledger
+            .process_transaction(&transaction(
+                2,
+                1,
+                TransactionType::EscrowRelease,
+                Some("20"),
+                None,
+            ))
+            .unwrap();
+
+        // Escrowed funds are reported apart from the dispute holds
+        let buyer = snapshot(&ledger, "1");
+        assert_eq!(buyer.available, "40.0000");
+        assert_eq!(buyer.held, "0.0000");
+        assert_eq!(buyer.escrow.as_deref(), Some("40.0000"));
+        assert_eq!(buyer.total, "80.0000");
+        assert_eq!(snapshot(&ledger, "2").available, "20.0000");
+        assert_eq!(snapshot(&ledger, "2").escrow.as_deref(), Some("0.0000"));
+
+        // A failing batch gives the release back to the escrow
+        let err = ledger
+            .process_batch(&[
+                transaction(2, 1, TransactionType::EscrowRelease, Some("30"), None),
+                transaction(3, 2, TransactionType::Withdrawal, Some("60"), None),
+            ])
+            .unwrap_err();
+        assert!(matches!(err, BatchError::Rejected { index: 1, .. }));
+        assert_eq!(snapshot(&ledger, "2").available, "20.0000");
+
+        let err = ledger
+            .process_transaction(&transaction(
+                2,
+                1,
+                TransactionType::EscrowRefund,
+                Some("41"),
+                None,
+            ))
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            LedgerError::Account(AccountOperationError::EscrowExceeded(2))
+        ));
+        ledger
+            .process_transaction(&transaction(
+                2,
+                1,
+                TransactionType::EscrowRefund,
+                None,
+                None,
+            ))
+            .unwrap();
+        let buyer = snapshot(&ledger, "1");
+        assert_eq!(
+            (buyer.available.as_str(), buyer.total.as_str()),
+            ("80.0000", "80.0000")
+        );
+
+        ledger.verify_journal().unwrap();
+        let trial_balance = ledger.trial_balance().unwrap();
+        assert!(trial_balance.is_balanced);
+        let clearing = trial_balance
+            .rows
+            .iter()
+            .find(|row| row.account == "escrow:clearing")
+            .unwrap();
+        assert_eq!(clearing.balance, "0.0000");
+
+        // Operations are named in snake case, like the records
+        let kinds: Vec<String> = ledger
+            .operations
+            .iter()
+            .map(|op| op.kind.to_string())
+            .collect();
+        assert_eq!(
+            kinds,
+            vec![
+                "deposit",
+                "escrow_fund",
+                "escrow_release",
+                "escrow_payout",
+                "escrow_refund"
+            ]
+        );
+        assert_eq!(
+            serde_json::to_string(&OperationKind::EscrowPayout).unwrap(),
+            "\"escrow_payout\""
+        );
+    }
+
+    fn stamped(
+        id: u32,
+        account_id: u16,
+        typ: TransactionType,
+        amount: Option<&str>,
+        timestamp: u64,
+    ) -> Transaction {
+        Transaction {
+            id,
+            account_id,
+            typ,
+            amount: amount.map(String::from),
+            timestamp: Some(timestamp),
+            wallet: None,
+            to_wallet: None,
+            seller: None,
+        }
+    }
+
+    // Ledger keeping a rollback log after a deposit of each client at 100 and 200 (the second
+    // one idempotent under the key `k`), a dispute and a chargeback of the first one at 300 and
+    // 400, then a batch rolled back at 500
+    fn ledger_with_rollback_log() -> Ledger {
+        let mut ledger = Ledger::with_config(LedgerConfig {
+            retention: RetentionPolicy {
+                rollback_log: true,
+                ..RetentionPolicy::default()
+            },
+            ..LedgerConfig::default()
+        });
+        ledger
+            .process_transaction(&stamped(1, 1, TransactionType::Deposit, Some("10"), 100))
+            .unwrap();
+        ledger
+            .process_idempotent(
+                "k",
+                &stamped(2, 2, TransactionType::Deposit, Some("5"), 200),
+            )
+            .unwrap();
+        ledger
+            .process_transaction(&stamped(1, 1, TransactionType::Dispute, None, 300))
+            .unwrap();
+        ledger
+            .process_transaction(&stamped(1, 1, TransactionType::Chargeback, None, 400))
+            .unwrap();
+        assert!(
+            ledger
+                .process_batch(&[stamped(3, 2, TransactionType::Withdrawal, Some("9"), 500)])
+                .is_err()
+        );
+        ledger
+    }
+
+    fn balances(snapshots: Vec<AccountSnapshot>) -> Vec<(String, String, String, bool)> {
+        snapshots
+            .into_iter()
+            .map(|s| (s.client, s.available, s.held, s.locked))
+            .collect()
+    }
+
+    fn row(
+        client: &str,
+        available: &str,
+        held: &str,
+        locked: bool,
+    ) -> (String, String, String, bool) {
+        (
+            client.to_string(),
+            available.to_string(),
+            held.to_string(),
+            locked,
+        )
+    }
+
+    #[test]
+    fn test_that_rolled_back_batch_counts_as_a_record() {
+        let ledger = ledger_with_rollback_log();
+        assert_eq!(ledger.sequence(), 5);
+    }
+
+    #[test]
+    fn test_that_snapshots_are_queried_after_a_record() {
+        let ledger = ledger_with_rollback_log();
+
+        assert_eq!(
+            balances(ledger.snapshots_at(PointInTime::Sequence(2)).unwrap()),
+            vec![
+                row("1", "10.0000", "0.0000", false),
+                row("2", "5.0000", "0.0000", false)
+            ]
+        );
+        assert_eq!(
+            balances(ledger.snapshots_at(PointInTime::Sequence(0)).unwrap()),
+            vec![]
+        );
+        // A point after the last record is the current state
+        assert_eq!(
+            balances(ledger.snapshots_at(PointInTime::Sequence(9)).unwrap()),
+            balances(ledger.account_snapshots().collect())
+        );
+    }
+
+    #[test]
+    fn test_that_snapshots_are_queried_at_a_timestamp() {
+        let ledger = ledger_with_rollback_log();
+
+        assert_eq!(
+            balances(ledger.snapshots_at(PointInTime::Timestamp(350)).unwrap()),
+            vec![
+                row("1", "0.0000", "10.0000", false),
+                row("2", "5.0000", "0.0000", false)
+            ]
+        );
+        // The record stamped at the timestamp is included, none is before the first one
+        assert_eq!(
+            balances(ledger.snapshots_at(PointInTime::Timestamp(400)).unwrap()),
+            vec![
+                row("1", "0.0000", "0.0000", true),
+                row("2", "5.0000", "0.0000", false)
+            ]
+        );
+        assert_eq!(
+            balances(ledger.snapshots_at(PointInTime::Timestamp(99)).unwrap()),
+            vec![]
+        );
+    }
+
+    #[test]
+    fn test_that_ledger_is_rolled_back_after_a_record() {
+        let mut ledger = ledger_with_rollback_log();
+
+        ledger.rollback_to(PointInTime::Sequence(2)).unwrap();
+        assert_eq!(ledger.sequence(), 2);
+        assert_eq!(
+            balances(ledger.account_snapshots().collect()),
+            vec![
+                row("1", "10.0000", "0.0000", false),
+                row("2", "5.0000", "0.0000", false)
+            ]
+        );
+        assert_eq!(ledger.operations.len(), 2);
+        assert!(ledger.verify_journal().is_ok());
+
+        // Rolling back to the present or the future changes nothing
+        ledger.rollback_to(PointInTime::Sequence(9)).unwrap();
+        assert_eq!(ledger.sequence(), 2);
+    }
+
+    #[test]
+    fn test_that_records_undone_by_a_rollback_can_be_given_again() {
+        let mut ledger = ledger_with_rollback_log();
+
+        ledger.rollback_to(PointInTime::Timestamp(150)).unwrap();
+        assert_eq!(ledger.sequence(), 1);
+        assert_eq!(ledger.last_timestamp, Some(100));
+        assert!(!ledger.is_known(2));
+        // Tx ids and idempotency keys of undone records can be used again
+        assert_eq!(
+            ledger
+                .process_idempotent(
+                    "k",
+                    &stamped(2, 2, TransactionType::Deposit, Some("7"), 200)
+                )
+                .unwrap(),
+            IdempotentResult::Applied
+        );
+        assert_eq!(ledger.client_snapshot(2).unwrap().available, "7.0000");
+    }
+
+    #[test]
+    fn test_that_going_back_in_time_requires_the_rollback_log() {
+        let mut ledger = Ledger::new();
+        ledger
+            .process_transaction(&stamped(1, 1, TransactionType::Deposit, Some("10"), 100))
+            .unwrap();
+
+        assert!(matches!(
+            ledger.rollback_to(PointInTime::Sequence(0)),
+            Err(LedgerError::NoRollbackLog)
+        ));
+        assert!(matches!(
+            ledger.snapshots_at(PointInTime::Timestamp(0)),
+            Err(LedgerError::NoRollbackLog)
+        ));
+        assert_eq!(ledger.sequence(), 1);
+    }
+
+    #[test]
+    fn test_that_rollback_is_refused_once_the_operation_log_is_truncated() {
+        let mut ledger = Ledger::with_config(LedgerConfig {
+            retention: RetentionPolicy {
+                rollback_log: true,
+                operation_log_limit: Some(1),
+                ..RetentionPolicy::default()
+            },
+            ..LedgerConfig::default()
+        });
+        for id in 1..=2 {
+            ledger
+                .process_transaction(&stamped(id, 1, TransactionType::Deposit, Some("1"), 100))
+                .unwrap();
+        }
+
+        assert!(matches!(
+            ledger.rollback_to(PointInTime::Sequence(1)),
+            Err(LedgerError::RollbackUnsupported(_))
+        ));
+        assert_eq!(ledger.client_snapshot(1).unwrap().available, "2.0000");
+    }
+
+    #[test]
+    fn test_that_observers_receive_the_events_of_the_ledger() {
+        let events = Arc::new(Mutex::new(vec![]));
+        let sink = Arc::clone(&events);
+        let mut ledger = Ledger::new().with_observer(move |event: &LedgerEvent| {
+            sink.lock().unwrap().push(event.clone());
+        });
+        let transaction = |id, account_id, typ, amount: Option<&str>| Transaction {
+            id,
+            account_id,
+            typ,
+            amount: amount.map(String::from),
+            timestamp: None,
+            wallet: None,
+            to_wallet: None,
+            seller: None,
+        };
+        let take = || std::mem::take(&mut *events.lock().unwrap());
+        let amount = |amount: &str| amount.to_string();
+
+        ledger
+            .process_transaction(&transaction(1, 1, TransactionType::Deposit, Some("10")))
+            .unwrap();
+        ledger
+            .process_transaction(&transaction(1, 1, TransactionType::Dispute, None))
+            .unwrap();
+        ledger
+            .process_transaction(&transaction(1, 1, TransactionType::Chargeback, None))
+            .unwrap();
+        assert_eq!(
+            take(),
+            vec![
+                LedgerEvent::AccountCreated {
+                    client: 1,
+                    wallet: MAIN_WALLET.to_string()
+                },
+                LedgerEvent::DepositApplied {
+                    client: 1,
+                    tx: 1,
+                    amount: amount("10.0000")
+                },
+                LedgerEvent::FundsHeld {
+                    client: 1,
+                    tx: 1,
+                    operation: OperationKind::Dispute,
+                    amount: amount("10.0000")
+                },
+                LedgerEvent::Chargeback {
+                    client: 1,
+                    tx: 1,
+                    amount: amount("10.0000")
+                },
+                LedgerEvent::AccountLocked {
+                    client: 1,
+                    wallet: MAIN_WALLET.to_string()
+                },
+            ]
+        );
+
+        assert!(
+            ledger
+                .process_transaction(&transaction(2, 1, TransactionType::Deposit, Some("1")))
+                .is_err()
+        );
+        assert_eq!(
+            take(),
+            vec![LedgerEvent::TransactionRejected {
+                client: 1,
+                tx: 2,
+                code: "account_locked",
+                reason: "Account operation failed: Account is locked (tx id 2)".to_string()
+            }]
+        );
+
+        // Only the failure of a batch is reported
+        assert!(
+            ledger
+                .process_batch(&[
+                    transaction(3, 2, TransactionType::Deposit, Some("1")),
+                    transaction(3, 2, TransactionType::Deposit, Some("1")),
+                ])
+                .is_err()
+        );
+        assert_eq!(
+            take(),
+            vec![LedgerEvent::TransactionRejected {
+                client: 2,
+                tx: 3,
+                code: "duplicate_tx_id",
+                reason: "Duplicate transaction id (tx id 3)".to_string()
+            }]
+        );
+        ledger
+            .process_batch(&[transaction(3, 2, TransactionType::Deposit, Some("1"))])
+            .unwrap();
+        assert_eq!(take().len(), 2);
     }
 }
@@ -1,13 +1,66 @@
 mod account;
+mod account_creation;
 mod account_snapshot;
+mod aml;
 mod amount;
+mod anomaly;
+mod config;
+mod dispute_window;
+mod fees;
+mod history;
+mod history_store;
+mod idempotency;
+mod interest;
+mod journal;
 mod ledger;
+mod observer;
+mod operation;
+mod point_in_time;
+mod reconcile;
 mod record;
+mod registry;
+mod retention;
+mod rules;
+mod snapshot_diff;
+mod statement;
 mod transaction;
+mod tx_id_filter;
+mod wallet;
 
-pub use ledger::Ledger;
-pub use record::InputRecord;
+pub use account_creation::{AccountCreation, AccountCreationError};
+pub use aml::{AmlError, AmlReportKind, AmlReportRow, AmlThresholds};
+pub use amount::{Amount, AmountError};
+pub use anomaly::{
+    Activity, Alert, AlertsCsvObserver, AnomalyDetector, DetectorError, Detectors,
+    LargeDepositWithdrawal, NearThreshold, RapidCycle, RepeatedDisputes,
+};
+pub use config::LedgerConfig;
+pub use dispute_window::{DisputeWindow, TxStamp};
+pub use fees::{FeeBand, FeeError, FeeRule, FeeSchedule, FeeTxType};
+pub use history::{TxEvent, TxHistoryEntry, TxKind, TxLifecycle, TxLifecycleEvent, TxStatus};
+pub use history_store::HistoryStore;
+pub use idempotency::{IdempotentOutcome, IdempotentResult};
+pub use interest::{DayCount, InterestError, InterestPolicy};
+pub use journal::{TrialBalance, TrialBalanceRow};
+pub use ledger::{BatchError, Ledger, LedgerError};
+pub use observer::{JsonLinesObserver, LedgerEvent, LedgerObserver};
+pub use operation::OperationKind;
+pub use point_in_time::{PointInTime, PointInTimeError};
+pub use reconcile::{Discrepancy, DiscrepancyKind, reconcile};
+pub use record::{InputRecord, RecordType};
+pub use registry::{
+    ClientInfo, ClientRegistry, ClientStatus, KycLimits, KycTier, RegistryColumns, RegistryError,
+    TierLimits,
+};
+pub use retention::RetentionPolicy;
+pub use rules::{
+    BlockedClients, MaxDeposit, MaxWithdrawal, RuleChain, RuleContext, RuleError, ValidationRule,
+};
 pub use transaction::{Transaction, TransactionType};
+pub use tx_id_filter::TxIdFilter;
+pub use wallet::{LockScope, MAIN_WALLET, WalletError};
 
 #[allow(unused_imports)]
-pub use account_snapshot::AccountSnapshot;
+pub use account_snapshot::{AccountSnapshot, WalletSnapshot};
+pub use snapshot_diff::{SnapshotChange, SnapshotDeltaRow, SnapshotDiff, SnapshotDiffError};
+pub use statement::{Statement, StatementBalance, StatementLine, StatementPeriod};
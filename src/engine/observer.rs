@@ -0,0 +1,289 @@
+use crate::engine::Transaction;
+use crate::engine::amount::Amount;
+use crate::engine::ledger::LedgerError;
+use crate::engine::operation::OperationKind;
+use serde::Serialize;
+use std::io::Write;
+
+/// Something that happened in the ledger.
+/// Every applied operation is reported by exactly one of the `*Applied`, `Funds*` or
+/// `Chargeback` events, amounts being formatted like in `AccountSnapshot`.
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum LedgerEvent {
+    /// A wallet was opened, `main` for the account of a new client
+    AccountCreated { client: u16, wallet: String },
+    DepositApplied {
+        client: u16,
+        tx: u32,
+        amount: String,
+    },
+    WithdrawalApplied {
+        client: u16,
+        tx: u32,
+        amount: String,
+    },
+    /// Available funds held by a dispute or an escrow
+    FundsHeld {
+        client: u16,
+        tx: u32,
+        operation: OperationKind,
+        amount: String,
+    },
+    /// Held funds made available again by a resolve or an escrow refund
+    FundsReleased {
+        client: u16,
+        tx: u32,
+        operation: OperationKind,
+        amount: String,
+    },
+    /// Held funds removed by a chargeback
+    Chargeback {
+        client: u16,
+        tx: u32,
+        amount: String,
+    },
+    /// Any other operation: fees, interest, reversals, corrections, transfers, escrow releases
+    OperationApplied {
+        client: u16,
+        tx: u32,
+        operation: OperationKind,
+        amount: String,
+    },
+    /// A wallet was locked, by a chargeback on it or on another wallet of the client
+    AccountLocked { client: u16, wallet: String },
+    /// A transaction was rejected (for a batch, the one that made it fail)
+    TransactionRejected {
+        client: u16,
+        tx: u32,
+        code: &'static str,
+        reason: String,
+    },
+    /// The records given after `sequence` were undone
+    RolledBack { sequence: u64 },
+    /// Suspicious behaviour of a client flagged by the `rule` anomaly detector, `txs` being the
+    /// transactions that raised it. The last one was applied, unless the detector blocked it.
+    AnomalyFlagged {
+        client: u16,
+        rule: String,
+        txs: Vec<u32>,
+    },
+}
+
+/// Receives the events of a `Ledger`, in the order they happen.
+/// Events of a record are delivered once it is processed, those of a batch once it is applied.
+pub trait LedgerObserver: Send {
+    fn on_event(&mut self, event: &LedgerEvent);
+}
+
+impl<F> LedgerObserver for F
+where
+    F: FnMut(&LedgerEvent) + Send,
+{
+    fn on_event(&mut self, event: &LedgerEvent) {
+        self(event)
+    }
+}
+
+/// Observer writing every event as a line of JSON, for audit trails
+pub struct JsonLinesObserver<W> {
+    writer: W,
+}
+
+impl<W: Write + Send> JsonLinesObserver<W> {
+    pub fn new(writer: W) -> Self {
+        JsonLinesObserver { writer }
+    }
+}
+
+impl<W: Write + Send> LedgerObserver for JsonLinesObserver<W> {
+    fn on_event(&mut self, event: &LedgerEvent) {
+        let result = serde_json::to_writer(&mut self.writer, event)
+            .map_err(std::io::Error::from)
+            .and_then(|()| writeln!(self.writer))
+            .and_then(|()| self.writer.flush());
+        if let Err(e) = result {
+            log::warn!("Error writing ledger event {event:?}: {e}");
+        }
+    }
+}
+
+impl LedgerEvent {
+    /// Event reporting an operation applied to `client`
+    pub(crate) fn operation(
+        client: u16,
+        tx: u32,
+        operation: OperationKind,
+        amount: &Amount,
+    ) -> Self {
+        let amount = amount.to_string();
+        match operation {
+            OperationKind::Deposit => LedgerEvent::DepositApplied { client, tx, amount },
+            OperationKind::Withdrawal => LedgerEvent::WithdrawalApplied { client, tx, amount },
+            OperationKind::Dispute | OperationKind::EscrowFund => LedgerEvent::FundsHeld {
+                client,
+                tx,
+                operation,
+                amount,
+            },
+            OperationKind::Resolve | OperationKind::EscrowRefund => LedgerEvent::FundsReleased {
+                client,
+                tx,
+                operation,
+                amount,
+            },
+            OperationKind::Chargeback => LedgerEvent::Chargeback { client, tx, amount },
+            _ => LedgerEvent::OperationApplied {
+                client,
+                tx,
+                operation,
+                amount,
+            },
+        }
+    }
+
+    pub(crate) fn rejected(tx: &Transaction, error: &LedgerError) -> Self {
+        LedgerEvent::TransactionRejected {
+            client: tx.account_id,
+            tx: tx.id,
+            code: error.code(),
+            reason: error.to_string(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::TransactionType;
+    use crate::engine::ledger::Ledger;
+    use std::str::FromStr;
+
+    // Writer failing its first `failures` writes
+    struct FailingWriter {
+        failures: usize,
+        written: Vec<u8>,
+    }
+
+    impl Write for FailingWriter {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            if self.failures > 0 {
+                self.failures -= 1;
+                return Err(std::io::Error::other("disk full"));
+            }
+            self.written.write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_that_events_are_written_as_json_lines() {
+        let mut observer = JsonLinesObserver::new(vec![]);
+        let amount = Amount::from_str("1.5").unwrap();
+        observer.on_event(&LedgerEvent::operation(
+            1,
+            2,
+            OperationKind::Dispute,
+            &amount,
+        ));
+        observer.on_event(&LedgerEvent::RolledBack { sequence: 3 });
+
+        assert_eq!(
+            String::from_utf8(observer.writer).unwrap(),
+            "{\"event\":\"funds_held\",\"client\":1,\"tx\":2,\"operation\":\"dispute\",\"amount\":\"1.5000\"}\n\
+             {\"event\":\"rolled_back\",\"sequence\":3}\n"
+        );
+    }
+
+    #[test]
+    fn test_that_every_operation_is_reported_by_one_event() {
+        let amount = Amount::from_str("1").unwrap();
+        let event = |operation| match LedgerEvent::operation(1, 2, operation, &amount) {
+            LedgerEvent::DepositApplied { .. } => "deposit_applied",
+            LedgerEvent::WithdrawalApplied { .. } => "withdrawal_applied",
+            LedgerEvent::FundsHeld { .. } => "funds_held",
+            LedgerEvent::FundsReleased { .. } => "funds_released",
+            LedgerEvent::Chargeback { .. } => "chargeback",
+            LedgerEvent::OperationApplied { .. } => "operation_applied",
+            _ => "other",
+        };
+
+        assert_eq!(event(OperationKind::Deposit), "deposit_applied");
+        assert_eq!(event(OperationKind::Withdrawal), "withdrawal_applied");
+        assert_eq!(event(OperationKind::EscrowFund), "funds_held");
+        assert_eq!(event(OperationKind::Resolve), "funds_released");
+        assert_eq!(event(OperationKind::EscrowRefund), "funds_released");
+        assert_eq!(event(OperationKind::Chargeback), "chargeback");
+        for operation in [
+            OperationKind::Fee,
+            OperationKind::Interest,
+            OperationKind::Transfer,
+            OperationKind::EscrowRelease,
+        ] {
+            assert_eq!(event(operation), "operation_applied");
+        }
+    }
+
+    #[test]
+    fn test_that_rejection_reports_the_code_and_reason_of_the_error() {
+        let tx = Transaction {
+            id: 7,
+            account_id: 3,
+            typ: TransactionType::Deposit,
+            amount: None,
+            timestamp: None,
+            wallet: None,
+            to_wallet: None,
+            seller: None,
+        };
+        assert_eq!(
+            LedgerEvent::rejected(&tx, &LedgerError::MissingAmount(7)),
+            LedgerEvent::TransactionRejected {
+                client: 3,
+                tx: 7,
+                code: "missing_amount",
+                reason: "Missing Amount id (tx id 7)".to_string(),
+            }
+        );
+    }
+
+    #[test]
+    fn test_that_failed_write_does_not_stop_the_next_events() {
+        let mut observer = JsonLinesObserver::new(FailingWriter {
+            failures: 1,
+            written: vec![],
+        });
+        observer.on_event(&LedgerEvent::RolledBack { sequence: 1 });
+        observer.on_event(&LedgerEvent::RolledBack { sequence: 2 });
+
+        assert_eq!(
+            String::from_utf8(observer.writer.written).unwrap(),
+            "{\"event\":\"rolled_back\",\"sequence\":2}\n"
+        );
+    }
+
+    #[test]
+    fn test_that_failing_observer_does_not_fail_the_ledger() {
+        let observer = JsonLinesObserver::new(FailingWriter {
+            failures: usize::MAX,
+            written: vec![],
+        });
+        let mut ledger = Ledger::new().with_observer(observer);
+        let deposit = Transaction {
+            id: 1,
+            account_id: 1,
+            typ: TransactionType::Deposit,
+            amount: Some("10".to_string()),
+            timestamp: None,
+            wallet: None,
+            to_wallet: None,
+            seller: None,
+        };
+
+        assert!(ledger.process_transaction(&deposit).is_ok());
+        assert_eq!(ledger.client_snapshot(1).unwrap().available, "10.0000");
+    }
+}
@@ -0,0 +1,169 @@
+use crate::engine::amount::{Amount, AmountError};
+use crate::engine::dispute_window::TxStamp;
+use serde::Serialize;
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+
+/// Kind of operation applied to an account
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "snake_case")]
+pub enum OperationKind {
+    Deposit,
+    Withdrawal,
+    Dispute,
+    Resolve,
+    Chargeback,
+    /// Fee charged on a deposit or a withdrawal
+    Fee,
+    /// Fee given back after a chargeback
+    FeeRefund,
+    /// Interest paid on the available balance
+    Interest,
+    /// Undo of a deposit or a withdrawal, signed by its effect on the available funds
+    Reversal,
+    /// Amendment of a deposit or a withdrawal, signed by its effect on the available funds
+    Correction,
+    /// Move of available funds between two wallets of the client
+    Transfer,
+    /// Available funds of the buyer put in escrow (held)
+    EscrowFund,
+    /// Escrowed funds released to the seller
+    EscrowRelease,
+    /// Escrowed funds given back to the buyer
+    EscrowRefund,
+    /// Escrowed funds received by the seller
+    EscrowPayout,
+}
+
+impl OperationKind {
+    /// Operations derived from a record, which are not logged when they move no funds
+    pub(crate) fn skipped_when_zero(&self) -> bool {
+        matches!(
+            self,
+            OperationKind::Fee
+                | OperationKind::FeeRefund
+                | OperationKind::Interest
+                | OperationKind::Correction
+        )
+    }
+
+    /// Balances (available, held) after moving `amount` with this operation
+    pub(crate) fn apply(
+        &self,
+        amount: &Amount,
+        available: &Amount,
+        held: &Amount,
+    ) -> Result<(Amount, Amount), AmountError> {
+        match self {
+            OperationKind::Deposit
+            | OperationKind::FeeRefund
+            | OperationKind::Interest
+            | OperationKind::Reversal
+            | OperationKind::Correction => Ok((available.add(amount)?, *held)),
+            OperationKind::Withdrawal | OperationKind::Fee => Ok((available.sub(amount)?, *held)),
+            OperationKind::Dispute | OperationKind::EscrowFund => {
+                Ok((available.sub(amount)?, held.add(amount)?))
+            }
+            OperationKind::Resolve | OperationKind::EscrowRefund => {
+                Ok((available.add(amount)?, held.sub(amount)?))
+            }
+            OperationKind::Chargeback | OperationKind::EscrowRelease => {
+                Ok((*available, held.sub(amount)?))
+            }
+            OperationKind::EscrowPayout => Ok((available.add(amount)?, *held)),
+            // Funds stay with the client
+            OperationKind::Transfer => Ok((*available, *held)),
+        }
+    }
+}
+
+/// An operation successfully applied by the ledger, with the account balances right after it
+#[derive(Debug, Clone)]
+pub(crate) struct AppliedOperation {
+    pub(crate) stamp: TxStamp,
+    pub(crate) client: u16,
+    pub(crate) tx: u32,
+    pub(crate) kind: OperationKind,
+    pub(crate) amount: Amount,
+    pub(crate) available: Amount,
+    pub(crate) held: Amount,
+}
+
+/// Operations applied by the ledger, in order. With a `limit`, only the last operations are kept
+/// and the last one dropped of every client is remembered, as the opening of what is left.
+#[derive(Debug, Default)]
+pub(crate) struct OperationLog {
+    operations: VecDeque<AppliedOperation>,
+    limit: Option<usize>,
+    dropped: usize, // Operations dropped to stay within the limit
+    last_dropped: HashMap<u16, AppliedOperation>,
+}
+
+impl OperationLog {
+    pub(crate) fn new(limit: Option<usize>) -> Self {
+        OperationLog {
+            limit,
+            ..OperationLog::default()
+        }
+    }
+
+    /// Number of operations logged so far, dropped ones included
+    pub(crate) fn len(&self) -> usize {
+        self.dropped + self.operations.len()
+    }
+
+    pub(crate) fn push(&mut self, operation: AppliedOperation) {
+        self.operations.push_back(operation);
+        while self
+            .limit
+            .is_some_and(|limit| self.operations.len() > limit)
+        {
+            if let Some(oldest) = self.operations.pop_front() {
+                self.dropped += 1;
+                self.last_dropped.insert(oldest.client, oldest);
+            }
+        }
+    }
+
+    /// Forget the operations logged after the first `len` ones, those already dropped excepted
+    pub(crate) fn truncate(&mut self, len: usize) {
+        self.operations.truncate(len.saturating_sub(self.dropped));
+    }
+
+    pub(crate) fn iter(&self) -> impl Iterator<Item = &AppliedOperation> {
+        self.operations.iter()
+    }
+
+    /// True when operations were dropped to stay within the limit
+    pub(crate) fn is_truncated(&self) -> bool {
+        self.dropped > 0
+    }
+
+    /// Last operation of `client` dropped to stay within the limit
+    pub(crate) fn last_dropped(&self, client: u16) -> Option<&AppliedOperation> {
+        self.last_dropped.get(&client)
+    }
+}
+
+impl fmt::Display for OperationKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let kind = match self {
+            OperationKind::Deposit => "deposit",
+            OperationKind::Withdrawal => "withdrawal",
+            OperationKind::Dispute => "dispute",
+            OperationKind::Resolve => "resolve",
+            OperationKind::Chargeback => "chargeback",
+            OperationKind::Fee => "fee",
+            OperationKind::FeeRefund => "fee_refund",
+            OperationKind::Interest => "interest",
+            OperationKind::Reversal => "reversal",
+            OperationKind::Correction => "correction",
+            OperationKind::Transfer => "transfer",
+            OperationKind::EscrowFund => "escrow_fund",
+            OperationKind::EscrowRelease => "escrow_release",
+            OperationKind::EscrowRefund => "escrow_refund",
+            OperationKind::EscrowPayout => "escrow_payout",
+        };
+        write!(f, "{kind}")
+    }
+}
@@ -0,0 +1,95 @@
+use std::fmt;
+use std::str::FromStr;
+use thiserror::Error;
+
+/// Point of the input feed the ledger can be queried at or rolled back to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PointInTime {
+    /// Right after the record with this sequence number (1-based), `0` being before any record
+    Sequence(u64),
+    /// Right after the last record stamped at or before this unix timestamp (seconds).
+    /// Records without a timestamp inherit the last one seen, the first ones are before any timestamp.
+    Timestamp(u64),
+}
+
+#[derive(Error, Debug, Clone)]
+pub enum PointInTimeError {
+    #[error("Invalid point in time: {0}")]
+    Invalid(String),
+}
+
+impl FromStr for PointInTime {
+    type Err = PointInTimeError;
+
+    /// Accepts a unix timestamp or a sequence number suffixed by `r` (`1000r`), as dispute windows
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let invalid = || PointInTimeError::Invalid(s.into());
+        match s.strip_suffix('r') {
+            Some(records) => Ok(PointInTime::Sequence(
+                records.parse::<u64>().map_err(|_| invalid())?,
+            )),
+            None => Ok(PointInTime::Timestamp(
+                s.parse::<u64>().map_err(|_| invalid())?,
+            )),
+        }
+    }
+}
+
+impl fmt::Display for PointInTime {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PointInTime::Sequence(sequence) => write!(f, "{sequence}r"),
+            PointInTime::Timestamp(timestamp) => write!(f, "{timestamp}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_that_record_is_suffixed_by_r() {
+        assert_eq!(
+            PointInTime::from_str("1000r").unwrap(),
+            PointInTime::Sequence(1000)
+        );
+        assert_eq!(
+            PointInTime::from_str("0r").unwrap(),
+            PointInTime::Sequence(0)
+        );
+    }
+
+    #[test]
+    fn test_that_timestamp_is_a_plain_number() {
+        assert_eq!(
+            PointInTime::from_str(" 1700000000 ").unwrap(),
+            PointInTime::Timestamp(1_700_000_000)
+        );
+    }
+
+    #[test]
+    fn test_that_point_in_time_is_displayed_as_parsed() {
+        for point in [
+            PointInTime::Sequence(3),
+            PointInTime::Timestamp(1_700_000_000),
+        ] {
+            assert_eq!(PointInTime::from_str(&point.to_string()).unwrap(), point);
+        }
+        assert_eq!(PointInTime::Sequence(3).to_string(), "3r");
+    }
+
+    #[test]
+    fn test_that_invalid_point_in_time_is_rejected() {
+        for invalid in ["", "r", "12h", "-1r", "1.5", "r12"] {
+            assert!(
+                matches!(
+                    PointInTime::from_str(invalid),
+                    Err(PointInTimeError::Invalid(_))
+                ),
+                "{invalid}"
+            );
+        }
+    }
+}
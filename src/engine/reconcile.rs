@@ -0,0 +1,233 @@
+use crate::engine::account_snapshot::AccountSnapshot;
+use crate::engine::amount::Amount;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::str::FromStr;
+
+/// How the snapshot of a client differs from the external view of it
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum DiscrepancyKind {
+    /// The client is missing from the external view
+    Missing,
+    /// The client is only in the external view
+    Extra,
+    /// The client is listed more than once in the external view
+    Duplicate,
+    /// A field has another value in the external view
+    Mismatch,
+}
+
+/// Difference found by `reconcile`, with the values of the field on both sides when relevant
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct Discrepancy {
+    pub client: String,
+    pub issue: DiscrepancyKind,
+    pub field: Option<&'static str>,
+    pub ours: Option<String>,
+    pub theirs: Option<String>,
+}
+
+/// Compare our snapshots to an external view of the same clients, ordered by client.
+/// Amounts are compared by value (`1.5` matches `1.5000`), or as text when they are not valid
+/// amounts. Escrowed funds are compared to the `escrow` column of the external view when it has
+/// one, and are part of its `held` otherwise.
+pub fn reconcile(
+    ours: impl IntoIterator<Item = AccountSnapshot>,
+    theirs: impl IntoIterator<Item = AccountSnapshot>,
+) -> Vec<Discrepancy> {
+    let mut discrepancies = vec![];
+    let mut external = BTreeMap::new();
+    for snapshot in theirs {
+        let client = snapshot.client.trim().to_string();
+        if external.contains_key(&client_key(&client)) {
+            discrepancies.push(Discrepancy {
+                client,
+                issue: DiscrepancyKind::Duplicate,
+                field: None,
+                ours: None,
+                theirs: None,
+            });
+            continue;
+        }
+        external.insert(client_key(&client), snapshot);
+    }
+
+    for snapshot in ours {
+        let Some(theirs) = external.remove(&client_key(&snapshot.client)) else {
+            discrepancies.push(Discrepancy {
+                client: snapshot.client,
+                issue: DiscrepancyKind::Missing,
+                field: None,
+                ours: None,
+                theirs: None,
+            });
+            continue;
+        };
+        let zero = Amount::new().to_string();
+        let held = match (&snapshot.escrow, &theirs.escrow) {
+            (Some(escrow), None) => with_escrow(&snapshot.held, escrow),
+            _ => snapshot.held.clone(),
+        };
+        let mut fields = vec![
+            ("available", &snapshot.available, &theirs.available),
+            ("held", &held, &theirs.held),
+            ("total", &snapshot.total, &theirs.total),
+        ];
+        if let Some(escrow) = &theirs.escrow {
+            fields.push(("escrow", snapshot.escrow.as_ref().unwrap_or(&zero), escrow));
+        }
+        for (field, ours, theirs) in fields {
+            if !same_amount(ours, theirs) {
+                discrepancies.push(Discrepancy {
+                    client: snapshot.client.clone(),
+                    issue: DiscrepancyKind::Mismatch,
+                    field: Some(field),
+                    ours: Some(ours.clone()),
+                    theirs: Some(theirs.clone()),
+                });
+            }
+        }
+        if snapshot.locked != theirs.locked {
+            discrepancies.push(Discrepancy {
+                client: snapshot.client.clone(),
+                issue: DiscrepancyKind::Mismatch,
+                field: Some("locked"),
+                ours: Some(snapshot.locked.to_string()),
+                theirs: Some(theirs.locked.to_string()),
+            });
+        }
+    }
+
+    discrepancies.extend(external.into_values().map(|snapshot| Discrepancy {
+        client: snapshot.client.trim().to_string(),
+        issue: DiscrepancyKind::Extra,
+        field: None,
+        ours: None,
+        theirs: None,
+    }));
+    discrepancies.sort_by_cached_key(|d| client_key(&d.client));
+    discrepancies
+}
+
+// Clients ordered by id, the invalid ones last
+fn client_key(client: &str) -> Result<u16, String> {
+    let client = client.trim();
+    client.parse::<u16>().map_err(|_| client.to_string())
+}
+
+// Held funds including the escrowed ones, as text like the snapshot amounts
+fn with_escrow(held: &str, escrow: &str) -> String {
+    match (Amount::from_str(held), Amount::from_str(escrow)) {
+        (Ok(held), Ok(escrow)) => held
+            .add(&escrow)
+            .map_or_else(|_| held.to_string(), |h| h.to_string()),
+        _ => held.to_string(),
+    }
+}
+
+fn same_amount(ours: &str, theirs: &str) -> bool {
+    match (Amount::from_str(ours), Amount::from_str(theirs)) {
+        (Ok(ours), Ok(theirs)) => ours == theirs,
+        _ => ours.trim() == theirs.trim(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_that_missing_extra_and_different_clients_are_reported() {
+        let snapshot =
+            |client: &str, available: &str, held: &str, total: &str, locked| AccountSnapshot {
+                client: client.into(),
+                available: available.into(),
+                held: held.into(),
+                escrow: None,
+                total: total.into(),
+                locked,
+            };
+        let ours = vec![
+            snapshot("1", "1.5000", "0.0000", "1.5000", false),
+            snapshot("2", "2.0000", "1.0000", "3.0000", false),
+            snapshot("3", "0.0000", "0.0000", "0.0000", true),
+        ];
+        let theirs = vec![
+            snapshot("10", "1", "0", "1", false),
+            snapshot(" 2", "2", "0", "2", true),
+            snapshot("1", "1.5", "0", "1.5", false),
+            snapshot("1", "1.5", "0", "1.5", false),
+        ];
+
+        let issues: Vec<_> = reconcile(ours, theirs)
+            .into_iter()
+            .map(|d| (d.client, d.issue, d.field))
+            .collect();
+        assert_eq!(
+            issues,
+            vec![
+                ("1".to_string(), DiscrepancyKind::Duplicate, None),
+                ("2".to_string(), DiscrepancyKind::Mismatch, Some("held")),
+                ("2".to_string(), DiscrepancyKind::Mismatch, Some("total")),
+                ("2".to_string(), DiscrepancyKind::Mismatch, Some("locked")),
+                ("3".to_string(), DiscrepancyKind::Missing, None),
+                ("10".to_string(), DiscrepancyKind::Extra, None),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_that_escrowed_funds_are_part_of_their_held_without_escrow_column() {
+        let snapshot = |held: &str, escrow: Option<&str>| AccountSnapshot {
+            client: "1".into(),
+            available: "1".into(),
+            held: held.into(),
+            escrow: escrow.map(Into::into),
+            total: "6".into(),
+            locked: false,
+        };
+
+        assert!(
+            reconcile(
+                vec![snapshot("2.0000", Some("3.0000"))],
+                vec![snapshot("5", None)]
+            )
+            .is_empty()
+        );
+        let issues = reconcile(
+            vec![snapshot("2.0000", Some("3.0000"))],
+            vec![snapshot("2", None)],
+        );
+        assert_eq!(issues.len(), 1);
+        assert_eq!(issues[0].field, Some("held"));
+        assert_eq!(issues[0].ours.as_deref(), Some("5.0000"));
+    }
+
+    #[test]
+    fn test_that_escrow_column_is_compared_when_they_have_one() {
+        let snapshot = |held: &str, escrow: Option<&str>| AccountSnapshot {
+            client: "1".into(),
+            available: "1".into(),
+            held: held.into(),
+            escrow: escrow.map(Into::into),
+            total: "6".into(),
+            locked: false,
+        };
+
+        assert!(
+            reconcile(
+                vec![snapshot("2.0000", Some("3.0000"))],
+                vec![snapshot("2", Some("3"))]
+            )
+            .is_empty()
+        );
+        // A client without escrow on our side has none escrowed
+        let issues = reconcile(
+            vec![snapshot("5.0000", None)],
+            vec![snapshot("5", Some("1"))],
+        );
+        let fields: Vec<_> = issues.iter().map(|d| (d.field, d.ours.clone())).collect();
+        assert_eq!(fields, vec![(Some("escrow"), Some("0.0000".to_string()))]);
+    }
+}
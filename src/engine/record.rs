@@ -1,17 +1,36 @@
-use serde::Deserialize;
+use serde::de::IntoDeserializer;
+use serde::{Deserialize, Serialize};
+use std::str::FromStr;
 
 use crate::engine::{Transaction, TransactionType};
 
-#[derive(Deserialize, Debug, Clone)]
+#[derive(Deserialize, Serialize, Debug, Clone)]
 pub struct InputRecord {
     #[serde(rename = "type")]
     pub typ: RecordType,
     pub client: u16,
     pub tx: u32,
     pub amount: Option<String>,
+    #[serde(default)]
+    pub timestamp: Option<u64>,
+    /// Consecutive records sharing a batch id are applied all-or-nothing
+    #[serde(default)]
+    pub batch_id: Option<String>,
+    /// Retries of a record with the same key are acknowledged without being applied again
+    #[serde(default)]
+    pub idempotency_key: Option<String>,
+    /// Wallet of the client, `main` when missing or empty
+    #[serde(default)]
+    pub wallet: Option<String>,
+    /// Destination wallet of a transfer
+    #[serde(default)]
+    pub to_wallet: Option<String>,
+    /// Client paid when the escrow funded by an `escrow_fund` record is released
+    #[serde(default)]
+    pub seller: Option<u16>,
 }
 
-#[derive(Deserialize, Debug, Clone)]
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq)]
 #[serde(rename_all = "lowercase")]
 pub enum RecordType {
     Deposit,
@@ -19,6 +38,26 @@ pub enum RecordType {
     Dispute,
     Resolve,
     Chargeback,
+    Reversal,
+    Correction,
+    #[serde(rename = "accrue_interest")]
+    AccrueInterest,
+    Transfer,
+    #[serde(rename = "escrow_fund")]
+    EscrowFund,
+    #[serde(rename = "escrow_release")]
+    EscrowRelease,
+    #[serde(rename = "escrow_refund")]
+    EscrowRefund,
+}
+
+impl FromStr for RecordType {
+    type Err = serde::de::value::Error;
+
+    /// Accepts the values of the `type` column
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        RecordType::deserialize(s.trim().into_deserializer())
+    }
 }
 
 impl InputRecord {
@@ -28,31 +67,121 @@ impl InputRecord {
                 account_id: self.client,
                 id: self.tx,
                 amount: self.amount.clone(),
+                timestamp: self.timestamp,
                 typ: TransactionType::Deposit,
+                wallet: self.wallet.clone(),
+                to_wallet: None,
+                seller: None,
             },
             RecordType::Withdrawal => Transaction {
                 account_id: self.client,
                 id: self.tx,
                 amount: self.amount.clone(),
+                timestamp: self.timestamp,
                 typ: TransactionType::Withdrawal,
+                wallet: self.wallet.clone(),
+                to_wallet: None,
+                seller: None,
             },
             RecordType::Dispute => Transaction {
                 account_id: self.client,
                 id: self.tx,
                 amount: None,
+                timestamp: self.timestamp,
                 typ: TransactionType::Dispute,
+                wallet: self.wallet.clone(),
+                to_wallet: None,
+                seller: None,
             },
             RecordType::Resolve => Transaction {
                 account_id: self.client,
                 id: self.tx,
                 amount: None,
+                timestamp: self.timestamp,
                 typ: TransactionType::Resolve,
+                wallet: self.wallet.clone(),
+                to_wallet: None,
+                seller: None,
             },
             RecordType::Chargeback => Transaction {
                 account_id: self.client,
                 id: self.tx,
                 amount: None,
+                timestamp: self.timestamp,
                 typ: TransactionType::Chargeback,
+                wallet: self.wallet.clone(),
+                to_wallet: None,
+                seller: None,
+            },
+            RecordType::Reversal => Transaction {
+                account_id: self.client,
+                id: self.tx,
+                amount: None,
+                timestamp: self.timestamp,
+                typ: TransactionType::Reversal,
+                wallet: self.wallet.clone(),
+                to_wallet: None,
+                seller: None,
+            },
+            RecordType::Correction => Transaction {
+                account_id: self.client,
+                id: self.tx,
+                amount: self.amount.clone(),
+                timestamp: self.timestamp,
+                typ: TransactionType::Correction,
+                wallet: self.wallet.clone(),
+                to_wallet: None,
+                seller: None,
+            },
+            RecordType::AccrueInterest => Transaction {
+                account_id: self.client,
+                id: self.tx,
+                amount: None,
+                timestamp: self.timestamp,
+                typ: TransactionType::AccrueInterest,
+                wallet: self.wallet.clone(),
+                to_wallet: None,
+                seller: None,
+            },
+            RecordType::Transfer => Transaction {
+                account_id: self.client,
+                id: self.tx,
+                amount: self.amount.clone(),
+                timestamp: self.timestamp,
+                typ: TransactionType::Transfer,
+                wallet: self.wallet.clone(),
+                to_wallet: self.to_wallet.clone(),
+                seller: None,
+            },
+            RecordType::EscrowFund => Transaction {
+                account_id: self.client,
+                id: self.tx,
+                amount: self.amount.clone(),
+                timestamp: self.timestamp,
+                typ: TransactionType::EscrowFund,
+                wallet: self.wallet.clone(),
+                to_wallet: None,
+                seller: self.seller,
+            },
+            RecordType::EscrowRelease => Transaction {
+                account_id: self.client,
+                id: self.tx,
+                amount: self.amount.clone(),
+                timestamp: self.timestamp,
+                typ: TransactionType::EscrowRelease,
+                wallet: self.wallet.clone(),
+                to_wallet: None,
+                seller: None,
+            },
+            RecordType::EscrowRefund => Transaction {
+                account_id: self.client,
+                id: self.tx,
+                amount: self.amount.clone(),
+                timestamp: self.timestamp,
+                typ: TransactionType::EscrowRefund,
+                wallet: self.wallet.clone(),
+                to_wallet: None,
+                seller: None,
             },
         }
     }
@@ -0,0 +1,205 @@
+use crate::engine::amount::Amount;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+use thiserror::Error;
+
+/// Level of verification of a client identity
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum KycTier {
+    Unverified,
+    Basic,
+    Full,
+}
+
+/// Only active clients can make transactions
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ClientStatus {
+    Active,
+    Suspended,
+    Closed,
+}
+
+/// Client as described in the registry file
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ClientInfo {
+    pub client: u16,
+    pub name: String,
+    pub country: String,
+    pub kyc_tier: KycTier,
+    pub status: ClientStatus,
+    /// Opening date of the client relationship (e.g. `2024-01-31`)
+    pub opened_on: String,
+}
+
+#[derive(Error, Debug)]
+pub enum RegistryError {
+    #[error("Invalid registry file: {0}")]
+    Csv(#[from] csv::Error),
+
+    #[error("Invalid KYC limits: {0}")]
+    Limits(#[from] toml::de::Error),
+}
+
+/// Clients known from the registry file.
+/// Clients missing from the registry have no KYC rules applied.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ClientRegistry {
+    clients: HashMap<u16, ClientInfo>,
+}
+
+impl ClientRegistry {
+    /// Reads a CSV file with the `client,name,country,kyc_tier,status,opened_on` columns
+    pub fn from_csv(reader: impl io::Read) -> Result<Self, RegistryError> {
+        let mut rdr = csv::ReaderBuilder::new()
+            .trim(csv::Trim::All)
+            .from_reader(reader);
+        let clients = rdr
+            .deserialize::<ClientInfo>()
+            .map(|r| r.map(|info| (info.client, info)))
+            .collect::<Result<HashMap<u16, ClientInfo>, csv::Error>>()?;
+
+        Ok(ClientRegistry { clients })
+    }
+
+    pub fn get(&self, client: u16) -> Option<&ClientInfo> {
+        self.clients.get(&client)
+    }
+
+    /// Ids of the registered clients, in no particular order
+    pub fn clients(&self) -> impl Iterator<Item = u16> + '_ {
+        self.clients.keys().copied()
+    }
+
+    pub fn len(&self) -> usize {
+        self.clients.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.clients.is_empty()
+    }
+}
+
+/// Limits of the transactions of a KYC tier
+#[derive(Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct TierLimits {
+    pub max_deposit: Option<Amount>,
+    pub max_withdrawal: Option<Amount>,
+}
+
+/// Limits applied to registered clients depending on their KYC tier, loaded from a TOML file:
+///
+/// ```toml
+/// [unverified]
+/// max_deposit = "1000"
+/// max_withdrawal = "0"
+///
+/// [basic]
+/// max_deposit = "10000"
+/// ```
+#[derive(Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct KycLimits {
+    #[serde(default)]
+    pub unverified: TierLimits,
+    #[serde(default)]
+    pub basic: TierLimits,
+    #[serde(default)]
+    pub full: TierLimits,
+}
+
+impl KycLimits {
+    pub fn from_toml_str(s: &str) -> Result<Self, RegistryError> {
+        Ok(toml::from_str(s)?)
+    }
+
+    pub(crate) fn tier(&self, tier: KycTier) -> &TierLimits {
+        match tier {
+            KycTier::Unverified => &self.unverified,
+            KycTier::Basic => &self.basic,
+            KycTier::Full => &self.full,
+        }
+    }
+}
+
+impl fmt::Display for KycTier {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KycTier::Unverified => write!(f, "unverified"),
+            KycTier::Basic => write!(f, "basic"),
+            KycTier::Full => write!(f, "full"),
+        }
+    }
+}
+
+impl fmt::Display for ClientStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ClientStatus::Active => write!(f, "active"),
+            ClientStatus::Suspended => write!(f, "suspended"),
+            ClientStatus::Closed => write!(f, "closed"),
+        }
+    }
+}
+
+/// Registry fields appended to the rows of an output, empty for unregistered clients
+#[derive(Serialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct RegistryColumns {
+    pub name: Option<String>,
+    pub country: Option<String>,
+    pub kyc_tier: Option<KycTier>,
+    pub status: Option<ClientStatus>,
+    pub opened_on: Option<String>,
+}
+
+impl From<Option<&ClientInfo>> for RegistryColumns {
+    fn from(info: Option<&ClientInfo>) -> Self {
+        match info {
+            None => RegistryColumns::default(),
+            Some(info) => RegistryColumns {
+                name: Some(info.name.clone()),
+                country: Some(info.country.clone()),
+                kyc_tier: Some(info.kyc_tier),
+                status: Some(info.status),
+                opened_on: Some(info.opened_on.clone()),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    #[test]
+    fn test_that_registry_and_limits_can_be_loaded() {
+        let registry = ClientRegistry::from_csv(
+            "client,name,country,kyc_tier,status,opened_on\n\
+             1, Alice Martin, FR, unverified, active, 2024-01-31\n\
+             2, Bob Smith, GB, full, suspended, 2023-06-01\n"
+                .as_bytes(),
+        )
+        .unwrap();
+        assert_eq!(registry.len(), 2);
+        assert_eq!(registry.get(1).unwrap().kyc_tier, KycTier::Unverified);
+        assert_eq!(registry.get(2).unwrap().status, ClientStatus::Suspended);
+        assert!(registry.get(3).is_none());
+
+        let invalid =
+            "client,name,country,kyc_tier,status,opened_on\n1,A,FR,gold,active,2024-01-31\n";
+        assert!(ClientRegistry::from_csv(invalid.as_bytes()).is_err());
+
+        let limits = KycLimits::from_toml_str("[unverified]\nmax_deposit = \"1000\"\n").unwrap();
+        assert_eq!(
+            limits.tier(KycTier::Unverified).max_deposit,
+            Some(Amount::from_str("1000").unwrap())
+        );
+        assert_eq!(limits.tier(KycTier::Full), &TierLimits::default());
+        assert!(KycLimits::from_toml_str("[gold]\nmax_deposit = 1\n").is_err());
+    }
+}
@@ -0,0 +1,20 @@
+/// Limits on the transaction history kept in memory by each `Account`.
+/// The default policy keeps everything, which matches the historical behaviour.
+#[derive(Debug, Clone, Default)]
+pub struct RetentionPolicy {
+    /// Keep at most the last N transactions per account, older ones are evicted
+    pub keep_last: Option<usize>,
+    /// Withdrawals can't be disputed, so do not keep them in the history
+    pub deposits_only: bool,
+    /// Drop transactions whose dispute window is over as soon as the account is touched
+    pub drop_expired: bool,
+    /// Do not keep the log of applied operations, statements are then empty.
+    /// The log grows with every operation applied unless it is limited.
+    pub skip_operation_log: bool,
+    /// Keep at most the last N operations in the log. Statements can only cover the periods
+    /// after the operations dropped, and the AML report needs the whole log.
+    pub operation_log_limit: Option<usize>,
+    /// Keep the changes made by every record, to query or roll back the ledger at an earlier
+    /// point. Costly: every wallet touched by a record is copied with its history.
+    pub rollback_log: bool,
+}
@@ -0,0 +1,348 @@
+use crate::engine::amount::Amount;
+use crate::engine::ledger::LedgerError;
+use crate::engine::{Transaction, TransactionType};
+use serde::Deserialize;
+use std::collections::HashSet;
+use std::fmt;
+use std::sync::Arc;
+use thiserror::Error;
+
+/// Check made on a transaction right before the ledger applies it
+pub trait ValidationRule: fmt::Debug + Send + Sync {
+    /// Name of the rule, reported when it rejects a transaction
+    fn name(&self) -> &str;
+
+    fn check(&self, ctx: &RuleContext) -> Result<(), LedgerError>;
+}
+
+/// What a rule sees of a transaction and of the wallet it applies to
+#[derive(Debug)]
+pub struct RuleContext<'a> {
+    pub tx: &'a Transaction,
+    /// Amount of the transaction, `None` when missing or invalid (rejected by the ledger anyway)
+    pub amount: Option<Amount>,
+    /// Balances of the wallet the transaction applies to, empty when it does not exist yet
+    pub available: Amount,
+    pub held: Amount,
+    pub locked: bool,
+    /// The transaction creates a tx id that was already used
+    pub duplicate: bool,
+}
+
+impl RuleContext<'_> {
+    /// Rejection of the transaction by `rule`
+    pub fn violation(&self, rule: &dyn ValidationRule) -> LedgerError {
+        LedgerError::RuleViolation {
+            rule: rule.name().to_string(),
+            tx: self.tx.id,
+        }
+    }
+}
+
+/// Ordered rules checked before applying a transaction, the first failing one rejects it.
+/// The default chain is empty.
+///
+/// A chain can be loaded from a TOML file listing the rules in order, with their thresholds:
+///
+/// ```toml
+/// [[rules]]
+/// rule = "blocked_clients"
+/// clients = [4, 7]
+///
+/// [[rules]]
+/// rule = "max_deposit"
+/// amount = "10000"
+/// ```
+///
+/// NOTE: Duplicate ids, negative amounts, locked accounts and overdrafts are not rules, they
+/// are invariants of the ledger and always refused when applying the transaction.
+#[derive(Debug, Clone, Default)]
+pub struct RuleChain {
+    rules: Vec<Arc<dyn ValidationRule>>,
+}
+
+#[derive(Error, Debug)]
+pub enum RuleError {
+    #[error("Invalid validation rules: {0}")]
+    Parse(#[from] toml::de::Error),
+}
+
+/// Rejects deposits larger than `amount`
+#[derive(Debug)]
+pub struct MaxDeposit {
+    pub amount: Amount,
+}
+
+/// Rejects withdrawals larger than `amount`
+#[derive(Debug)]
+pub struct MaxWithdrawal {
+    pub amount: Amount,
+}
+
+/// Rejects every transaction of the `clients`
+#[derive(Debug)]
+pub struct BlockedClients {
+    pub clients: HashSet<u16>,
+}
+
+// Rule of a TOML chain, tagged by its name
+#[derive(Deserialize, Debug)]
+#[serde(tag = "rule", rename_all = "snake_case", deny_unknown_fields)]
+enum RuleConfig {
+    MaxDeposit { amount: Amount },
+    MaxWithdrawal { amount: Amount },
+    BlockedClients { clients: HashSet<u16> },
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+struct RuleFile {
+    #[serde(default)]
+    rules: Vec<RuleConfig>,
+}
+
+impl RuleChain {
+    /// Chain without any rule
+    pub fn empty() -> Self {
+        RuleChain { rules: vec![] }
+    }
+
+    /// Check `rule` after the rules already in the chain
+    pub fn with_rule(mut self, rule: impl ValidationRule + 'static) -> Self {
+        self.rules.push(Arc::new(rule));
+        self
+    }
+
+    pub fn from_toml_str(s: &str) -> Result<Self, RuleError> {
+        let file: RuleFile = toml::from_str(s)?;
+        Ok(file
+            .rules
+            .into_iter()
+            .fold(RuleChain::empty(), |chain, rule| match rule {
+                RuleConfig::MaxDeposit { amount } => chain.with_rule(MaxDeposit { amount }),
+                RuleConfig::MaxWithdrawal { amount } => chain.with_rule(MaxWithdrawal { amount }),
+                RuleConfig::BlockedClients { clients } => {
+                    chain.with_rule(BlockedClients { clients })
+                }
+            }))
+    }
+
+    /// Names of the rules, in the order they are checked
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.rules.iter().map(|rule| rule.name())
+    }
+
+    pub(crate) fn check(&self, ctx: &RuleContext) -> Result<(), LedgerError> {
+        self.rules.iter().try_for_each(|rule| rule.check(ctx))
+    }
+}
+
+impl<'a> RuleContext<'a> {
+    pub(crate) fn new(
+        tx: &'a Transaction,
+        available: Amount,
+        held: Amount,
+        locked: bool,
+        duplicate: bool,
+    ) -> Self {
+        RuleContext {
+            tx,
+            amount: tx.record_amount(),
+            available,
+            held,
+            locked,
+            duplicate,
+        }
+    }
+}
+
+impl ValidationRule for MaxDeposit {
+    fn name(&self) -> &str {
+        "max_deposit"
+    }
+
+    fn check(&self, ctx: &RuleContext) -> Result<(), LedgerError> {
+        if ctx.tx.typ == TransactionType::Deposit
+            && ctx.amount.is_some_and(|amount| amount > self.amount)
+        {
+            Err(ctx.violation(self))?
+        }
+        Ok(())
+    }
+}
+
+impl ValidationRule for MaxWithdrawal {
+    fn name(&self) -> &str {
+        "max_withdrawal"
+    }
+
+    fn check(&self, ctx: &RuleContext) -> Result<(), LedgerError> {
+        if ctx.tx.typ == TransactionType::Withdrawal
+            && ctx.amount.is_some_and(|amount| amount > self.amount)
+        {
+            Err(ctx.violation(self))?
+        }
+        Ok(())
+    }
+}
+
+impl ValidationRule for BlockedClients {
+    fn name(&self) -> &str {
+        "blocked_clients"
+    }
+
+    fn check(&self, ctx: &RuleContext) -> Result<(), LedgerError> {
+        if self.clients.contains(&ctx.tx.account_id) {
+            Err(ctx.violation(self))?
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    fn transaction(typ: TransactionType, client: u16, amount: &str) -> Transaction {
+        Transaction {
+            account_id: client,
+            id: 1,
+            amount: Some(amount.to_string()),
+            timestamp: None,
+            typ,
+            wallet: None,
+            to_wallet: None,
+            seller: None,
+        }
+    }
+
+    fn check(chain: &RuleChain, tx: &Transaction) -> Result<(), LedgerError> {
+        chain.check(&RuleContext::new(
+            tx,
+            Amount::new(),
+            Amount::new(),
+            false,
+            false,
+        ))
+    }
+
+    fn violated(result: Result<(), LedgerError>) -> Option<String> {
+        match result {
+            Err(LedgerError::RuleViolation { rule, .. }) => Some(rule),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn test_that_rule_chain_is_loaded_in_order() {
+        let chain = RuleChain::from_toml_str(
+            r#"
+            [[rules]]
+            rule = "blocked_clients"
+            clients = [4]
+
+            [[rules]]
+            rule = "max_deposit"
+            amount = "100"
+
+            [[rules]]
+            rule = "max_withdrawal"
+            amount = "50"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            chain.names().collect::<Vec<_>>(),
+            vec!["blocked_clients", "max_deposit", "max_withdrawal"]
+        );
+    }
+
+    #[test]
+    fn test_that_empty_chain_accepts_every_transaction() {
+        assert_eq!(RuleChain::default().names().count(), 0);
+        assert_eq!(RuleChain::from_toml_str("").unwrap().names().count(), 0);
+        let deposit = transaction(TransactionType::Deposit, 4, "1000000");
+        assert!(check(&RuleChain::default(), &deposit).is_ok());
+    }
+
+    #[test]
+    fn test_that_limits_are_inclusive() {
+        let chain = RuleChain::empty().with_rule(MaxDeposit {
+            amount: Amount::from_str("100").unwrap(),
+        });
+
+        assert!(check(&chain, &transaction(TransactionType::Deposit, 1, "100")).is_ok());
+        assert!(matches!(
+            check(&chain, &transaction(TransactionType::Deposit, 1, "100.0001")),
+            Err(LedgerError::RuleViolation { rule, tx: 1 }) if rule == "max_deposit"
+        ));
+    }
+
+    #[test]
+    fn test_that_limits_only_apply_to_their_transaction_type() {
+        let chain = RuleChain::empty()
+            .with_rule(MaxDeposit {
+                amount: Amount::from_str("10").unwrap(),
+            })
+            .with_rule(MaxWithdrawal {
+                amount: Amount::from_str("5").unwrap(),
+            });
+
+        assert!(check(&chain, &transaction(TransactionType::Withdrawal, 1, "10")).is_err());
+        assert!(check(&chain, &transaction(TransactionType::Deposit, 1, "10")).is_ok());
+        assert!(check(&chain, &transaction(TransactionType::Transfer, 1, "100")).is_ok());
+    }
+
+    #[test]
+    fn test_that_first_violated_rule_in_chain_order_is_reported() {
+        let blocked = || BlockedClients {
+            clients: HashSet::from([4]),
+        };
+        let max_deposit = || MaxDeposit {
+            amount: Amount::from_str("100").unwrap(),
+        };
+        // Blocked client depositing above the limit violates both rules
+        let deposit = transaction(TransactionType::Deposit, 4, "1000");
+
+        let chain = RuleChain::empty()
+            .with_rule(blocked())
+            .with_rule(max_deposit());
+        assert_eq!(
+            violated(check(&chain, &deposit)).as_deref(),
+            Some("blocked_clients")
+        );
+        let chain = RuleChain::empty()
+            .with_rule(max_deposit())
+            .with_rule(blocked());
+        assert_eq!(
+            violated(check(&chain, &deposit)).as_deref(),
+            Some("max_deposit")
+        );
+
+        // The rules after a passing one are still checked
+        let small = transaction(TransactionType::Deposit, 4, "1");
+        assert_eq!(
+            violated(check(&chain, &small)).as_deref(),
+            Some("blocked_clients")
+        );
+    }
+
+    #[test]
+    fn test_that_invalid_rule_files_are_rejected() {
+        // Missing threshold, unknown rule, unknown field
+        assert!(RuleChain::from_toml_str("[[rules]]\nrule = \"max_deposit\"").is_err());
+        assert!(RuleChain::from_toml_str("[[rules]]\nrule = \"unknown\"").is_err());
+        assert!(
+            RuleChain::from_toml_str(
+                "[[rules]]\nrule = \"max_deposit\"\namount = \"1\"\nclients = [1]"
+            )
+            .is_err()
+        );
+        assert!(
+            RuleChain::from_toml_str("[[rules]]\nrule = \"max_deposit\"\namount = \"x\"").is_err()
+        );
+        // Invariants of the ledger are not configurable
+        assert!(RuleChain::from_toml_str("[[rules]]\nrule = \"no_overdraft\"").is_err());
+    }
+}
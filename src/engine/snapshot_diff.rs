@@ -0,0 +1,241 @@
+use crate::engine::account_snapshot::AccountSnapshot;
+use crate::engine::amount::{Amount, AmountError};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::str::FromStr;
+use thiserror::Error;
+
+#[derive(Error, Debug, Clone)]
+pub enum SnapshotDiffError {
+    #[error("Invalid snapshot amount: {0}")]
+    Amount(#[from] AmountError),
+
+    #[error("Client {0} is listed more than once in a snapshot file")]
+    DuplicateClient(String),
+}
+
+/// How a client changed between two snapshot files
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum SnapshotChange {
+    /// Only in the later file
+    Added,
+    /// Only in the earlier file
+    Removed,
+    Changed,
+    Unchanged,
+}
+
+/// Movement of a client between two snapshot files. Amounts are the later balance minus the
+/// earlier one (a missing client counting as empty), `locked` is `locked` or `unlocked` when
+/// the lock state of a client in both files changed and empty otherwise.
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct SnapshotDeltaRow {
+    pub client: String,
+    pub change: Option<SnapshotChange>,
+    pub available: String,
+    pub held: String,
+    pub total: String,
+    pub locked: String,
+}
+
+/// Movements of every client between two snapshot files, ordered by client, with their sums
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SnapshotDiff {
+    pub rows: Vec<SnapshotDeltaRow>,
+    pub total_available: String,
+    pub total_held: String,
+    pub total_total: String,
+    /// Number of locked clients in the later file minus in the earlier one
+    pub locked_change: i64,
+}
+
+#[derive(Default, PartialEq)]
+struct Balances {
+    available: Amount,
+    held: Amount,
+    total: Amount,
+    locked: bool,
+}
+
+impl SnapshotDiff {
+    /// Compare the snapshots of an earlier run to those of a later one.
+    /// A client listed twice in either file is rejected rather than compared by one of its rows.
+    pub fn new(
+        before: impl IntoIterator<Item = AccountSnapshot>,
+        after: impl IntoIterator<Item = AccountSnapshot>,
+    ) -> Result<Self, SnapshotDiffError> {
+        let mut clients: BTreeMap<Result<u16, String>, (Option<Balances>, Option<Balances>)> =
+            BTreeMap::new();
+        for snapshot in before {
+            let balances = &mut clients.entry(client_key(&snapshot.client)).or_default().0;
+            if balances.is_some() {
+                Err(SnapshotDiffError::DuplicateClient(
+                    snapshot.client.trim().to_string(),
+                ))?
+            }
+            *balances = Some(Balances::try_from(&snapshot)?);
+        }
+        for snapshot in after {
+            let balances = &mut clients.entry(client_key(&snapshot.client)).or_default().1;
+            if balances.is_some() {
+                Err(SnapshotDiffError::DuplicateClient(
+                    snapshot.client.trim().to_string(),
+                ))?
+            }
+            *balances = Some(Balances::try_from(&snapshot)?);
+        }
+
+        let mut rows = vec![];
+        let mut totals = Balances::default();
+        let mut locked_change = 0;
+        for (client, (before, after)) in clients {
+            let change = match (&before, &after) {
+                (None, _) => SnapshotChange::Added,
+                (_, None) => SnapshotChange::Removed,
+                (Some(before), Some(after)) if before == after => SnapshotChange::Unchanged,
+                _ => SnapshotChange::Changed,
+            };
+            let locked = match (
+                before.as_ref().map(|b| b.locked),
+                after.as_ref().map(|a| a.locked),
+            ) {
+                (Some(false), Some(true)) => "locked",
+                (Some(true), Some(false)) => "unlocked",
+                _ => "",
+            };
+            let before = before.unwrap_or_default();
+            let after = after.unwrap_or_default();
+            let available = after.available.sub(&before.available)?;
+            let held = after.held.sub(&before.held)?;
+            let total = after.total.sub(&before.total)?;
+            totals.available = totals.available.add(&available)?;
+            totals.held = totals.held.add(&held)?;
+            totals.total = totals.total.add(&total)?;
+            locked_change += i64::from(after.locked) - i64::from(before.locked);
+
+            rows.push(SnapshotDeltaRow {
+                client: client.map_or_else(|client| client, |id| id.to_string()),
+                change: Some(change),
+                available: available.to_string(),
+                held: held.to_string(),
+                total: total.to_string(),
+                locked: locked.to_string(),
+            });
+        }
+
+        Ok(SnapshotDiff {
+            rows,
+            total_available: totals.available.to_string(),
+            total_held: totals.held.to_string(),
+            total_total: totals.total.to_string(),
+            locked_change,
+        })
+    }
+
+    /// Write the rows as CSV followed by a `total` row, whose `locked` is the change in the
+    /// number of locked clients
+    pub fn write_csv<W: std::io::Write>(&self, wtr: &mut csv::Writer<W>) -> Result<(), csv::Error> {
+        for row in &self.rows {
+            wtr.serialize(row)?;
+        }
+        wtr.serialize(SnapshotDeltaRow {
+            client: String::from("total"),
+            change: None,
+            available: self.total_available.clone(),
+            held: self.total_held.clone(),
+            total: self.total_total.clone(),
+            locked: format!("{:+}", self.locked_change),
+        })
+    }
+}
+
+impl TryFrom<&AccountSnapshot> for Balances {
+    type Error = AmountError;
+
+    fn try_from(snapshot: &AccountSnapshot) -> Result<Self, Self::Error> {
+        Ok(Balances {
+            available: Amount::from_str(&snapshot.available)?,
+            held: Amount::from_str(&snapshot.held)?,
+            total: Amount::from_str(&snapshot.total)?,
+            locked: snapshot.locked,
+        })
+    }
+}
+
+// Clients ordered by id, the invalid ones last
+fn client_key(client: &str) -> Result<u16, String> {
+    let client = client.trim();
+    client.parse::<u16>().map_err(|_| client.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_that_snapshot_files_are_compared_client_by_client() {
+        let snapshot = |client: &str, available: &str, held: &str, locked| AccountSnapshot {
+            client: client.into(),
+            available: available.into(),
+            held: held.into(),
+            escrow: None,
+            total: Amount::from_str(available)
+                .unwrap()
+                .add(&Amount::from_str(held).unwrap())
+                .unwrap()
+                .to_string(),
+            locked,
+        };
+        let before = vec![
+            snapshot("1", "10", "0", false),
+            snapshot("2", "5", "1", false),
+            snapshot("3", "7", "0", true),
+        ];
+        let after = vec![
+            snapshot("2", "2.5", "0", true),
+            snapshot("1", "10.0000", "0", false),
+            snapshot("4", "1", "0", false),
+        ];
+
+        let diff = SnapshotDiff::new(before, after).unwrap();
+        let mut wtr = csv::Writer::from_writer(vec![]);
+        diff.write_csv(&mut wtr).unwrap();
+        assert_eq!(
+            String::from_utf8(wtr.into_inner().unwrap()).unwrap(),
+            "client,change,available,held,total,locked\n\
+             1,unchanged,0.0000,0.0000,0.0000,\n\
+             2,changed,-2.5000,-1.0000,-3.5000,locked\n\
+             3,removed,-7.0000,0.0000,-7.0000,\n\
+             4,added,1.0000,0.0000,1.0000,\n\
+             total,,-8.5000,-1.0000,-9.5000,+0\n"
+        );
+
+        let mut invalid = snapshot("1", "1", "0", false);
+        invalid.held = "abc".into();
+        assert!(SnapshotDiff::new(vec![invalid], vec![]).is_err());
+    }
+
+    #[test]
+    fn test_that_client_listed_twice_in_a_file_is_rejected() {
+        let snapshot = |client: &str, available: &str| AccountSnapshot {
+            client: client.into(),
+            available: available.into(),
+            held: "0".into(),
+            escrow: None,
+            total: available.into(),
+            locked: false,
+        };
+
+        let before = vec![snapshot("1", "10"), snapshot(" 1", "5")];
+        assert!(matches!(
+            SnapshotDiff::new(before, vec![snapshot("1", "10")]),
+            Err(SnapshotDiffError::DuplicateClient(client)) if client == "1"
+        ));
+        let after = vec![snapshot("2", "1"), snapshot("2", "1")];
+        assert!(matches!(
+            SnapshotDiff::new(vec![snapshot("2", "1")], after),
+            Err(SnapshotDiffError::DuplicateClient(client)) if client == "2"
+        ));
+    }
+}
@@ -0,0 +1,291 @@
+use crate::engine::amount::{Amount, AmountError};
+use crate::engine::operation::{AppliedOperation, OperationKind};
+use crate::engine::registry::{ClientInfo, RegistryColumns};
+use serde::Serialize;
+use std::io;
+
+/// Period covered by a statement, on the record timestamps: `from` inclusive, `to` exclusive.
+/// Operations without timestamp are only part of unbounded periods.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct StatementPeriod {
+    pub from: Option<u64>,
+    pub to: Option<u64>,
+}
+
+impl StatementPeriod {
+    pub(crate) fn is_before(&self, timestamp: Option<u64>) -> bool {
+        match (self.from, timestamp) {
+            (None, _) => false,
+            (Some(_), None) => true,
+            (Some(from), Some(t)) => t < from,
+        }
+    }
+
+    fn contains(&self, timestamp: Option<u64>) -> bool {
+        if self.from.is_none() && self.to.is_none() {
+            return true;
+        }
+        match timestamp {
+            None => false,
+            Some(t) => self.from.is_none_or(|from| t >= from) && self.to.is_none_or(|to| t < to),
+        }
+    }
+}
+
+/// Balances of an account at a point in time, formatted like in `AccountSnapshot`
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct StatementBalance {
+    pub available: String,
+    pub held: String,
+    pub total: String,
+}
+
+/// One applied operation with the running balances right after it
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct StatementLine {
+    pub sequence: u64,
+    pub timestamp: Option<u64>,
+    pub tx: u32,
+    pub operation: OperationKind,
+    pub amount: String,
+    pub available: String,
+    pub held: String,
+    pub total: String,
+}
+
+/// Statement of one client over a period
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Statement {
+    pub client: u16,
+    pub period: StatementPeriod,
+    pub opening: StatementBalance,
+    pub lines: Vec<StatementLine>,
+    pub closing: StatementBalance,
+    /// Registry details of the client, when it is registered
+    pub holder: Option<ClientInfo>,
+}
+
+// Flat row used for the CSV export, opening and closing balances are rows of their own
+#[derive(Serialize)]
+struct StatementCsvRow<'a> {
+    client: u16,
+    row: &'a str,
+    sequence: Option<u64>,
+    timestamp: Option<u64>,
+    tx: Option<u32>,
+    operation: Option<OperationKind>,
+    amount: Option<&'a str>,
+    available: &'a str,
+    held: &'a str,
+    total: &'a str,
+}
+
+impl Statement {
+    /// Build the statement of `client` from the ledger operations, in the order they were applied
+    pub(crate) fn build<'a>(
+        client: u16,
+        period: StatementPeriod,
+        operations: impl Iterator<Item = &'a AppliedOperation>,
+    ) -> Result<Statement, AmountError> {
+        let zero = Amount::new();
+        let mut opening = balance(&zero, &zero)?;
+        let mut lines = vec![];
+
+        for op in operations.filter(|op| op.client == client) {
+            if period.is_before(op.stamp.timestamp) {
+                opening = balance(&op.available, &op.held)?;
+            } else if period.contains(op.stamp.timestamp) {
+                let after = balance(&op.available, &op.held)?;
+                lines.push(StatementLine {
+                    sequence: op.stamp.sequence,
+                    timestamp: op.stamp.timestamp,
+                    tx: op.tx,
+                    operation: op.kind,
+                    amount: op.amount.to_string(),
+                    available: after.available,
+                    held: after.held,
+                    total: after.total,
+                });
+            }
+        }
+
+        let closing = match lines.last() {
+            Some(last) => StatementBalance {
+                available: last.available.clone(),
+                held: last.held.clone(),
+                total: last.total.clone(),
+            },
+            None => opening.clone(),
+        };
+
+        Ok(Statement {
+            client,
+            period,
+            opening,
+            lines,
+            closing,
+            holder: None,
+        })
+    }
+
+    /// Write the statement as CSV rows: `opening`, one `operation` row per line, then `closing`.
+    /// Several statements can be written one after the other with the same writer.
+    pub fn write_csv<W: io::Write>(&self, wtr: &mut csv::Writer<W>) -> Result<(), csv::Error> {
+        for row in self.csv_rows() {
+            wtr.serialize(row)?;
+        }
+        Ok(())
+    }
+
+    /// Same as `write_csv`, with the registry details of the holder appended to every row
+    pub fn write_csv_with_holder<W: io::Write>(
+        &self,
+        wtr: &mut csv::Writer<W>,
+    ) -> Result<(), csv::Error> {
+        let holder = RegistryColumns::from(self.holder.as_ref());
+        for row in self.csv_rows() {
+            wtr.serialize((row, &holder))?;
+        }
+        Ok(())
+    }
+
+    fn csv_rows(&self) -> impl Iterator<Item = StatementCsvRow<'_>> {
+        let lines = self.lines.iter().map(|line| StatementCsvRow {
+            client: self.client,
+            row: "operation",
+            sequence: Some(line.sequence),
+            timestamp: line.timestamp,
+            tx: Some(line.tx),
+            operation: Some(line.operation),
+            amount: Some(&line.amount),
+            available: &line.available,
+            held: &line.held,
+            total: &line.total,
+        });
+        std::iter::once(balance_row(self.client, "opening", &self.opening))
+            .chain(lines)
+            .chain(std::iter::once(balance_row(
+                self.client,
+                "closing",
+                &self.closing,
+            )))
+    }
+
+    /// Write the statement as a markdown document (also readable as plain text)
+    pub fn write_markdown<W: io::Write>(&self, out: &mut W) -> io::Result<()> {
+        let bound = |t: Option<u64>| t.map(|t| t.to_string()).unwrap_or("-".into());
+
+        writeln!(out, "## Statement for client {}\n", self.client)?;
+        if let Some(holder) = &self.holder {
+            writeln!(
+                out,
+                "Holder: {} ({}), KYC tier {}, {} since {}\n",
+                holder.name, holder.country, holder.kyc_tier, holder.status, holder.opened_on
+            )?;
+        }
+        writeln!(
+            out,
+            "Period: from {} to {}\n",
+            bound(self.period.from),
+            bound(self.period.to)
+        )?;
+        writeln!(
+            out,
+            "Opening balance: available {}, held {}, total {}\n",
+            self.opening.available, self.opening.held, self.opening.total
+        )?;
+        writeln!(
+            out,
+            "| seq | timestamp | tx | operation | amount | available | held | total |"
+        )?;
+        writeln!(out, "|---|---|---|---|---|---|---|---|")?;
+        for line in &self.lines {
+            writeln!(
+                out,
+                "| {} | {} | {} | {} | {} | {} | {} | {} |",
+                line.sequence,
+                bound(line.timestamp),
+                line.tx,
+                line.operation,
+                line.amount,
+                line.available,
+                line.held,
+                line.total
+            )?;
+        }
+        writeln!(
+            out,
+            "\nClosing balance: available {}, held {}, total {}\n",
+            self.closing.available, self.closing.held, self.closing.total
+        )
+    }
+}
+
+fn balance_row<'a>(
+    client: u16,
+    row: &'a str,
+    balance: &'a StatementBalance,
+) -> StatementCsvRow<'a> {
+    StatementCsvRow {
+        client,
+        row,
+        sequence: None,
+        timestamp: None,
+        tx: None,
+        operation: None,
+        amount: None,
+        available: &balance.available,
+        held: &balance.held,
+        total: &balance.total,
+    }
+}
+
+fn balance(available: &Amount, held: &Amount) -> Result<StatementBalance, AmountError> {
+    Ok(StatementBalance {
+        available: available.to_string(),
+        held: held.to_string(),
+        total: available.add(held)?.to_string(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::dispute_window::TxStamp;
+    use std::str::FromStr;
+
+    #[test]
+    fn test_that_statement_can_be_exported() {
+        let operations = [AppliedOperation {
+            stamp: TxStamp {
+                sequence: 1,
+                timestamp: None,
+            },
+            client: 7,
+            tx: 3,
+            kind: OperationKind::Deposit,
+            amount: Amount::from_str("2.5").unwrap(),
+            available: Amount::from_str("2.5").unwrap(),
+            held: Amount::new(),
+        }];
+        let statement = Statement::build(7, StatementPeriod::default(), operations.iter()).unwrap();
+
+        let mut wtr = csv::Writer::from_writer(vec![]);
+        statement.write_csv(&mut wtr).unwrap();
+        let csv = String::from_utf8(wtr.into_inner().unwrap()).unwrap();
+        assert_eq!(
+            csv,
+            "client,row,sequence,timestamp,tx,operation,amount,available,held,total\n\
+             7,opening,,,,,,0.0000,0.0000,0.0000\n\
+             7,operation,1,,3,deposit,2.5000,2.5000,0.0000,2.5000\n\
+             7,closing,,,,,,2.5000,0.0000,2.5000\n"
+        );
+
+        let mut markdown = vec![];
+        statement.write_markdown(&mut markdown).unwrap();
+        let markdown = String::from_utf8(markdown).unwrap();
+        assert!(markdown.starts_with("## Statement for client 7\n"));
+        assert!(markdown.contains("| 1 | - | 3 | deposit | 2.5000 | 2.5000 | 0.0000 | 2.5000 |"));
+        assert!(markdown.contains("Closing balance: available 2.5000, held 0.0000, total 2.5000"));
+    }
+}
@@ -1,16 +1,51 @@
+use crate::engine::amount::Amount;
+use std::str::FromStr;
+
 #[derive(Debug, Clone)]
 pub struct Transaction {
     pub id: u32,
     pub account_id: u16,
     pub amount: Option<String>,
     pub typ: TransactionType,
+    pub timestamp: Option<u64>,
+    /// Wallet of the client, `main` when `None`
+    pub wallet: Option<String>,
+    /// Destination wallet of a transfer
+    pub to_wallet: Option<String>,
+    /// Client paid when an escrow is released
+    pub seller: Option<u16>,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum TransactionType {
     Deposit,
     Withdrawal,
     Dispute,
     Resolve,
     Chargeback,
+    Reversal,
+    Correction,
+    AccrueInterest,
+    Transfer,
+    EscrowFund,
+    EscrowRelease,
+    EscrowRefund,
+}
+
+impl Transaction {
+    /// Amount of the record, `None` when missing or invalid, and for the follow-ups of a
+    /// transaction which do not use the amount of their record
+    pub(crate) fn record_amount(&self) -> Option<Amount> {
+        match self.typ {
+            TransactionType::Dispute
+            | TransactionType::Resolve
+            | TransactionType::Chargeback
+            | TransactionType::Reversal
+            | TransactionType::AccrueInterest => None,
+            _ => self
+                .amount
+                .as_deref()
+                .and_then(|amount| Amount::from_str(amount).ok()),
+        }
+    }
 }
@@ -0,0 +1,174 @@
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+
+const BUCKET_COUNT: u32 = 256;
+const HASH_COUNT: u64 = 4;
+const MIN_FILTER_BYTES: usize = 8;
+
+/// Memory-bounded set of transaction ids.
+///
+/// A bloom filter of a fixed size answers most lookups from memory. As a bloom filter
+/// can return false positives, every positive hit is confirmed against an exact copy of
+/// the ids kept on disk, split in `BUCKET_COUNT` files of little-endian `u32`.
+/// Duplicate detection is therefore exact, only slower when the filter says "maybe".
+pub struct TxIdFilter {
+    bits: Vec<u64>,
+    dir: PathBuf,
+    writers: HashMap<u32, BufWriter<File>>,
+}
+
+impl TxIdFilter {
+    /// Open a filter using `filter_bytes` of memory, with its exact index in `dir`.
+    /// Ids left by a previous run are removed as they don't match the new ledger.
+    pub fn open(dir: impl AsRef<Path>, filter_bytes: usize) -> io::Result<Self> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)?;
+        for bucket in 0..BUCKET_COUNT {
+            let path = bucket_path(&dir, bucket);
+            if path.exists() {
+                fs::remove_file(path)?;
+            }
+        }
+
+        let words = filter_bytes.max(MIN_FILTER_BYTES) / 8;
+        Ok(TxIdFilter {
+            bits: vec![0; words],
+            dir,
+            writers: HashMap::new(),
+        })
+    }
+
+    pub fn contains(&mut self, tx_id: u32) -> io::Result<bool> {
+        if !self.bit_positions(tx_id).all(|bit| self.is_set(bit)) {
+            return Ok(false);
+        }
+
+        // Maybe present: confirm on disk
+        let found = self.contains_on_disk(tx_id)?;
+        if !found {
+            log::debug!("TxIdFilter: false positive for tx id {tx_id}");
+        }
+        Ok(found)
+    }
+
+    pub fn insert(&mut self, tx_id: u32) -> io::Result<()> {
+        let bucket = tx_id % BUCKET_COUNT;
+        let writer = match self.writers.entry(bucket) {
+            Entry::Occupied(e) => e.into_mut(),
+            Entry::Vacant(e) => {
+                let file = OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(bucket_path(&self.dir, bucket))?;
+                e.insert(BufWriter::new(file))
+            }
+        };
+        writer.write_all(&tx_id.to_le_bytes())?;
+
+        let positions: Vec<usize> = self.bit_positions(tx_id).collect();
+        for bit in positions {
+            self.bits[bit / 64] |= 1 << (bit % 64);
+        }
+        Ok(())
+    }
+
+    fn is_set(&self, bit: usize) -> bool {
+        self.bits[bit / 64] & (1 << (bit % 64)) != 0
+    }
+
+    // Double hashing: position_i = h1 + i * h2 (Kirsch-Mitzenmacher)
+    fn bit_positions(&self, tx_id: u32) -> impl Iterator<Item = usize> + use<> {
+        let len = (self.bits.len() * 64) as u64;
+        let h1 = mix(tx_id as u64);
+        let h2 = mix(h1) | 1;
+        (0..HASH_COUNT).map(move |i| (h1.wrapping_add(i.wrapping_mul(h2)) % len) as usize)
+    }
+
+    fn contains_on_disk(&mut self, tx_id: u32) -> io::Result<bool> {
+        let bucket = tx_id % BUCKET_COUNT;
+        let Some(writer) = self.writers.get_mut(&bucket) else {
+            return Ok(false);
+        };
+        writer.flush()?;
+
+        let mut reader = BufReader::new(File::open(bucket_path(&self.dir, bucket))?);
+        let mut buf = [0u8; 4];
+        loop {
+            match reader.read_exact(&mut buf) {
+                Ok(()) if u32::from_le_bytes(buf) == tx_id => return Ok(true),
+                Ok(()) => continue,
+                Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(false),
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+// SplitMix64 finaliser
+fn mix(value: u64) -> u64 {
+    let mut z = value.wrapping_add(0x9E37_79B9_7F4A_7C15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    z ^ (z >> 31)
+}
+
+fn bucket_path(dir: &Path, bucket: u32) -> PathBuf {
+    dir.join(format!("tx_ids_{bucket:04}.bin"))
+}
+
+/// Ids of the transactions successfully processed by the ledger
+pub(crate) enum ProcessedTxIds {
+    Exact(HashSet<u32>),
+    Filtered(TxIdFilter),
+}
+
+impl ProcessedTxIds {
+    pub(crate) fn contains(&mut self, tx_id: u32) -> io::Result<bool> {
+        match self {
+            ProcessedTxIds::Exact(ids) => Ok(ids.contains(&tx_id)),
+            ProcessedTxIds::Filtered(filter) => filter.contains(tx_id),
+        }
+    }
+
+    pub(crate) fn insert(&mut self, tx_id: u32) -> io::Result<()> {
+        match self {
+            ProcessedTxIds::Exact(ids) => {
+                ids.insert(tx_id);
+                Ok(())
+            }
+            ProcessedTxIds::Filtered(filter) => filter.insert(tx_id),
+        }
+    }
+
+    /// Forget `tx_id`, a no-op for filtered ids which can't be removed from the filter
+    pub(crate) fn remove(&mut self, tx_id: u32) {
+        if let ProcessedTxIds::Exact(ids) = self {
+            ids.remove(&tx_id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_that_filter_is_exact_despite_false_positives() {
+        let dir = std::env::temp_dir().join(format!("crab_cash_tx_ids_{}", std::process::id()));
+
+        // A tiny filter saturates quickly and produces many false positives
+        let mut filter = TxIdFilter::open(&dir, 8).unwrap();
+        for id in (0..2_000).step_by(2) {
+            filter.insert(id).unwrap();
+        }
+
+        for id in 0..2_000 {
+            assert_eq!(filter.contains(id).unwrap(), id % 2 == 0);
+        }
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
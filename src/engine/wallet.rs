@@ -0,0 +1,87 @@
+use std::fmt;
+use std::str::FromStr;
+use thiserror::Error;
+
+/// Wallet of records without a `wallet` column, the only wallet of legacy clients
+pub const MAIN_WALLET: &str = "main";
+
+/// Which wallets are locked by a chargeback
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum LockScope {
+    /// Every wallet of the client, including the ones it opens afterwards (legacy behaviour)
+    #[default]
+    Client,
+    /// Only the wallet that received the charged back deposit
+    Wallet,
+}
+
+#[derive(Error, Debug, Clone)]
+pub enum WalletError {
+    #[error("Invalid chargeback lock scope: {0}")]
+    InvalidLockScope(String),
+}
+
+/// Name of the wallet addressed by a record, `main` when the column is missing or empty
+pub(crate) fn wallet_name(wallet: Option<&str>) -> &str {
+    match wallet.map(str::trim) {
+        None | Some("") => MAIN_WALLET,
+        Some(name) => name,
+    }
+}
+
+impl FromStr for LockScope {
+    type Err = WalletError;
+
+    /// Accepts `client` or `wallet`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "client" => Ok(LockScope::Client),
+            "wallet" => Ok(LockScope::Wallet),
+            _ => Err(WalletError::InvalidLockScope(s.into())),
+        }
+    }
+}
+
+impl fmt::Display for LockScope {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LockScope::Client => write!(f, "client"),
+            LockScope::Wallet => write!(f, "wallet"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_that_missing_wallet_is_the_main_wallet() {
+        assert_eq!(wallet_name(None), MAIN_WALLET);
+        assert_eq!(wallet_name(Some("")), MAIN_WALLET);
+        assert_eq!(wallet_name(Some(" ")), MAIN_WALLET);
+    }
+
+    #[test]
+    fn test_that_wallet_name_is_trimmed() {
+        assert_eq!(wallet_name(Some("savings")), "savings");
+        assert_eq!(wallet_name(Some(" savings ")), "savings");
+    }
+
+    #[test]
+    fn test_that_lock_scope_is_parsed_whatever_its_case() {
+        assert_eq!(LockScope::from_str("Wallet").unwrap(), LockScope::Wallet);
+        assert_eq!(LockScope::from_str(" client ").unwrap(), LockScope::Client);
+        for scope in [LockScope::Client, LockScope::Wallet] {
+            assert_eq!(LockScope::from_str(&scope.to_string()).unwrap(), scope);
+        }
+    }
+
+    #[test]
+    fn test_that_unknown_lock_scope_is_rejected() {
+        assert!(matches!(
+            LockScope::from_str("account"),
+            Err(WalletError::InvalidLockScope(scope)) if scope == "account"
+        ));
+    }
+}
@@ -1,65 +1,225 @@
-mod engine;
+mod cli;
+mod repl;
+mod server;
+mod stream;
 
+use cli::{CliArgs, Command, StatementFormat};
+use crab_cash::engine::{
+    AccountSnapshot, AlertsCsvObserver, AmlThresholds, BatchError, ClientRegistry, Detectors,
+    HistoryStore, IdempotentResult, InputRecord, JsonLinesObserver, Ledger, PointInTime,
+    SnapshotDiff, StatementPeriod, Transaction, TxIdFilter,
+};
 use csv::Trim;
-use engine::{InputRecord, Ledger};
 use simple_logger::SimpleLogger;
-use std::path::PathBuf;
-use std::{env, error::Error, ffi::OsString, fs::File};
+use std::collections::HashSet;
+use std::fs::OpenOptions;
+use std::io::{BufWriter, IsTerminal};
+use std::path::{Path, PathBuf};
+use std::{env, error::Error, fs::File};
 
 fn main() -> Result<(), Box<dyn Error>> {
     SimpleLogger::new().env().init()?;
 
     log::debug!("Application started");
 
+    let args = cli::parse_args(env::args_os().skip(1))?;
+    log::debug!("Extracted filepath fom args: {:?}", args.input);
+    log::debug!("Ledger configuration: {:?}", args.config);
+
     log::debug!("Transactions processing: Starting");
-    let ledger = process_transactions()?;
+    let ledger = match &args.input {
+        // The shell loads the transactions file itself, to be able to undo its records
+        Some(_) if args.command == Command::Repl => build_ledger(&args)?,
+        Some(input) => process_transactions_from_filepath(input, build_ledger(&args)?)?,
+        None => build_ledger(&args)?,
+    };
     log::debug!("Transactions processing: Done");
 
-    log::debug!("Exporting account snapshots to stdout: Started");
-    write_to_std_out(&ledger)?;
-    log::debug!("Exporting account snapshots to stdout: Done");
+    match args.command {
+        Command::Process => {
+            log::debug!("Exporting account snapshots to stdout: Started");
+            match args.as_of {
+                Some(point) => write_snapshots_at_to_std_out(&ledger, point)?,
+                None => write_to_std_out(&ledger, args.join_registry, args.by_wallet)?,
+            }
+            log::debug!("Exporting account snapshots to stdout: Done");
+        }
+        Command::History { client, tx } => {
+            log::debug!("Exporting history of client {client} to stdout: Started");
+            write_history_to_std_out(&ledger, client, tx)?;
+            log::debug!("Exporting history of client {client} to stdout: Done");
+        }
+        Command::Statement {
+            client,
+            period,
+            format,
+        } => {
+            log::debug!("Exporting statements to stdout: Started");
+            write_statements_to_std_out(&ledger, client, period, format, args.join_registry)?;
+            log::debug!("Exporting statements to stdout: Done");
+        }
+        Command::TrialBalance => {
+            log::debug!("Exporting trial balance to stdout: Started");
+            write_trial_balance_to_std_out(&ledger)?;
+            log::debug!("Exporting trial balance to stdout: Done");
+        }
+        Command::AmlReport { thresholds } => {
+            log::debug!("Exporting AML report to stdout: Started");
+            write_aml_report_to_std_out(&ledger, &thresholds)?;
+            log::debug!("Exporting AML report to stdout: Done");
+        }
+        Command::Reconcile { external } => {
+            log::debug!("Reconciling account snapshots with {external:?}: Started");
+            write_reconciliation_to_std_out(&ledger, &external)?;
+            log::debug!("Reconciling account snapshots with {external:?}: Done");
+        }
+        Command::Diff { before, after } => {
+            log::debug!("Comparing snapshots of {before:?} and {after:?}: Started");
+            write_snapshot_diff_to_std_out(&before, &after)?;
+            log::debug!("Comparing snapshots of {before:?} and {after:?}: Done");
+        }
+        Command::Serve { listen, workers } => {
+            server::serve(ledger, &listen, workers)?;
+        }
+        Command::Stream {
+            endpoint,
+            format,
+            max_connections,
+        } => {
+            let ledger = stream::stream(ledger, &endpoint, format, max_connections)?;
+            log::debug!("Exporting final account snapshots to stdout: Started");
+            write_to_std_out(&ledger, args.join_registry, args.by_wallet)?;
+            log::debug!("Exporting final account snapshots to stdout: Done");
+        }
+        Command::Repl => {
+            let mut repl = repl::Repl::new(|| build_ledger(&args))?;
+            let mut out = std::io::stdout().lock();
+            if let Some(input) = &args.input {
+                repl.execute(repl::ReplCommand::Load(input.clone()), &mut out)?;
+            }
+            let stdin = std::io::stdin();
+            let prompt = stdin.is_terminal();
+            repl.run(stdin.lock(), &mut out, prompt)?;
+        }
+    }
 
     log::debug!("Application finished");
 
     Ok(())
 }
 
-fn get_first_arg() -> Result<OsString, Box<dyn Error>> {
-    match env::args_os().nth(1) {
-        None => Err(From::from("expected 1 argument, but got none")),
-        Some(file_path) => Ok(file_path),
+fn build_ledger(args: &CliArgs) -> Result<Ledger, Box<dyn Error>> {
+    let mut ledger = Ledger::with_config(args.config.clone());
+    if let Some(dir) = &args.spill_dir {
+        log::debug!("Spilling evicted history to {dir:?}");
+        ledger = ledger.with_history_store(HistoryStore::open(dir)?);
     }
+    if let Some(dir) = &args.dedup_dir {
+        log::debug!(
+            "Deduplicating tx ids with a filter of {} bytes",
+            args.dedup_memory
+        );
+        ledger = ledger.with_tx_id_filter(TxIdFilter::open(dir, args.dedup_memory)?);
+    }
+    if let Some(path) = &args.registry {
+        let registry = ClientRegistry::from_csv(File::open(path)?)?;
+        log::debug!("Loaded {} clients from registry {path:?}", registry.len());
+        ledger = ledger.with_registry(registry);
+    }
+    if let Some(path) = &args.events {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        log::debug!("Appending ledger events to {path:?}");
+        ledger = ledger.with_observer(JsonLinesObserver::new(BufWriter::new(file)));
+    }
+    if let Some(path) = &args.detectors {
+        let detectors = std::fs::read_to_string(path)
+            .map_err(|err| format!("cannot read anomaly detectors {path:?}: {err}"))?;
+        ledger = ledger.with_detectors(Detectors::from_toml_str(&detectors)?);
+    }
+    if let Some(path) = &args.alerts {
+        log::debug!("Writing anomaly alerts to {path:?}");
+        ledger = ledger.with_observer(AlertsCsvObserver::new(File::create(path)?));
+    }
+    Ok(ledger)
 }
 
-fn process_transactions() -> Result<Ledger, Box<dyn Error>> {
-    let file_path = get_first_arg()?;
-    let path = PathBuf::from(file_path);
-    log::debug!("Extracted filepath fom args: {path:?}");
-
-    process_transactions_from_filepath(&path)
-}
-
-fn process_transactions_from_filepath(filepath: &PathBuf) -> Result<Ledger, Box<dyn Error>> {
+fn process_transactions_from_filepath(
+    filepath: &PathBuf,
+    mut ledger: Ledger,
+) -> Result<Ledger, Box<dyn Error>> {
     let file: File = File::open(filepath)?;
 
     let mut rdr = csv::ReaderBuilder::new().trim(Trim::All).from_reader(file);
 
-    let mut ledger = Ledger::new();
-
     log::debug!("Started deserialising records");
-    for result in rdr.deserialize::<InputRecord>() {
-        log::debug!("Deserialising record into InputRecord: {result:?}");
-        let record = match result {
+    let headers = rdr.headers()?.clone();
+    let batch_column = headers.iter().position(|h| h == "batch_id");
+    // Consecutive records of the same batch, applied together once the batch is over
+    let mut batch = PendingBatch::default();
+    // Batches already over, their legs must be contiguous
+    let mut done: HashSet<String> = HashSet::new();
+    for result in rdr.records() {
+        let row = match result {
             Ok(r) => r,
+            Err(e) => {
+                log::warn!("Error reading record:{e}");
+                continue;
+            }
+        };
+
+        // The batch id is read before deserialising, so that an invalid leg fails its batch
+        let batch_id = batch_column
+            .and_then(|i| row.get(i))
+            .filter(|id| !id.is_empty())
+            .map(String::from);
+        if batch.id != batch_id {
+            let over = std::mem::take(&mut batch);
+            done.extend(over.id.clone());
+            process_batch(&mut ledger, over)?;
+            batch.id = batch_id.clone();
+            if let Some(id) = &batch_id
+                && done.contains(id)
+            {
+                batch.invalid = Some(String::from("it was already over, legs must be contiguous"));
+            }
+        }
+
+        log::debug!("Deserialising record into InputRecord: {row:?}");
+        let record = match row.deserialize::<InputRecord>(Some(&headers)) {
+            Ok(r) => r,
+            Err(e) if batch_id.is_some() => {
+                batch.invalid.get_or_insert(format!("invalid record: {e}"));
+                continue;
+            }
             Err(e) => {
                 log::warn!("Error deserializing record:{e}");
                 continue;
             }
         };
+        if batch_id.is_some() {
+            if record.idempotency_key.is_some() {
+                let reason = String::from("idempotency keys are not supported in batches");
+                batch.invalid.get_or_insert(reason);
+            }
+            batch.records.push(record);
+            continue;
+        }
+
         log::debug!("Converting InputRecord into Transaction: {record:?}");
         let transaction = record.to_transaction();
         log::debug!("Processing transaction in ledger: {transaction:?}");
-        if let Err(e) = ledger.process_transaction(&transaction) {
+        let result = match &record.idempotency_key {
+            None => ledger.process_transaction(&transaction),
+            Some(key) => match ledger.process_idempotent(key, &transaction) {
+                Ok(IdempotentResult::Retried(outcome)) => {
+                    log::info!("Retry of request {key} acknowledged, it was {outcome}");
+                    Ok(())
+                }
+                Ok(IdempotentResult::Applied) => Ok(()),
+                Err(e) => Err(e),
+            },
+        };
+        if let Err(e) = result {
             log::warn!(
                 "Error processing transaction id={} client={}: {}",
                 record.tx,
@@ -68,16 +228,61 @@ fn process_transactions_from_filepath(filepath: &PathBuf) -> Result<Ledger, Box<
             );
         }
     }
+    process_batch(&mut ledger, batch)?;
     Ok(ledger)
 }
 
-pub fn write_to_std_out(ledger: &Ledger) -> Result<(), Box<dyn Error>> {
+// Records of a batch read so far, `invalid` telling why the whole batch must be rejected
+#[derive(Default)]
+struct PendingBatch {
+    id: Option<String>,
+    records: Vec<InputRecord>,
+    invalid: Option<String>,
+}
+
+fn process_batch(ledger: &mut Ledger, batch: PendingBatch) -> Result<(), Box<dyn Error>> {
+    let Some(batch_id) = batch.id else {
+        return Ok(());
+    };
+    if let Some(reason) = batch.invalid {
+        log::warn!("Error processing batch {batch_id}: {reason}, its records are rejected");
+        return Ok(());
+    }
+    let transactions: Vec<Transaction> = batch.records.iter().map(|r| r.to_transaction()).collect();
+    log::debug!("Processing batch {batch_id} in ledger: {transactions:?}");
+    match ledger.process_batch(&transactions) {
+        Ok(()) => Ok(()),
+        Err(e @ BatchError::Rejected { .. }) => {
+            log::warn!("Error processing batch {batch_id}: {e}");
+            Ok(())
+        }
+        Err(e) => Err(e)?,
+    }
+}
+
+pub fn write_to_std_out(
+    ledger: &Ledger,
+    join_registry: bool,
+    by_wallet: bool,
+) -> Result<(), Box<dyn Error>> {
     let mut wtr = csv::Writer::from_writer(std::io::stdout());
 
     log::debug!("Starting account snapshot serialisation");
-    for acc in ledger.account_snapshots() {
-        log::debug!("Serialising account snapshot: {acc:?}");
-        wtr.serialize(acc)?;
+    if by_wallet {
+        for wallet in ledger.wallet_snapshots() {
+            log::debug!("Serialising wallet snapshot: {wallet:?}");
+            wtr.serialize(wallet)?;
+        }
+    } else if join_registry {
+        for row in ledger.registered_snapshots() {
+            log::debug!("Serialising account snapshot: {row:?}");
+            wtr.serialize(row)?;
+        }
+    } else {
+        for acc in ledger.account_snapshots() {
+            log::debug!("Serialising account snapshot: {acc:?}");
+            wtr.serialize(acc)?;
+        }
     }
 
     log::debug!("Account snapshot serialisation done -> Flushing to stdout");
@@ -85,3 +290,156 @@ pub fn write_to_std_out(ledger: &Ledger) -> Result<(), Box<dyn Error>> {
 
     Ok(())
 }
+
+pub fn write_snapshots_at_to_std_out(
+    ledger: &Ledger,
+    point: PointInTime,
+) -> Result<(), Box<dyn Error>> {
+    let mut wtr = csv::Writer::from_writer(std::io::stdout());
+    for snapshot in ledger.snapshots_at(point)? {
+        wtr.serialize(snapshot)?;
+    }
+    wtr.flush()?;
+
+    Ok(())
+}
+
+pub fn write_history_to_std_out(
+    ledger: &Ledger,
+    client: u16,
+    tx: Option<u32>,
+) -> Result<(), Box<dyn Error>> {
+    let mut wtr = csv::Writer::from_writer(std::io::stdout());
+
+    match tx {
+        None => {
+            let history = ledger
+                .client_history(client)
+                .ok_or_else(|| format!("unknown client {client}"))?;
+            for entry in history {
+                wtr.serialize(entry)?;
+            }
+        }
+        Some(tx_id) => {
+            let lifecycle = ledger
+                .transaction_lifecycle(tx_id)
+                .filter(|l| l.entry.client == client)
+                .ok_or_else(|| format!("unknown tx {tx_id} for client {client}"))?;
+            for event in lifecycle.events {
+                wtr.serialize(event)?;
+            }
+        }
+    }
+
+    wtr.flush()?;
+
+    Ok(())
+}
+
+pub fn write_statements_to_std_out(
+    ledger: &Ledger,
+    client: Option<u16>,
+    period: StatementPeriod,
+    format: StatementFormat,
+    join_registry: bool,
+) -> Result<(), Box<dyn Error>> {
+    let statements = match client {
+        None => ledger.statements(period)?,
+        Some(client) => vec![
+            ledger
+                .statement(client, period)
+                .ok_or_else(|| format!("unknown client {client}"))??,
+        ],
+    };
+
+    match format {
+        StatementFormat::Csv => {
+            let mut wtr = csv::Writer::from_writer(std::io::stdout());
+            for statement in &statements {
+                if join_registry {
+                    statement.write_csv_with_holder(&mut wtr)?;
+                } else {
+                    statement.write_csv(&mut wtr)?;
+                }
+            }
+            wtr.flush()?;
+        }
+        StatementFormat::Markdown => {
+            let mut out = std::io::stdout().lock();
+            for statement in &statements {
+                statement.write_markdown(&mut out)?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+pub fn write_reconciliation_to_std_out(
+    ledger: &Ledger,
+    external: &Path,
+) -> Result<(), Box<dyn Error>> {
+    let discrepancies = ledger.reconcile(read_snapshots(external)?);
+
+    let mut wtr = csv::Writer::from_writer(std::io::stdout());
+    for discrepancy in &discrepancies {
+        wtr.serialize(discrepancy)?;
+    }
+    wtr.flush()?;
+
+    if !discrepancies.is_empty() {
+        Err(format!(
+            "{} discrepancies with {}",
+            discrepancies.len(),
+            external.display()
+        ))?
+    }
+    Ok(())
+}
+
+pub fn write_snapshot_diff_to_std_out(before: &Path, after: &Path) -> Result<(), Box<dyn Error>> {
+    let diff = SnapshotDiff::new(read_snapshots(before)?, read_snapshots(after)?)?;
+
+    let mut wtr = csv::Writer::from_writer(std::io::stdout());
+    diff.write_csv(&mut wtr)?;
+    wtr.flush()?;
+
+    Ok(())
+}
+
+// Account snapshots of a CSV file written by a previous run, or of the same shape
+fn read_snapshots(path: &Path) -> Result<Vec<AccountSnapshot>, Box<dyn Error>> {
+    let file = File::open(path)
+        .map_err(|err| format!("cannot read snapshots {}: {err}", path.display()))?;
+    let mut rdr = csv::ReaderBuilder::new().trim(Trim::All).from_reader(file);
+    Ok(rdr
+        .deserialize::<AccountSnapshot>()
+        .collect::<Result<Vec<_>, _>>()?)
+}
+
+pub fn write_aml_report_to_std_out(
+    ledger: &Ledger,
+    thresholds: &AmlThresholds,
+) -> Result<(), Box<dyn Error>> {
+    let mut wtr = csv::Writer::from_writer(std::io::stdout());
+    for row in ledger.aml_report(thresholds)? {
+        wtr.serialize(row)?;
+    }
+    wtr.flush()?;
+    Ok(())
+}
+
+pub fn write_trial_balance_to_std_out(ledger: &Ledger) -> Result<(), Box<dyn Error>> {
+    let trial_balance = ledger.trial_balance()?;
+
+    let mut wtr = csv::Writer::from_writer(std::io::stdout());
+    trial_balance.write_csv(&mut wtr)?;
+    wtr.flush()?;
+
+    if !trial_balance.is_balanced {
+        Err("trial balance does not sum to zero")?
+    }
+    ledger.verify_journal()?;
+
+    Ok(())
+}
@@ -0,0 +1,387 @@
+use crab_cash::engine::{IdempotentResult, InputRecord, Ledger, PointInTime, RecordType};
+use csv::Trim;
+use std::error::Error;
+use std::fs::File;
+use std::io::{BufRead, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+const HELP: &str = "\
+Commands:
+  <type> <client> <tx> [amount] [timestamp=<ts>] [wallet=<name>] [to_wallet=<name>] [seller=<client>]
+                     apply a record, e.g. `deposit 1 10 5.0` or `dispute 1 10`
+  show [client]      snapshot of a client, or of every client
+  history <client>   transaction history of a client
+  undo               revert the last record
+  save <file.csv>    write the records of the session, in the format read by `load`
+  load <file.csv>    start a new session from the records of a file
+  help               print this help
+  quit               leave the shell
+";
+
+/// Command of the interactive shell
+#[derive(Debug)]
+pub enum ReplCommand {
+    Apply(Box<InputRecord>),
+    Show(Option<u16>),
+    History(u16),
+    Undo,
+    Save(PathBuf),
+    Load(PathBuf),
+    Help,
+    Quit,
+}
+
+/// Interactive session over a ledger, which must keep a rollback log for `undo`.
+/// The records of the session are kept with the sequence of the ledger before each of them, to
+/// roll the ledger back before the last one.
+pub struct Repl<F> {
+    new_ledger: F,
+    ledger: Ledger,
+    records: Vec<(u64, InputRecord)>,
+}
+
+impl FromStr for ReplCommand {
+    type Err = Box<dyn Error>;
+
+    fn from_str(line: &str) -> Result<Self, Self::Err> {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let client = |word: Option<&&str>| -> Result<u16, Box<dyn Error>> {
+            Ok(word.ok_or("expected a client")?.parse::<u16>()?)
+        };
+        let path = |word: Option<&&str>| -> Result<PathBuf, Box<dyn Error>> {
+            Ok(PathBuf::from(word.ok_or("expected a file")?))
+        };
+
+        match words.as_slice() {
+            ["show"] => Ok(ReplCommand::Show(None)),
+            ["show", id] => Ok(ReplCommand::Show(Some(client(Some(id))?))),
+            ["history", rest @ ..] if rest.len() <= 1 => {
+                Ok(ReplCommand::History(client(rest.first())?))
+            }
+            ["undo"] => Ok(ReplCommand::Undo),
+            ["save", rest @ ..] if rest.len() <= 1 => Ok(ReplCommand::Save(path(rest.first())?)),
+            ["load", rest @ ..] if rest.len() <= 1 => Ok(ReplCommand::Load(path(rest.first())?)),
+            ["help"] => Ok(ReplCommand::Help),
+            ["quit" | "exit"] => Ok(ReplCommand::Quit),
+            [typ, id, tx, fields @ ..] => {
+                let typ = RecordType::from_str(typ)?;
+                let mut record = InputRecord {
+                    typ,
+                    client: client(Some(id))?,
+                    tx: tx.parse::<u32>()?,
+                    amount: None,
+                    timestamp: None,
+                    batch_id: None,
+                    idempotency_key: None,
+                    wallet: None,
+                    to_wallet: None,
+                    seller: None,
+                };
+                for (i, field) in fields.iter().enumerate() {
+                    match field.split_once('=') {
+                        None if i == 0 => record.amount = Some(field.to_string()),
+                        Some(("timestamp", ts)) => record.timestamp = Some(ts.parse::<u64>()?),
+                        Some(("wallet", wallet)) => record.wallet = Some(wallet.to_string()),
+                        Some(("to_wallet", wallet)) => record.to_wallet = Some(wallet.to_string()),
+                        Some(("seller", seller)) => record.seller = Some(client(Some(&seller))?),
+                        _ => Err(format!("unexpected argument {field}"))?,
+                    }
+                }
+                Ok(ReplCommand::Apply(Box::new(record)))
+            }
+            _ => Err(format!("unknown command {line:?}, try `help`"))?,
+        }
+    }
+}
+
+impl<F> Repl<F>
+where
+    F: Fn() -> Result<Ledger, Box<dyn Error>>,
+{
+    /// New session over the ledger built by `new_ledger`, also used to rebuild it on `load`
+    pub fn new(new_ledger: F) -> Result<Self, Box<dyn Error>> {
+        Ok(Repl {
+            ledger: new_ledger()?,
+            new_ledger,
+            records: vec![],
+        })
+    }
+
+    /// Read commands from `input` until it is over or `quit` is entered, printing a prompt before
+    /// each one when `prompt` is set. Errors of a command are printed and the session goes on.
+    pub fn run(
+        &mut self,
+        input: impl BufRead,
+        out: &mut impl Write,
+        prompt: bool,
+    ) -> Result<(), Box<dyn Error>> {
+        let mut lines = input.lines();
+        loop {
+            if prompt {
+                write!(out, "> ")?;
+                out.flush()?;
+            }
+            let Some(line) = lines.next().transpose()? else {
+                return Ok(());
+            };
+            if line.trim().is_empty() {
+                continue;
+            }
+            match ReplCommand::from_str(&line) {
+                Ok(ReplCommand::Quit) => return Ok(()),
+                Ok(command) => {
+                    if let Err(e) = self.execute(command, out) {
+                        writeln!(out, "error: {e}")?;
+                    }
+                }
+                Err(e) => writeln!(out, "error: {e}")?,
+            }
+        }
+    }
+
+    /// Execute a command and print its outcome followed by the snapshots it affected
+    pub fn execute(
+        &mut self,
+        command: ReplCommand,
+        out: &mut impl Write,
+    ) -> Result<(), Box<dyn Error>> {
+        match command {
+            ReplCommand::Apply(record) => {
+                let client = record.client;
+                let sequence = self.ledger.sequence();
+                if let Err(e) = apply(&mut self.ledger, &record) {
+                    writeln!(out, "rejected: {e}")?;
+                }
+                self.records.push((sequence, *record));
+                self.show(Some(client), out)
+            }
+            ReplCommand::Show(client) => self.show(client, out),
+            ReplCommand::History(client) => {
+                let history = self
+                    .ledger
+                    .client_history(client)
+                    .ok_or_else(|| format!("unknown client {client}"))?;
+                let mut wtr = csv::Writer::from_writer(out);
+                for entry in history {
+                    wtr.serialize(entry)?;
+                }
+                wtr.flush()?;
+                Ok(())
+            }
+            ReplCommand::Undo => {
+                let (sequence, record) = self.records.pop().ok_or("nothing to undo")?;
+                self.ledger.rollback_to(PointInTime::Sequence(sequence))?;
+                writeln!(out, "undone: {:?} of tx {}", record.typ, record.tx)?;
+                match self.ledger.client_snapshot(record.client) {
+                    Some(_) => self.show(Some(record.client), out),
+                    None => self.show(None, out),
+                }
+            }
+            ReplCommand::Save(path) => {
+                let mut wtr = csv::Writer::from_path(&path)?;
+                for (_, record) in &self.records {
+                    wtr.serialize(record)?;
+                }
+                wtr.flush()?;
+                writeln!(
+                    out,
+                    "saved {} records to {}",
+                    self.records.len(),
+                    path.display()
+                )?;
+                Ok(())
+            }
+            ReplCommand::Load(path) => {
+                let records = read_records(&path)?;
+                self.replay(records)?;
+                writeln!(
+                    out,
+                    "loaded {} records from {}",
+                    self.records.len(),
+                    path.display()
+                )?;
+                self.show(None, out)
+            }
+            ReplCommand::Help => Ok(write!(out, "{HELP}")?),
+            ReplCommand::Quit => Ok(()),
+        }
+    }
+
+    /// Start the session over from a new ledger with `records`
+    pub fn replay(&mut self, records: Vec<InputRecord>) -> Result<(), Box<dyn Error>> {
+        self.ledger = (self.new_ledger)()?;
+        self.records.clear();
+        for record in records {
+            let sequence = self.ledger.sequence();
+            if let Err(e) = apply(&mut self.ledger, &record) {
+                log::debug!("Replayed record of tx {} rejected: {e}", record.tx);
+            }
+            self.records.push((sequence, record));
+        }
+        Ok(())
+    }
+
+    fn show(&self, client: Option<u16>, out: &mut impl Write) -> Result<(), Box<dyn Error>> {
+        let mut wtr = csv::Writer::from_writer(out);
+        match client {
+            Some(client) => wtr.serialize(
+                self.ledger
+                    .client_snapshot(client)
+                    .ok_or_else(|| format!("unknown client {client}"))?,
+            )?,
+            None => {
+                for snapshot in self.ledger.account_snapshots() {
+                    wtr.serialize(snapshot)?;
+                }
+            }
+        }
+        wtr.flush()?;
+        Ok(())
+    }
+}
+
+fn apply(ledger: &mut Ledger, record: &InputRecord) -> Result<(), Box<dyn Error>> {
+    if record.batch_id.is_some() {
+        Err("batches are not supported in the shell")?
+    }
+    let transaction = record.to_transaction();
+    match &record.idempotency_key {
+        None => ledger.process_transaction(&transaction)?,
+        Some(key) => match ledger.process_idempotent(key, &transaction)? {
+            IdempotentResult::Applied => {}
+            IdempotentResult::Retried(outcome) => {
+                log::info!("Retry of request {key} acknowledged, it was {outcome}");
+            }
+        },
+    }
+    Ok(())
+}
+
+// Records of a CSV file, skipping the malformed ones
+fn read_records(path: &Path) -> Result<Vec<InputRecord>, Box<dyn Error>> {
+    let file = File::open(path).map_err(|e| format!("cannot read {}: {e}", path.display()))?;
+    let mut rdr = csv::ReaderBuilder::new().trim(Trim::All).from_reader(file);
+    let mut records = vec![];
+    for result in rdr.deserialize::<InputRecord>() {
+        match result {
+            Ok(record) => records.push(record),
+            Err(e) => log::warn!("Error deserializing record:{e}"),
+        }
+    }
+    Ok(records)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crab_cash::engine::LedgerConfig;
+
+    const HEADER: &str = "client,available,held,total,locked\n";
+
+    type NewLedger = fn() -> Result<Ledger, Box<dyn Error>>;
+
+    fn new_ledger() -> Result<Ledger, Box<dyn Error>> {
+        let mut config = LedgerConfig::default();
+        config.retention.rollback_log = true;
+        Ok(Ledger::with_config(config))
+    }
+
+    fn repl() -> Repl<NewLedger> {
+        Repl::new(new_ledger as NewLedger).unwrap()
+    }
+
+    fn run<F: Fn() -> Result<Ledger, Box<dyn Error>>>(repl: &mut Repl<F>, input: &str) -> String {
+        let mut out = vec![];
+        repl.run(input.as_bytes(), &mut out, false).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn test_that_shell_applies_records_and_shows_the_client() {
+        let mut repl = repl();
+
+        assert_eq!(
+            run(&mut repl, "deposit 1 10 5.0\n"),
+            format!("{HEADER}1,5.0000,0.0000,5.0000,false\n")
+        );
+        assert_eq!(
+            run(&mut repl, "dispute 1 10\n"),
+            format!("{HEADER}1,0.0000,5.0000,5.0000,false\n")
+        );
+        assert_eq!(
+            run(&mut repl, "show 1\n"),
+            format!("{HEADER}1,0.0000,5.0000,5.0000,false\n")
+        );
+    }
+
+    #[test]
+    fn test_that_shell_reports_rejected_records() {
+        let mut repl = repl();
+        run(&mut repl, "deposit 1 10 5.0\n");
+
+        assert!(run(&mut repl, "withdrawal 1 11 6\n").starts_with("rejected: "));
+        assert_eq!(
+            run(&mut repl, "show 1\n"),
+            format!("{HEADER}1,5.0000,0.0000,5.0000,false\n")
+        );
+    }
+
+    #[test]
+    fn test_that_shell_shows_the_history_of_a_client() {
+        let mut repl = repl();
+        run(&mut repl, "deposit 1 10 5.0\ndispute 1 10\n");
+
+        assert!(run(&mut repl, "history 1\n").contains("\n1,,1,10,deposit,5.0000,disputed\n"));
+    }
+
+    #[test]
+    fn test_that_shell_undoes_the_last_records() {
+        let mut repl = repl();
+        run(
+            &mut repl,
+            "deposit 1 10 5.0\ndispute 1 10\nwithdrawal 1 11 1\n",
+        );
+
+        assert_eq!(
+            run(&mut repl, "undo\nundo\n"),
+            format!(
+                "undone: Withdrawal of tx 11\n\
+                 {HEADER}1,0.0000,5.0000,5.0000,false\n\
+                 undone: Dispute of tx 10\n\
+                 {HEADER}1,5.0000,0.0000,5.0000,false\n"
+            )
+        );
+    }
+
+    #[test]
+    fn test_that_shell_reports_invalid_commands() {
+        let mut repl = repl();
+
+        assert!(run(&mut repl, "refund 1 12\n").starts_with("error: "));
+        assert_eq!(run(&mut repl, "show 2\n"), "error: unknown client 2\n");
+    }
+
+    #[test]
+    fn test_that_shell_reloads_saved_records() {
+        let mut repl = repl();
+        let path = std::env::temp_dir().join(format!("crab_cash_repl_{}.csv", std::process::id()));
+        let path = path.display();
+        run(
+            &mut repl,
+            &format!("deposit 1 10 5.0\ndeposit 2 20 1 wallet=savings\nsave {path}\nundo\nundo\n"),
+        );
+        assert_eq!(run(&mut repl, "show\n"), "");
+
+        assert_eq!(
+            run(&mut repl, &format!("load {path}\nquit\nshow\n")),
+            format!(
+                "loaded 2 records from {path}\n\
+                 {HEADER}\
+                 1,5.0000,0.0000,5.0000,false\n\
+                 2,1.0000,0.0000,1.0000,false\n"
+            )
+        );
+        std::fs::remove_file(path.to_string()).unwrap();
+    }
+}
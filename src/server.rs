@@ -0,0 +1,378 @@
+use crab_cash::engine::{IdempotentResult, InputRecord, Ledger, LedgerError};
+use serde_json::{Value, json};
+use std::error::Error;
+use std::io::Read;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::thread;
+use tiny_http::{Header, Method, Request, Response, Server};
+
+/// Largest request body accepted, records being small JSON objects
+pub const MAX_BODY_BYTES: usize = 64 * 1024;
+
+/// Request understood by the API
+///
+/// - `POST /transactions`: apply one record, given as a JSON object with the CSV columns
+/// - `GET /clients`: snapshots of every client
+/// - `GET /clients/<id>`: snapshot of a client
+/// - `GET /clients/<id>/history`: transaction history of a client
+#[derive(Debug)]
+pub enum ApiRequest {
+    Transaction(InputRecord),
+    Snapshots,
+    Snapshot(u16),
+    History(u16),
+}
+
+/// JSON answer with its HTTP status
+#[derive(Debug, PartialEq)]
+pub struct ApiResponse {
+    pub status: u16,
+    pub body: Value,
+}
+
+impl ApiResponse {
+    fn ok(body: Value) -> Self {
+        ApiResponse { status: 200, body }
+    }
+
+    fn error(status: u16, code: &str, message: impl ToString) -> Self {
+        ApiResponse {
+            status,
+            body: json!({ "error": { "code": code, "message": message.to_string() } }),
+        }
+    }
+}
+
+/// Serve the API on `addr` until the process is stopped.
+/// Requests are read, parsed and answered by `workers` threads, while the ledger applies them
+/// one at a time. A request whose handling panics gets a 500 and leaves the ledger usable.
+pub fn serve(ledger: Ledger, addr: &str, workers: usize) -> Result<(), Box<dyn Error>> {
+    let server = Arc::new(Server::http(addr).map_err(|e| format!("cannot listen on {addr}: {e}"))?);
+    let content_type = Header::from_bytes("Content-Type", "application/json")
+        .map_err(|()| "invalid Content-Type header")?;
+    let ledger = Arc::new(Mutex::new(ledger));
+    log::info!("Serving the ledger API on http://{addr} with {workers} workers");
+
+    let handles: Vec<_> = (0..workers.max(1))
+        .map(|_| {
+            let server = Arc::clone(&server);
+            let ledger = Arc::clone(&ledger);
+            let content_type = content_type.clone();
+            thread::spawn(move || {
+                for request in server.incoming_requests() {
+                    serve_request(request, &ledger, &content_type);
+                }
+            })
+        })
+        .collect();
+    for handle in handles {
+        if handle.join().is_err() {
+            Err("API worker stopped unexpectedly")?
+        }
+    }
+    Ok(())
+}
+
+fn serve_request(mut request: Request, ledger: &Mutex<Ledger>, content_type: &Header) {
+    let length = request.body_length();
+    let body = match read_body(request.as_reader(), length) {
+        Ok(body) => body,
+        Err(response) => return respond(request, response, content_type),
+    };
+    log::debug!("{} {} {body}", request.method(), request.url());
+
+    let response = match parse_request(request.method(), request.url(), &body) {
+        Ok(api_request) => apply(ledger, api_request),
+        Err(response) => response,
+    };
+    respond(request, response, content_type);
+}
+
+// A panic while the ledger is held answers a 500 without making the ledger unusable for the
+// requests that follow
+fn apply(ledger: &Mutex<Ledger>, request: ApiRequest) -> ApiResponse {
+    let mut ledger = ledger.lock().unwrap_or_else(PoisonError::into_inner);
+    panic::catch_unwind(AssertUnwindSafe(|| handle(&mut ledger, request)))
+        .unwrap_or_else(|_| ApiResponse::error(500, "internal_error", "request failed"))
+}
+
+/// Read a request body of at most `MAX_BODY_BYTES`, whether its length is announced or not
+pub fn read_body(reader: impl Read, length: Option<usize>) -> Result<String, ApiResponse> {
+    let too_large = || {
+        ApiResponse::error(
+            413,
+            "body_too_large",
+            format!("bodies are limited to {MAX_BODY_BYTES} bytes"),
+        )
+    };
+    if length.is_some_and(|length| length > MAX_BODY_BYTES) {
+        Err(too_large())?
+    }
+    let mut body = String::new();
+    reader
+        .take(MAX_BODY_BYTES as u64 + 1)
+        .read_to_string(&mut body)
+        .map_err(|e| ApiResponse::error(400, "invalid_body", e))?;
+    if body.len() > MAX_BODY_BYTES {
+        Err(too_large())?
+    }
+    Ok(body)
+}
+
+/// Route a request, rejecting unknown routes and malformed bodies
+pub fn parse_request(method: &Method, url: &str, body: &str) -> Result<ApiRequest, ApiResponse> {
+    let path = url.split('?').next().unwrap_or_default();
+    let segments: Vec<&str> = path.split('/').filter(|s| !s.is_empty()).collect();
+    let client = |id: &str| {
+        id.parse::<u16>()
+            .map_err(|_| ApiResponse::error(400, "invalid_client", format!("invalid client {id}")))
+    };
+
+    match (method, segments.as_slice()) {
+        (Method::Post, ["transactions"]) => {
+            let record: InputRecord = serde_json::from_str(body)
+                .map_err(|e| ApiResponse::error(400, "invalid_record", e))?;
+            if record.batch_id.is_some() {
+                Err(ApiResponse::error(
+                    400,
+                    "unsupported",
+                    "batches are not supported by the API",
+                ))?
+            }
+            Ok(ApiRequest::Transaction(record))
+        }
+        (Method::Get, ["clients"]) => Ok(ApiRequest::Snapshots),
+        (Method::Get, ["clients", id]) => Ok(ApiRequest::Snapshot(client(id)?)),
+        (Method::Get, ["clients", id, "history"]) => Ok(ApiRequest::History(client(id)?)),
+        (_, ["transactions"] | ["clients"] | ["clients", _] | ["clients", _, "history"]) => Err(
+            ApiResponse::error(405, "method_not_allowed", format!("{method} not allowed")),
+        ),
+        _ => Err(ApiResponse::error(
+            404,
+            "not_found",
+            format!("no route for {path}"),
+        )),
+    }
+}
+
+/// Apply a request to the ledger
+pub fn handle(ledger: &mut Ledger, request: ApiRequest) -> ApiResponse {
+    match request {
+        ApiRequest::Transaction(record) => {
+            let transaction = record.to_transaction();
+            let result = match &record.idempotency_key {
+                None => ledger.process_transaction(&transaction).map(|()| "applied"),
+                Some(key) => match ledger.process_idempotent(key, &transaction) {
+                    Ok(IdempotentResult::Applied) => Ok("applied"),
+                    Ok(IdempotentResult::Retried(outcome)) => {
+                        return ApiResponse::ok(json!({
+                            "status": "retried",
+                            "client": record.client,
+                            "tx": record.tx,
+                            "outcome": outcome.to_string(),
+                        }));
+                    }
+                    Err(e) => Err(e),
+                },
+            };
+            match result {
+                Ok(status) => ApiResponse::ok(json!({
+                    "status": status,
+                    "client": record.client,
+                    "tx": record.tx,
+                })),
+                Err(e) => {
+                    log::warn!(
+                        "Error processing transaction id={} client={}: {}",
+                        record.tx,
+                        record.client,
+                        e
+                    );
+                    let status = match e {
+                        LedgerError::Storage(_)
+                        | LedgerError::Journal(_)
+                        | LedgerError::JournalMismatch(_) => 500,
+                        _ => 422,
+                    };
+                    ApiResponse::error(status, e.code(), &e)
+                }
+            }
+        }
+        ApiRequest::Snapshots => {
+            ApiResponse::ok(json!(ledger.account_snapshots().collect::<Vec<_>>()))
+        }
+        ApiRequest::Snapshot(client) => match ledger.client_snapshot(client) {
+            Some(snapshot) => ApiResponse::ok(json!(snapshot)),
+            None => unknown_client(client),
+        },
+        ApiRequest::History(client) => match ledger.client_history(client) {
+            Some(history) => ApiResponse::ok(json!(history)),
+            None => unknown_client(client),
+        },
+    }
+}
+
+fn unknown_client(client: u16) -> ApiResponse {
+    ApiResponse::error(404, "unknown_client", format!("unknown client {client}"))
+}
+
+fn respond(request: Request, response: ApiResponse, content_type: &Header) {
+    let result = request.respond(
+        Response::from_string(response.body.to_string())
+            .with_status_code(response.status)
+            .with_header(content_type.clone()),
+    );
+    if let Err(e) = result {
+        log::warn!("Error writing response: {e}");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn call(ledger: &mut Ledger, method: Method, url: &str, body: &str) -> ApiResponse {
+        match parse_request(&method, url, body) {
+            Ok(request) => handle(ledger, request),
+            Err(response) => response,
+        }
+    }
+
+    fn deposit(ledger: &mut Ledger, client: u16, tx: u32, amount: &str) -> ApiResponse {
+        let body = format!(
+            r#"{{"type": "deposit", "client": {client}, "tx": {tx}, "amount": "{amount}"}}"#
+        );
+        call(ledger, Method::Post, "/transactions", &body)
+    }
+
+    #[test]
+    fn test_that_api_applies_and_rejects_transactions() {
+        let mut ledger = Ledger::new();
+
+        let response = deposit(&mut ledger, 1, 1, "10.5");
+        assert_eq!(response.status, 200);
+        assert_eq!(response.body["status"], "applied");
+
+        let response = call(
+            &mut ledger,
+            Method::Post,
+            "/transactions",
+            r#"{"type": "withdrawal", "client": 1, "tx": 2, "amount": "11"}"#,
+        );
+        assert_eq!(response.status, 422);
+        assert_eq!(response.body["error"]["code"], "withdrawal_limit_exceeded");
+    }
+
+    #[test]
+    fn test_that_api_reports_snapshots() {
+        let mut ledger = Ledger::new();
+        deposit(&mut ledger, 1, 1, "10.5");
+
+        let response = call(&mut ledger, Method::Get, "/clients/1", "");
+        assert_eq!(response.status, 200);
+        assert_eq!(response.body["available"], "10.5000");
+        let response = call(&mut ledger, Method::Get, "/clients", "");
+        assert_eq!(response.body.as_array().unwrap().len(), 1);
+
+        let response = call(&mut ledger, Method::Get, "/clients/2", "");
+        assert_eq!(response.status, 404);
+        assert_eq!(response.body["error"]["code"], "unknown_client");
+    }
+
+    #[test]
+    fn test_that_api_reports_the_history_of_one_client() {
+        let mut ledger = Ledger::new();
+        deposit(&mut ledger, 1, 1, "10.5");
+        deposit(&mut ledger, 2, 2, "3");
+        deposit(&mut ledger, 1, 3, "1");
+
+        let response = call(&mut ledger, Method::Get, "/clients/1/history", "");
+        assert_eq!(response.status, 200);
+        let txs: Vec<_> = response
+            .body
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|tx| tx["tx"].clone())
+            .collect();
+        assert_eq!(txs, vec![json!(1), json!(3)]);
+
+        let response = call(&mut ledger, Method::Get, "/clients/3/history", "");
+        assert_eq!(response.status, 404);
+        assert_eq!(response.body["error"]["code"], "unknown_client");
+    }
+
+    #[test]
+    fn test_that_unknown_routes_are_not_found() {
+        let mut ledger = Ledger::new();
+
+        for url in ["/", "/accounts", "/clients/1/history/1", "/transactions/1"] {
+            let response = call(&mut ledger, Method::Get, url, "");
+            assert_eq!(response.status, 404, "{url}");
+            assert_eq!(response.body["error"]["code"], "not_found");
+        }
+        let response = call(&mut ledger, Method::Delete, "/clients", "");
+        assert_eq!(response.status, 405);
+        assert_eq!(response.body["error"]["code"], "method_not_allowed");
+    }
+
+    #[test]
+    fn test_that_malformed_requests_are_rejected() {
+        let mut ledger = Ledger::new();
+
+        for body in ["{", "[]", r#"{"type": "deposit", "client": 1}"#] {
+            let response = call(&mut ledger, Method::Post, "/transactions", body);
+            assert_eq!(response.status, 400, "{body}");
+            assert_eq!(response.body["error"]["code"], "invalid_record");
+        }
+        let response = call(
+            &mut ledger,
+            Method::Post,
+            "/transactions",
+            r#"{"type": "deposit", "client": 1, "tx": 1, "amount": "1", "batch_id": "7"}"#,
+        );
+        assert_eq!(response.status, 400);
+        assert_eq!(response.body["error"]["code"], "unsupported");
+
+        let response = call(&mut ledger, Method::Get, "/clients/x", "");
+        assert_eq!(response.status, 400);
+        assert_eq!(response.body["error"]["code"], "invalid_client");
+        assert_eq!(ledger.account_snapshots().count(), 0);
+    }
+
+    #[test]
+    fn test_that_request_bodies_are_capped() {
+        let body = "x".repeat(MAX_BODY_BYTES);
+        assert_eq!(read_body(body.as_bytes(), None).unwrap(), body);
+
+        let too_large = "x".repeat(MAX_BODY_BYTES + 1);
+        for length in [None, Some(too_large.len())] {
+            let response = read_body(too_large.as_bytes(), length).unwrap_err();
+            assert_eq!(response.status, 413);
+            assert_eq!(response.body["error"]["code"], "body_too_large");
+        }
+        // An announced length is enough to refuse the body without reading it
+        assert_eq!(
+            read_body("".as_bytes(), Some(MAX_BODY_BYTES + 1))
+                .unwrap_err()
+                .status,
+            413
+        );
+    }
+
+    #[test]
+    fn test_that_poisoned_ledger_keeps_serving() {
+        let ledger = Arc::new(Mutex::new(Ledger::new()));
+        let poisoner = Arc::clone(&ledger);
+        let result = thread::spawn(move || {
+            let _guard = poisoner.lock().unwrap();
+            panic!("handler panicked");
+        })
+        .join();
+        assert!(result.is_err() && ledger.is_poisoned());
+
+        let request = parse_request(&Method::Get, "/clients", "").unwrap();
+        assert_eq!(apply(&ledger, request).status, 200);
+    }
+}
@@ -0,0 +1,512 @@
+use crab_cash::engine::{IdempotentOutcome, IdempotentResult, InputRecord, Ledger};
+use csv::{StringRecord, Trim};
+use serde::Serialize;
+use std::error::Error;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+// How often idle connections and the listener check for a shutdown request
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Where the stream of records is received
+#[derive(Debug, Clone, PartialEq)]
+pub enum Endpoint {
+    Tcp(String),
+    #[cfg(unix)]
+    Unix(PathBuf),
+}
+
+/// Format of the records (and of the acknowledgements written back)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StreamFormat {
+    /// CSV lines, the first line of every connection being the header
+    Csv,
+    /// One JSON object per line
+    Jsonl,
+}
+
+/// Acknowledgement of one record
+#[derive(Serialize, Debug, PartialEq)]
+pub struct Ack {
+    pub status: AckStatus,
+    pub client: Option<u16>,
+    pub tx: Option<u32>,
+    pub code: Option<String>,
+    pub reason: Option<String>,
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum AckStatus {
+    Accepted,
+    Rejected,
+}
+
+enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixListener, PathBuf),
+}
+
+enum Connection {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+}
+
+impl Listener {
+    fn bind(endpoint: &Endpoint) -> io::Result<Self> {
+        let listener = match endpoint {
+            Endpoint::Tcp(addr) => Listener::Tcp(TcpListener::bind(addr)?),
+            #[cfg(unix)]
+            Endpoint::Unix(path) => Listener::Unix(UnixListener::bind(path)?, path.clone()),
+        };
+        // Non blocking, to notice shutdown requests between connections
+        match &listener {
+            Listener::Tcp(l) => l.set_nonblocking(true)?,
+            #[cfg(unix)]
+            Listener::Unix(l, _) => l.set_nonblocking(true)?,
+        }
+        Ok(listener)
+    }
+
+    fn accept(&self) -> io::Result<Connection> {
+        let connection = match self {
+            Listener::Tcp(l) => Connection::Tcp(l.accept()?.0),
+            #[cfg(unix)]
+            Listener::Unix(l, _) => Connection::Unix(l.accept()?.0),
+        };
+        // Connections block on reads for at most POLL_INTERVAL, to notice shutdown requests
+        match &connection {
+            Connection::Tcp(s) => {
+                s.set_nonblocking(false)?;
+                s.set_read_timeout(Some(POLL_INTERVAL))?;
+            }
+            #[cfg(unix)]
+            Connection::Unix(s) => {
+                s.set_nonblocking(false)?;
+                s.set_read_timeout(Some(POLL_INTERVAL))?;
+            }
+        }
+        Ok(connection)
+    }
+}
+
+impl Drop for Listener {
+    fn drop(&mut self) {
+        #[cfg(unix)]
+        if let Listener::Unix(_, path) = self {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+impl Connection {
+    fn try_clone(&self) -> io::Result<Self> {
+        Ok(match self {
+            Connection::Tcp(s) => Connection::Tcp(s.try_clone()?),
+            #[cfg(unix)]
+            Connection::Unix(s) => Connection::Unix(s.try_clone()?),
+        })
+    }
+}
+
+impl Read for Connection {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Connection::Tcp(s) => s.read(buf),
+            #[cfg(unix)]
+            Connection::Unix(s) => s.read(buf),
+        }
+    }
+}
+
+impl Write for Connection {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Connection::Tcp(s) => s.write(buf),
+            #[cfg(unix)]
+            Connection::Unix(s) => s.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Connection::Tcp(s) => s.flush(),
+            #[cfg(unix)]
+            Connection::Unix(s) => s.flush(),
+        }
+    }
+}
+
+/// Apply the records received on `endpoint` until SIGINT or SIGTERM, then return the ledger.
+/// Each connection is read one line at a time and the acknowledgement of a record is written
+/// before the next line is read, so that senders are slowed down to the pace of the ledger.
+/// At most `max_connections` are served at once, others wait to be accepted.
+pub fn stream(
+    ledger: Ledger,
+    endpoint: &Endpoint,
+    format: StreamFormat,
+    max_connections: usize,
+) -> Result<Ledger, Box<dyn Error>> {
+    let shutdown = Arc::new(AtomicBool::new(false));
+    for signal in [signal_hook::consts::SIGINT, signal_hook::consts::SIGTERM] {
+        signal_hook::flag::register(signal, Arc::clone(&shutdown))?;
+    }
+
+    let listener = Listener::bind(endpoint)?;
+    log::info!("Streaming {format:?} records from {endpoint:?}");
+
+    let ledger = Arc::new(Mutex::new(ledger));
+    let active = Arc::new(AtomicUsize::new(0));
+    let mut handlers: Vec<JoinHandle<()>> = vec![];
+    while !shutdown.load(Ordering::Relaxed) {
+        if active.load(Ordering::Relaxed) >= max_connections.max(1) {
+            thread::sleep(POLL_INTERVAL);
+            continue;
+        }
+        let connection = match listener.accept() {
+            Ok(connection) => connection,
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                thread::sleep(POLL_INTERVAL);
+                continue;
+            }
+            Err(e) => {
+                log::warn!("Error accepting connection: {e}");
+                continue;
+            }
+        };
+
+        active.fetch_add(1, Ordering::Relaxed);
+        let (ledger, active, shutdown) = (
+            Arc::clone(&ledger),
+            Arc::clone(&active),
+            Arc::clone(&shutdown),
+        );
+        handlers.retain(|handler| !handler.is_finished());
+        handlers.push(thread::spawn(move || {
+            if let Err(e) = serve_connection(connection, &ledger, format, &shutdown) {
+                log::warn!("Connection closed on error: {e}");
+            }
+            active.fetch_sub(1, Ordering::Relaxed);
+        }));
+    }
+
+    log::info!("Shutting down, waiting for {} connections", handlers.len());
+    drop(listener);
+    for handler in handlers {
+        if handler.join().is_err() {
+            log::warn!("Connection handler panicked");
+        }
+    }
+    let ledger = Arc::into_inner(ledger).ok_or("ledger still in use")?;
+    Ok(ledger
+        .into_inner()
+        .map_err(|_| "ledger poisoned by a panic")?)
+}
+
+fn serve_connection(
+    connection: Connection,
+    ledger: &Mutex<Ledger>,
+    format: StreamFormat,
+    shutdown: &AtomicBool,
+) -> Result<(), Box<dyn Error>> {
+    let mut writer = connection.try_clone()?;
+    let mut reader = BufReader::new(connection);
+    let mut headers = None;
+    let mut line = vec![];
+
+    loop {
+        match reader.read_until(b'\n', &mut line) {
+            Ok(read) => {
+                // A line without newline is the last one, sent right before closing
+                let closed = read == 0 || !line.ends_with(b"\n");
+                if let Some(ack) = process_line(ledger, format, &mut headers, &line)? {
+                    writer.write_all(ack.as_bytes())?;
+                    writer.flush()?;
+                }
+                line.clear();
+                if closed {
+                    return Ok(());
+                }
+            }
+            // Nothing received for a while: a partial line stays in `line` until its end arrives
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                ) =>
+            {
+                if shutdown.load(Ordering::Relaxed) {
+                    return Ok(());
+                }
+            }
+            Err(e) => Err(e)?,
+        }
+    }
+}
+
+/// Apply one line and return its acknowledgement line.
+/// Returns `None` for blank lines and for the CSV header, which is kept in `headers`.
+pub fn process_line(
+    ledger: &Mutex<Ledger>,
+    format: StreamFormat,
+    headers: &mut Option<StringRecord>,
+    line: &[u8],
+) -> Result<Option<String>, Box<dyn Error>> {
+    if line.iter().all(u8::is_ascii_whitespace) {
+        return Ok(None);
+    }
+
+    let record = match format {
+        StreamFormat::Jsonl => {
+            serde_json::from_slice::<InputRecord>(line).map_err(|e| e.to_string())
+        }
+        StreamFormat::Csv => {
+            let mut rdr = csv::ReaderBuilder::new()
+                .has_headers(false)
+                .trim(Trim::All)
+                .from_reader(line);
+            let fields = rdr.records().next().transpose()?.unwrap_or_default();
+            let Some(headers) = headers else {
+                *headers = Some(fields);
+                return Ok(None);
+            };
+            fields
+                .deserialize::<InputRecord>(Some(headers))
+                .map_err(|e| e.to_string())
+        }
+    };
+
+    let ack = match record {
+        Ok(record) => {
+            let mut ledger = ledger.lock().map_err(|_| "ledger poisoned by a panic")?;
+            apply(&mut ledger, &record)
+        }
+        Err(reason) => Ack {
+            status: AckStatus::Rejected,
+            client: None,
+            tx: None,
+            code: Some("invalid_record".into()),
+            reason: Some(reason),
+        },
+    };
+    Ok(Some(ack_line(&ack, format)?))
+}
+
+fn apply(ledger: &mut Ledger, record: &InputRecord) -> Ack {
+    let ack = |status, code: Option<&str>, reason: Option<String>| Ack {
+        status,
+        client: Some(record.client),
+        tx: Some(record.tx),
+        code: code.map(String::from),
+        reason,
+    };
+    if record.batch_id.is_some() {
+        return ack(
+            AckStatus::Rejected,
+            Some("unsupported"),
+            Some("batches are not supported when streaming".into()),
+        );
+    }
+
+    let transaction = record.to_transaction();
+    let result = match &record.idempotency_key {
+        None => ledger.process_transaction(&transaction),
+        Some(key) => match ledger.process_idempotent(key, &transaction) {
+            Ok(IdempotentResult::Applied) => Ok(()),
+            Ok(IdempotentResult::Retried(IdempotentOutcome::Applied)) => {
+                return ack(AckStatus::Accepted, Some("retry"), None);
+            }
+            Ok(IdempotentResult::Retried(IdempotentOutcome::Rejected(reason))) => {
+                return ack(AckStatus::Rejected, Some("retry"), Some(reason));
+            }
+            Err(e) => Err(e),
+        },
+    };
+    match result {
+        Ok(()) => ack(AckStatus::Accepted, None, None),
+        Err(e) => {
+            log::warn!(
+                "Error processing transaction id={} client={}: {}",
+                record.tx,
+                record.client,
+                e
+            );
+            ack(AckStatus::Rejected, Some(e.code()), Some(e.to_string()))
+        }
+    }
+}
+
+// Acknowledgement as a line in the format of the records
+fn ack_line(ack: &Ack, format: StreamFormat) -> Result<String, Box<dyn Error>> {
+    match format {
+        StreamFormat::Jsonl => Ok(serde_json::to_string(ack)? + "\n"),
+        StreamFormat::Csv => {
+            let mut wtr = csv::WriterBuilder::new()
+                .has_headers(false)
+                .from_writer(vec![]);
+            wtr.serialize(ack)?;
+            Ok(String::from_utf8(wtr.into_inner()?)?)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn csv(
+        ledger: &Mutex<Ledger>,
+        headers: &mut Option<StringRecord>,
+        line: &str,
+    ) -> Option<String> {
+        process_line(ledger, StreamFormat::Csv, headers, line.as_bytes()).unwrap()
+    }
+
+    // Acknowledgement of a JSON line, parsed back
+    fn json(ledger: &Mutex<Ledger>, line: &str) -> serde_json::Value {
+        let ack = process_line(ledger, StreamFormat::Jsonl, &mut None, line.as_bytes())
+            .unwrap()
+            .unwrap();
+        serde_json::from_str(&ack).unwrap()
+    }
+
+    #[test]
+    fn test_that_csv_header_is_kept_and_records_are_acknowledged() {
+        let ledger = Mutex::new(Ledger::new());
+        let mut headers = None;
+
+        assert_eq!(
+            csv(&ledger, &mut headers, "type, client, tx, amount\n"),
+            None
+        );
+        assert!(headers.is_some());
+        assert_eq!(
+            csv(&ledger, &mut headers, "deposit, 1, 1, 10.0\n").unwrap(),
+            "accepted,1,1,,\n"
+        );
+    }
+
+    #[test]
+    fn test_that_blank_lines_are_not_acknowledged() {
+        let ledger = Mutex::new(Ledger::new());
+        let mut headers = None;
+
+        assert_eq!(csv(&ledger, &mut headers, "\n"), None);
+        assert_eq!(csv(&ledger, &mut headers, " \r\n"), None);
+        // Blank lines are not taken for the header
+        assert!(headers.is_none());
+    }
+
+    #[test]
+    fn test_that_rejected_record_is_acknowledged_with_its_code() {
+        let ledger = Mutex::new(Ledger::new());
+        let mut headers = None;
+        csv(&ledger, &mut headers, "type, client, tx, amount\n");
+        csv(&ledger, &mut headers, "deposit, 1, 1, 10.0\n");
+
+        assert_eq!(
+            csv(&ledger, &mut headers, "withdrawal, 1, 2, 20.0\n").unwrap(),
+            "rejected,1,2,withdrawal_limit_exceeded,\
+             Account operation failed: Withdrawal limit exceeded (tx id 2)\n"
+        );
+    }
+
+    #[test]
+    fn test_that_invalid_record_is_acknowledged_without_client() {
+        let ledger = Mutex::new(Ledger::new());
+        let mut headers = None;
+        csv(&ledger, &mut headers, "type, client, tx, amount\n");
+
+        assert!(
+            csv(&ledger, &mut headers, "refund, 1, 3,\n")
+                .unwrap()
+                .starts_with("rejected,,,invalid_record,")
+        );
+        let ack = json(&ledger, "{\"type\": \"deposit\"}");
+        assert_eq!(ack["status"], "rejected");
+        assert_eq!(ack["client"], serde_json::Value::Null);
+        assert_eq!(ack["code"], "invalid_record");
+    }
+
+    #[test]
+    fn test_that_json_records_are_acknowledged_in_json() {
+        let ledger = Mutex::new(Ledger::new());
+
+        let ack = json(
+            &ledger,
+            r#"{"type": "deposit", "client": 2, "tx": 1, "amount": "1"}"#,
+        );
+        assert_eq!(ack["status"], "accepted");
+        assert_eq!(ack["client"], 2);
+        assert_eq!(ack["tx"], 1);
+        let ack = json(
+            &ledger,
+            r#"{"type": "deposit", "client": 2, "tx": 1, "amount": "1"}"#,
+        );
+        assert_eq!(ack["status"], "rejected");
+        assert_eq!(ack["code"], "duplicate_tx_id");
+    }
+
+    #[test]
+    fn test_that_retried_record_is_acknowledged_with_its_first_outcome() {
+        let ledger = Mutex::new(Ledger::new());
+        let deposit =
+            r#"{"type": "deposit", "client": 1, "tx": 1, "amount": "1", "idempotency_key": "a"}"#;
+        let withdrawal = r#"{"type": "withdrawal", "client": 1, "tx": 2, "amount": "5", "idempotency_key": "b"}"#;
+
+        assert_eq!(json(&ledger, deposit)["code"], serde_json::Value::Null);
+        let ack = json(&ledger, deposit);
+        assert_eq!(
+            (&ack["status"], &ack["code"]),
+            (&"accepted".into(), &"retry".into())
+        );
+
+        assert_eq!(json(&ledger, withdrawal)["status"], "rejected");
+        let ack = json(&ledger, withdrawal);
+        assert_eq!(
+            (&ack["status"], &ack["code"]),
+            (&"rejected".into(), &"retry".into())
+        );
+        assert_eq!(
+            ack["reason"],
+            "Account operation failed: Withdrawal limit exceeded (tx id 2)"
+        );
+    }
+
+    #[test]
+    fn test_that_other_record_under_a_used_key_is_rejected() {
+        let ledger = Mutex::new(Ledger::new());
+        json(
+            &ledger,
+            r#"{"type": "deposit", "client": 1, "tx": 1, "amount": "1", "idempotency_key": "a"}"#,
+        );
+
+        let ack = json(
+            &ledger,
+            r#"{"type": "deposit", "client": 1, "tx": 1, "amount": "2", "idempotency_key": "a"}"#,
+        );
+        assert_eq!(ack["status"], "rejected");
+        assert_eq!(ack["code"], "idempotency_conflict");
+    }
+
+    #[test]
+    fn test_that_batches_are_rejected() {
+        let ledger = Mutex::new(Ledger::new());
+
+        let ack = json(
+            &ledger,
+            r#"{"type": "deposit", "client": 1, "tx": 1, "amount": "1", "batch_id": "b"}"#,
+        );
+        assert_eq!(ack["status"], "rejected");
+        assert_eq!(ack["code"], "unsupported");
+    }
+}
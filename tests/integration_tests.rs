@@ -23,10 +23,14 @@ fn test_integration() {
         assert!(input_path.exists());
         assert!(expected_output_path.exists());
 
+        // Optional command line options for this case
+        let options = fs::read_to_string(case_dir.join("args")).unwrap_or_default();
+
         // Execute the cargo run command
         let output = Command::new("cargo")
             .arg("run")
             .arg("--")
+            .args(options.split_whitespace())
             .arg(input_path)
             .output()
             .expect("failed to execute cargo run");
@@ -65,3 +69,71 @@ fn test_integration() {
         assert_eq!(generated_res, expected_res);
     }
 }
+
+#[test]
+fn test_history_command() {
+    let input_path = PathBuf::from("./tests/files/test_3/input.csv");
+
+    let output = Command::new("cargo")
+        .arg("run")
+        .arg("--")
+        .arg("history")
+        .arg("1")
+        .arg(&input_path)
+        .output()
+        .expect("failed to execute cargo run");
+
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert_eq!(
+        stdout,
+        "sequence,timestamp,client,tx,type,amount,status\n\
+         1,,1,1,deposit,5.0000,applied\n\
+         2,,1,2,deposit,2.0000,resolved\n\
+         6,,1,4,withdrawal,1.0000,applied\n"
+    );
+
+    // Lifecycle of the disputed then resolved deposit
+    let output = Command::new("cargo")
+        .arg("run")
+        .arg("--")
+        .arg("history")
+        .arg("1")
+        .arg("--tx")
+        .arg("2")
+        .arg(&input_path)
+        .output()
+        .expect("failed to execute cargo run");
+
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert_eq!(
+        stdout,
+        "sequence,timestamp,event\n2,,applied\n4,,disputed\n5,,resolved\n"
+    );
+}
+
+#[test]
+fn test_alerts_file() {
+    let case_dir = PathBuf::from("./tests/files/test_25");
+    // Apart from the alerts file written by the test_25 case of `test_integration`
+    let alerts_path = PathBuf::from("target/test_alerts_file.csv");
+
+    let output = Command::new("cargo")
+        .arg("run")
+        .arg("--")
+        .arg("--detectors")
+        .arg(case_dir.join("detectors.toml"))
+        .arg("--alerts")
+        .arg(&alerts_path)
+        .arg(case_dir.join("input.csv"))
+        .output()
+        .expect("failed to execute cargo run");
+
+    assert!(output.status.success());
+    // Alerts of blocked transactions are written too
+    assert_eq!(
+        fs::read_to_string(&alerts_path).unwrap(),
+        fs::read_to_string(case_dir.join("alerts.csv")).unwrap()
+    );
+}